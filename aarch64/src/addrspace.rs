@@ -0,0 +1,436 @@
+//! Per-process user address spaces.  Each address space owns its own root
+//! page table, which is installed in TTBR0 along with an ASID when the address
+//! space is switched to.  Translations are tagged with the ASID, so switching
+//! between address spaces doesn't require a TLB flush.
+
+use crate::param::KZERO;
+use crate::vm::{
+    self, Entry, PageTableError, RootPageTable, RootPageTableType, UnmapPages, VaMapping,
+};
+use crate::{kmem, pagealloc};
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::ops::Range;
+use port::asid::{Asid, AsidAllocator};
+use port::elf::EM_AARCH64;
+use port::exec::{Perm, USTACK_SIZE, UserSpace};
+use port::mcslock::{ILock, Lock};
+use port::mem::{PAGE_SIZE_4K, PhysAddr, PhysRange, VirtRange};
+use port::pagealloc::PageAllocError;
+use port::pgrp::UserMem;
+use port::sys9::{self, Abi};
+use port::usermem::Segments;
+
+#[cfg(not(test))]
+use port::println;
+
+/// TCR_EL1.AS is clear, so we have 8-bit ASIDs.
+static ASIDS: Lock<AsidAllocator<4>> = Lock::new("asids", AsidAllocator::new());
+
+/// User virtual addresses must be below this.  The last entry of the root page
+/// table is the recursive entry, so the top 512GiB of the TTBR0 range isn't
+/// available.
+pub const USER_VA_END: usize = 511 << 39;
+
+/// Bottom of the stack that exec maps at the top of user space.  A child
+/// gets its own copy of the stack even when it shares memory.
+const USER_STACK: usize = USER_VA_END - USTACK_SIZE;
+
+/// A range of a child address space, and how it's made.
+struct ForkRange {
+    range: Range<usize>,
+    perm: Perm,
+    shared: Option<Vec<PhysAddr>>, // The parent's pages, if shared, else copied
+}
+
+pub struct AddressSpace {
+    root: &'static mut RootPageTable,
+    asid: Asid,
+    segments: Segments,       // What the user may access, as set by protect
+    owned: Vec<Range<usize>>, // Pages allocated by map, freed on drop
+}
+
+impl AddressSpace {
+    /// Create an empty address space.  An ASID isn't allocated until the
+    /// address space is first switched to.
+    pub fn new() -> Result<Self, PageAllocError> {
+        let page = pagealloc::allocate_virtpage(
+            vm::kernel_pagetable(),
+            "uroot",
+            Entry::rw_kernel_data(),
+            VaMapping::Offset(KZERO),
+            RootPageTableType::Kernel,
+        )?;
+        let root = unsafe { &mut *(page as *mut vm::VirtPage4K as *mut RootPageTable) };
+        root.entries.fill(Entry::empty());
+        unsafe { vm::init_empty_root_page_table(root, RootPageTableType::User) };
+        Ok(Self { root, asid: Asid::stale(), segments: Segments::new(), owned: Vec::new() })
+    }
+
+    /// The root page table, for use with functions that map pages into it.
+    /// Mappings must be below `USER_VA_END`.
+    #[allow(dead_code)]
+    pub fn page_table(&mut self) -> &mut RootPageTable {
+        self.root
+    }
+
+    /// Map the physical range at va, using the largest pages possible.  The
+    /// pages remain owned by the caller, and aren't freed with the address
+    /// space.
+    #[allow(dead_code)]
+    pub fn map_phys_range(
+        &mut self,
+        debug_name: &str,
+        range: &PhysRange,
+        va: usize,
+        entry: Entry,
+    ) -> Result<VirtRange, PageTableError> {
+        if va.checked_add(range.size()).is_none_or(|end| end > USER_VA_END) {
+            println!("error:addrspace:map_phys_range:range beyond user space. va:{va:#x}");
+            return Err(PageTableError::OutOfVirtualSpace);
+        }
+        self.root.map_phys_range_auto(
+            debug_name,
+            range,
+            VaMapping::Addr(va),
+            entry,
+            RootPageTableType::User,
+        )
+    }
+
+    /// Make this the current user address space, allocating an ASID if it
+    /// doesn't have a current one.  Only a rollover of the ASIDs requires the
+    /// TLB to be flushed.
+    pub fn switch_to(&mut self) {
+        let mut asids = ASIDS.lock();
+        if asids.refresh(&mut self.asid) {
+            unsafe { vm::invalidate_all_tlb_entries() };
+        }
+        unsafe { vm::switch_user(self.root, self.asid.id()) };
+    }
+
+    /// The pages the user may access, and how.
+    pub fn segments(&self) -> &Segments {
+        &self.segments
+    }
+
+    /// Record that the page at va was allocated for this address space.
+    fn own(&mut self, va: usize) {
+        match self.owned.last_mut() {
+            Some(last) if last.end == va => last.end += PAGE_SIZE_4K,
+            _ => self.owned.push(va..va + PAGE_SIZE_4K),
+        }
+    }
+
+    fn is_current(&self) -> bool {
+        vm::ttbr0_el1() == kmem::from_ptr_to_physaddr_offset_from_kzero(&*self.root)
+    }
+
+    /// What a child of this address space, which must be current, is made
+    /// of: the ranges the user may access, with the pages of those it shares
+    /// if share is set.  Taken under the address space's lock, leaving the
+    /// copying to `fork_from`.
+    fn fork_plan(&mut self, share: bool) -> port::Result<Vec<ForkRange>> {
+        assert!(self.is_current(), "forking an address space that isn't current");
+        let stack = if share { USER_STACK } else { 0 };
+        let mut plan = Vec::new();
+        for (range, perm, shared) in self.segments.fork_shared(stack) {
+            let shared = if shared {
+                let pages = range.clone().step_by(PAGE_SIZE_4K).map(|va| {
+                    self.root.phys_addr_of(va, RootPageTableType::User).map_err(|err| {
+                        println!("error:addrspace:fork_plan:couldn't find {va:#x}: {err:?}");
+                        "rfork: couldn't share user memory"
+                    })
+                });
+                Some(pages.collect::<port::Result<_>>()?)
+            } else {
+                None
+            };
+            plan.push(ForkRange { range, perm, shared });
+        }
+        Ok(plan)
+    }
+
+    /// A child of the current address space, made as planned by
+    /// `fork_plan`.  Copying is done with interrupts enabled, but making
+    /// the child's mappings borrows the recursive entry of the current
+    /// address space, so it's done holding parent, its lock.
+    fn fork_from(plan: &[ForkRange], parent: &ILock<AddressSpace>) -> port::Result<AddressSpace> {
+        let mut child = AddressSpace::new().map_err(|_| "rfork: out of memory")?;
+        for ForkRange { range, perm, shared } in plan {
+            let vas = range.clone().step_by(PAGE_SIZE_4K);
+            match shared {
+                Some(pages) => {
+                    for (va, pa) in vas.zip(pages) {
+                        child.map_page(va, *pa, *perm, parent).map_err(|err| {
+                            println!("error:addrspace:fork_from:couldn't map {va:#x}: {err:?}");
+                            "rfork: couldn't share user memory"
+                        })?;
+                    }
+                }
+                None => {
+                    for va in vas {
+                        child.copy_page(va, *perm, parent)?;
+                    }
+                }
+            }
+            child.segments.protect(range.clone(), *perm);
+        }
+        Ok(child)
+    }
+
+    /// Map the page at pa at va, where the user has perm on it, holding
+    /// parent, the lock of the current address space.
+    fn map_page(
+        &mut self,
+        va: usize,
+        pa: PhysAddr,
+        perm: Perm,
+        parent: &ILock<AddressSpace>,
+    ) -> Result<VirtRange, PageTableError> {
+        let _parent = parent.lock();
+        self.root.map_phys_range(
+            "user",
+            &PhysRange::with_pa_len(pa, PAGE_SIZE_4K),
+            VaMapping::Addr(va),
+            user_entry(perm),
+            vm::PageSize::Page4K,
+            RootPageTableType::User,
+        )
+    }
+
+    /// Map a copy of the page at va in the current address space, whose
+    /// lock is parent, at the same va in this one, where the user has perm
+    /// on it.
+    fn copy_page(
+        &mut self,
+        va: usize,
+        perm: Perm,
+        parent: &ILock<AddressSpace>,
+    ) -> port::Result<()> {
+        let page = pagealloc::allocate_virtpage(
+            vm::kernel_pagetable(),
+            "ufork",
+            Entry::rw_kernel_data(),
+            VaMapping::Offset(KZERO),
+            RootPageTableType::Kernel,
+        )
+        .map_err(|_| "rfork: out of memory")?;
+        unsafe {
+            core::ptr::copy_nonoverlapping(va as *const u8, page.0.as_mut_ptr(), PAGE_SIZE_4K)
+        };
+        let kva = VirtRange::with_len((&*page as *const vm::VirtPage4K).addr(), PAGE_SIZE_4K);
+        if perm.contains(Perm::X) {
+            sync_icache(&kva);
+        }
+        let pa = kmem::from_ptr_to_physaddr_offset_from_kzero(&*page);
+        let mapped = self.map_page(va, pa, perm, parent);
+        // Once it's mapped, the page belongs to this address space
+        let pages = if mapped.is_ok() { UnmapPages::Keep } else { UnmapPages::Free };
+        if let Err(err) = vm::kernel_pagetable().unmap_range(&kva, RootPageTableType::Kernel, pages)
+        {
+            println!("error:addrspace:copy_page:couldn't unmap kernel page: {err:?}");
+        }
+        mapped.map_err(|err| {
+            println!("error:addrspace:copy_page:couldn't map {va:#x}: {err:?}");
+            "rfork: couldn't copy user memory"
+        })?;
+        self.own(va);
+        Ok(())
+    }
+}
+
+/// The page table entry giving the user perm.
+fn user_entry(perm: Perm) -> Entry {
+    match (perm.contains(Perm::W), perm.contains(Perm::X)) {
+        (false, false) => Entry::ro_user_data(),
+        (true, false) => Entry::rw_user_data(),
+        (false, true) => Entry::ro_user_text(),
+        (true, true) => Entry::rw_user_text(),
+    }
+}
+
+/// Programs are loaded into the current address space, through its user
+/// mappings, which are only made read-only once they've been filled.
+impl UserSpace for AddressSpace {
+    const ELF_MACHINE: u16 = EM_AARCH64;
+    const AOUT: Option<Abi> = Some(sys9::ARM64);
+
+    fn end(&self) -> usize {
+        USER_VA_END
+    }
+
+    fn map(&mut self, range: &VirtRange) -> port::Result<()> {
+        assert!(self.is_current(), "loading into an address space that isn't current");
+        for va in range.0.clone().step_by(PAGE_SIZE_4K) {
+            let page = pagealloc::allocate_virtpage(
+                self.root,
+                "user",
+                Entry::rw_user_data(),
+                VaMapping::Addr(va),
+                RootPageTableType::User,
+            )
+            .map_err(|_| "exec: out of memory")?;
+            page.0.fill(0);
+            self.own(va);
+        }
+        Ok(())
+    }
+
+    fn copy_to(&mut self, va: usize, data: &[u8]) -> port::Result<()> {
+        assert!(self.is_current(), "loading into an address space that isn't current");
+        unsafe { core::ptr::copy_nonoverlapping(data.as_ptr(), va as *mut u8, data.len()) };
+        Ok(())
+    }
+
+    fn protect(&mut self, range: &VirtRange, perm: Perm) -> port::Result<()> {
+        if perm.contains(Perm::X) {
+            sync_icache(range);
+        }
+        self.root
+            .protect_range(range, user_entry(perm), vm::PageSize::Page4K, RootPageTableType::User)
+            .map_err(|err| {
+                println!("error:addrspace:protect:couldn't protect {range}: {err:?}");
+                "exec: couldn't set permissions"
+            })?;
+        self.segments.protect(range.0.clone(), perm);
+        Ok(())
+    }
+}
+
+/// An address space as a process's user memory.  It's locked only briefly,
+/// by the scheduler switching to it, by each step of loading a program into
+/// it, and by forking, which copies pages without the lock.  The lock is an
+/// ILock, so a process holding it can't be preempted by one that wants it.
+///
+/// A child sharing memory with its parent gets its own address space,
+/// mapping the parent's pages but for the stack, and keeps the parent's
+/// alive until it's done with those pages.
+pub struct UserAddressSpace {
+    space: ILock<AddressSpace>,
+    _parent: Option<Arc<UserAddressSpace>>, // Kept until space is unmapped
+}
+
+impl UserAddressSpace {
+    pub fn new() -> Result<Self, PageAllocError> {
+        Ok(Self::with_parent(AddressSpace::new()?, None))
+    }
+
+    fn with_parent(space: AddressSpace, parent: Option<Arc<UserAddressSpace>>) -> Self {
+        Self { space: ILock::new("addrspace", space), _parent: parent }
+    }
+}
+
+impl UserMem for UserAddressSpace {
+    fn switch_to(&self) {
+        self.space.lock().switch_to();
+    }
+
+    fn validaddr(&self, va: usize, len: usize, perm: Perm) -> port::Result<()> {
+        self.space.lock().segments().check(va, len, perm)
+    }
+
+    /// Called by the process forking, so the address space is current.
+    fn fork(&self) -> port::Result<Arc<dyn UserMem>> {
+        let plan = self.space.lock().fork_plan(false)?;
+        let child = AddressSpace::fork_from(&plan, &self.space)?;
+        Ok(Arc::new(Self::with_parent(child, None)))
+    }
+
+    /// Called by the process forking, so the address space is current.
+    fn fork_shared(self: Arc<Self>) -> port::Result<Arc<dyn UserMem>> {
+        let plan = self.space.lock().fork_plan(true)?;
+        let child = AddressSpace::fork_from(&plan, &self.space)?;
+        Ok(Arc::new(Self::with_parent(child, Some(self))))
+    }
+}
+
+/// Programs are loaded into a process's address space once it's been
+/// installed as the process's memory, so the process can be preempted
+/// between steps.
+impl UserSpace for &UserAddressSpace {
+    const ELF_MACHINE: u16 = AddressSpace::ELF_MACHINE;
+    const AOUT: Option<Abi> = AddressSpace::AOUT;
+
+    fn end(&self) -> usize {
+        self.space.lock().end()
+    }
+
+    fn map(&mut self, range: &VirtRange) -> port::Result<()> {
+        self.space.lock().map(range)
+    }
+
+    fn copy_to(&mut self, va: usize, data: &[u8]) -> port::Result<()> {
+        self.space.lock().copy_to(va, data)
+    }
+
+    fn protect(&mut self, range: &VirtRange, perm: Perm) -> port::Result<()> {
+        self.space.lock().protect(range, perm)
+    }
+}
+
+/// Make instructions written to the range through the data cache visible to
+/// instruction fetches.
+fn sync_icache(range: &VirtRange) {
+    #[cfg(not(test))]
+    unsafe {
+        use core::arch::asm;
+        let ctr: usize;
+        asm!("mrs {}, ctr_el0", out(reg) ctr);
+        let dline = 4 << ((ctr >> 16) & 0xf);
+        let iline = 4 << (ctr & 0xf);
+        for va in range.0.clone().step_by(dline) {
+            asm!("dc cvau, {}", in(reg) va);
+        }
+        asm!("dsb ish");
+        for va in range.0.clone().step_by(iline) {
+            asm!("ic ivau, {}", in(reg) va);
+        }
+        asm!("dsb ish", "isb");
+    }
+    #[cfg(test)]
+    let _ = range;
+}
+
+impl Drop for AddressSpace {
+    /// Free the pages the address space allocated, its page tables and its
+    /// ASID.  Pages that were mapped into it from elsewhere are only unmapped.
+    fn drop(&mut self) {
+        if self.is_current() {
+            unsafe { vm::switch_user(vm::user_pagetable(), 0) };
+        }
+
+        for range in self.owned.drain(..) {
+            if let Err(err) =
+                self.root.unmap_range(&VirtRange(range), RootPageTableType::User, UnmapPages::Free)
+            {
+                println!("error:addrspace:drop:couldn't free user pages: {err:?}");
+            }
+        }
+        let user_range = VirtRange(0..USER_VA_END);
+        if let Err(err) =
+            self.root.unmap_range(&user_range, RootPageTableType::User, UnmapPages::Keep)
+        {
+            println!("error:addrspace:drop:couldn't unmap user space: {err:?}");
+        }
+
+        {
+            let mut asids = ASIDS.lock();
+            if asids.is_current(self.asid) {
+                // Stale translations must be gone before the ASID is reused
+                unsafe { vm::invalidate_tlb_asid(self.asid.id()) };
+                asids.free(self.asid);
+            }
+        }
+
+        let root_range =
+            VirtRange::with_len((&*self.root as *const RootPageTable).addr(), PAGE_SIZE_4K);
+        if let Err(err) = vm::kernel_pagetable().unmap_range(
+            &root_range,
+            RootPageTableType::Kernel,
+            UnmapPages::Free,
+        ) {
+            println!("error:addrspace:drop:couldn't free root page table: {err:?}");
+        }
+    }
+}
@@ -1,17 +1,93 @@
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use port::allocator::Block;
+use port::maths::round_up2_usize;
+use port::mem::{PAGE_SIZE_4K, VirtRange};
+
+use crate::pagealloc;
+use crate::param::KZERO;
+use crate::vm::{self, Entry, RootPageTableType, UnmapPages, VaMapping};
+
+#[cfg(not(test))]
+use port::println;
+
+/// The heap starts out in the statically allocated GlobalHeap.  Once that's
+/// exhausted, it grows by mapping pages from the page allocator into this
+/// region of kernel virtual address space.
+const HEAP_BASE: usize = KZERO + 0x0800_0000_0000;
+const HEAP_SIZE: usize = 0x10_0000_0000;
+
+/// Minimum number of bytes to grow the heap by at a time.
+const HEAP_GROW_MIN: usize = 256 * 1024;
+
+static HEAP_GROWTH_ENABLED: AtomicBool = AtomicBool::new(false);
+static HEAP_MAPPED: AtomicUsize = AtomicUsize::new(0);
+
+/// Allow the heap to grow beyond the GlobalHeap.  Must only be called once
+/// the kernel page tables are in use, and the page allocator is initialised.
+pub fn enable_heap_growth() {
+    HEAP_GROWTH_ENABLED.store(true, Ordering::Release);
+}
+
+/// Return the number of bytes the heap has grown by.
+pub fn heap_growth_bytes() -> usize {
+    HEAP_MAPPED.load(Ordering::Relaxed)
+}
+
+/// Map at least min_len more bytes of heap.  Called with the global allocator
+/// held, so must not allocate from the heap.
+#[cfg_attr(test, allow(dead_code))]
+fn grow_heap(min_len: usize) -> Option<Block> {
+    if !HEAP_GROWTH_ENABLED.load(Ordering::Acquire) {
+        return None;
+    }
+
+    let len = round_up2_usize(usize::max(min_len, HEAP_GROW_MIN), PAGE_SIZE_4K);
+    let mapped = HEAP_MAPPED.load(Ordering::Relaxed);
+    if mapped.checked_add(len).is_none_or(|end| end > HEAP_SIZE) {
+        println!("error:allocator:grow_heap:heap region exhausted");
+        return None;
+    }
+
+    let start = HEAP_BASE + mapped;
+    for va in (start..start + len).step_by(PAGE_SIZE_4K) {
+        let page = pagealloc::allocate_virtpage(
+            vm::kernel_pagetable(),
+            "heap",
+            Entry::rw_kernel_data(),
+            VaMapping::Addr(va),
+            RootPageTableType::Kernel,
+        );
+        if page.is_err() {
+            // Give back whatever we managed to map
+            let _ = vm::kernel_pagetable().unmap_range(
+                &VirtRange(start..va),
+                RootPageTableType::Kernel,
+                UnmapPages::Free,
+            );
+            return None;
+        }
+    }
+
+    HEAP_MAPPED.store(mapped + len, Ordering::Relaxed);
+    Some(unsafe { Block::new_from_raw_parts(start as *mut u8, len) })
+}
+
 #[cfg(not(test))]
 mod global {
     use core::mem;
-    use core::sync::atomic::AtomicPtr;
     use port::allocator::{
         Block, BumpAlloc, QuickFit, global::GlobalHeap, global::GlobalQuickAlloc,
     };
 
     #[global_allocator]
-    static GLOBAL_ALLOCATOR: GlobalQuickAlloc = GlobalQuickAlloc(AtomicPtr::new({
-        static mut HEAP: GlobalHeap = GlobalHeap::new();
-        static mut ALLOC: QuickFit = QuickFit::new(BumpAlloc::new(unsafe {
-            Block::new_from_raw_parts((&raw mut HEAP).cast(), mem::size_of::<GlobalHeap>())
-        }));
-        &raw mut ALLOC
-    }));
+    static GLOBAL_ALLOCATOR: GlobalQuickAlloc =
+        GlobalQuickAlloc::new(QuickFit::new(BumpAlloc::new_growable(
+            {
+                static mut HEAP: GlobalHeap = GlobalHeap::new();
+                unsafe {
+                    Block::new_from_raw_parts((&raw mut HEAP).cast(), mem::size_of::<GlobalHeap>())
+                }
+            },
+            super::grow_heap,
+        )));
 }
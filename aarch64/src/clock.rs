@@ -0,0 +1,84 @@
+/// The clock that drives the scheduler, from each core's generic timer.
+/// The EL1 physical timer is set to fire HZ times a second, and its
+/// interrupt is routed to the core by the BCM2836 local interrupt controller,
+/// as on the Raspberry Pi 3.  The Pi 4 routes the timers through its GIC
+/// instead, which isn't supported yet, so there's no clock there, and
+/// processes are never preempted.
+use crate::deviceutil::map_device_register;
+use crate::io::{read_reg, write_or_reg};
+use aarch64_cpu::registers::{CNTFRQ_EL0, CNTP_CTL_EL0, CNTP_TVAL_EL0, Readable, Writeable};
+use port::fdt::DeviceTree;
+use port::mcslock::Lock;
+use port::mem::{PhysRange, VirtRange};
+use port::println;
+use port::sched::{self, HZ};
+
+/// Per-core registers of the local interrupt controller, 4 bytes apart.
+const CORE_TIMER_CTL: usize = 0x40; // Which timers interrupt the core
+const CORE_IRQ_SOURCE: usize = 0x60; // Which sources are interrupting it
+
+/// The EL1 physical timer's bit in both registers.
+const CNTPNSIRQ: u32 = 1 << 1;
+
+static LOCAL_INTC: Lock<Option<VirtRange>> = Lock::new("local_intc", None);
+
+/// Find the local interrupt controller, if the timers are routed through
+/// it, and start this core's clock.
+pub fn init(dt: &DeviceTree) {
+    let Some(physrange) = find_local_intc(dt) else {
+        println!("clock: no BCM2836 local interrupt controller for the timers, so no clock");
+        return;
+    };
+    let intc = match map_device_register("local_intc", physrange) {
+        Ok(intc) => intc,
+        Err(msg) => {
+            println!("clock: can't map local interrupt controller: {msg}");
+            return;
+        }
+    };
+    write_or_reg(&intc, CORE_TIMER_CTL + 4 * core(), CNTPNSIRQ);
+    *LOCAL_INTC.lock() = Some(intc);
+    reload();
+    CNTP_CTL_EL0.write(CNTP_CTL_EL0::ENABLE::SET + CNTP_CTL_EL0::IMASK::CLEAR);
+}
+
+/// The local interrupt controller's registers, if it's the timer interrupts'
+/// parent.
+fn find_local_intc(dt: &DeviceTree) -> Option<PhysRange> {
+    let intc = dt.find_compatible("brcm,bcm2836-l1-intc").next()?;
+    let phandle = dt.property(&intc, "phandle").and_then(|p| dt.property_value_as_u32(&p))?;
+    let timer = dt
+        .find_compatible("arm,armv7-timer")
+        .chain(dt.find_compatible("arm,armv8-timer"))
+        .next()?;
+    let parent =
+        dt.property(&timer, "interrupt-parent").and_then(|p| dt.property_value_as_u32(&p))?;
+    if parent != phandle {
+        return None;
+    }
+    let reg = dt.property_translated_reg_iter(intc).next()?.regblock()?;
+    Some(PhysRange::from(&reg))
+}
+
+fn core() -> usize {
+    port::cpu::cpu_id()
+}
+
+/// Have the timer fire again in a tick's time.
+fn reload() {
+    CNTP_TVAL_EL0.set(CNTFRQ_EL0.get() / HZ as u64);
+}
+
+/// Handle an IRQ if it's the clock, returning whether it was.  Interrupts
+/// are disabled.
+pub fn interrupt() -> bool {
+    let pending = match LOCAL_INTC.lock().as_ref() {
+        Some(intc) => read_reg(intc, CORE_IRQ_SOURCE + 4 * core()) & CNTPNSIRQ != 0,
+        None => false,
+    };
+    if pending {
+        reload();
+        sched::tick();
+    }
+    pending
+}
@@ -1,30 +1,12 @@
 use port::Result;
 use port::mem::{PhysRange, VirtRange};
 
-use crate::{pagealloc, vm};
+use crate::{pagealloc, vm, vmap};
 
 /// Map a device register to device memory
 /// TODO Maybe make this a macro and wrap the error reporting?
-pub fn map_device_register(
-    id: &'static str,
-    physrange: PhysRange,
-    page_size: vm::PageSize,
-) -> Result<VirtRange> {
-    let page_physrange = physrange.round(page_size.size());
-
-    if let Ok(vr) = vm::kernel_pagetable().map_phys_range(
-        id,
-        &page_physrange,
-        vm::next_free_device_page4k(),
-        vm::Entry::rw_device(),
-        page_size,
-        vm::RootPageTableType::Kernel,
-    ) {
-        let offset = vr.start() - page_physrange.start().addr() as usize;
-        Ok(VirtRange::from_physrange(&physrange, offset))
-    } else {
-        Err("failed to map device register")
-    }
+pub fn map_device_register(id: &'static str, physrange: PhysRange) -> Result<VirtRange> {
+    vmap::ioremap(id, &physrange).map_err(|_| "failed to map device register")
 }
 
 /// Map a buffer to device memory
@@ -36,14 +18,7 @@ pub fn alloc_device_page(
     let page_pa = pagealloc::allocate_physpage().expect("couldn't allocate page");
     let page_physrange = PhysRange::with_pa_len(page_pa, page_size.size());
 
-    if let Ok(vr) = vm::kernel_pagetable().map_phys_range(
-        id,
-        &page_physrange,
-        vm::next_free_device_page4k(),
-        vm::Entry::rw_device(),
-        page_size,
-        vm::RootPageTableType::Kernel,
-    ) {
+    if let Ok(vr) = vmap::vmap(id, &page_physrange, vm::Entry::rw_device()) {
         Ok((vr, page_physrange))
     } else {
         Err("failed to map device buffer")
@@ -0,0 +1,124 @@
+/// Loading and starting user programs.  Each program is loaded into a new
+/// address space, which replaces the process's user memory.
+use crate::addrspace::UserAddressSpace;
+use alloc::sync::Arc;
+use port::exec::{Perm, Start};
+use port::sched;
+use port::usermem::EBADADDR;
+
+#[cfg(not(test))]
+use port::println;
+
+/// The first user program, which makes the sysr1 system call, then exits.
+/// It's an ELF header, then one program header for a read-only, executable
+/// segment at 0x1000, then the segment's 24 bytes of text:
+///
+/// ```text
+/// mov x0, #0          // sysr1()
+/// svc #0
+/// str xzr, [sp, #8]   // exits(nil)
+/// mov x0, #8
+/// svc #0
+/// b .
+/// ```
+static INITCODE: [u8; 144] = [
+    0x7f, 0x45, 0x4c, 0x46, 0x02, 0x01, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+    0x02, 0x00, 0xb7, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x10, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+    0x40, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+    0x00, 0x00, 0x00, 0x00, 0x40, 0x00, 0x38, 0x00, 0x01, 0x00, 0x40, 0x00, 0x00, 0x00, 0x00, 0x00,
+    0x01, 0x00, 0x00, 0x00, 0x05, 0x00, 0x00, 0x00, 0x78, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+    0x00, 0x10, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x10, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+    0x18, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x18, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+    0x00, 0x10, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x80, 0xd2, 0x01, 0x00, 0x00, 0xd4,
+    0xff, 0x07, 0x00, 0xf9, 0x00, 0x01, 0x80, 0xd2, 0x01, 0x00, 0x00, 0xd4, 0x00, 0x00, 0x00, 0x14,
+];
+
+/// Make the programs built into the kernel available to exec.
+pub fn init() {
+    port::exec::register("/boot/init", &INITCODE);
+}
+
+/// Replace the current process's user memory with a new address space
+/// holding the named program, and switch to it.  The old one is kept if the
+/// program can't be loaded.
+pub fn exec(name: &[u8], argv: &[&[u8]]) -> port::Result<Start> {
+    let space = Arc::new(UserAddressSpace::new().map_err(|_| "exec: out of memory")?);
+    let old = sched::set_usermem(Some(space.clone()))?;
+    port::exec::exec(&mut &*space, name, argv).inspect_err(|_| {
+        // The new space switches itself out when it's dropped, if it's
+        // still current
+        let _ = sched::set_usermem(old);
+    })
+}
+
+/// Check a range against the segments of the current process's user memory.
+pub fn validaddr(va: usize, len: usize, perm: Perm) -> port::Result<()> {
+    sched::usermem().ok_or(EBADADDR)?.validaddr(va, len, perm)
+}
+
+/// Create the first user process, which runs /boot/init.
+#[cfg(not(test))]
+pub fn userinit() {
+    sched::userproc("init", initproc, 0).expect("couldn't create the init process");
+}
+
+#[cfg(not(test))]
+fn initproc(_: usize) {
+    println!("Starting /boot/init");
+    match exec(b"/boot/init", &[b"init"]) {
+        Ok(start) => touser(&start),
+        Err(err) => panic!("couldn't start /boot/init: {err}"),
+    }
+}
+
+/// Drop to EL0 at the program's entry point, on its stack, with argc in x0
+/// and argv in x1, or for a Plan 9 program, the Tos in x0.  The other
+/// registers are cleared so nothing leaks from the kernel, and interrupts
+/// are unmasked.
+#[cfg(not(test))]
+pub fn touser(start: &Start) -> ! {
+    unsafe {
+        core::arch::asm!(
+            "msr sp_el0, x3",
+            "msr elr_el1, x2",
+            "msr spsr_el1, xzr",
+            "mov x2, xzr", "mov x3, xzr", "mov x4, xzr", "mov x5, xzr",
+            "mov x6, xzr", "mov x7, xzr", "mov x8, xzr", "mov x9, xzr",
+            "mov x10, xzr", "mov x11, xzr", "mov x12, xzr", "mov x13, xzr",
+            "mov x14, xzr", "mov x15, xzr", "mov x16, xzr", "mov x17, xzr",
+            "mov x18, xzr", "mov x19, xzr", "mov x20, xzr", "mov x21, xzr",
+            "mov x22, xzr", "mov x23, xzr", "mov x24, xzr", "mov x25, xzr",
+            "mov x26, xzr", "mov x27, xzr", "mov x28, xzr", "mov x29, xzr",
+            "mov x30, xzr",
+            "eret",
+            in("x0") start.tos.unwrap_or(start.argc),
+            in("x1") start.argv,
+            in("x2") start.entry,
+            in("x3") start.sp,
+            options(noreturn),
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::addrspace::USER_VA_END;
+    use port::elf::{self, EM_AARCH64};
+
+    #[test]
+    fn initcode_is_a_valid_executable() {
+        let image = elf::parse(&INITCODE, EM_AARCH64).unwrap();
+        assert_eq!(image.entry, 0x1000);
+        assert_eq!(image.segments.len(), 1);
+        assert_eq!(image.segments[0].va, 0x1000);
+        assert_eq!(image.segments[0].data, &INITCODE[120..]);
+        assert_eq!(image.segments[0].perm, Perm::R | Perm::X);
+        image.validate(USER_VA_END).unwrap();
+    }
+
+    #[test]
+    fn no_user_addresses_before_exec() {
+        assert_eq!(validaddr(0x1000, 1, Perm::R), Err(EBADADDR));
+    }
+}
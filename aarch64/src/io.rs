@@ -26,7 +26,6 @@ pub fn write_reg(range: &VirtRange, offset: usize, val: u32) {
 /// Write val|old into the reg RegBlock at offset from reg.addr,
 /// where `old` is the existing value.
 /// Panics if offset is outside any range specified by reg.len.
-#[allow(dead_code)]
 pub fn write_or_reg(range: &VirtRange, offset: usize, val: u32) {
     let dst = range.offset_addr(offset).expect("offset outside bounds");
     unsafe {
@@ -5,7 +5,7 @@ use crate::io::{read_reg, write_reg};
 use crate::vm;
 use port::Result;
 use port::fdt::DeviceTree;
-use port::mcslock::{Lock, LockNode};
+use port::mcslock::Lock;
 use port::mem::{PhysAddr, PhysRange, VirtRange};
 
 #[cfg(not(test))]
@@ -25,8 +25,7 @@ static MAILBOX: Lock<Option<Mailbox>> = Lock::new("mailbox", None);
 pub fn init(dt: &DeviceTree) {
     match Mailbox::new(dt) {
         Ok(mbox) => {
-            let node = LockNode::new();
-            let mut mailbox = MAILBOX.lock(&node);
+            let mut mailbox = MAILBOX.lock();
             *mailbox = Some(mbox);
         }
         Err(msg) => {
@@ -51,7 +50,7 @@ impl Mailbox {
             deviceutil::alloc_device_page("mailboxbuf", vm::PageSize::Page4K)?;
 
         let mbox_physrange = Self::find_mbox_physrange(dt)?;
-        let mbox = match map_device_register("mailbox", mbox_physrange, vm::PageSize::Page4K) {
+        let mbox = match map_device_register("mailbox", mbox_physrange) {
             Ok(mbox_virtrange) => Ok(Mailbox { mbox_virtrange, req_buffer_va, req_buffer_pa }),
             Err(msg) => {
                 println!("can't map mailbox {:?}", msg);
@@ -144,9 +143,8 @@ where
     U: Copy,
 {
     let size = size_of::<Message<T, U>>() as u32;
-    let node = LockNode::new();
     MAILBOX
-        .lock(&node)
+        .lock()
         .as_mut()
         .map(|mb| {
             let msg = unsafe {
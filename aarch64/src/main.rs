@@ -7,9 +7,14 @@
 #![feature(sync_unsafe_cell)]
 #![forbid(unsafe_op_in_unsafe_fn)]
 
+mod addrspace;
 mod allocator;
+#[cfg(feature = "bench")]
+mod bench;
+mod clock;
 mod devcons;
 mod deviceutil;
+mod exec;
 mod io;
 mod kmem;
 mod mailbox;
@@ -20,13 +25,14 @@ mod swtch;
 mod trap;
 mod uartmini;
 mod uartpl011;
+mod usermem;
 mod vm;
+mod vmap;
 mod vmdebug;
 
 extern crate alloc;
 
 use alloc::boxed::Box;
-use core::ptr::null_mut;
 use kmem::{boottext_range, bss_range, data_range, rodata_range, text_range, total_kernel_range};
 use param::KZERO;
 use port::mem::{PhysRange, VirtRange};
@@ -57,6 +63,10 @@ fn print_memory_info() {
     let (used, total) = pagealloc::usage_bytes();
     println!("  Used:\t\t{used:#016x}");
     println!("  Total:\t{total:#016x}");
+    let (used, total) = vmap::usage_bytes();
+    println!("  VA Used:\t{used:#016x}");
+    println!("  VA Total:\t{total:#016x}");
+    println!("  Heap Growth:\t{:#016x}", allocator::heap_growth_bytes());
 }
 
 // https://github.com/raspberrypi/documentation/blob/develop/documentation/asciidoc/computers/raspberry-pi/revision-codes.adoc
@@ -104,6 +114,7 @@ fn print_stacks() {
 #[unsafe(no_mangle)]
 pub extern "C" fn main9(dtb_va: usize) {
     trap::init();
+    port::sched::init::<swtch::SchedArch>();
 
     // Parse the DTB before we set up memory so we can correctly map it
     let dt = unsafe { DeviceTree::from_usize(dtb_va).unwrap() };
@@ -132,13 +143,16 @@ pub extern "C" fn main9(dtb_va: usize) {
         vm::switch(vm::user_pagetable(), RootPageTableType::User);
     }
 
-    // From this point we can use the global allocator
+    // From this point we can use the global allocator, and it can grow
+    allocator::enable_heap_growth();
 
     devcons::init(&dt, false);
     mailbox::init(&dt);
 
     print_board_info();
     print_memory_info();
+    #[cfg(feature = "bench")]
+    bench::bench_map_phys_range();
 
     // vmdebug::print_recursive_tables(RootPageTableType::Kernel);
     // vmdebug::print_recursive_tables(RootPageTableType::User);
@@ -167,78 +181,19 @@ pub extern "C" fn main9(dtb_va: usize) {
     // vmdebug::print_recursive_tables(RootPageTableType::Kernel);
     // vmdebug::print_recursive_tables(RootPageTableType::User);
 
-    println!("Set up a user process");
-
-    test_sysexit();
-
     vmdebug::print_recursive_tables(RootPageTableType::Kernel);
     vmdebug::print_recursive_tables(RootPageTableType::User);
 
     let _b = Box::new("ddododo");
 
-    println!("looping now");
-
-    #[allow(clippy::empty_loop)]
-    loop {}
+    usermem::init();
+    exec::init();
+    clock::init(&dt);
+    #[cfg(not(test))]
+    {
+        exec::userinit();
+        port::sched::schedinit();
+    }
 }
 
 mod runtime;
-
-fn test_sysexit() {
-    let page_table = vm::user_pagetable();
-
-    // Allocate pages for a user process
-    let user_text = {
-        let user_text = pagealloc::allocate_virtpage(
-            page_table,
-            "usertext",
-            Entry::rw_user_text(),
-            VaMapping::Addr(0x1000),
-            RootPageTableType::User,
-        )
-        .expect("couldn't allocate user_text");
-
-        // Machine code and assembly to call syscall exit
-        //   00 00 80 D2    ; mov x0, #0
-        //   21 00 80 D2    ; mov x1, #1
-        //   61 00 00 D4    ; svc #3
-        let proc_text_bytes: [u8; 12] =
-            [0x00, 0x00, 0x80, 0xd2, 0x21, 0x00, 0x80, 0xd2, 0x61, 0x00, 0x00, 0xd4];
-        user_text.0[..proc_text_bytes.len()].copy_from_slice(&proc_text_bytes);
-        user_text
-    };
-    let user_text_va = user_text as *const _ as u64;
-
-    let user_stack = pagealloc::allocate_virtpage(
-        page_table,
-        "userstack",
-        Entry::rw_user_data(),
-        VaMapping::Addr(KZERO - 0x1000),
-        RootPageTableType::User,
-    )
-    .expect("couldn't allocate user_stack");
-
-    // Executing user process!
-    println!("Executing user process");
-    let proc_stack = unsafe { core::slice::from_raw_parts_mut(user_stack, 4096) };
-
-    // Initialise a Context struct on the process stack, at the end of the proc_stack_buffer.
-    let ps_addr = &proc_stack as *const _ as u64;
-    let proc_stack_initial_ctx = ps_addr + (4096 - size_of::<swtch::Context>()) as u64;
-    let proc_context_ptr: *mut swtch::Context = proc_stack_initial_ctx as *mut swtch::Context;
-
-    // Need to push a context object onto the stack, with x30 populated at the
-    // address of proc_textbuf
-    let proc_context_ref: &mut swtch::Context = unsafe { &mut *proc_context_ptr };
-    proc_context_ref.set_stack_pointer(&proc_context_ptr as *const _ as u64);
-    proc_context_ref.set_return(user_text_va);
-
-    let mut kernel_context: *mut swtch::Context = null_mut();
-    let kernel_context_ptr: *mut *mut swtch::Context = &mut kernel_context;
-
-    //println!("proc ctx: {:#?}", proc_context_ref);
-
-    unsafe { swtch::swtch(kernel_context_ptr, &*proc_context_ptr) };
-
-    //println!("x30: {:#016x}", proc_context_ref.x30);
-}
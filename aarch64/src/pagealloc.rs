@@ -17,10 +17,7 @@ use port::bitmapalloc::BitmapPageAlloc;
 use port::mem::PhysAddr;
 use port::mem::PhysRange;
 use port::pagealloc::PageAllocError;
-use port::{
-    mcslock::{Lock, LockNode},
-    mem::PAGE_SIZE_4K,
-};
+use port::{mcslock::Lock, mem::PAGE_SIZE_4K};
 
 #[cfg(not(test))]
 use port::println;
@@ -36,8 +33,7 @@ static PAGE_ALLOC: Lock<BitmapPageAlloc<32, PAGE_SIZE_4K>> = Lock::new(
 /// a memory map.  Once the memory map has been build, we can mark all the unused
 /// space as available.  This allows us to use only one page allocator throughout.
 pub fn init_page_allocator() {
-    let node = LockNode::new();
-    let mut lock = PAGE_ALLOC.lock(&node);
+    let mut lock = PAGE_ALLOC.lock();
     let page_alloc = &mut *lock;
 
     let early_pages_range = kmem::early_pages_range();
@@ -55,8 +51,7 @@ pub fn free_unused_ranges<'a>(
     available_mem: &PhysRange,
     used_ranges: impl Iterator<Item = &'a PhysRange>,
 ) -> Result<(), PageAllocError> {
-    let node = LockNode::new();
-    let mut lock = PAGE_ALLOC.lock(&node);
+    let mut lock = PAGE_ALLOC.lock();
     let page_alloc = &mut *lock;
 
     page_alloc.free_unused_ranges(available_mem, used_ranges)?;
@@ -68,10 +63,17 @@ pub fn free_unused_ranges<'a>(
     page_alloc.mark_allocated(&kmem::early_pages_range())
 }
 
+/// Mark the given range as allocated, so the page allocator never hands it out.
+/// Used to exclude firmware-reserved memory.
+pub fn mark_allocated(range: &PhysRange) -> Result<(), PageAllocError> {
+    let mut lock = PAGE_ALLOC.lock();
+    let page_alloc = &mut *lock;
+    page_alloc.mark_allocated(range)
+}
+
 /// Try to allocate a physical page.  Note that this is NOT mapped.
 pub fn allocate_physpage() -> Result<PhysAddr, PageAllocError> {
-    let node = LockNode::new();
-    let mut lock = PAGE_ALLOC.lock(&node);
+    let mut lock = PAGE_ALLOC.lock();
     let page_alloc = &mut *lock;
 
     match page_alloc.allocate() {
@@ -86,6 +88,49 @@ pub fn allocate_physpage() -> Result<PhysAddr, PageAllocError> {
     }
 }
 
+/// Return a physical page to the page allocator.  It must not be mapped.
+pub fn deallocate_physpage(pa: PhysAddr) -> Result<(), PageAllocError> {
+    let mut lock = PAGE_ALLOC.lock();
+    let page_alloc = &mut *lock;
+
+    page_alloc.deallocate(pa).inspect_err(|err| {
+        println!("error:pagealloc:deallocate_physpage:failed to deallocate {pa:?}: {err:?}");
+    })
+}
+
+/// Try to allocate npages physically contiguous pages for DMA, aligned to
+/// `align` bytes and ending at or below `max_pa`.  Note that these are NOT
+/// mapped.
+#[allow(dead_code)]
+pub fn allocate_physpages_contiguous(
+    npages: usize,
+    align: usize,
+    max_pa: PhysAddr,
+) -> Result<PhysAddr, PageAllocError> {
+    let mut lock = PAGE_ALLOC.lock();
+    let page_alloc = &mut *lock;
+
+    page_alloc.allocate_contiguous(npages, align, max_pa).inspect_err(|err| {
+        println!(
+            "error:pagealloc:allocate_physpages_contiguous:failed to allocate {npages} pages: {err:?}"
+        );
+    })
+}
+
+/// Return pages allocated by `allocate_physpages_contiguous`.  They must not
+/// be mapped.
+#[allow(dead_code)]
+pub fn deallocate_physpages_contiguous(pa: PhysAddr, npages: usize) -> Result<(), PageAllocError> {
+    let mut lock = PAGE_ALLOC.lock();
+    let page_alloc = &mut *lock;
+
+    page_alloc.deallocate_contiguous(pa, npages).inspect_err(|err| {
+        println!(
+            "error:pagealloc:deallocate_physpages_contiguous:failed to deallocate {pa:?}: {err:?}"
+        );
+    })
+}
+
 /// Try to allocate a physical page and map it into virtual memory at va.
 pub fn allocate_virtpage(
     page_table: &mut RootPageTable,
@@ -115,8 +160,7 @@ pub fn allocate_virtpage(
 
 /// Return a tuple of (bytes used, total bytes available) based on the page allocator.
 pub fn usage_bytes() -> (usize, usize) {
-    let node = LockNode::new();
-    let mut lock = PAGE_ALLOC.lock(&node);
+    let mut lock = PAGE_ALLOC.lock();
     let page_alloc = &mut *lock;
     page_alloc.usage_bytes()
 }
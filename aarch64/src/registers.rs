@@ -82,7 +82,7 @@ pub enum PartNum {
 }
 
 bitstruct! {
-    #[derive(Copy, Clone)]
+    #[derive(Copy, Clone, Default)]
     pub struct EsrEl1(pub u64) {
         pub iss: u32 = 0..25;
         pub il: bool = 25;
@@ -120,6 +120,7 @@ pub enum ExceptionClass {
     Ls64 = 10,
     BranchTargetException = 13,
     IllegalExecutionState = 14,
+    Svc64 = 21,
     MsrMrsSystem = 24,
     Sve = 25,
     Tstart = 27,
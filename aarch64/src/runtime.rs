@@ -14,6 +14,10 @@ use port::println;
 pub fn panic(info: &PanicInfo) -> ! {
     println!("{}\n", info);
 
+    // List the most contended locks, in case the panic was a deadlock
+    #[cfg(feature = "lockdebug")]
+    let _ = port::lockstat::lockstat(&mut port::devcons::Console, 10);
+
     #[allow(clippy::empty_loop)]
     loop {}
 }
@@ -1,4 +1,5 @@
 use core::fmt;
+use port::mem::PAGE_SIZE_4K;
 
 #[cfg(not(test))]
 core::arch::global_asm!(include_str!("swtch.S"));
@@ -23,6 +24,25 @@ pub struct Context {
 }
 
 impl Context {
+    pub const fn new() -> Context {
+        Context {
+            x19: 0,
+            x20: 0,
+            x21: 0,
+            x22: 0,
+            x23: 0,
+            x24: 0,
+            x25: 0,
+            x26: 0,
+            x27: 0,
+            x28: 0,
+            x29: 0,
+            x30: 0,
+            sp: 0,
+            spsr: 0,
+        }
+    }
+
     pub fn set_return(&mut self, addr: u64) {
         self.x30 = addr;
     }
@@ -56,3 +76,36 @@ impl fmt::Debug for Context {
 unsafe extern "C" {
     pub(crate) fn swtch(from: *mut *mut Context, to: &Context);
 }
+
+/// SPSR for a new kernel context: EL1h, with all interrupts masked.
+const SPSR_EL1H_MASKED: u64 = 0x3c5;
+
+/// Context switching for the portable scheduler.  `swtch` saves registers
+/// on the stack being switched away from, so a `port::sched::Context` just
+/// holds a pointer to them.
+pub struct SchedArch;
+
+impl port::sched::Arch for SchedArch {
+    const KSTACK_SIZE: usize = 16 * PAGE_SIZE_4K;
+
+    unsafe fn swtch(save: &mut port::sched::Context, next: &mut port::sched::Context) {
+        unsafe {
+            let save = save.regs_mut::<*mut Context>();
+            let next = *next.regs_mut::<*mut Context>();
+            swtch(save, &*next);
+        }
+    }
+
+    fn init_context(ctx: &mut port::sched::Context, start: extern "C" fn() -> !, stack_top: usize) {
+        let sp = stack_top & !15;
+        let frame = (sp - size_of::<Context>()) as *mut Context;
+        let mut context = Context::new();
+        context.set_return(start as usize as u64);
+        context.set_stack_pointer(sp as u64);
+        context.spsr = SPSR_EL1H_MASKED;
+        unsafe {
+            frame.write(context);
+            *ctx.regs_mut() = frame;
+        }
+    }
+}
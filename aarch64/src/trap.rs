@@ -1,12 +1,25 @@
+use alloc::boxed::Box;
 use core::fmt;
 
-use crate::registers::EsrEl1;
+use crate::registers::{EsrEl1, ExceptionClass};
+use crate::{clock, exec, usermem};
+use aarch64_cpu::registers::{DAIF, MPIDR_EL1, ReadWriteable, Readable, Writeable};
+use port::cpu::CpuHooks;
+use port::exec::Start;
 use port::println;
+use port::sched;
+use port::sys9::{ARG_OFFSET, ARG_SLOT};
+use port::usermem::EBADADDR;
 
 #[cfg(not(test))]
 core::arch::global_asm!(include_str!("trap.S"));
 
+/// How the portable code masks interrupts and finds the current CPU.
+static CPU_HOOKS: CpuHooks = CpuHooks { cpu_id, splhi, splx, spllo };
+
 pub fn init() {
+    port::cpu::set_hooks(&CPU_HOOKS);
+
     #[cfg(not(test))]
     unsafe {
         // Set up a vector table for any exception that is taken to EL1, then enable IRQ
@@ -19,7 +32,30 @@ pub fn init() {
     }
 }
 
+/// Index of the current core, from the lowest affinity level of MPIDR_EL1.
+fn cpu_id() -> usize {
+    (MPIDR_EL1.get() & 0xff) as usize
+}
+
+/// Mask IRQs, returning the previous DAIF value.
+fn splhi() -> usize {
+    let daif = DAIF.get();
+    DAIF.modify(DAIF::I::Masked);
+    daif as usize
+}
+
+/// Restore the DAIF value returned by splhi.
+fn splx(daif: usize) {
+    DAIF.set(daif as u64);
+}
+
+/// Unmask IRQs.
+fn spllo() {
+    DAIF.modify(DAIF::I::Unmasked);
+}
+
 /// Register frame at time interrupt was taken
+#[derive(Clone, Default)]
 #[repr(C, align(16))]
 pub struct TrapFrame {
     x0: u64,
@@ -57,8 +93,13 @@ pub struct TrapFrame {
     elr_el1: u64,
     far_el1: u64,
     interrupt_type: u64,
+    spsr_el1: u64,
+    sp_el0: u64,
 }
 
+// Must match the frame built by trap.S
+const _: () = assert!(size_of::<TrapFrame>() == 304);
+
 impl fmt::Debug for TrapFrame {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         f.debug_struct("TrapFrame")
@@ -97,6 +138,8 @@ impl fmt::Debug for TrapFrame {
             .field("elr_el1", &format_args!("{:#018?}", self.elr_el1))
             .field("far_el1", &format_args!("{:#018?}", self.far_el1))
             .field("interrupt_type", &format_args!("{}", self.interrupt_type))
+            .field("spsr_el1", &format_args!("{:#018x}", self.spsr_el1))
+            .field("sp_el0", &format_args!("{:#018x}", self.sp_el0))
             .finish()
     }
 }
@@ -106,17 +149,90 @@ pub extern "C" fn trap_unsafe(frame: *mut TrapFrame) {
     unsafe { trap(frame.as_mut().unwrap()) }
 }
 
+/// Interrupt types, from trap.S.
+const IRQ_EL1H: u64 = 5;
+const SYNC_EL0_64: u64 = 8;
+const IRQ_EL0_64: u64 = 9;
+
 fn trap(frame: &mut TrapFrame) {
-    if frame.esr_el1.ec() == 0x15 {
-        // Syscall
-        let syscallid = frame.esr_el1.iss();
-        println!("Syscall {syscallid}");
-    } else {
-        println!("{:#?}", frame);
-        println!("Unhandled interrupt");
+    if matches!(frame.interrupt_type, IRQ_EL1H | IRQ_EL0_64) {
+        if !clock::interrupt() {
+            println!("Unexpected IRQ");
+        }
+        // Interrupts are taken on the kernel stack of whatever was
+        // running, so it's safe to switch away here.
+        sched::preempt();
+        return;
+    }
+    let ec = frame.esr_el1.exception_class_enum();
+    if ec == Ok(ExceptionClass::Svc64) && frame.interrupt_type == SYNC_EL0_64 {
+        // ELR_EL1 already points past the SVC, so returning resumes the
+        // caller.
+        port::syscall::syscall(frame);
+        return;
+    }
+    if ec == Ok(ExceptionClass::DataAbortSameEl)
+        && let Some(pc) = usermem::fixup(frame.elr_el1)
+    {
+        frame.elr_el1 = pc;
+        return;
     }
 
+    println!("{:#?}", frame);
+    println!("Unhandled interrupt");
     loop {
         core::hint::spin_loop();
     }
 }
+
+/// System calls from EL0 follow 9front's arm64 convention: SVC 0 with the
+/// number in x0, the arguments on the user stack, and the result in x0.
+impl port::syscall::Frame for TrapFrame {
+    fn sysno(&self) -> usize {
+        self.x0 as usize
+    }
+
+    fn arg(&self, i: usize) -> port::Result<u64> {
+        let mut slot = [0u8; ARG_SLOT];
+        let va = (self.sp_el0 as usize).checked_add(ARG_OFFSET + i * ARG_SLOT);
+        port::usermem::copyin(va.ok_or(EBADADDR)?, &mut slot)?;
+        Ok(u64::from_le_bytes(slot))
+    }
+
+    fn set_ret(&mut self, ret: usize) {
+        self.x0 = ret as u64;
+    }
+
+    fn exec(&mut self, name: &[u8], argv: &[&[u8]]) -> port::Result<Start> {
+        let start = exec::exec(name, argv)?;
+        // Nothing of the old program survives, not even its registers.
+        *self = TrapFrame {
+            x1: start.argv as u64,
+            esr_el1: self.esr_el1,
+            elr_el1: start.entry as u64,
+            interrupt_type: self.interrupt_type,
+            sp_el0: start.sp as u64,
+            ..TrapFrame::default()
+        };
+        Ok(start)
+    }
+
+    fn fork(&self) -> Box<dyn port::syscall::Frame + Send> {
+        Box::new(self.clone())
+    }
+
+    #[cfg(not(test))]
+    fn touser(self: Box<Self>) -> ! {
+        unsafe extern "C" {
+            fn forkret(frame: *const TrapFrame) -> !;
+        }
+        // trap.S restores the frame from the stack
+        let frame = *self;
+        unsafe { forkret(&frame) }
+    }
+
+    #[cfg(test)]
+    fn touser(self: Box<Self>) -> ! {
+        unreachable!("trap: no user mode in tests");
+    }
+}
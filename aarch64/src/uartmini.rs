@@ -9,7 +9,6 @@ use crate::registers::{
     AUX_ENABLE, AUX_MU_BAUD, AUX_MU_CNTL, AUX_MU_IER, AUX_MU_IIR, AUX_MU_IO, AUX_MU_LCR,
     AUX_MU_LSR, AUX_MU_MCR, GPFSEL1, GPPUD, GPPUDCLK0,
 };
-use crate::vm;
 
 #[cfg(not(test))]
 use port::println;
@@ -44,8 +43,7 @@ impl MiniUart {
 
     pub fn new_with_map_ranges(dt: &DeviceTree) -> Result<MiniUart> {
         let gpio_physrange = Self::find_gpio_physrange(dt)?;
-        let gpio_virtrange = match map_device_register("gpio", gpio_physrange, vm::PageSize::Page4K)
-        {
+        let gpio_virtrange = match map_device_register("gpio", gpio_physrange) {
             Ok(gpio_virtrange) => gpio_virtrange,
             Err(msg) => {
                 println!("can't map gpio {:?}", msg);
@@ -54,7 +52,7 @@ impl MiniUart {
         };
 
         let aux_physrange = Self::find_aux_physrange(dt)?;
-        let aux_virtrange = match map_device_register("aux", aux_physrange, vm::PageSize::Page4K) {
+        let aux_virtrange = match map_device_register("aux", aux_physrange) {
             Ok(aux_virtrange) => aux_virtrange,
             Err(msg) => {
                 println!("can't map aux {:?}", msg);
@@ -63,14 +61,13 @@ impl MiniUart {
         };
 
         let miniuart_physrange = Self::find_miniuart_physrange(dt)?;
-        let miniuart_virtrange =
-            match map_device_register("miniuart", miniuart_physrange, vm::PageSize::Page4K) {
-                Ok(aux_virtrange) => aux_virtrange,
-                Err(msg) => {
-                    println!("can't map miniuart {:?}", msg);
-                    return Err("can't create miniuart");
-                }
-            };
+        let miniuart_virtrange = match map_device_register("miniuart", miniuart_physrange) {
+            Ok(aux_virtrange) => aux_virtrange,
+            Err(msg) => {
+                println!("can't map miniuart {:?}", msg);
+                return Err("can't create miniuart");
+            }
+        };
 
         Ok(MiniUart { gpio_virtrange, aux_virtrange, miniuart_virtrange })
     }
@@ -1,10 +1,10 @@
 use crate::deviceutil::map_device_register;
 use crate::io::{GpioPull, delay, read_reg, write_reg};
+use crate::mailbox;
 use crate::registers::{
     GPPUD, GPPUDCLK0, UART0_CR, UART0_DR, UART0_FBRD, UART0_FR, UART0_IBRD, UART0_ICR, UART0_IMSC,
     UART0_LCRH,
 };
-use crate::{mailbox, vm};
 use port::Result;
 use port::devcons::Uart;
 use port::fdt::DeviceTree;
@@ -26,8 +26,7 @@ pub struct Pl011Uart {
 impl Pl011Uart {
     pub fn new(dt: &DeviceTree) -> Result<Pl011Uart> {
         let gpio_physrange = Self::find_gpio_physrange(dt)?;
-        let gpio_virtrange = match map_device_register("gpio", gpio_physrange, vm::PageSize::Page4K)
-        {
+        let gpio_virtrange = match map_device_register("gpio", gpio_physrange) {
             Ok(gpio_virtrange) => gpio_virtrange,
             Err(msg) => {
                 println!("can't map gpio {:?}", msg);
@@ -36,14 +35,13 @@ impl Pl011Uart {
         };
 
         let pl011_physrange = Self::find_pl011_physrange(dt)?;
-        let pl011_virtrange =
-            match map_device_register("pl011", pl011_physrange, vm::PageSize::Page4K) {
-                Ok(pl011_virtrange) => pl011_virtrange,
-                Err(msg) => {
-                    println!("can't map pl011 {:?}", msg);
-                    return Err("can't create pl011");
-                }
-            };
+        let pl011_virtrange = match map_device_register("pl011", pl011_physrange) {
+            Ok(pl011_virtrange) => pl011_virtrange,
+            Err(msg) => {
+                println!("can't map pl011 {:?}", msg);
+                return Err("can't create pl011");
+            }
+        };
 
         Ok(Pl011Uart { gpio_virtrange, pl011_virtrange })
     }
@@ -0,0 +1,100 @@
+/// Access to user memory for system calls.  Ranges are checked against the
+/// segments of the user address space, then copied with the unprivileged
+/// LDTRB and STTRB, which are checked against EL0's permissions rather than
+/// the kernel's, so even a range that passes validaddr can't be used to
+/// reach kernel memory.  A fault in the copy routines, say on a page that's
+/// been unmapped since, resumes at ucopy_fault, which fails the copy.
+#[cfg(not(test))]
+use crate::exec;
+#[cfg(not(test))]
+use port::usermem::UserHooks;
+
+#[cfg(not(test))]
+core::arch::global_asm!(
+    r#"
+.section .text
+.globl ucopy_start
+ucopy_start:
+
+// ucopyin(dst, src, len): copy len bytes from user address src to dst.
+// Returns 0, or -1 from ucopy_fault.
+.globl ucopyin
+ucopyin:
+	cbz	x2, 2f
+1:	ldtrb	w3, [x1]
+	strb	w3, [x0], #1
+	add	x1, x1, #1
+	subs	x2, x2, #1
+	b.ne	1b
+2:	mov	x0, #0
+	ret
+
+// ucopyout(dst, src, len): copy len bytes from src to user address dst.
+.globl ucopyout
+ucopyout:
+	cbz	x2, 2f
+1:	ldrb	w3, [x1], #1
+	sttrb	w3, [x0]
+	add	x0, x0, #1
+	subs	x2, x2, #1
+	b.ne	1b
+2:	mov	x0, #0
+	ret
+
+.globl ucopy_end
+ucopy_end:
+
+.globl ucopy_fault
+ucopy_fault:
+	mov	x0, #-1
+	ret
+"#
+);
+
+#[cfg(not(test))]
+unsafe extern "C" {
+    fn ucopyin(dst: *mut u8, src: usize, len: usize) -> isize;
+    fn ucopyout(dst: usize, src: *const u8, len: usize) -> isize;
+    static ucopy_start: [u8; 0];
+    static ucopy_end: [u8; 0];
+    static ucopy_fault: [u8; 0];
+}
+
+#[cfg(not(test))]
+static USER_HOOKS: UserHooks = UserHooks { validaddr: exec::validaddr, copyin, copyout };
+
+/// Register the copy routines with port.  Host tests use port's own hooks.
+pub fn init() {
+    #[cfg(not(test))]
+    port::usermem::set_hooks(&USER_HOOKS);
+}
+
+#[cfg(not(test))]
+fn copyin(va: usize, buf: &mut [u8]) -> port::Result<()> {
+    match unsafe { ucopyin(buf.as_mut_ptr(), va, buf.len()) } {
+        0 => Ok(()),
+        _ => Err("fault reading user memory"),
+    }
+}
+
+#[cfg(not(test))]
+fn copyout(va: usize, data: &[u8]) -> port::Result<()> {
+    match unsafe { ucopyout(va, data.as_ptr(), data.len()) } {
+        0 => Ok(()),
+        _ => Err("fault writing user memory"),
+    }
+}
+
+/// Where to resume after a data abort at pc, if it was in a user copy.
+pub fn fixup(pc: u64) -> Option<u64> {
+    #[cfg(not(test))]
+    unsafe {
+        let copies = ucopy_start.as_ptr().addr()..ucopy_end.as_ptr().addr();
+        copies.contains(&(pc as usize)).then(|| ucopy_fault.as_ptr().addr() as u64)
+    }
+    #[cfg(test)]
+    {
+        let _ = pc;
+        None
+    }
+}
@@ -12,8 +12,8 @@ use crate::{
     param::KZERO,
 };
 use bitstruct::bitstruct;
-use core::{fmt, ptr, sync::atomic::Ordering};
-use core::{ptr::write_volatile, sync::atomic::AtomicUsize};
+use core::ptr::write_volatile;
+use core::{fmt, ptr};
 use num_enum::{FromPrimitive, IntoPrimitive};
 use port::{
     fdt::DeviceTree,
@@ -51,6 +51,22 @@ impl PageSize {
             PageSize::Page1G => PAGE_SIZE_1G,
         }
     }
+
+    /// The level of the page table that holds entries of this size
+    pub const fn level(&self) -> Level {
+        match self {
+            PageSize::Page4K => Level::Level3,
+            PageSize::Page2M => Level::Level2,
+            PageSize::Page1G => Level::Level1,
+        }
+    }
+}
+
+/// What to do with the physical pages backing a mapping when it's removed
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum UnmapPages {
+    Keep, // Device memory, or pages owned by someone else
+    Free, // Return the pages to the page allocator
 }
 
 #[repr(C, align(4096))]
@@ -115,6 +131,7 @@ bitstruct! {
         pub access_permission: AccessPermission = 6..8;
         pub shareable: Shareable = 8..10;
         pub accessed: bool = 10; // Was accessed by code
+        pub not_global: bool = 11; // Only valid for the current ASID
         pub addr: u64 = 12..48;
         pub pxn: bool = 53; // Privileged eXecute Never
         pub uxn: bool = 54; // Unprivileged eXecute Never
@@ -178,6 +195,7 @@ impl Entry {
             .with_uxn(false)
             .with_pxn(true)
             .with_mair_index(Mair::Normal)
+            .with_not_global(true)
             .with_valid(true)
     }
 
@@ -189,13 +207,52 @@ impl Entry {
             .with_uxn(true)
             .with_pxn(true)
             .with_mair_index(Mair::Normal)
+            .with_not_global(true)
+            .with_valid(true)
+    }
+
+    pub fn ro_user_text() -> Self {
+        Entry(0)
+            .with_access_permission(AccessPermission::AllRo)
+            .with_shareable(Shareable::Inner)
+            .with_accessed(true)
+            .with_uxn(false)
+            .with_pxn(true)
+            .with_mair_index(Mair::Normal)
+            .with_not_global(true)
+            .with_valid(true)
+    }
+
+    pub fn ro_user_data() -> Self {
+        Entry(0)
+            .with_access_permission(AccessPermission::AllRo)
+            .with_shareable(Shareable::Inner)
+            .with_accessed(true)
+            .with_uxn(true)
+            .with_pxn(true)
+            .with_mair_index(Mair::Normal)
+            .with_not_global(true)
             .with_valid(true)
     }
 
+    /// Entry pointing to a page table.  User tables are also reached via the
+    /// recursive entry, where the entry is treated as a page, so they must not
+    /// be global, otherwise the translation would outlive an ASID switch.
+    fn table(pa: PhysAddr, pgtype: RootPageTableType) -> Self {
+        Entry::rw_kernel_data()
+            .with_phys_addr(pa)
+            .with_page_or_table(true)
+            .with_not_global(pgtype == RootPageTableType::User)
+    }
+
     const fn with_phys_addr(self, pa: PhysAddr) -> Self {
         Entry(self.0).with_addr(pa.addr() >> 12)
     }
 
+    fn phys_addr(self) -> PhysAddr {
+        PhysAddr::new(self.addr() << 12)
+    }
+
     pub fn is_table(self, level: Level) -> bool {
         self.page_or_table() && level != Level::Level3
     }
@@ -224,6 +281,9 @@ impl fmt::Debug for Entry {
         if self.accessed() {
             write!(f, " Accessed")?;
         }
+        if self.not_global() {
+            write!(f, " nG")?;
+        }
         if self.pxn() {
             write!(f, " PXN")?;
         }
@@ -257,6 +317,16 @@ impl Level {
         }
     }
 
+    /// Returns the previous level, closer to the root
+    pub fn prev(&self) -> Option<Level> {
+        match self {
+            Level::Level0 => None,
+            Level::Level1 => Some(Level::Level0),
+            Level::Level2 => Some(Level::Level1),
+            Level::Level3 => Some(Level::Level2),
+        }
+    }
+
     pub fn depth(&self) -> usize {
         match self {
             Level::Level0 => 0,
@@ -265,6 +335,16 @@ impl Level {
             Level::Level3 => 3,
         }
     }
+
+    /// Size of the virtual address space covered by one entry at this level
+    pub const fn entry_size(&self) -> usize {
+        match self {
+            Level::Level0 => 512 * PAGE_SIZE_1G,
+            Level::Level1 => PAGE_SIZE_1G,
+            Level::Level2 => PAGE_SIZE_2M,
+            Level::Level3 => PAGE_SIZE_4K,
+        }
+    }
 }
 
 pub fn va_index(va: usize, level: Level) -> usize {
@@ -305,6 +385,10 @@ fn recursive_table_addr(pgtype: RootPageTableType, va: usize, level: Level) -> u
 pub enum PageTableError {
     AllocationFailed(PageAllocError),
     EntryIsNotTable,
+    EntryIsTable,
+    RangeSplitsBlock,
+    NotMapped,
+    OutOfVirtualSpace,
     PhysRangeIsZero,
     PhysRangeIsNotOnPageBoundary,
 }
@@ -350,10 +434,13 @@ impl Table {
                     return Err(PageTableError::AllocationFailed(err));
                 }
             };
-            entry = Entry::rw_kernel_data().with_phys_addr(page_pa).with_page_or_table(true);
+            entry = Entry::table(page_pa, pgtype);
             unsafe {
                 write_volatile(&mut self.entries[index], entry);
             }
+            // The walker must see the new table before we clear it through
+            // the recursive mapping.
+            sync_table_writes();
 
             // Clear out the new page
             let recursive_page_addr = recursive_table_addr(pgtype, va, level.next().unwrap());
@@ -368,6 +455,24 @@ impl Table {
         let recursive_page_addr = recursive_table_addr(pgtype, va, level.next().unwrap());
         Ok(unsafe { &mut *(recursive_page_addr as *mut Table) })
     }
+
+    /// Return the next table in the walk.  Unlike `next_mut`, this never
+    /// creates a table, and fails if there isn't one.
+    fn next_existing_mut(
+        &mut self,
+        pgtype: RootPageTableType,
+        level: Level,
+        va: usize,
+    ) -> Result<&mut Table, PageTableError> {
+        let entry = self.entries[va_index(va, level)];
+        if !entry.valid() {
+            return Err(PageTableError::NotMapped);
+        } else if !entry.is_table(level) {
+            return Err(PageTableError::EntryIsNotTable);
+        }
+        let recursive_page_addr = recursive_table_addr(pgtype, va, level.next().unwrap());
+        Ok(unsafe { &mut *(recursive_page_addr as *mut Table) })
+    }
 }
 
 impl fmt::Debug for Table {
@@ -399,32 +504,16 @@ impl RootPageTable {
 
     /// Ensure there's a mapping from va to entry, creating any intermediate
     /// page tables that don't already exist.  If a mapping already exists,
-    /// replace it.
-    /// root_page_table should be a direct va - not a recursive va.
+    /// replace it, and add va to the TLB batch.  The recursive entry must point
+    /// to self.
     fn map_to(
         &mut self,
         entry: Entry,
         va: usize,
         page_size: PageSize,
-        root_page_table: &mut RootPageTable,
         pgtype: RootPageTableType,
+        tlb: &mut TlbBatch,
     ) -> Result<(), PageTableError> {
-        // We change the last entry of the root page table to the address of
-        // self for the duration of this method.  This allows us to work with
-        // this hierarchy of pagetables even if it's not the current translation
-        // table.  We *must* return it to its original state on exit.
-        // TODO Only do this if self != kernel_root()
-        let old_recursive_entry = root_page_table.entries[511];
-        let temp_recursive_entry = Entry::rw_kernel_data()
-            .with_phys_addr(from_ptr_to_physaddr_offset_from_kzero(self))
-            .with_page_or_table(true);
-
-        unsafe {
-            write_volatile(&mut root_page_table.entries[511], temp_recursive_entry);
-            // TODO Need to invalidate the single cache entry
-            invalidate_all_tlb_entries();
-        };
-
         let dest_entry = match page_size {
             PageSize::Page4K => self
                 .next_mut(pgtype, Level::Level0, va)
@@ -450,27 +539,178 @@ impl RootPageTable {
             }
         };
 
+        // Replacing a table with a block would leak the table and everything
+        // mapped by it.
+        if dest_entry.valid() && dest_entry.is_table(page_size.level()) {
+            println!("error:vm:map_to:entry is a table, not a block. va:{va:#x} {page_size:?}");
+            return Err(PageTableError::EntryIsTable);
+        }
+
         // Entries at level 3 should have the page flag set
         let entry =
             if page_size == PageSize::Page4K { entry.with_page_or_table(true) } else { entry };
 
+        // Invalid entries are never cached, so only replaced entries need
+        // invalidating.  New ones only need to be made visible.
+        if dest_entry.valid() {
+            tlb.add(va);
+        } else {
+            tlb.add_new();
+        }
+        unsafe { write_volatile(dest_entry, entry) };
+
+        Ok(())
+    }
+
+    /// Point the recursive entry of root_page_table at self for the duration
+    /// of f, so that this hierarchy of page tables can be walked even if it's
+    /// not the current translation table.  The recursive entry is always
+    /// returned to its original state.  Any TLB invalidations added to the
+    /// batch by f are done before returning.
+    /// root_page_table should be a direct va - not a recursive va.
+    fn with_recursive_entry<R>(
+        &mut self,
+        root_page_table: &mut RootPageTable,
+        pgtype: RootPageTableType,
+        f: impl FnOnce(&mut Self, &mut TlbBatch) -> R,
+    ) -> R {
+        let mut tlb = TlbBatch::new(pgtype);
+
+        // If self is already the table the recursive entry points at, there's
+        // nothing to change, and we only need to invalidate what f changed.
+        let old_recursive_entry = root_page_table.entries[511];
+        let temp_recursive_entry =
+            Entry::table(from_ptr_to_physaddr_offset_from_kzero(self), pgtype);
+        if old_recursive_entry == temp_recursive_entry {
+            return f(self, &mut tlb);
+        }
+
+        // Otherwise, every cached translation of the recursive region refers to
+        // the wrong tables, so we have to flush everything, on the way in and
+        // out.  That also covers anything f added to the batch.
+        unsafe {
+            write_volatile(&mut root_page_table.entries[511], temp_recursive_entry);
+            invalidate_all_tlb_entries();
+        };
+
+        let result = f(self, &mut tlb);
+
         unsafe {
-            write_volatile(dest_entry, entry);
-            // Return the recursive entry to its original state
             write_volatile(&mut root_page_table.entries[511], old_recursive_entry);
-            // TODO Need to invalidate the single cache entry (+ optionally the recursive entry)
             invalidate_all_tlb_entries();
         }
+        tlb.clear();
 
-        Ok(())
+        result
+    }
+
+    /// Return the entry for va at the level used by page_size, without
+    /// creating any intermediate page tables.  The recursive entry must point
+    /// to self.
+    fn existing_entry_mut(
+        &mut self,
+        va: usize,
+        page_size: PageSize,
+        pgtype: RootPageTableType,
+    ) -> Result<&mut Entry, PageTableError> {
+        let mut table = self;
+        let mut level = Level::Level0;
+        while level != page_size.level() {
+            table = table.next_existing_mut(pgtype, level, va)?;
+            level = level.next().unwrap();
+        }
+        table.entry_mut(level, va)
+    }
+
+    /// Remove the page or block mapping va, which must start at va, and must
+    /// not extend beyond end.  Returns the entry that was there, or None if
+    /// nothing was mapped, along with the size of the virtual address space
+    /// that was covered by it (or by the hole).  Any intermediate page tables
+    /// left empty are returned to the page allocator.  The recursive entry must
+    /// point to self.
+    fn unmap_leaf(
+        &mut self,
+        va: usize,
+        end: usize,
+        pgtype: RootPageTableType,
+        tlb: &mut TlbBatch,
+    ) -> Result<(Option<Entry>, usize), PageTableError> {
+        let mut table: &mut Table = self;
+        let mut level = Level::Level0;
+        loop {
+            let entry = table.entries[va_index(va, level)];
+            if !entry.valid() {
+                return Ok((None, level.entry_size()));
+            } else if !entry.is_table(level) {
+                break;
+            } else if let Some(next_level) = level.next() {
+                let recursive_page_addr = recursive_table_addr(pgtype, va, next_level);
+                table = unsafe { &mut *(recursive_page_addr as *mut Table) };
+                level = next_level;
+            }
+        }
+        if level == Level::Level0 {
+            println!("error:vm:unmap_leaf:level 0 entry is not a table. va:{va:#x}");
+            return Err(PageTableError::EntryIsNotTable);
+        }
+
+        let size = level.entry_size();
+        if !va.is_multiple_of(size) || va.saturating_add(size) > end {
+            println!("error:vm:unmap_leaf:range splits block. va:{va:#x} end:{end:#x} {level:?}");
+            return Err(PageTableError::RangeSplitsBlock);
+        }
+
+        let dest_entry = &mut table.entries[va_index(va, level)];
+        let old_entry = *dest_entry;
+        unsafe { write_volatile(dest_entry, Entry::empty()) };
+        tlb.add(va);
+        self.reclaim_empty_tables(va, level, pgtype, tlb);
+        Ok((Some(old_entry), size))
+    }
+
+    /// Starting with the table at level for va, free each table that no
+    /// longer has any valid entries and remove it from its parent.  The root
+    /// table itself is never freed.  The recursive entry must point to self.
+    fn reclaim_empty_tables(
+        &mut self,
+        va: usize,
+        level: Level,
+        pgtype: RootPageTableType,
+        tlb: &mut TlbBatch,
+    ) {
+        let mut level = level;
+        while let Some(parent_level) = level.prev() {
+            let table_va = recursive_table_addr(pgtype, va, level);
+            let table = unsafe { &*(table_va as *const Table) };
+            if table.entries.iter().any(|e| e.valid()) {
+                break;
+            }
+
+            let parent =
+                unsafe { &mut *(recursive_table_addr(pgtype, va, parent_level) as *mut Table) };
+            let parent_entry = &mut parent.entries[va_index(va, parent_level)];
+            let table_pa = parent_entry.phys_addr();
+            unsafe { write_volatile(parent_entry, Entry::empty()) };
+
+            // The table must no longer be reachable, either through va or
+            // through the recursive mapping, before it's reused.
+            tlb.add(va);
+            tlb.add(table_va);
+            tlb.flush();
+            if let Err(err) = pagealloc::deallocate_physpage(table_pa) {
+                println!(
+                    "error:vm:reclaim_empty_tables:can't free table pa:{table_pa:?} err:{err:?}"
+                );
+            }
+            level = parent_level;
+        }
     }
 
     /// Map the physical range using the requested page size.
     /// This aligns on page size boundaries, and rounds the requested range so
     /// that both the alignment requirements are met and the requested range are
     /// covered.
-    /// TODO Assuming some of these requests are dynamic, but should not fail,
-    /// we should fall back to the smaller page sizes if the requested size fails.
+    /// Use `map_phys_range_auto` to have the page sizes chosen automatically.
     pub fn map_phys_range(
         &mut self,
         debug_name: &str,
@@ -491,32 +731,174 @@ impl RootPageTable {
 
         let root_page_table = root_page_table(pgtype);
 
-        let mut startva = None;
-        let mut endva = 0;
-        let mut currva = 0;
-        for pa in range.step_by_rounded(page_size.size()) {
-            if startva.is_none() {
-                currva = va_mapping.map(pa);
-                startva = Some(currva);
-            } else {
-                currva += page_size.size();
+        // TLB invalidations are batched across the whole range
+        self.with_recursive_entry(root_page_table, pgtype, |table, tlb| {
+            let mut startva = None;
+            let mut endva = 0;
+            let mut currva = 0;
+            for pa in range.step_by_rounded(page_size.size()) {
+                if startva.is_none() {
+                    currva = va_mapping.map(pa);
+                    startva = Some(currva);
+                } else {
+                    currva += page_size.size();
+                }
+                endva = currva + page_size.size();
+                table.map_to(entry.with_phys_addr(pa), currva, page_size, pgtype, tlb)?;
             }
-            endva = currva + page_size.size();
-            self.map_to(entry.with_phys_addr(pa), currva, page_size, root_page_table, pgtype)?;
+            startva.map(|startva| VirtRange(startva..endva)).ok_or(PageTableError::PhysRangeIsZero)
+        })
+    }
+
+    /// Remove the mappings for the virtual range, which must be on 4KiB
+    /// boundaries, whatever page or block sizes were used to map it.  Holes in
+    /// the range are skipped, but blocks must lie entirely within it.
+    /// Intermediate page tables that become empty are returned to the page
+    /// allocator, as are the mapped physical pages if `pages` is
+    /// `UnmapPages::Free`.
+    pub fn unmap_range(
+        &mut self,
+        range: &VirtRange,
+        pgtype: RootPageTableType,
+        pages: UnmapPages,
+    ) -> Result<(), PageTableError> {
+        if !range.start().is_multiple_of(PAGE_SIZE_4K) || !range.end().is_multiple_of(PAGE_SIZE_4K)
+        {
+            println!("error:vm:unmap_range:range not on page boundary. range:{range}");
+            return Err(PageTableError::PhysRangeIsNotOnPageBoundary);
         }
-        startva.map(|startva| VirtRange(startva..endva)).ok_or(PageTableError::PhysRangeIsZero)
+
+        let root_page_table = root_page_table(pgtype);
+        self.with_recursive_entry(root_page_table, pgtype, |table, tlb| {
+            let mut va = range.start();
+            while va < range.end() {
+                let (old_entry, size) = table.unmap_leaf(va, range.end(), pgtype, tlb)?;
+                if let Some(old_entry) = old_entry
+                    && pages == UnmapPages::Free
+                {
+                    // Make sure the pages aren't reachable before they're reused
+                    tlb.flush();
+                    let page_range = PhysRange::with_pa_len(old_entry.phys_addr(), size);
+                    for pa in page_range.step_by_rounded(PAGE_SIZE_4K) {
+                        pagealloc::deallocate_physpage(pa)?;
+                    }
+                }
+
+                // Holes may start part way through the region covered by an entry
+                let Some(next) = (va & !(size - 1)).checked_add(size) else {
+                    break;
+                };
+                va = next;
+            }
+            Ok(())
+        })
     }
-}
 
-// TODO this needs to be a real virtual address allocator...
-static next_free_device_page_va: AtomicUsize = AtomicUsize::new(KZERO + 0x100000000000);
-pub fn next_free_device_page4k() -> VaMapping {
-    next_free_device_page_va
-        .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |current| {
-            Some(current + PageSize::Page4K.size())
+    /// Map the physical range, which must be on 4KiB boundaries, using the
+    /// largest pages possible.  1GiB and 2MiB blocks are used wherever both the
+    /// physical and virtual addresses are suitably aligned and enough of the
+    /// range remains, and 4KiB pages elsewhere.
+    pub fn map_phys_range_auto(
+        &mut self,
+        debug_name: &str,
+        range: &PhysRange,
+        va_mapping: VaMapping,
+        entry: Entry,
+        pgtype: RootPageTableType,
+    ) -> Result<VirtRange, PageTableError> {
+        if !range.start().is_multiple_of(PAGE_SIZE_4K as u64)
+            || !range.end().is_multiple_of(PAGE_SIZE_4K as u64)
+        {
+            println!(
+                "error:vm:map_phys_range_auto:range not on page boundary. debug_name:{debug_name} range:{range}",
+            );
+            return Err(PageTableError::PhysRangeIsNotOnPageBoundary);
+        } else if range.size() == 0 {
+            return Err(PageTableError::PhysRangeIsZero);
+        }
+
+        let root_page_table = root_page_table(pgtype);
+        let startva = va_mapping.map(range.start());
+
+        self.with_recursive_entry(root_page_table, pgtype, |table, tlb| {
+            let mut pa = range.start();
+            let mut va = startva;
+            while pa < range.end() {
+                let remaining = (range.end().addr() - pa.addr()) as usize;
+                let page_size = [PageSize::Page1G, PageSize::Page2M, PageSize::Page4K]
+                    .into_iter()
+                    .find(|ps| {
+                        ps.size() <= remaining
+                            && va.is_multiple_of(ps.size())
+                            && pa.is_multiple_of(ps.size() as u64)
+                    })
+                    .unwrap_or(PageSize::Page4K);
+                table.map_to(entry.with_phys_addr(pa), va, page_size, pgtype, tlb)?;
+                pa = pa + page_size.size() as u64;
+                va += page_size.size();
+            }
+            Ok(VirtRange(startva..va))
         })
-        .map(VaMapping::Addr)
-        .expect("next_free_device_page4k: unable to return new va")
+    }
+
+    /// The physical address of the 4KiB page mapped at va.
+    pub fn phys_addr_of(
+        &mut self,
+        va: usize,
+        pgtype: RootPageTableType,
+    ) -> Result<PhysAddr, PageTableError> {
+        let root_page_table = root_page_table(pgtype);
+        self.with_recursive_entry(root_page_table, pgtype, |table, _| {
+            let entry = table.existing_entry_mut(va, PageSize::Page4K, pgtype)?;
+            if entry.valid() { Ok(entry.phys_addr()) } else { Err(PageTableError::NotMapped) }
+        })
+    }
+
+    /// Change the attributes of every mapping in the virtual range, which must
+    /// be on page_size boundaries, to those of entry.  The physical addresses
+    /// are unchanged.  Every page in the range must already be mapped,
+    /// otherwise nothing is changed.
+    pub fn protect_range(
+        &mut self,
+        range: &VirtRange,
+        entry: Entry,
+        page_size: PageSize,
+        pgtype: RootPageTableType,
+    ) -> Result<(), PageTableError> {
+        if !range.start().is_multiple_of(page_size.size())
+            || !range.end().is_multiple_of(page_size.size())
+        {
+            println!(
+                "error:vm:protect_range:range not on page boundary. range:{range} page_size:{page_size:?}",
+            );
+            return Err(PageTableError::PhysRangeIsNotOnPageBoundary);
+        }
+
+        let root_page_table = root_page_table(pgtype);
+        self.with_recursive_entry(root_page_table, pgtype, |table, tlb| {
+            // Check the whole range first, so we don't leave it half changed
+            for va in range.0.clone().step_by(page_size.size()) {
+                let dest_entry = table.existing_entry_mut(va, page_size, pgtype)?;
+                if !dest_entry.valid() {
+                    println!("error:vm:protect_range:va not mapped. va:{va:#x}");
+                    return Err(PageTableError::NotMapped);
+                } else if dest_entry.is_table(page_size.level()) {
+                    println!("error:vm:protect_range:entry is a table, not a page. va:{va:#x}");
+                    return Err(PageTableError::EntryIsTable);
+                }
+            }
+
+            for va in range.0.clone().step_by(page_size.size()) {
+                let dest_entry = table.existing_entry_mut(va, page_size, pgtype)?;
+                let new_entry = entry
+                    .with_phys_addr(dest_entry.phys_addr())
+                    .with_page_or_table(dest_entry.page_or_table());
+                unsafe { write_volatile(dest_entry, new_entry) };
+                tlb.add(va);
+            }
+            Ok(())
+        })
+    }
 }
 
 /// Return the root user or kernel level page table
@@ -534,7 +916,7 @@ pub unsafe fn init_kernel_page_tables(dt: &DeviceTree, dtb_physrange: PhysRange)
     // recursive addressing of (511, 511, 511, 511) always points to the
     // physical address of the root page table, which isn't what we want here
     // because kpage_table hasn't been switched to yet.
-    unsafe { init_empty_root_page_table(kernel_pagetable()) };
+    unsafe { init_empty_root_page_table(kernel_pagetable(), RootPageTableType::Kernel) };
 
     // We only use the first memory range for now.
     // TODO Handle multiple memory ranges
@@ -548,34 +930,33 @@ pub unsafe fn init_kernel_page_tables(dt: &DeviceTree, dtb_physrange: PhysRange)
     println!("  {}", &available_mem);
 
     // TODO leave the first page unmapped to catch null pointer dereferences in unsafe code
+    // Each range is mapped with the largest pages its alignment allows.
     let custom_map = {
         // The DTB range might not end on a page boundary, so round up.
-        let dtb_page_size = PageSize::Page4K;
-        let dtb_physrange = dtb_physrange.round(dtb_page_size.size());
+        let dtb_physrange = dtb_physrange.round(PAGE_SIZE_4K);
 
         let text_physrange = boottext_range().add(&text_range());
         let ro_data_physrange = rodata_range();
         let data_physrange = data_range().add(&bss_range());
 
         let mut map = [
-            ("DTB", dtb_physrange, Entry::ro_kernel_data(), dtb_page_size),
-            ("Kernel Text", text_physrange, Entry::ro_kernel_text(), PageSize::Page2M),
-            ("Kernel RO Data", ro_data_physrange, Entry::ro_kernel_data(), PageSize::Page2M),
-            ("Kernel Data", data_physrange, Entry::rw_kernel_data(), PageSize::Page2M),
+            ("DTB", dtb_physrange, Entry::ro_kernel_data()),
+            ("Kernel Text", text_physrange, Entry::ro_kernel_text()),
+            ("Kernel RO Data", ro_data_physrange, Entry::ro_kernel_data()),
+            ("Kernel Data", data_physrange, Entry::rw_kernel_data()),
         ];
         map.sort_by_key(|a| a.1.start());
         map
     };
 
     println!("Memory map ranges:");
-    for (name, range, flags, page_size) in custom_map.iter() {
+    for (name, range, flags) in custom_map.iter() {
         let mapped_virtrange = kernel_pagetable()
-            .map_phys_range(
+            .map_phys_range_auto(
                 name,
                 range,
                 VaMapping::Offset(KZERO),
                 *flags,
-                *page_size,
                 RootPageTableType::Kernel,
             )
             .expect("error:init:mapping failed");
@@ -583,28 +964,49 @@ pub unsafe fn init_kernel_page_tables(dt: &DeviceTree, dtb_physrange: PhysRange)
         println!("  {:16}{} to {}", name, range, mapped_virtrange);
     }
     println!("Memory map details:");
-    for (name, _, flags, page_size) in custom_map.iter() {
-        println!("  {:16}flags: {:?} page_size: {:?}", name, flags, page_size);
+    for (name, _, flags) in custom_map.iter() {
+        println!("  {:16}flags: {:?}", name, flags);
     }
 
     if let Err(err) = pagealloc::free_unused_ranges(&available_mem, custom_map.map(|m| m.1).iter())
     {
         panic!("error:Couldn't mark unused pages as free: err: {:?}", err);
     }
+
+    // Firmware may reserve memory either via the memory reservation block or
+    // via /reserved-memory (e.g. for the VideoCore on the Pi).  Ensure the page
+    // allocator never hands these regions out.  Dynamically placed regions have
+    // no address, so there's nothing to exclude.
+    println!("Reserved memory:");
+    let reserved_regions = dt.memory_reservations().map(|reg| ("memreserve", reg)).chain(
+        dt.reserved_memory()
+            .flat_map(|r| r.reg.map(|reg| (dt.node_name(&r.node).unwrap_or("?"), reg))),
+    );
+    for (name, reg) in reserved_regions {
+        let range = PhysRange::from(&reg).round(PageSize::Page4K.size());
+        let Some(range) = range.intersect(&available_mem) else {
+            continue;
+        };
+        println!("  {:16}{}", name, range);
+        if let Err(err) = pagealloc::mark_allocated(&range) {
+            panic!("error:Couldn't mark reserved pages as allocated: {range} err: {:?}", err);
+        }
+    }
 }
 
 pub unsafe fn init_user_page_tables() {
-    unsafe { init_empty_root_page_table(user_pagetable()) };
+    unsafe { init_empty_root_page_table(user_pagetable(), RootPageTableType::User) };
 }
 
 /// Given an empty, statically allocated page table.  We need to write a
 /// recursive entry in the last entry.  To do this, we need to know the physical
 /// address, but all we have is the virtual address
-unsafe fn init_empty_root_page_table(root_page_table: &mut RootPageTable) {
+pub unsafe fn init_empty_root_page_table(
+    root_page_table: &mut RootPageTable,
+    pgtype: RootPageTableType,
+) {
     unsafe {
-        let entry = Entry::rw_kernel_data()
-            .with_phys_addr(from_ptr_to_physaddr_offset_from_kzero(root_page_table))
-            .with_page_or_table(true);
+        let entry = Entry::table(from_ptr_to_physaddr_offset_from_kzero(root_page_table), pgtype);
         write_volatile(&mut root_page_table.entries[511], entry);
     }
 }
@@ -616,17 +1018,22 @@ pub enum RootPageTableType {
 }
 
 /// Return the root user-level page table physical address
-fn ttbr0_el1() -> PhysAddr {
+pub fn ttbr0_el1() -> PhysAddr {
+    PhysAddr::new(ttbr0_el1_raw() & 0x0000_ffff_ffff_fffe)
+}
+
+/// Return the raw value of ttbr0_el1, including the ASID
+fn ttbr0_el1_raw() -> u64 {
     #[cfg(not(test))]
     {
-        let mut addr: u64;
+        let mut value: u64;
         unsafe {
-            core::arch::asm!("mrs {value}, ttbr0_el1", value = out(reg) addr);
+            core::arch::asm!("mrs {value}, ttbr0_el1", value = out(reg) value);
         }
-        PhysAddr::new(addr)
+        value
     }
     #[cfg(test)]
-    PhysAddr::new(0)
+    0
 }
 
 /// Return the root kernel page table physical address
@@ -673,12 +1080,43 @@ pub unsafe fn switch(page_table: &RootPageTable, pgtype: RootPageTableType) {
     }
 }
 
+/// Switch the user page table to page_table, tagging its translations with
+/// asid.  Unlike `switch`, this doesn't flush the TLB, since translations
+/// belonging to other ASIDs can't be used.
+#[allow(unused_variables)]
+pub unsafe fn switch_user(page_table: &RootPageTable, asid: u16) {
+    #[cfg(not(test))]
+    unsafe {
+        let pt_phys = from_ptr_to_physaddr_offset_from_kzero(page_table).addr();
+        core::arch::asm!(
+            "dsb ishst", // ensure the tables are visible to the walker
+            "msr ttbr0_el1, {ttbr}",
+            "isb",
+            ttbr = in(reg) ((asid as u64) << 48) | pt_phys);
+    }
+}
+
+/// Make page table writes visible to the table walker, and to anything after
+/// this that depends on them, such as an access through a new mapping.
+/// Invalid entries are never cached, so this is all that's needed after
+/// replacing one.
+fn sync_table_writes() {
+    #[cfg(not(test))]
+    unsafe {
+        core::arch::asm!(
+            "dsb ishst", // ensure page table writes are visible to the walker
+            "isb"
+        );
+    }
+}
+
 #[allow(unused_variables)]
 pub unsafe fn invalidate_all_tlb_entries() {
     #[cfg(not(test))]
     unsafe {
         // https://forum.osdev.org/viewtopic.php?t=36412&p=303237
         core::arch::asm!(
+            "dsb ishst",      // ensure page table writes are visible to the walker
             "tlbi vmalle1is", // invalidate all TLB entries
             "dsb ish",        // ensure write has completed
             "isb"             // synchronize context and ensure that no instructions
@@ -687,6 +1125,122 @@ pub unsafe fn invalidate_all_tlb_entries() {
     }
 }
 
+/// Invalidate all non-global TLB entries for the address space identified by
+/// asid, on all cores in the inner shareable domain.
+#[allow(unused_variables)]
+pub unsafe fn invalidate_tlb_asid(asid: u16) {
+    #[cfg(not(test))]
+    unsafe {
+        core::arch::asm!(
+            "dsb ishst",
+            "tlbi aside1is, {arg}",
+            "dsb ish",
+            "isb",
+            arg = in(reg) (asid as u64) << 48
+        );
+    }
+}
+
+/// Build the operand for the TLBI VA instructions: ASID in bits 63:48 and
+/// VA[55:12] in bits 43:0.
+const fn tlbi_va_arg(va: usize, asid: u16) -> u64 {
+    ((asid as u64) << 48) | ((va as u64 >> 12) & 0xfff_ffff_ffff)
+}
+
+/// Return the ASID of the current address space for pgtype.  Kernel mappings
+/// are global, so kernel invalidations don't need one.
+fn current_asid(pgtype: RootPageTableType) -> u16 {
+    match pgtype {
+        RootPageTableType::User => (ttbr0_el1_raw() >> 48) as u16,
+        RootPageTableType::Kernel => 0,
+    }
+}
+
+/// Maximum number of addresses a TlbBatch collects before falling back to
+/// flushing the whole TLB.
+const TLB_BATCH_MAX: usize = 32;
+
+/// Collects virtual addresses whose TLB entries are stale after page table
+/// changes, so that they're invalidated together, with one set of barriers,
+/// once the changes are complete.  If too many addresses are collected it's
+/// cheaper to flush the whole TLB, so we do that instead.  Entries that
+/// replaced invalid ones aren't in any TLB, but still need a barrier before
+/// they're used, which the batch also provides.  Any outstanding
+/// invalidations are done when the batch is dropped.
+pub struct TlbBatch {
+    asid: u16,
+    vas: [usize; TLB_BATCH_MAX],
+    len: usize,
+    flush_all: bool,
+    new_entries: bool, // Entries were written over invalid ones
+}
+
+impl TlbBatch {
+    pub fn new(pgtype: RootPageTableType) -> Self {
+        Self {
+            asid: current_asid(pgtype),
+            vas: [0; TLB_BATCH_MAX],
+            len: 0,
+            flush_all: false,
+            new_entries: false,
+        }
+    }
+
+    /// Note that an invalid entry was replaced, so a barrier is needed
+    /// before it's used, though nothing needs invalidating.
+    pub fn add_new(&mut self) {
+        self.new_entries = true;
+    }
+
+    /// Add va to the batch of addresses to invalidate
+    pub fn add(&mut self, va: usize) {
+        if self.flush_all {
+            return;
+        }
+        if self.len == TLB_BATCH_MAX {
+            self.flush_all = true;
+            return;
+        }
+        self.vas[self.len] = va;
+        self.len += 1;
+    }
+
+    /// Invalidate everything in the batch now, leaving it empty
+    #[allow(unused_variables)]
+    pub fn flush(&mut self) {
+        if self.flush_all {
+            unsafe { invalidate_all_tlb_entries() };
+        } else if self.len > 0 {
+            let args = self.vas[..self.len].iter().map(|va| tlbi_va_arg(*va, self.asid));
+            #[cfg(not(test))]
+            unsafe {
+                core::arch::asm!("dsb ishst");
+                for arg in args {
+                    core::arch::asm!("tlbi vae1is, {arg}", arg = in(reg) arg);
+                }
+                core::arch::asm!("dsb ish", "isb");
+            }
+        } else if self.new_entries {
+            sync_table_writes();
+        }
+        self.clear();
+    }
+
+    /// Forget everything in the batch.  Only for use when the TLB has already
+    /// been flushed by other means.
+    fn clear(&mut self) {
+        self.len = 0;
+        self.flush_all = false;
+        self.new_entries = false;
+    }
+}
+
+impl Drop for TlbBatch {
+    fn drop(&mut self) {
+        self.flush();
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use crate::vmdebug::va_indices;
@@ -750,4 +1304,10 @@ mod tests {
             (511, 256, 0, 64)
         );
     }
+
+    #[test]
+    fn test_tlbi_va_arg() {
+        assert_eq!(tlbi_va_arg(0xffff_8000_0800_0000, 0), 0x0000_0ff8_0000_8000);
+        assert_eq!(tlbi_va_arg(0x1000, 5), 0x0005_0000_0000_0001);
+    }
 }
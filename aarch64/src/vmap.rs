@@ -0,0 +1,166 @@
+/// Kernel virtual address space allocation for MMIO and other dynamic
+/// mappings.  Address space is taken from a vmem arena, with an unmapped guard
+/// page after each mapping, and returned to it when the mapping is removed.
+use crate::param::KZERO;
+use crate::vm::{self, Entry, PageSize, PageTableError, RootPageTableType, UnmapPages, VaMapping};
+use port::mcslock::Lock;
+use port::mem::{PAGE_SIZE_4K, PhysRange, VirtRange};
+use port::vmem::VmemArena;
+
+#[cfg(not(test))]
+use port::println;
+
+/// Base of the region of kernel virtual address space used for dynamic
+/// mappings, and its size.
+const VMAP_BASE: usize = KZERO + 0x1000_0000_0000;
+const VMAP_SIZE: usize = 0x100_0000_0000;
+
+/// Maximum number of discontiguous free spans we can track.
+const VMAP_MAX_SPANS: usize = 256;
+
+static VMAP_ARENA: Lock<VmemArena<VMAP_MAX_SPANS>> =
+    Lock::new("vmap", VmemArena::new("vmap", VMAP_BASE, VMAP_SIZE, PAGE_SIZE_4K, PAGE_SIZE_4K));
+
+/// Return the alignment to use for a mapping of size bytes: the largest page
+/// or block size no bigger than the mapping, so that blocks can be used for
+/// its aligned middle.
+fn mapping_align(size: usize) -> usize {
+    [PageSize::Page1G, PageSize::Page2M, PageSize::Page4K]
+        .into_iter()
+        .map(|ps| ps.size())
+        .find(|&ps| ps <= size)
+        .unwrap_or(PAGE_SIZE_4K)
+}
+
+/// Allocate virtual address space for a mapping of physrange.  The virtual
+/// address has the same offset as the physical address from a boundary of
+/// `mapping_align`, so that blocks can be used wherever the physical range
+/// allows.
+fn alloc_va(debug_name: &str, physrange: &PhysRange) -> Result<VirtRange, PageTableError> {
+    let size = physrange.size();
+    let align = mapping_align(size);
+    let offset = physrange.start().addr() as usize & (align - 1);
+
+    let mut arena = VMAP_ARENA.lock();
+    let va = arena.alloc(offset + size, align).map_err(|err| {
+        println!("error:vmap:alloc_va:can't allocate va. debug_name:{debug_name} err:{err:?}");
+        PageTableError::OutOfVirtualSpace
+    })?;
+    Ok(VirtRange::with_len(va.start() + offset, size))
+}
+
+/// Free virtual address space allocated by `alloc_va` for range.
+fn free_va(range: &VirtRange) -> Result<(), PageTableError> {
+    let align = mapping_align(range.size());
+    let start = range.start() & !(align - 1);
+
+    let mut arena = VMAP_ARENA.lock();
+    arena.free(&VirtRange(start..range.end())).map_err(|err| {
+        println!("error:vmap:free_va:can't free va {range}: {err:?}");
+        PageTableError::NotMapped
+    })
+}
+
+/// Map the physical range into newly allocated kernel virtual address space,
+/// using the given entry flags.  The range must be on 4KiB boundaries.  The
+/// largest possible pages are used, so large ranges are mostly mapped with
+/// blocks.
+pub fn vmap(
+    debug_name: &str,
+    physrange: &PhysRange,
+    entry: Entry,
+) -> Result<VirtRange, PageTableError> {
+    map_va(
+        debug_name,
+        physrange,
+        |va| {
+            vm::kernel_pagetable().map_phys_range_auto(
+                debug_name,
+                physrange,
+                VaMapping::Addr(va.start()),
+                entry,
+                RootPageTableType::Kernel,
+            )
+        },
+        |va| {
+            if let Err(err) =
+                vm::kernel_pagetable().unmap_range(va, RootPageTableType::Kernel, UnmapPages::Keep)
+            {
+                println!("error:vmap:vmap:can't unmap {va} after failing to map it: {err:?}");
+            }
+        },
+    )
+}
+
+/// Allocate virtual address space for physrange, and map it with map.  If
+/// that fails partway, unmap removes whatever was mapped before the address
+/// space is freed, so that no stale translations are left for its next user.
+fn map_va(
+    debug_name: &str,
+    physrange: &PhysRange,
+    map: impl FnOnce(&VirtRange) -> Result<VirtRange, PageTableError>,
+    unmap: impl FnOnce(&VirtRange),
+) -> Result<VirtRange, PageTableError> {
+    let va = alloc_va(debug_name, physrange)?;
+    map(&va).inspect_err(|_| {
+        unmap(&va);
+        let _ = free_va(&va);
+    })
+}
+
+/// Remove a mapping created by `vmap` and release its virtual address space.
+/// The physical pages are not freed.
+pub fn vunmap(range: &VirtRange) -> Result<(), PageTableError> {
+    vm::kernel_pagetable().unmap_range(range, RootPageTableType::Kernel, UnmapPages::Keep)?;
+    free_va(range)
+}
+
+/// Map device registers.  The physical range need not be page aligned; the
+/// returned range covers exactly the requested registers.
+pub fn ioremap(debug_name: &str, physrange: &PhysRange) -> Result<VirtRange, PageTableError> {
+    let page_physrange = physrange.round(PAGE_SIZE_4K);
+    let vr = vmap(debug_name, &page_physrange, Entry::rw_device())?;
+    let offset = vr.start() - page_physrange.start().addr() as usize;
+    Ok(VirtRange::from_physrange(physrange, offset))
+}
+
+/// Remove a mapping created by `ioremap`.
+#[allow(dead_code)]
+pub fn iounmap(range: &VirtRange) -> Result<(), PageTableError> {
+    let start = range.start() & !(PAGE_SIZE_4K - 1);
+    let end = port::maths::round_up2_usize(range.end(), PAGE_SIZE_4K);
+    vunmap(&VirtRange(start..end))
+}
+
+/// Return a tuple of (bytes used, total bytes available) of kernel virtual
+/// address space for dynamic mappings.
+pub fn usage_bytes() -> (usize, usize) {
+    let arena = VMAP_ARENA.lock();
+    arena.usage_bytes()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use port::pagealloc::PageAllocError;
+
+    #[test]
+    fn failed_mapping_is_unmapped_before_freeing() {
+        let physrange = PhysRange::with_len(0x4000_0000, 3 * PAGE_SIZE_4K);
+        let before = usage_bytes();
+        let mut unmapped = None;
+        let result = map_va(
+            "test",
+            &physrange,
+            |_| Err(PageTableError::AllocationFailed(PageAllocError::OutOfSpace)),
+            |va| {
+                // Still allocated, so nothing else can map it meanwhile
+                assert!(usage_bytes().0 > before.0);
+                unmapped = Some(VirtRange(va.0.clone()));
+            },
+        );
+        assert!(result.is_err());
+        assert_eq!(unmapped.unwrap().size(), physrange.size());
+        assert_eq!(usage_bytes(), before);
+    }
+}
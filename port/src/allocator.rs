@@ -12,6 +12,9 @@ use core::ptr::NonNull;
 use core::sync::atomic::{AtomicUsize, Ordering};
 use core::{mem, ptr};
 
+#[cfg(feature = "heapdebug")]
+use core::fmt;
+
 /// The allocator works in terms of an owned region of memory
 /// that is represented by a Block, which describes the region
 /// in terms of a non-nil pointer and a length.  A Block is an
@@ -62,20 +65,113 @@ impl Block {
     }
 }
 
+/// A function that provides more memory for a growable
+/// BumpAlloc.  It's passed the minimum number of bytes needed,
+/// and returns a newly owned region of at least that size, or
+/// None if no more memory is available.  Regions that start
+/// exactly where the current arena ends are merged into it.
+pub type GrowFn = fn(min_len: usize) -> Option<Block>;
+
 /// A Bump Allocator takes ownership a region of memory, called
 /// an "arena", represented by a Block, and maintains a cursor
 /// into that region.  The cursor denotes the point between
 /// allocated and unallocated memory in the arena.
+///
+/// A growable bump allocator can also ask for more memory
+/// once its arena is exhausted.
+///
+/// Memory is only returned to the arena in LIFO order: the
+/// most recent allocation can be freed or resized in place,
+/// and the cursor can be reset to an earlier mark, freeing
+/// everything allocated since.  `scope` does this
+/// automatically, so that temporary data structures can be
+/// built in the arena using `Vec::new_in` and friends.
 pub struct BumpAlloc {
     arena: Block,
     cursor: AtomicUsize,
+    grow_fn: Option<GrowFn>,
 }
 
 impl BumpAlloc {
     /// Creates a new bump allocator over the given Block.
     /// Takes ownership of the provided region.
     pub const fn new(arena: Block) -> BumpAlloc {
-        BumpAlloc { arena, cursor: AtomicUsize::new(0) }
+        BumpAlloc { arena, cursor: AtomicUsize::new(0), grow_fn: None }
+    }
+
+    /// Creates a new bump allocator over the given Block, that
+    /// calls `grow_fn` for more memory when the arena is exhausted.
+    pub const fn new_growable(arena: Block, grow_fn: GrowFn) -> BumpAlloc {
+        BumpAlloc { arena, cursor: AtomicUsize::new(0), grow_fn: Some(grow_fn) }
+    }
+
+    /// Asks for at least `min_len` more bytes of memory.  If the
+    /// new region is contiguous with the arena, the arena is
+    /// extended.  Otherwise, the new region replaces the arena,
+    /// and the unallocated remainder of the old arena is
+    /// returned so that the caller can make use of it.  Returns
+    /// None if the allocator isn't growable or no more memory is
+    /// available.
+    ///
+    /// This takes `&mut self` since growing must not race with
+    /// other allocations.
+    pub fn grow_arena(&mut self, min_len: usize) -> Option<Block> {
+        let region = (self.grow_fn?)(min_len)?;
+        let cursor = *self.cursor.get_mut();
+        let arena_end = self.arena.as_ptr().wrapping_add(self.arena.len());
+        if region.as_ptr() == arena_end {
+            self.arena.len += region.len();
+            return Some(unsafe { Block::new_from_raw_parts(arena_end, 0) });
+        }
+        let (_, remainder) = self.arena.split_at_mut(cursor)?;
+        self.arena = region;
+        *self.cursor.get_mut() = 0;
+        Some(remainder)
+    }
+
+    /// Returns a mark for the current position of the cursor.
+    pub fn mark(&self) -> ArenaMark {
+        ArenaMark(self.cursor.load(Ordering::Relaxed))
+    }
+
+    /// Resets the cursor to the mark, freeing everything that
+    /// was allocated after the mark was taken.
+    ///
+    /// # Safety
+    /// Nothing allocated after the mark was taken may be used
+    /// again.
+    pub unsafe fn reset(&self, mark: ArenaMark) {
+        let cursor = self.cursor.load(Ordering::Relaxed);
+        assert!(mark.0 <= cursor, "reset to a mark beyond the cursor");
+        self.cursor.store(mark.0, Ordering::Relaxed);
+    }
+
+    /// Returns a scope that allocates from the arena, and frees
+    /// everything it allocated when dropped.  Scopes nest: the
+    /// arena, or an enclosing scope, can't be used while a scope
+    /// is live.
+    pub fn scope(&mut self) -> ArenaScope<'_> {
+        let mark = self.mark();
+        ArenaScope { arena: self, mark }
+    }
+
+    /// Returns the offset of ptr into the arena.
+    fn offset_of(&self, ptr: NonNull<u8>) -> usize {
+        ptr.as_ptr().addr() - self.arena.as_ptr().addr()
+    }
+
+    /// Changes the size of the block at ptr, if it's the most
+    /// recent allocation and there's room.  Returns whether it
+    /// did so.
+    fn resize_last(&self, ptr: NonNull<u8>, old_size: usize, new_size: usize) -> bool {
+        let offset = self.offset_of(ptr);
+        let Some(new_end) = offset.checked_add(new_size).filter(|&end| end <= self.arena.len())
+        else {
+            return false;
+        };
+        self.cursor
+            .compare_exchange(offset + old_size, new_end, Ordering::Relaxed, Ordering::Relaxed)
+            .is_ok()
     }
 
     /// Allocates the requested number of bytes with the given
@@ -104,16 +200,107 @@ impl BumpAlloc {
 }
 
 /// BumpAlloc<T> implements the allocator interface, and is
-/// suitable for e.g. page allocators and so forth.  Dealloc is
-/// unimplemented and will panic.
+/// suitable for e.g. page allocators and so forth.  Only the
+/// most recent allocation is actually freed by deallocate, or
+/// resized in place by grow and shrink.  Anything else leaks
+/// until the arena is reset.
 unsafe impl Allocator for BumpAlloc {
     fn allocate(&self, layout: Layout) -> Result<NonNull<[u8]>, AllocError> {
-        let (_, block) = self.try_alloc(layout.size(), layout.align()).ok_or(AllocError)?;
+        let (_, block) = self.try_alloc(layout.align(), layout.size()).ok_or(AllocError)?;
         Ok(NonNull::slice_from_raw_parts(block.ptr, block.len()))
     }
 
-    unsafe fn deallocate(&self, _ptr: NonNull<u8>, _layout: Layout) {
-        unimplemented!();
+    unsafe fn deallocate(&self, ptr: NonNull<u8>, layout: Layout) {
+        self.resize_last(ptr, layout.size(), 0);
+    }
+
+    unsafe fn grow(
+        &self,
+        ptr: NonNull<u8>,
+        old_layout: Layout,
+        new_layout: Layout,
+    ) -> Result<NonNull<[u8]>, AllocError> {
+        if ptr.align_offset(new_layout.align()) == 0
+            && self.resize_last(ptr, old_layout.size(), new_layout.size())
+        {
+            return Ok(NonNull::slice_from_raw_parts(ptr, new_layout.size()));
+        }
+        let new = self.allocate(new_layout)?;
+        unsafe {
+            ptr::copy_nonoverlapping(ptr.as_ptr(), new.cast().as_ptr(), old_layout.size());
+        }
+        Ok(new)
+    }
+
+    unsafe fn shrink(
+        &self,
+        ptr: NonNull<u8>,
+        old_layout: Layout,
+        new_layout: Layout,
+    ) -> Result<NonNull<[u8]>, AllocError> {
+        if ptr.align_offset(new_layout.align()) == 0 {
+            self.resize_last(ptr, old_layout.size(), new_layout.size());
+            return Ok(NonNull::slice_from_raw_parts(ptr, new_layout.size()));
+        }
+        let new = self.allocate(new_layout)?;
+        unsafe {
+            ptr::copy_nonoverlapping(ptr.as_ptr(), new.cast().as_ptr(), new_layout.size());
+            self.deallocate(ptr, old_layout);
+        }
+        Ok(new)
+    }
+}
+
+/// A position in a BumpAlloc's arena, that it can be reset to.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ArenaMark(usize);
+
+/// Allocates from a BumpAlloc, and frees everything that was
+/// allocated through it when dropped.  Allocations can't
+/// outlive the scope, since collections using it borrow it.
+pub struct ArenaScope<'a> {
+    arena: &'a mut BumpAlloc,
+    mark: ArenaMark,
+}
+
+impl ArenaScope<'_> {
+    /// Returns a nested scope.
+    pub fn scope(&mut self) -> ArenaScope<'_> {
+        self.arena.scope()
+    }
+}
+
+impl Drop for ArenaScope<'_> {
+    fn drop(&mut self) {
+        unsafe { self.arena.reset(self.mark) };
+    }
+}
+
+unsafe impl Allocator for ArenaScope<'_> {
+    fn allocate(&self, layout: Layout) -> Result<NonNull<[u8]>, AllocError> {
+        self.arena.allocate(layout)
+    }
+
+    unsafe fn deallocate(&self, ptr: NonNull<u8>, layout: Layout) {
+        unsafe { self.arena.deallocate(ptr, layout) }
+    }
+
+    unsafe fn grow(
+        &self,
+        ptr: NonNull<u8>,
+        old_layout: Layout,
+        new_layout: Layout,
+    ) -> Result<NonNull<[u8]>, AllocError> {
+        unsafe { self.arena.grow(ptr, old_layout, new_layout) }
+    }
+
+    unsafe fn shrink(
+        &self,
+        ptr: NonNull<u8>,
+        old_layout: Layout,
+        new_layout: Layout,
+    ) -> Result<NonNull<[u8]>, AllocError> {
+        unsafe { self.arena.shrink(ptr, old_layout, new_layout) }
     }
 }
 
@@ -138,6 +325,18 @@ const MAX_QUICK_SIZE: usize = 1 << MAX_QUICK_SHIFT;
 const NUM_QLISTS: usize = 14 - ALLOC_UNIT_SHIFT + 1;
 const NUM_HASH_BUCKETS: usize = 31; // Prime.
 
+// With the heapdebug feature, every allocation is surrounded
+// by red zones that are checked when it's freed, and free
+// blocks are poisoned, and checked when they're reused.
+#[cfg(feature = "heapdebug")]
+const REDZONE_SIZE: usize = 32;
+#[cfg(feature = "heapdebug")]
+const REDZONE_BYTE: u8 = 0xfd;
+#[cfg(feature = "heapdebug")]
+const POISON_BYTE: u8 = 0x6b;
+#[cfg(feature = "heapdebug")]
+const MAX_HEAPSTAT_SITES: usize = 64;
+
 /// A linked block header containing size, alignment, and
 /// address information for the block.  This is used both for
 /// linking unallocated blocks into one of the free lists and
@@ -162,6 +361,10 @@ const NUM_HASH_BUCKETS: usize = 31; // Prime.
 ///
 /// We use the link pointer to point to the next entry in the
 /// list in all cases.
+///
+/// With the heapdebug feature, every live allocation also has
+/// a header in front of it, recording the pc it was allocated
+/// from.
 #[derive(Debug)]
 #[repr(C, align(64))]
 struct Header {
@@ -169,16 +372,80 @@ struct Header {
     addr: NonNull<u8>,
     size: usize,
     align: usize,
+    #[cfg(feature = "heapdebug")]
+    pc: usize,
 }
 
 impl Header {
     /// Returns a new header for a block of the given size and
     /// alignment at the given address.
     fn new(addr: NonNull<u8>, size: usize, align: usize, next: Option<NonNull<Header>>) -> Header {
+        #[cfg(feature = "heapdebug")]
+        return Header { next, addr, size, align, pc: 0 };
+        #[cfg(not(feature = "heapdebug"))]
         Header { next, addr, size, align }
     }
 }
 
+/// Returns the pc that the function this is inlined into will
+/// return to.
+#[cfg(feature = "heapdebug")]
+#[inline(always)]
+fn callerpc() -> usize {
+    core::intrinsics::return_address().addr()
+}
+
+/// Returns the pc that the caller of the function this is
+/// inlined into will return to, by following the frame
+/// pointer chain up one frame.  This relies on frame pointers
+/// being kept, which the kernel targets ask for, and `xtask
+/// test` does for the host tests.  Returns 0 if the frame
+/// pointer is null or misaligned.
+#[cfg(feature = "heapdebug")]
+#[inline(always)]
+fn callercallerpc() -> usize {
+    let fp: *const usize;
+    // Each frame record is the caller's frame pointer and the
+    // return address, which RISC-V keeps just below the frame
+    // pointer, and everyone else just above it.
+    #[cfg(target_arch = "x86_64")]
+    unsafe {
+        core::arch::asm!("mov {}, rbp", out(reg) fp, options(nomem, nostack, preserves_flags));
+    }
+    #[cfg(target_arch = "aarch64")]
+    unsafe {
+        core::arch::asm!("mov {}, x29", out(reg) fp, options(nomem, nostack, preserves_flags));
+    }
+    #[cfg(target_arch = "riscv64")]
+    unsafe {
+        core::arch::asm!("mv {}, s0", out(reg) fp, options(nomem, nostack, preserves_flags));
+    }
+    #[cfg(not(target_arch = "riscv64"))]
+    let (prevfp, pc) = (0, 1);
+    #[cfg(target_arch = "riscv64")]
+    let (prevfp, pc) = (-2, -1);
+    // Without frame pointers, fp is whatever the register held, so
+    // give up on anything that can't be a frame record.
+    let valid = |fp: *const usize| !fp.is_null() && fp.is_aligned();
+    if !valid(fp) {
+        return 0;
+    }
+    let caller = unsafe { *fp.offset(prevfp) } as *const usize;
+    if !valid(caller) {
+        return 0;
+    }
+    unsafe { *caller.offset(pc) }
+}
+
+/// The live allocations made from one pc.
+#[cfg(feature = "heapdebug")]
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct HeapSite {
+    pub pc: usize,
+    pub count: usize,
+    pub bytes: usize,
+}
+
 /// The QuickFit allocator itself.  The allocator takes
 /// ownership of a bump allocator for the tail, and contains a
 /// set of lists for the quick blocks, as well as a misc list
@@ -192,6 +459,8 @@ pub struct QuickFit {
     qlists: [Option<NonNull<Header>>; NUM_QLISTS],
     misc: Option<NonNull<Header>>,
     allocated_misc: [Option<NonNull<Header>>; NUM_HASH_BUCKETS],
+    #[cfg(feature = "heapdebug")]
+    live: [Option<NonNull<Header>>; NUM_HASH_BUCKETS],
 }
 
 impl QuickFit {
@@ -200,6 +469,9 @@ impl QuickFit {
         let qlists = [None; NUM_QLISTS];
         let misc = None;
         let allocated_misc = [None; NUM_HASH_BUCKETS];
+        #[cfg(feature = "heapdebug")]
+        return QuickFit { tail, qlists, misc, allocated_misc, live: [None; NUM_HASH_BUCKETS] };
+        #[cfg(not(feature = "heapdebug"))]
         QuickFit { tail, qlists, misc, allocated_misc }
     }
 
@@ -207,8 +479,20 @@ impl QuickFit {
     /// alignment.  Returns a pointer to such a block, or nil if
     /// the block cannot be allocated.
     pub fn malloc(&mut self, layout: Layout) -> *mut u8 {
+        #[cfg(feature = "heapdebug")]
+        return self.malloc_debug(layout, callerpc());
+        #[cfg(not(feature = "heapdebug"))]
+        self.malloc_block(layout)
+    }
+
+    /// Allocates a block without any debugging wrapper.
+    fn malloc_block(&mut self, layout: Layout) -> *mut u8 {
         let (size, align) = Self::adjust(layout);
         let p = self.alloc_quick(size, align);
+        #[cfg(feature = "heapdebug")]
+        if let Some(p) = p {
+            Self::check_poison(p, size);
+        }
         p.or_else(|| self.alloc_tail(size, align)).map(|p| p.as_ptr()).unwrap_or(ptr::null_mut())
     }
 
@@ -227,16 +511,35 @@ impl QuickFit {
         (size, align)
     }
 
+    /// Returns the index of the quick list that blocks with the
+    /// given layout belong to, or None if they belong to the
+    /// misc list.
+    fn quick_class(layout: Layout) -> Option<usize> {
+        let (size, align) = Self::adjust(layout);
+        (size <= MAX_QUICK_SIZE && align == size).then(|| size.ilog2() as usize - ALLOC_UNIT_SHIFT)
+    }
+
+    /// Returns the layout of the blocks on quick list `k`.
+    fn quick_layout(k: usize) -> Layout {
+        let size = 1 << (k + ALLOC_UNIT_SHIFT);
+        Layout::from_size_align(size, size).unwrap()
+    }
+
+    /// Takes a block from quick list `k`, if it isn't empty.
+    /// Unlike `malloc`, this never allocates from the tail.
+    fn take_quick(&mut self, k: usize) -> Option<NonNull<u8>> {
+        let (node, list) = Self::head(self.qlists[k].take());
+        self.qlists[k] = list;
+        node.map(|header| unsafe { header.as_ref() }.addr)
+    }
+
     /// Attempts to allocate from an existing list: for requests
     /// that can be satisfied from one of the quick lists, try
     /// and do so; otherwise, attempt an allocation from the
     /// misc list.
     fn alloc_quick(&mut self, size: usize, align: usize) -> Option<NonNull<u8>> {
         if size <= MAX_QUICK_SIZE && align == size {
-            let k: usize = size.ilog2() as usize - ALLOC_UNIT_SHIFT;
-            let (node, list) = Self::head(self.qlists[k].take());
-            self.qlists[k] = list;
-            node.map(|header| unsafe { header.as_ref() }.addr)
+            self.take_quick(size.ilog2() as usize - ALLOC_UNIT_SHIFT)
         } else {
             self.alloc_misc(size, align)
         }
@@ -262,15 +565,29 @@ impl QuickFit {
     /// then we try to free blocks larger than or equal in size
     /// to the minimum allocation unit into the quick lists
     /// until it is.
+    ///
+    /// If the tail is exhausted, we try to grow it.  Whatever was
+    /// left of the old tail is freed into the quick lists.
     fn alloc_tail(&mut self, size: usize, align: usize) -> Option<NonNull<u8>> {
-        let (prefix, block) = { self.tail.try_alloc(size, align)? };
+        let (prefix, block) = match self.tail.try_alloc(align, size) {
+            Some(blocks) => blocks,
+            None => {
+                let remainder = self.tail.grow_arena(size.checked_add(align)?)?;
+                self.free_prefix(remainder);
+                self.tail.try_alloc(align, size)?
+            }
+        };
         self.free_prefix(prefix);
         Some(block.ptr)
     }
 
     /// Frees a prefix that came from a tail allocation.  This
-    /// attempts to store blocks into the quick lists.
+    /// attempts to store blocks into the quick lists.  Prefixes
+    /// too small to hold a minimum sized block are dropped.
     fn free_prefix(&mut self, prefix: Block) {
+        if prefix.len() < MIN_ALLOC_SIZE {
+            return;
+        }
         let mut prefix = Self::align_prefix(prefix);
         while let Some(rest) = self.try_free_prefix(prefix) {
             prefix = rest;
@@ -295,7 +612,7 @@ impl QuickFit {
             let size = 1 << (k + ALLOC_UNIT_SHIFT);
             if prefix.len() >= size && ptr.align_offset(size) == 0 {
                 let (_, rest) = prefix.split_at_mut(size)?;
-                self.free(ptr, Layout::from_size_align(size, size).unwrap());
+                self.free_block(ptr, Layout::from_size_align(size, size).unwrap());
                 return (rest.len() >= MIN_ALLOC_SIZE).then_some(rest);
             }
         }
@@ -354,10 +671,22 @@ impl QuickFit {
     /// quick lists, it is; otherwise, it is treated as a misc
     /// block and freed there.
     pub fn free(&mut self, block: *mut u8, layout: Layout) {
+        #[cfg(feature = "heapdebug")]
+        return self.free_debug(block, layout);
+        #[cfg(not(feature = "heapdebug"))]
+        self.free_block(block, layout)
+    }
+
+    /// Frees a block without any debugging wrapper.
+    fn free_block(&mut self, block: *mut u8, layout: Layout) {
         let Some(block) = NonNull::new(block) else {
             return;
         };
         let (size, align) = Self::adjust(layout);
+        #[cfg(feature = "heapdebug")]
+        unsafe {
+            ptr::write_bytes(block.as_ptr(), POISON_BYTE, size);
+        }
         if size <= MAX_QUICK_SIZE && align == size {
             let k: usize = size.ilog2() as usize - ALLOC_UNIT_SHIFT;
             let header = Header::new(block, size, align, self.qlists[k].take());
@@ -390,7 +719,7 @@ impl QuickFit {
         let mut header = self
             .unlink_allocated_misc(block)
             .or_else(|| {
-                let hblock = self.malloc(Layout::new::<Header>()).cast::<Header>();
+                let hblock = self.malloc_block(Layout::new::<Header>()).cast::<Header>();
                 let hblock = hblock
                     .is_null()
                     .then(|| {
@@ -433,27 +762,27 @@ impl QuickFit {
     /// None, and the list head.  The list head will be None if
     /// the list is empty.
     fn unlink<F>(
-        mut list: Option<NonNull<Header>>,
+        list: Option<NonNull<Header>>,
         predicate: F,
     ) -> (Option<NonNull<Header>>, Option<NonNull<Header>>)
     where
         F: Fn(&Header) -> bool,
     {
         let mut prev: Option<NonNull<Header>> = None;
-        while let Some(mut node) = list {
+        let mut cursor = list;
+        while let Some(mut node) = cursor {
             let node = unsafe { node.as_mut() };
             if predicate(node) {
                 let next = node.next.take();
                 if let Some(mut prev) = prev {
                     let prev = unsafe { prev.as_mut() };
                     prev.next = next;
-                } else {
-                    list = next;
+                    return (NonNull::new(node), list);
                 }
-                return (NonNull::new(node), list);
+                return (NonNull::new(node), next);
             }
             prev = NonNull::new(node);
-            list = node.next;
+            cursor = node.next;
         }
         (None, list)
     }
@@ -476,15 +805,200 @@ impl QuickFit {
     }
 }
 
-#[cfg(not(test))]
+// # Heap debugging.
+//
+// With the heapdebug feature, each allocation is laid out as
+// a header recording the allocation, a red zone, the memory
+// returned to the caller, and another red zone.  The headers
+// of live allocations are kept in a hash table, so that they
+// can be listed by the pc they were allocated from.  Freeing
+// an allocation checks its red zones, so an overrun or
+// underrun is caught at the latest when the block is freed.
+//
+// Free blocks are poisoned, and the poison is checked when a
+// block is reused, catching writes to freed memory.
+#[cfg(feature = "heapdebug")]
+impl QuickFit {
+    /// Returns the layout of the block that holds a debug
+    /// allocation with the given layout, and the offset of the
+    /// allocation in that block.
+    fn debug_layout(layout: Layout) -> Option<(Layout, usize)> {
+        let align = usize::max(layout.align(), mem::align_of::<Header>());
+        let offset = (mem::size_of::<Header>() + REDZONE_SIZE).next_multiple_of(align);
+        let size = offset.checked_add(layout.size())?.checked_add(REDZONE_SIZE)?;
+        Some((Layout::from_size_align(size, align).ok()?, offset))
+    }
+
+    /// Allocates a block, recording `pc` as the call site.
+    pub fn malloc_debug(&mut self, layout: Layout, pc: usize) -> *mut u8 {
+        let Some((outer, offset)) = Self::debug_layout(layout) else {
+            return ptr::null_mut();
+        };
+        let block = self.malloc_block(outer);
+        if block.is_null() {
+            return block;
+        }
+        let addr = unsafe { NonNull::new_unchecked(block.add(offset)) };
+        let header_size = mem::size_of::<Header>();
+        unsafe {
+            ptr::write_bytes(block.add(header_size), REDZONE_BYTE, offset - header_size);
+            ptr::write_bytes(addr.as_ptr().add(layout.size()), REDZONE_BYTE, REDZONE_SIZE);
+        }
+        let k = Self::hash(addr.as_ptr());
+        let mut header = Header::new(addr, layout.size(), layout.align(), self.live[k].take());
+        header.pc = pc;
+        unsafe { ptr::write(block.cast::<Header>(), header) };
+        self.live[k] = NonNull::new(block.cast());
+        addr.as_ptr()
+    }
+
+    /// Frees a block allocated by `malloc_debug`, checking its
+    /// red zones.  Panics if they've been overwritten, or the
+    /// block isn't a live allocation of the given layout.
+    fn free_debug(&mut self, block: *mut u8, layout: Layout) {
+        let Some(addr) = NonNull::new(block) else {
+            return;
+        };
+        let k = Self::hash(block);
+        let (node, list) = Self::unlink(self.live[k].take(), |node| node.addr == addr);
+        self.live[k] = list;
+        let Some(header) = node else {
+            panic!("heap: free of {block:p}, which isn't allocated");
+        };
+        let header = unsafe { header.as_ref() };
+        let pc = header.pc;
+        if header.size != layout.size() || header.align != layout.align() {
+            panic!(
+                "heap: free of {block:p} with size {} align {}, allocated with size {} align {} at pc {pc:#x}",
+                layout.size(),
+                layout.align(),
+                header.size,
+                header.align,
+            );
+        }
+
+        let (outer, offset) = Self::debug_layout(layout).expect("layout");
+        let outer_block = block.wrapping_sub(offset);
+        let header_size = mem::size_of::<Header>();
+        let before = unsafe {
+            core::slice::from_raw_parts(outer_block.add(header_size), offset - header_size)
+        };
+        let after = unsafe { core::slice::from_raw_parts(block.add(layout.size()), REDZONE_SIZE) };
+        if before.iter().any(|&b| b != REDZONE_BYTE) {
+            panic!(
+                "heap: red zone before {block:p} (size {}, allocated at pc {pc:#x}) overwritten",
+                layout.size()
+            );
+        }
+        if after.iter().any(|&b| b != REDZONE_BYTE) {
+            panic!(
+                "heap: red zone after {block:p} (size {}, allocated at pc {pc:#x}) overwritten",
+                layout.size()
+            );
+        }
+        self.free_block(outer_block, outer);
+    }
+
+    /// Checks that a free block that's about to be reused is
+    /// still poisoned.  The start of the block held its free
+    /// list header, so isn't checked.
+    fn check_poison(block: NonNull<u8>, size: usize) {
+        let header_size = mem::size_of::<Header>();
+        if size <= header_size {
+            return;
+        }
+        let ptr = block.as_ptr();
+        let body = unsafe { core::slice::from_raw_parts(ptr.add(header_size), size - header_size) };
+        if let Some(offset) = body.iter().position(|&b| b != POISON_BYTE) {
+            panic!(
+                "heap: free block {ptr:p} (size {size}) modified at offset {}",
+                offset + header_size
+            );
+        }
+    }
+
+    /// Returns the sites with the most live bytes allocated, in
+    /// decreasing order, along with the total over all sites.
+    /// If there are more sites than can be tracked, the excess
+    /// are counted against pc 0.
+    pub fn live_sites(&self) -> ([HeapSite; MAX_HEAPSTAT_SITES], HeapSite) {
+        let mut sites = [HeapSite::default(); MAX_HEAPSTAT_SITES];
+        let mut total = HeapSite::default();
+        let mut num_sites = 0;
+        for bucket in self.live {
+            let mut node = bucket;
+            while let Some(header) = node {
+                let header = unsafe { header.as_ref() };
+                total.count += 1;
+                total.bytes += header.size;
+                let pc = if num_sites < MAX_HEAPSTAT_SITES - 1
+                    || sites[..num_sites].iter().any(|site| site.pc == header.pc)
+                {
+                    header.pc
+                } else {
+                    0
+                };
+                let i = match sites[..num_sites].iter().position(|site| site.pc == pc) {
+                    Some(i) => i,
+                    None => {
+                        sites[num_sites].pc = pc;
+                        num_sites += 1;
+                        num_sites - 1
+                    }
+                };
+                sites[i].count += 1;
+                sites[i].bytes += header.size;
+                node = header.next;
+            }
+        }
+        sites[..num_sites].sort_unstable_by_key(|site| core::cmp::Reverse(site.bytes));
+        (sites, total)
+    }
+
+    /// Writes a table of the live allocations by site, largest
+    /// first.
+    pub fn heapstat(&self, w: &mut dyn fmt::Write) -> fmt::Result {
+        let (sites, total) = self.live_sites();
+        writeln!(w, "{:>18} {:>8} {:>12}", "pc", "count", "bytes")?;
+        for site in sites.iter().take_while(|site| site.count > 0) {
+            writeln!(w, "{:#18x} {:>8} {:>12}", site.pc, site.count, site.bytes)?;
+        }
+        writeln!(w, "{:>18} {:>8} {:>12}", "total", total.count, total.bytes)
+    }
+}
+
+/// The global allocator.  A single QuickFit behind a lock
+/// serves the whole kernel.  The lock is only taken with
+/// interrupts disabled, so interrupt handlers may allocate.
+///
+/// In front of the lock, each CPU keeps a magazine of free
+/// blocks for each quick list: a small stack of blocks that
+/// only that CPU touches, and only with interrupts disabled,
+/// so most small allocations and frees don't take the lock at
+/// all.  An empty magazine is refilled with half a magazine of
+/// blocks from the quick list, and a full one returns half of
+/// its blocks, so a CPU alternating between allocating and
+/// freeing doesn't go back to the lock every time.
+///
+/// The magazines are a simplified version of those in [Bon01].
+///
+/// [Bon01] Jeff Bonwick and Jonathan Adams. 2001. Magazines
+/// and Vmem: Extending the Slab Allocator to Many CPUs and
+/// Arbitrary Resources.  Proceedings of the 2001 USENIX
+/// Annual Technical Conference, 15-33.
 pub mod global {
-    use super::QuickFit;
+    use super::{NUM_QLISTS, QuickFit};
+    use crate::cpu::{self, MAX_CPUS};
+    use crate::mcslock::Lock;
     use alloc::alloc::{GlobalAlloc, Layout};
+    use core::cell::UnsafeCell;
     use core::ptr;
-    use core::sync::atomic::{AtomicPtr, Ordering};
 
     const GLOBAL_HEAP_SIZE: usize = 4 * 1024 * 1024;
 
+    /// Number of blocks held by a magazine.
+    const MAGAZINE_SIZE: usize = 16;
+
     /// A GlobalHeap is an aligned wrapper around an owned
     /// buffer.
     #[repr(C, align(4096))]
@@ -501,32 +1015,501 @@ pub mod global {
         }
     }
 
-    /// GlobalQuickAlloc is a wrapper around a QuickFit over a
-    /// GlobalHeap that uses interior mutability to implement
-    /// the GlobalAlloc trait.
-    pub struct GlobalQuickAlloc(pub AtomicPtr<QuickFit>);
+    /// A stack of free blocks from one of the quick lists.
+    struct Magazine {
+        rounds: [*mut u8; MAGAZINE_SIZE],
+        len: usize,
+    }
+
+    impl Magazine {
+        const fn new() -> Magazine {
+            Magazine { rounds: [ptr::null_mut(); MAGAZINE_SIZE], len: 0 }
+        }
+
+        fn is_full(&self) -> bool {
+            self.len == MAGAZINE_SIZE
+        }
+
+        fn push(&mut self, block: *mut u8) {
+            self.rounds[self.len] = block;
+            self.len += 1;
+        }
+
+        fn pop(&mut self) -> Option<*mut u8> {
+            self.len = self.len.checked_sub(1)?;
+            Some(self.rounds[self.len])
+        }
+    }
+
+    /// A CPU's magazines, one for each quick list.  Only ever
+    /// accessed by its own CPU, with interrupts disabled.
+    struct CpuCache(UnsafeCell<[Magazine; NUM_QLISTS]>);
+
+    unsafe impl Sync for CpuCache {}
+
+    impl CpuCache {
+        const fn new() -> CpuCache {
+            CpuCache(UnsafeCell::new([const { Magazine::new() }; NUM_QLISTS]))
+        }
+    }
+
+    /// GlobalQuickAlloc wraps a QuickFit over a GlobalHeap in
+    /// a lock and per-CPU caches to implement the GlobalAlloc
+    /// trait.
+    pub struct GlobalQuickAlloc {
+        quick: Lock<QuickFit>,
+        caches: [CpuCache; MAX_CPUS],
+    }
+
+    #[cfg_attr(feature = "heapdebug", allow(dead_code))]
     impl GlobalQuickAlloc {
+        pub const fn new(quick: QuickFit) -> GlobalQuickAlloc {
+            let caches = [const { CpuCache::new() }; MAX_CPUS];
+            GlobalQuickAlloc { quick: Lock::new("heap", quick), caches }
+        }
+
+        /// Runs the thunk with the QuickFit locked.  Must be
+        /// called with interrupts disabled.
         fn with_allocator<F, R>(&self, thunk: F) -> R
         where
             F: FnOnce(&mut QuickFit) -> R,
         {
-            let a = self.0.swap(ptr::null_mut(), Ordering::Relaxed);
-            assert!(!a.is_null(), "global allocator is nil");
-            let r = thunk(unsafe { &mut *a });
-            self.0.swap(a, Ordering::Relaxed);
-            r
+            let mut quick = self.quick.lock();
+            thunk(&mut quick)
+        }
+
+        /// Returns the current CPU's magazine for quick list
+        /// `k`, or None if the CPU has no cache.  Must be called
+        /// with interrupts disabled, and the magazine must not be
+        /// used once they're enabled again.
+        #[allow(clippy::mut_from_ref)]
+        fn magazine(&self, k: usize) -> Option<&mut Magazine> {
+            let cache = self.caches.get(cpu::cpu_id())?;
+            Some(unsafe { &mut (*cache.0.get())[k] })
+        }
+
+        /// Allocates a block from quick list `k`, via the
+        /// magazine if there is one.
+        fn alloc_quick(&self, k: usize) -> *mut u8 {
+            let layout = QuickFit::quick_layout(k);
+            let Some(magazine) = self.magazine(k) else {
+                return self.with_allocator(|quick| quick.malloc(layout));
+            };
+            if let Some(block) = magazine.pop() {
+                return block;
+            }
+            self.with_allocator(|quick| {
+                while magazine.len < MAGAZINE_SIZE / 2 {
+                    let Some(block) = quick.take_quick(k) else {
+                        break;
+                    };
+                    magazine.push(block.as_ptr());
+                }
+                magazine.pop().unwrap_or_else(|| quick.malloc(layout))
+            })
+        }
+
+        /// Frees a block to quick list `k`, via the magazine if
+        /// there is one.
+        fn free_quick(&self, block: *mut u8, k: usize) {
+            let layout = QuickFit::quick_layout(k);
+            let Some(magazine) = self.magazine(k) else {
+                return self.with_allocator(|quick| quick.free(block, layout));
+            };
+            if magazine.is_full() {
+                self.with_allocator(|quick| {
+                    while magazine.len > MAGAZINE_SIZE / 2 {
+                        quick.free(magazine.pop().unwrap(), layout);
+                    }
+                });
+            }
+            magazine.push(block);
+        }
+    }
+
+    #[cfg(feature = "heapdebug")]
+    impl GlobalQuickAlloc {
+        /// Writes a table of the live allocations by site.
+        pub fn heapstat(&self, w: &mut dyn core::fmt::Write) -> core::fmt::Result {
+            cpu::without_interrupts(|| self.with_allocator(|quick| quick.heapstat(w)))
         }
     }
 
     unsafe impl GlobalAlloc for GlobalQuickAlloc {
+        /// With the heapdebug feature, the magazines aren't used,
+        /// so that every allocation is tagged and checked.  The
+        /// allocation is charged to the caller of the allocator
+        /// shim (`__rust_alloc`) that calls this, rather than to
+        /// the shim itself.
+        #[cfg(feature = "heapdebug")]
+        #[inline(never)]
         unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
-            self.with_allocator(|quick| quick.malloc(layout))
+            let pc = super::callercallerpc();
+            cpu::without_interrupts(|| self.with_allocator(|quick| quick.malloc_debug(layout, pc)))
         }
+
+        #[cfg(feature = "heapdebug")]
         unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
-            self.with_allocator(|quick| quick.free(ptr, layout));
+            cpu::without_interrupts(|| self.with_allocator(|quick| quick.free_debug(ptr, layout)))
         }
+
+        #[cfg(not(feature = "heapdebug"))]
+        unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+            cpu::without_interrupts(|| match QuickFit::quick_class(layout) {
+                Some(k) => self.alloc_quick(k),
+                None => self.with_allocator(|quick| quick.malloc(layout)),
+            })
+        }
+
+        #[cfg(not(feature = "heapdebug"))]
+        unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+            cpu::without_interrupts(|| match QuickFit::quick_class(layout) {
+                Some(k) => self.free_quick(ptr, k),
+                None => self.with_allocator(|quick| quick.free(ptr, layout)),
+            })
+        }
+
+        /// Quick blocks are always allocated at the full size of
+        /// their list, so resizing within a list is free.  Misc
+        /// blocks are left to QuickFit.  Anything else moves the
+        /// data between the quick and misc lists.
         unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
-            self.with_allocator(|quick| unsafe { quick.realloc(ptr, layout, new_size) })
+            let new_layout = Layout::from_size_align(new_size, layout.align()).expect("layout");
+            match (QuickFit::quick_class(layout), QuickFit::quick_class(new_layout)) {
+                _ if cfg!(feature = "heapdebug") => {}
+                (Some(k), Some(new_k)) if k == new_k => return ptr,
+                (None, None) => {
+                    return cpu::without_interrupts(|| {
+                        self.with_allocator(|quick| unsafe { quick.realloc(ptr, layout, new_size) })
+                    });
+                }
+                _ => {}
+            }
+            let np = unsafe { self.alloc(new_layout) };
+            if !np.is_null() {
+                unsafe {
+                    ptr::copy_nonoverlapping(ptr, np, usize::min(layout.size(), new_size));
+                    self.dealloc(ptr, layout);
+                }
+            }
+            np
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::global::GlobalQuickAlloc;
+    use super::*;
+    use alloc::alloc::GlobalAlloc;
+    use core::sync::atomic::AtomicUsize;
+
+    static GROW_CALLS: AtomicUsize = AtomicUsize::new(0);
+
+    fn region(len: usize) -> Block {
+        let layout = Layout::from_size_align(len, 4096).unwrap();
+        let ptr = unsafe { std::alloc::alloc(layout) };
+        assert!(!ptr.is_null());
+        unsafe { Block::new_from_raw_parts(ptr, len) }
+    }
+
+    fn grow(min_len: usize) -> Option<Block> {
+        GROW_CALLS.fetch_add(1, Ordering::Relaxed);
+        Some(region(usize::max(min_len, 64 * 1024)))
+    }
+
+    fn no_grow(_min_len: usize) -> Option<Block> {
+        None
+    }
+
+    #[test]
+    fn bump_allocate_aligns() {
+        let bump = BumpAlloc::new(region(4096));
+        bump.allocate(Layout::from_size_align(1, 1).unwrap()).unwrap();
+        let p = bump.allocate(Layout::from_size_align(100, 64).unwrap()).unwrap();
+        assert_eq!(p.cast::<u8>().align_offset(64), 0);
+        assert_eq!(p.len(), 100);
+        assert_eq!(bump.mark(), ArenaMark(164));
+    }
+
+    #[test]
+    fn bump_frees_last_allocation() {
+        let bump = BumpAlloc::new(region(4096));
+        let layout = Layout::from_size_align(100, 8).unwrap();
+        let a = bump.allocate(layout).unwrap().cast::<u8>();
+        let b = bump.allocate(layout).unwrap().cast::<u8>();
+        let mark = bump.mark();
+
+        // Only the most recent allocation is freed
+        unsafe { bump.deallocate(a, layout) };
+        assert_eq!(bump.mark(), mark);
+        unsafe { bump.deallocate(b, layout) };
+        assert_eq!(bump.allocate(layout).unwrap().cast(), b);
+    }
+
+    #[test]
+    fn bump_mark_and_reset() {
+        let bump = BumpAlloc::new(region(4096));
+        let layout = Layout::from_size_align(1000, 8).unwrap();
+        bump.allocate(layout).unwrap();
+        let mark = bump.mark();
+        let a = bump.allocate(layout).unwrap();
+        bump.allocate(layout).unwrap();
+        bump.allocate(layout).unwrap();
+        assert!(bump.allocate(layout).is_err());
+        unsafe { bump.reset(mark) };
+        assert_eq!(bump.allocate(layout).unwrap(), a);
+
+        // A mark beyond the cursor is refused, leaving the arena alone
+        let beyond = ArenaMark(bump.mark().0 + 1000);
+        let before = bump.mark();
+        let reset = std::panic::catch_unwind(|| unsafe { bump.reset(beyond) });
+        assert!(reset.is_err());
+        assert_eq!(bump.mark(), before);
+    }
+
+    #[test]
+    fn bump_grows_and_shrinks_in_place() {
+        let bump = BumpAlloc::new(region(4096));
+        let small = Layout::from_size_align(100, 8).unwrap();
+        let large = Layout::from_size_align(1000, 8).unwrap();
+        let p = bump.allocate(small).unwrap().cast::<u8>();
+        unsafe { p.write(42) };
+
+        let q = unsafe { bump.grow(p, small, large) }.unwrap();
+        assert_eq!(q.cast(), p);
+        assert_eq!(bump.mark(), ArenaMark(1000));
+        let q = unsafe { bump.shrink(p, large, small) }.unwrap();
+        assert_eq!(q.cast(), p);
+        assert_eq!(bump.mark(), ArenaMark(100));
+
+        // Once something else has been allocated, growing moves the block
+        bump.allocate(small).unwrap();
+        let q = unsafe { bump.grow(p, small, large) }.unwrap().cast::<u8>();
+        assert_ne!(q, p);
+        assert_eq!(unsafe { q.read() }, 42);
+    }
+
+    #[test]
+    fn bump_scopes() {
+        let mut bump = BumpAlloc::new(region(64 * 1024));
+        let start = bump.mark();
+        {
+            let mut outer = bump.scope();
+            let mut v = Vec::new_in(&outer);
+            v.extend(0..1000u32);
+            assert_eq!(v.iter().sum::<u32>(), 499500);
+            drop(v);
+            {
+                let inner = outer.scope();
+                let mut w: Vec<u64, _> = Vec::with_capacity_in(10, &inner);
+                w.push(1);
+            }
         }
+        assert_eq!(bump.mark(), start);
+    }
+
+    #[test]
+    fn quickfit_grows_tail() {
+        let mut quick = QuickFit::new(BumpAlloc::new_growable(region(4096), grow));
+        let layout = Layout::from_size_align(1024, 8).unwrap();
+        let ptrs: Vec<*mut u8> = (0..16).map(|_| quick.malloc(layout)).collect();
+        assert!(ptrs.iter().all(|p| !p.is_null()));
+        assert!(GROW_CALLS.load(Ordering::Relaxed) > 0);
+
+        // Large, irregular allocations come from the new tail too
+        let layout = Layout::from_size_align(100_000, 16).unwrap();
+        let p = quick.malloc(layout);
+        assert!(!p.is_null());
+        assert_eq!(p.align_offset(16), 0);
+    }
+
+    #[test]
+    fn quickfit_fails_without_growth() {
+        let mut quick = QuickFit::new(BumpAlloc::new_growable(region(4096), no_grow));
+        let layout = Layout::from_size_align(1024, 8).unwrap();
+        let n = (0..).take_while(|_| !quick.malloc(layout).is_null()).count();
+        assert!(n > 0 && n <= 4);
+    }
+
+    #[test]
+    fn unlink_keeps_rest_of_list() {
+        let mut blocks = [0u64; 3];
+        let mut headers: Vec<Header> =
+            blocks.iter_mut().map(|b| Header::new(NonNull::from(b).cast(), 8, 8, None)).collect();
+        let base = headers.as_mut_ptr();
+        let node = |i: usize| NonNull::new(base.wrapping_add(i));
+        let addr = |i: usize| unsafe { (*base.add(i)).addr };
+        for i in 0..2 {
+            unsafe { (*base.add(i)).next = node(i + 1) };
+        }
+
+        // Unlinking from the middle leaves the head in place
+        let (n, list) = QuickFit::unlink(node(0), |h| h.addr == addr(1));
+        assert_eq!((n, list), (node(1), node(0)));
+
+        // Unlinking something that isn't there leaves the list unchanged
+        let (n, list) = QuickFit::unlink(list, |h| h.addr == addr(1));
+        assert_eq!((n, list), (None, node(0)));
+
+        let (n, list) = QuickFit::unlink(list, |h| h.addr == addr(2));
+        assert_eq!((n, list), (node(2), node(0)));
+        assert_eq!(QuickFit::head(list), (node(0), None));
+    }
+
+    #[test]
+    fn global_reuses_freed_blocks() {
+        let global = GlobalQuickAlloc::new(QuickFit::new(BumpAlloc::new(region(64 * 1024))));
+        let layout = Layout::from_size_align(100, 8).unwrap();
+        let p = unsafe { global.alloc(layout) };
+        assert!(!p.is_null());
+        unsafe { global.dealloc(p, layout) };
+        assert_eq!(unsafe { global.alloc(layout) }, p);
+
+        // Growing within the block's quick list doesn't move it
+        #[cfg(not(feature = "heapdebug"))]
+        assert_eq!(unsafe { global.realloc(p, layout, 128) }, p);
+        let q = unsafe { global.realloc(p, layout, 20_000) };
+        assert!(!q.is_null());
+        assert_ne!(q, p);
+    }
+
+    // Exercises the magazines, which heapdebug doesn't use.
+    #[test]
+    #[cfg(not(feature = "heapdebug"))]
+    fn global_concurrent() {
+        // Each thread is its own CPU
+        crate::cpu::testhooks::install();
+        let global = GlobalQuickAlloc::new(QuickFit::new(BumpAlloc::new(region(16 << 20))));
+        let global = &global;
+        std::thread::scope(|s| {
+            for t in 0..8u8 {
+                s.spawn(move || {
+                    let mut blocks = Vec::new();
+                    for i in 0..2000usize {
+                        let size = 1 + (i * 37 + t as usize * 101) % 3000;
+                        let layout = Layout::from_size_align(size, 8).unwrap();
+                        let p = unsafe { global.alloc(layout) };
+                        assert!(!p.is_null());
+                        unsafe { ptr::write_bytes(p, t, size) };
+                        blocks.push((p, layout));
+                        if blocks.len() > 64 {
+                            // Free an older block, checking nobody else wrote to it
+                            let (p, layout) = blocks.swap_remove(i % blocks.len());
+                            let data = unsafe { std::slice::from_raw_parts(p, layout.size()) };
+                            assert!(data.iter().all(|&b| b == t));
+                            unsafe { global.dealloc(p, layout) };
+                        }
+                    }
+                    for (p, layout) in blocks {
+                        let data = unsafe { std::slice::from_raw_parts(p, layout.size()) };
+                        assert!(data.iter().all(|&b| b == t));
+                        unsafe { global.dealloc(p, layout) };
+                    }
+                });
+            }
+        });
+    }
+
+    #[cfg(feature = "heapdebug")]
+    fn debug_quickfit() -> QuickFit {
+        QuickFit::new(BumpAlloc::new(region(64 * 1024)))
+    }
+
+    #[test]
+    #[cfg(feature = "heapdebug")]
+    #[should_panic(expected = "red zone after")]
+    fn heapdebug_catches_overrun() {
+        let mut quick = debug_quickfit();
+        let layout = Layout::from_size_align(100, 8).unwrap();
+        let p = quick.malloc(layout);
+        unsafe { ptr::write_bytes(p, 0, 101) };
+        quick.free(p, layout);
+    }
+
+    #[test]
+    #[cfg(feature = "heapdebug")]
+    #[should_panic(expected = "red zone before")]
+    fn heapdebug_catches_underrun() {
+        let mut quick = debug_quickfit();
+        let layout = Layout::from_size_align(20_000, 8).unwrap();
+        let p = quick.malloc(layout);
+        unsafe { p.sub(1).write(0) };
+        quick.free(p, layout);
+    }
+
+    #[test]
+    #[cfg(feature = "heapdebug")]
+    #[should_panic(expected = "modified at offset")]
+    fn heapdebug_catches_use_after_free() {
+        let mut quick = debug_quickfit();
+        let layout = Layout::from_size_align(100, 8).unwrap();
+        let p = quick.malloc(layout);
+        quick.free(p, layout);
+        unsafe { p.write(1) };
+        quick.malloc(layout);
+    }
+
+    #[test]
+    #[cfg(feature = "heapdebug")]
+    #[should_panic(expected = "isn't allocated")]
+    fn heapdebug_catches_double_free() {
+        let mut quick = debug_quickfit();
+        let layout = Layout::from_size_align(100, 8).unwrap();
+        let p = quick.malloc(layout);
+        quick.free(p, layout);
+        quick.free(p, layout);
+    }
+
+    #[test]
+    #[cfg(feature = "heapdebug")]
+    fn heapdebug_lists_live_sites() {
+        let mut quick = debug_quickfit();
+        let small = Layout::from_size_align(100, 8).unwrap();
+        let large = Layout::from_size_align(5000, 8).unwrap();
+        let a: Vec<_> = (0..3).map(|_| quick.malloc_debug(small, 0x1000)).collect();
+        let b = quick.malloc_debug(large, 0x2000);
+        let c = quick.malloc_debug(small, 0x3000);
+        quick.free(c, small);
+        assert!(a.iter().chain([&b]).all(|p| !p.is_null()));
+
+        let (sites, total) = quick.live_sites();
+        assert_eq!(sites[0], HeapSite { pc: 0x2000, count: 1, bytes: 5000 });
+        assert_eq!(sites[1], HeapSite { pc: 0x1000, count: 3, bytes: 300 });
+        assert_eq!(sites[2], HeapSite::default());
+        assert_eq!(total, HeapSite { pc: 0, count: 4, bytes: 5300 });
+
+        let mut report = String::new();
+        quick.heapstat(&mut report).unwrap();
+        let lines: Vec<_> =
+            report.lines().map(|l| l.split_whitespace().collect::<Vec<_>>()).collect();
+        assert_eq!(lines[1], ["0x2000", "1", "5000"]);
+        assert_eq!(lines[2], ["0x1000", "3", "300"]);
+        assert_eq!(lines[3], ["total", "4", "5300"]);
+    }
+
+    /// Stands in for the `__rust_alloc` shim between a caller
+    /// and the global allocator, returning where it will return
+    /// to along with the allocation.
+    #[cfg(feature = "heapdebug")]
+    #[inline(never)]
+    fn rust_alloc(global: &GlobalQuickAlloc, layout: Layout) -> (*mut u8, usize) {
+        let p = unsafe { global.alloc(layout) };
+        (p, callerpc())
+    }
+
+    /// Needs frame pointers, which `xtask test` keeps for the
+    /// heapdebug tests.
+    #[test]
+    #[cfg(feature = "heapdebug")]
+    fn heapdebug_global_charges_shim_caller() {
+        let global = GlobalQuickAlloc::new(debug_quickfit());
+        let layout = Layout::from_size_align(100, 8).unwrap();
+        let (p, pc) = rust_alloc(&global, layout);
+        assert!(!p.is_null());
+        let mut report = String::new();
+        global.heapstat(&mut report).unwrap();
+        let site = report.lines().nth(1).unwrap().split_whitespace().collect::<Vec<_>>();
+        assert_eq!(site, [format!("{pc:#x}").as_str(), "1", "100"]);
     }
 }
@@ -0,0 +1,115 @@
+//! Parser for Plan 9 a.out executables, for `exec`.  The header is eight
+//! big-endian 32-bit words, followed on 64-bit architectures by a 64-bit
+//! entry point, then the text, the data and the symbol tables.  The header
+//! is loaded with the text, at UTZERO, and the data follows on the next
+//! boundary the linker rounded it to, then the bss.  The symbol tables
+//! aren't loaded.
+
+use alloc::vec;
+
+use crate::exec::{Format, Image, Perm, Segment};
+use crate::maths::round_up2_usize;
+use crate::sys9::{self, Abi};
+
+const HDR_SIZE: usize = 32;
+
+fn word(file: &[u8], i: usize) -> crate::Result<u32> {
+    let b = file.get(i * 4..i * 4 + 4).ok_or("aout: truncated header")?;
+    Ok(u32::from_be_bytes(b.try_into().unwrap()))
+}
+
+/// Parse and check the header of an executable for the architecture,
+/// returning the segments to load.  Segment data borrows from file.
+pub fn parse<'a>(file: &'a [u8], abi: &Abi) -> crate::Result<Image<'a>> {
+    if word(file, 0)? != abi.magic {
+        return Err("aout: bad magic");
+    }
+    let text = word(file, 1)? as usize;
+    let data = word(file, 2)? as usize;
+    let bss = word(file, 3)? as usize;
+    let (entry, hdr_size) = if sys9::has_long_entry(abi.magic) {
+        let b = file.get(HDR_SIZE..HDR_SIZE + 8).ok_or("aout: truncated header")?;
+        let entry = u64::from_be_bytes(b.try_into().unwrap());
+        (usize::try_from(entry).map_err(|_| "aout: bad entry point")?, HDR_SIZE + 8)
+    } else {
+        (word(file, 5)? as usize, HDR_SIZE)
+    };
+
+    let text_end = hdr_size + text;
+    let text_data = file.get(..text_end).ok_or("aout: text beyond end of file")?;
+    let data_data = file.get(text_end..text_end + data).ok_or("aout: data beyond end of file")?;
+
+    let mut segments =
+        vec![Segment { va: abi.utzero, data: text_data, memsz: text_end, perm: Perm::R | Perm::X }];
+    if data + bss > 0 {
+        segments.push(Segment {
+            va: round_up2_usize(abi.utzero + text_end, abi.round),
+            data: data_data,
+            memsz: data + bss,
+            perm: Perm::R | Perm::W,
+        });
+    }
+    Ok(Image { entry, segments, format: Format::Aout })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::exec::tests::TestSpace;
+    use crate::exec::{self, register};
+    use crate::sys9::{AMD64, ARM64, TOS_PID, TOS_SIZE};
+    use alloc::vec::Vec;
+
+    /// Build an executable with the given text, data, bss and symbols.
+    fn build(abi: &Abi, text: &[u8], data: &[u8], bss: u32, syms: &[u8]) -> Vec<u8> {
+        let entry = (abi.utzero + HDR_SIZE + 8) as u64;
+        let mut f = Vec::new();
+        let lens = [text.len() as u32, data.len() as u32, bss, syms.len() as u32];
+        for w in [abi.magic].into_iter().chain(lens).chain([entry as u32, 0, 0]) {
+            f.extend_from_slice(&w.to_be_bytes());
+        }
+        f.extend_from_slice(&entry.to_be_bytes());
+        f.extend_from_slice(text);
+        f.extend_from_slice(data);
+        f.extend_from_slice(syms);
+        f
+    }
+
+    #[test]
+    fn parses_segments() {
+        let file = build(&AMD64, &[1, 2, 3], &[4, 5], 100, &[9; 20]);
+        let image = parse(&file, &AMD64).unwrap();
+        assert_eq!(image.entry, 0x200028);
+        assert_eq!(image.segments.len(), 2);
+        let (text, data) = (&image.segments[0], &image.segments[1]);
+        assert_eq!((text.va, text.memsz, text.perm), (0x200000, 43, Perm::R | Perm::X));
+        assert_eq!(&text.data[40..], [1, 2, 3]);
+        assert_eq!((data.va, data.data, data.memsz), (0x400000, &[4, 5][..], 102));
+        image.validate(TestSpace::END).unwrap();
+
+        assert_eq!(parse(&file, &ARM64).unwrap_err(), "aout: bad magic");
+        assert!(parse(&file[..44], &AMD64).is_err());
+        assert!(parse(&file[..20], &AMD64).is_err());
+    }
+
+    #[test]
+    fn execs_with_plan9_stack() {
+        let file = build(&ARM64, &[0xaa; 8], &[], 0, &[]);
+        register("/bin/aouttest", Vec::leak(file));
+        let mut space = TestSpace::new();
+        let start = exec::exec(&mut space, b"/bin/aouttest", &[b"aouttest", b"x"]).unwrap();
+        assert_eq!(start.entry, 0x10028);
+        assert_eq!(space.read(0x10028, 8), [0xaa; 8]);
+
+        // argc, then argv, with the Tos at the very top
+        let word = |va| u64::from_le_bytes(space.read(va, 8).try_into().unwrap()) as usize;
+        assert_eq!(word(start.sp), 2);
+        assert_eq!(start.argv, start.sp + 8);
+        assert_eq!(space.read(word(start.argv), 9), b"aouttest\0");
+        assert_eq!(space.read(word(start.argv + 8), 2), b"x\0");
+        assert_eq!(word(start.argv + 16), 0);
+        let tos = start.tos.unwrap();
+        assert_eq!(tos, TestSpace::END - TOS_SIZE);
+        assert_eq!(space.read(tos + TOS_PID, 4), [0; 4]);
+    }
+}
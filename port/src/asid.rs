@@ -0,0 +1,170 @@
+//! asid implements a generational allocator for address space identifiers
+//! (ASIDs on Arm, ASIDs on RISC-V, PCIDs on x86-64).  Hardware provides a
+//! small number of identifiers, so once they've all been handed out the
+//! allocator rolls over to a new generation: every identifier becomes free
+//! again, and all identifiers from older generations become stale.  Address
+//! spaces check that their identifier is still current before they are
+//! switched to, and allocate a new one if not.  After a rollover, all TLB
+//! entries must be flushed before any identifier is reused.
+//!
+//! Identifier 0 is never allocated, and is reserved for use by the kernel or
+//! by code that runs before any address spaces exist.
+//!
+//! Doesn't require any allocations, so can be used while manipulating the page
+//! tables.
+
+/// An allocated identifier, tagged with the generation it belongs to.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Asid {
+    generation: u64,
+    id: u16,
+}
+
+impl Asid {
+    /// An identifier that is never current, so the first check of an address
+    /// space using it will allocate a real one.
+    pub const fn stale() -> Self {
+        Asid { generation: 0, id: 0 }
+    }
+
+    /// The hardware identifier
+    pub const fn id(&self) -> u16 {
+        self.id
+    }
+}
+
+/// Allocator for `NUM_WORDS * 64` identifiers.
+pub struct AsidAllocator<const NUM_WORDS: usize> {
+    generation: u64,
+    used: [u64; NUM_WORDS], // Bit set if the identifier is allocated
+    next: usize,            // Where to start searching for a free identifier
+}
+
+impl<const NUM_WORDS: usize> AsidAllocator<NUM_WORDS> {
+    pub const fn new() -> Self {
+        assert!(NUM_WORDS > 0 && NUM_WORDS * 64 <= u16::MAX as usize + 1);
+        let mut used = [0; NUM_WORDS];
+        used[0] = 1;
+        Self { generation: 1, used, next: 1 }
+    }
+
+    /// Number of identifiers managed by the allocator, including the reserved
+    /// identifier 0.
+    pub const fn capacity(&self) -> usize {
+        NUM_WORDS * 64
+    }
+
+    /// Is the identifier from the current generation?
+    pub fn is_current(&self, asid: Asid) -> bool {
+        asid.generation == self.generation
+    }
+
+    /// Allocate an identifier.  The second item of the result is true if the
+    /// allocator rolled over to a new generation, in which case the caller
+    /// must flush all TLB entries before using the identifier.
+    pub fn alloc(&mut self) -> (Asid, bool) {
+        if let Some(id) = self.find_free() {
+            return (self.take(id), false);
+        }
+
+        // Out of identifiers - start a new generation
+        self.generation += 1;
+        self.used = [0; NUM_WORDS];
+        self.used[0] = 1;
+        self.next = 1;
+        let id = self.find_free().expect("no identifiers after rollover");
+        (self.take(id), true)
+    }
+
+    /// Check that asid is current, and if not, replace it with a newly
+    /// allocated identifier.  Returns true if the caller must flush all TLB entries before
+    /// using the identifier, as with `alloc`.
+    pub fn refresh(&mut self, asid: &mut Asid) -> bool {
+        if self.is_current(*asid) {
+            return false;
+        }
+        let (new_asid, flush) = self.alloc();
+        *asid = new_asid;
+        flush
+    }
+
+    /// Release the identifier.  Stale identifiers are ignored, since the
+    /// rollover already released them.
+    pub fn free(&mut self, asid: Asid) {
+        if self.is_current(asid) && asid.id != 0 {
+            let id = asid.id as usize;
+            self.used[id / 64] &= !(1 << (id % 64));
+        }
+    }
+
+    fn find_free(&self) -> Option<usize> {
+        let capacity = self.capacity();
+        (self.next..capacity)
+            .chain(1..self.next)
+            .find(|&id| self.used[id / 64] & (1 << (id % 64)) == 0)
+    }
+
+    fn take(&mut self, id: usize) -> Asid {
+        self.used[id / 64] |= 1 << (id % 64);
+        self.next = if id + 1 < self.capacity() { id + 1 } else { 1 };
+        Asid { generation: self.generation, id: id as u16 }
+    }
+}
+
+impl<const NUM_WORDS: usize> Default for AsidAllocator<NUM_WORDS> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn alloc_skips_reserved() {
+        let mut a = AsidAllocator::<1>::new();
+        let (asid, flush) = a.alloc();
+        assert_eq!(asid.id(), 1);
+        assert!(!flush);
+        assert!(a.is_current(asid));
+        assert!(!a.is_current(Asid::stale()));
+    }
+
+    #[test]
+    fn rollover_makes_old_asids_stale() {
+        let mut a = AsidAllocator::<1>::new();
+        let asids: [Asid; 63] = core::array::from_fn(|_| a.alloc().0);
+        assert!(asids.iter().enumerate().all(|(i, asid)| asid.id() as usize == i + 1));
+
+        let (asid, flush) = a.alloc();
+        assert!(flush);
+        assert_eq!(asid.id(), 1);
+        assert!(asids.iter().all(|old| !a.is_current(*old)));
+
+        // Freeing a stale identifier doesn't free its id in the new generation
+        a.free(asids[0]);
+        assert_eq!(a.alloc().0.id(), 2);
+    }
+
+    #[test]
+    fn free_and_reuse() {
+        let mut a = AsidAllocator::<1>::new();
+        let asids: [Asid; 63] = core::array::from_fn(|_| a.alloc().0);
+        a.free(asids[9]);
+        let (asid, flush) = a.alloc();
+        assert!(!flush);
+        assert_eq!(asid.id(), 10);
+    }
+
+    #[test]
+    fn refresh() {
+        let mut a = AsidAllocator::<1>::new();
+        let mut asid = Asid::stale();
+        assert!(!a.refresh(&mut asid));
+        assert_eq!(asid.id(), 1);
+        let current = asid;
+        assert!(!a.refresh(&mut asid));
+        assert_eq!(asid, current);
+    }
+}
@@ -3,6 +3,10 @@
 /// Benefits of the current implementation:
 ///  - Doesn't require any allocations, so can be used without fear while
 ///    manipulating the page tables.
+///  - Each bitmap keeps a summary with a bit per 64 page word, set when every
+///    page in the word is allocated, along with a count of free pages.  Finding
+///    a free page skips full bitmaps and full words without looking at them, so
+///    the cost doesn't depend on how much memory is allocated.
 ///
 /// Downsides:
 ///  - Can't be dynamically resized.
@@ -13,15 +17,52 @@ use crate::{
     pagealloc::PageAllocError,
 };
 
+/// Number of bits in each word that the bitmap is scanned by.
+const WORD_BITS: usize = u64::BITS as usize;
+
+/// Number of words in the summary of each bitmap.  This limits a bitmap to
+/// SUMMARY_WORDS * WORD_BITS words, or 32KiB.
+const SUMMARY_WORDS: usize = 64;
+
+// Number of bitmap words read by this thread, so tests can check how much
+// of the bitmap a search looks at.
+#[cfg(test)]
+std::thread_local! {
+    static WORDS_READ: core::cell::Cell<usize> = const { core::cell::Cell::new(0) };
+}
+
 /// Simple bitmap.  Bear in mind that logically, bit 0 is the rightmost bit,
 /// so writing out as bytes will have the bits logically reversed.
+///
+/// The bytes are scanned a little-endian u64 word at a time.  Bytes past the
+/// end of the bitmap in the last word read as set.
 struct Bitmap<const SIZE_BYTES: usize> {
     bytes: [u8; SIZE_BYTES],
+    full: [u64; SUMMARY_WORDS], // Bit w is set if all bits in word w are set
+    num_clear: usize,           // Number of clear bits in bytes
 }
 
 impl<const SIZE_BYTES: usize> Bitmap<SIZE_BYTES> {
+    const NUM_WORDS: usize = SIZE_BYTES.div_ceil(8);
+
     pub const fn new(init_value: u8) -> Self {
-        Self { bytes: [init_value; SIZE_BYTES] }
+        assert!(Self::NUM_WORDS <= SUMMARY_WORDS * WORD_BITS);
+
+        // Summary bits for words past the end of the bitmap are always set,
+        // so they're never picked.
+        let mut full = [u64::MAX; SUMMARY_WORDS];
+        if init_value != 0xff {
+            let mut w = 0;
+            while w < Self::NUM_WORDS {
+                full[w / WORD_BITS] &= !(1 << (w % WORD_BITS));
+                w += 1;
+            }
+        }
+        Self {
+            bytes: [init_value; SIZE_BYTES],
+            full,
+            num_clear: init_value.count_zeros() as usize * SIZE_BYTES,
+        }
     }
 
     /// Is bit `i` within the bitmap set?
@@ -36,11 +77,91 @@ impl<const SIZE_BYTES: usize> Bitmap<SIZE_BYTES> {
     pub fn set(&mut self, i: usize, b: bool) {
         let byte_idx = i / 8;
         let bit_idx = i % 8;
+        let old = self.bytes[byte_idx];
         if b {
             self.bytes[byte_idx] |= 1 << bit_idx;
         } else {
             self.bytes[byte_idx] &= !(1 << bit_idx);
         }
+        if self.bytes[byte_idx] == old {
+            return;
+        }
+
+        let w = i / WORD_BITS;
+        let summary_bit = 1 << (w % WORD_BITS);
+        if b {
+            self.num_clear -= 1;
+            if self.word(w) == u64::MAX {
+                self.full[w / WORD_BITS] |= summary_bit;
+            }
+        } else {
+            self.num_clear += 1;
+            self.full[w / WORD_BITS] &= !summary_bit;
+        }
+    }
+
+    /// Return word `w` of the bitmap.
+    fn word(&self, w: usize) -> u64 {
+        #[cfg(test)]
+        WORDS_READ.set(WORDS_READ.get() + 1);
+        let start = w * 8;
+        if start + 8 <= SIZE_BYTES {
+            u64::from_le_bytes(self.bytes[start..start + 8].try_into().unwrap())
+        } else {
+            let mut word = [0xff; 8];
+            word[..SIZE_BYTES - start].copy_from_slice(&self.bytes[start..]);
+            u64::from_le_bytes(word)
+        }
+    }
+
+    /// Return the index of the first clear bit at or after bit `start`.
+    fn next_clear(&self, start: usize) -> Option<usize> {
+        let w = start / WORD_BITS;
+        if w >= Self::NUM_WORDS {
+            return None;
+        }
+        let clear = !self.word(w) & (u64::MAX << (start % WORD_BITS));
+        if clear != 0 {
+            return Some(w * WORD_BITS + clear.trailing_zeros() as usize);
+        }
+        self.find_clear((w + 1) * WORD_BITS)
+    }
+
+    /// Return the index of the first set bit in bits `start..end`.
+    fn next_set(&self, start: usize, end: usize) -> Option<usize> {
+        let mut i = start;
+        while i < end {
+            let w = i / WORD_BITS;
+            let set = self.word(w) & (u64::MAX << (i % WORD_BITS));
+            if set != 0 {
+                let found = w * WORD_BITS + set.trailing_zeros() as usize;
+                return (found < end).then_some(found);
+            }
+            i = (w + 1) * WORD_BITS;
+        }
+        None
+    }
+
+    /// Return the index of the first clear bit, starting the search from the
+    /// word containing bit `start`.  Bits in the earlier words aren't searched.
+    fn find_clear(&self, start: usize) -> Option<usize> {
+        if self.num_clear == 0 {
+            return None;
+        }
+
+        let first_word = start / WORD_BITS;
+        let mut summary_idx = first_word / WORD_BITS;
+        let mut skip = (1 << (first_word % WORD_BITS)) - 1;
+        while summary_idx * WORD_BITS < Self::NUM_WORDS {
+            let not_full = !(self.full[summary_idx] | skip);
+            if not_full != 0 {
+                let w = summary_idx * WORD_BITS + not_full.trailing_zeros() as usize;
+                return Some(w * WORD_BITS + self.word(w).trailing_ones() as usize);
+            }
+            skip = 0;
+            summary_idx += 1;
+        }
+        None
     }
 }
 
@@ -104,9 +225,11 @@ impl<const NUM_BITMAPS: usize, const BITMAP_SIZE_BYTES: usize>
         used_ranges: impl Iterator<Item = &'a PhysRange>,
     ) -> Result<(), PageAllocError> {
         let mut next_start = available_mem.start();
+        let mut first_free = None;
         for range in used_ranges {
             if next_start < range.0.start {
                 self.mark_free(&PhysRange::new(next_start, range.0.start))?;
+                first_free.get_or_insert(next_start);
             }
             if next_start < range.0.end {
                 next_start = range.0.end;
@@ -114,6 +237,7 @@ impl<const NUM_BITMAPS: usize, const BITMAP_SIZE_BYTES: usize>
         }
         if next_start < available_mem.end() {
             self.mark_free(&PhysRange::new(next_start, available_mem.end()))?;
+            first_free.get_or_insert(next_start);
         }
 
         self.end = available_mem.0.end;
@@ -122,26 +246,36 @@ impl<const NUM_BITMAPS: usize, const BITMAP_SIZE_BYTES: usize>
         let end_range = PhysRange::new(self.end, PhysAddr::new(self.max_bytes() as u64));
         self.mark_range(&end_range, true, false)?;
 
-        self.next_pa_to_scan = PhysAddr::new(0); // Just set to 0 for simplicity - could be smarter
+        // Start scanning from the lowest free page, rounded down to a page
+        // boundary in case the range wasn't aligned.
+        let first_free = first_free.unwrap_or(PhysAddr::new(0));
+        self.next_pa_to_scan = first_free.round_down2(self.alloc_page_size as u64);
 
         Ok(())
     }
 
-    /// Try to allocate the next available page.
+    /// Try to allocate the next available page.  The search is next-fit:
+    /// it starts from the word containing `next_pa_to_scan`, and wraps
+    /// around to the start if nothing is free after it.
     pub fn allocate(&mut self) -> Result<PhysAddr, PageAllocError> {
-        let (first_bitmap_idx, first_byte_idx, _) = self.physaddr_as_indices(self.next_pa_to_scan);
-
-        let found_indices = self
-            .indices_from(first_bitmap_idx, first_byte_idx)
-            .find(|indices| self.byte(indices) != 0xff);
-
-        if let Some(indices) = found_indices {
+        let (first_bitmap_idx, first_byte_idx, first_bit_idx) =
+            self.physaddr_as_indices(self.next_pa_to_scan);
+        let first_bit = 8 * first_byte_idx + first_bit_idx;
+
+        let found = (first_bitmap_idx..NUM_BITMAPS)
+            .map(|bitmap_idx| {
+                (bitmap_idx, if bitmap_idx == first_bitmap_idx { first_bit } else { 0 })
+            })
+            .chain((0..=first_bitmap_idx).map(|bitmap_idx| (bitmap_idx, 0)))
+            .find_map(|(bitmap_idx, start)| {
+                self.bitmaps[bitmap_idx].find_clear(start).map(|i| (bitmap_idx, i))
+            });
+
+        if let Some((bitmap_idx, i)) = found {
             // Mark the page as allocated and return the address
-            let byte = &mut self.bitmaps[indices.bitmap].bytes[indices.byte];
-            let num_leading_ones = byte.trailing_ones() as usize;
-            *byte |= 1 << num_leading_ones;
+            self.bitmaps[bitmap_idx].set(i, true);
 
-            let pa = self.indices_as_physaddr(indices.bitmap, indices.byte, num_leading_ones);
+            let pa = self.indices_as_physaddr(bitmap_idx, i / 8, i % 8);
             self.next_pa_to_scan = pa;
             Ok(pa)
         } else {
@@ -151,7 +285,7 @@ impl<const NUM_BITMAPS: usize, const BITMAP_SIZE_BYTES: usize>
 
     /// Deallocate the page corresponding to the given PhysAddr.
     pub fn deallocate(&mut self, pa: PhysAddr) -> Result<(), PageAllocError> {
-        if pa > self.end {
+        if pa >= self.end {
             return Err(PageAllocError::OutOfBounds);
         }
 
@@ -161,7 +295,77 @@ impl<const NUM_BITMAPS: usize, const BITMAP_SIZE_BYTES: usize>
         if !bitmap.is_set(8 * byte_idx + bit_idx) {
             return Err(PageAllocError::NotAllocated);
         }
-        bitmap.set(bit_idx, false);
+        bitmap.set(8 * byte_idx + bit_idx, false);
+
+        self.next_pa_to_scan = pa; // Next allocation will reuse this
+
+        Ok(())
+    }
+
+    /// Try to allocate npages physically contiguous pages, starting at an
+    /// address aligned to `align` bytes, and ending at or below `max_pa`.
+    /// `align` must be a power of 2, and is rounded up to the page size.
+    /// The lowest suitable range is used, leaving higher memory free, and
+    /// the pages must be freed with `deallocate_contiguous`.
+    pub fn allocate_contiguous(
+        &mut self,
+        npages: usize,
+        align: usize,
+        max_pa: PhysAddr,
+    ) -> Result<PhysAddr, PageAllocError> {
+        if npages == 0 {
+            return Err(PageAllocError::OutOfBounds);
+        }
+        if !align.is_power_of_two() {
+            return Err(PageAllocError::MisalignedAddr);
+        }
+        let align_pages = usize::max(align / self.alloc_page_size, 1);
+        let limit = PhysAddr::min(max_pa, self.end).addr() as usize / self.alloc_page_size;
+
+        // Find the first free page, align it, then check that the rest of the
+        // range is free.  If not, start again after the allocated page.
+        let mut first = 0;
+        loop {
+            first = self.next_clear_page(first).ok_or(PageAllocError::OutOfSpace)?;
+            first = first.next_multiple_of(align_pages);
+            let end = first + npages;
+            if end > limit {
+                return Err(PageAllocError::OutOfSpace);
+            }
+
+            match self.next_set_page(first, end) {
+                Some(allocated) => first = allocated + 1,
+                None => {
+                    let pa = PhysAddr::new((first * self.alloc_page_size) as u64);
+                    let range = PhysRange::with_pa_len(pa, npages * self.alloc_page_size);
+                    self.mark_range(&range, true, true)?;
+                    return Ok(pa);
+                }
+            }
+        }
+    }
+
+    /// Deallocate the npages pages starting at pa, previously allocated
+    /// with `allocate_contiguous`.  Nothing is freed unless all the pages are
+    /// allocated.
+    pub fn deallocate_contiguous(
+        &mut self,
+        pa: PhysAddr,
+        npages: usize,
+    ) -> Result<(), PageAllocError> {
+        if !pa.is_multiple_of(self.alloc_page_size as u64) {
+            return Err(PageAllocError::MisalignedAddr);
+        }
+        let range = PhysRange::with_pa_len(pa, npages * self.alloc_page_size);
+        if range.end() > self.end {
+            return Err(PageAllocError::OutOfBounds);
+        }
+
+        let first = pa.addr() as usize / self.alloc_page_size;
+        if self.next_clear_page(first).is_some_and(|page| page < first + npages) {
+            return Err(PageAllocError::NotAllocated);
+        }
+        self.mark_range(&range, false, true)?;
 
         self.next_pa_to_scan = pa; // Next allocation will reuse this
 
@@ -172,10 +376,8 @@ impl<const NUM_BITMAPS: usize, const BITMAP_SIZE_BYTES: usize>
     pub fn usage_bytes(&self) -> (usize, usize) {
         // We count free because the last bits might be marked partially 'allocated'
         // if the end comes in the middle of a byte in the bitmap.
-        let mut free_bytes: usize = 0;
-        for indices in self.indices() {
-            free_bytes += self.byte(&indices).count_zeros() as usize * self.alloc_page_size;
-        }
+        let free_pages: usize = self.bitmaps.iter().map(|bitmap| bitmap.num_clear).sum();
+        let free_bytes = free_pages * self.alloc_page_size;
         let total = self.end.0 as usize;
         (total - free_bytes, total)
     }
@@ -211,6 +413,34 @@ impl<const NUM_BITMAPS: usize, const BITMAP_SIZE_BYTES: usize>
         )
     }
 
+    /// Return the index of the first free page at or after `page`.
+    fn next_clear_page(&self, page: usize) -> Option<usize> {
+        let bits_per_bitmap = 8 * BITMAP_SIZE_BYTES;
+        let first_bitmap_idx = page / bits_per_bitmap;
+        (first_bitmap_idx..NUM_BITMAPS).find_map(|bitmap_idx| {
+            let start = if bitmap_idx == first_bitmap_idx { page % bits_per_bitmap } else { 0 };
+            let i = self.bitmaps[bitmap_idx].next_clear(start)?;
+            Some(bitmap_idx * bits_per_bitmap + i)
+        })
+    }
+
+    /// Return the index of the first allocated page in `start..end`.
+    fn next_set_page(&self, start: usize, end: usize) -> Option<usize> {
+        let bits_per_bitmap = 8 * BITMAP_SIZE_BYTES;
+        let mut page = start;
+        while page < end {
+            let bitmap_idx = page / bits_per_bitmap;
+            let bitmap_start = bitmap_idx * bits_per_bitmap;
+            let bitmap_end = usize::min(end, bitmap_start + bits_per_bitmap);
+            let bitmap = &self.bitmaps[bitmap_idx];
+            if let Some(i) = bitmap.next_set(page - bitmap_start, bitmap_end - bitmap_start) {
+                return Some(bitmap_start + i);
+            }
+            page = bitmap_end;
+        }
+        None
+    }
+
     fn mark_range(
         &mut self,
         range: &PhysRange,
@@ -343,6 +573,35 @@ mod tests {
         }
     }
 
+    #[test]
+    fn bitmap_summary() {
+        // 3 words, the last of which is only partially backed by bytes
+        let mut bitmap = Bitmap::<20>::new(0);
+        assert_eq!(bitmap.num_clear, 160);
+        assert_eq!(bitmap.full[0], !0b111);
+
+        // Filling a word marks it full in the summary
+        for i in 64..128 {
+            bitmap.set(i, true);
+        }
+        assert_eq!(bitmap.full[0], !0b101);
+        assert_eq!(bitmap.num_clear, 96);
+        assert_eq!(bitmap.find_clear(64), Some(128));
+
+        bitmap.set(100, false);
+        assert_eq!(bitmap.full[0], !0b111);
+        assert_eq!(bitmap.find_clear(64), Some(100));
+
+        // The missing bytes in the last word read as set
+        for i in 128..160 {
+            bitmap.set(i, true);
+        }
+        assert_eq!(bitmap.word(2), u64::MAX);
+        assert_eq!(bitmap.full[0], !0b011);
+        assert_eq!(bitmap.find_clear(128), None);
+        assert_eq!(bitmap.find_clear(0), Some(0));
+    }
+
     #[test]
     fn iterate() {
         let alloc = BitmapPageAlloc::<2, 2>::new_all_allocated(4);
@@ -409,6 +668,180 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn bitmappagealloc_deallocate_clears_right_bit() -> Result<(), PageAllocError> {
+        let mut alloc = BitmapPageAlloc::<2, 2>::new_all_allocated(4);
+        alloc.mark_free(&PhysRange::with_end(0, alloc.max_bytes() as u64))?;
+        alloc.mark_allocated(&PhysRange::with_end(32, 48))?;
+        assert_eq!(alloc.bytes(), [0x00, 0x0f, 0x00, 0x00]);
+
+        // Page 9 is bit 1 of the second byte
+        alloc.deallocate(PhysAddr::new(36))?;
+        assert_eq!(alloc.bytes(), [0x00, 0x0d, 0x00, 0x00]);
+        assert_eq!(alloc.usage_bytes(), (12, 128));
+
+        assert_eq!(alloc.deallocate(PhysAddr::new(128)).unwrap_err(), PageAllocError::OutOfBounds);
+        Ok(())
+    }
+
+    #[test]
+    fn bitmappagealloc_allocate_wraps_around() -> Result<(), PageAllocError> {
+        // 2 bitmaps of 2 words each, mapped to pages of 4 bytes
+        let mut alloc = BitmapPageAlloc::<2, 16>::new_all_allocated(4);
+        alloc.mark_free(&PhysRange::with_end(4 * 3, 4 * 4))?;
+        alloc.mark_free(&PhysRange::with_end(4 * 200, 4 * 201))?;
+
+        // Scanning starts from the hint, so finds the later page first
+        alloc.next_pa_to_scan = PhysAddr::new(4 * 130);
+        assert_eq!(alloc.allocate()?, PhysAddr::new(4 * 200));
+        assert_eq!(alloc.next_pa_to_scan, PhysAddr::new(4 * 200));
+
+        // Then wraps around to find the earlier one
+        assert_eq!(alloc.allocate()?, PhysAddr::new(4 * 3));
+        assert_eq!(alloc.allocate().unwrap_err(), PageAllocError::OutOfSpace);
+        Ok(())
+    }
+
+    #[test]
+    fn bitmappagealloc_free_unused_ranges() -> Result<(), PageAllocError> {
+        let mut alloc = BitmapPageAlloc::<2, 2>::new_all_allocated(4);
+        let used = [PhysRange::with_end(0, 40), PhysRange::with_end(48, 64)];
+        alloc.free_unused_ranges(&PhysRange::with_end(0, 100), used.iter())?;
+
+        // Pages from 100 onwards are past the end, so stay allocated
+        assert_eq!(alloc.bytes(), [0xff, 0xf3, 0x00, 0xfe]);
+        assert_eq!(alloc.usage_bytes(), (56, 100));
+
+        // The hint starts at the first free page
+        assert_eq!(alloc.next_pa_to_scan, PhysAddr::new(40));
+        assert_eq!(alloc.allocate()?, PhysAddr::new(40));
+        Ok(())
+    }
+
+    #[test]
+    fn bitmappagealloc_allocate_nearly_full_8gib() -> Result<(), PageAllocError> {
+        // 64 bitmaps of 4KiB, mapped to pages of 4KiB - 8GiB of physical memory
+        let mut alloc = Box::new(const { BitmapPageAlloc::<64, 4096>::new_all_allocated(4096) });
+        assert_eq!(alloc.max_bytes(), 8 << 30);
+
+        // Free a page in the middle and a few at the very end
+        let max_bytes = alloc.max_bytes() as u64;
+        alloc.mark_free(&PhysRange::with_end(4 << 30, (4 << 30) + 4096))?;
+        alloc.mark_free(&PhysRange::with_end(max_bytes - 4 * 4096, max_bytes))?;
+        assert_eq!(alloc.usage_bytes(), (max_bytes as usize - 5 * 4096, max_bytes as usize));
+
+        // Each allocation is forced to scan from the start of memory, which
+        // with a byte at a time scan would mean reading the whole 256KiB
+        // bitmap.  The summaries let it skip straight to the free word.
+        for _ in 0..1000 {
+            alloc.next_pa_to_scan = PhysAddr::new(0);
+            WORDS_READ.set(0);
+            let pa = alloc.allocate()?;
+            assert_eq!(pa, PhysAddr::new(4 << 30));
+            alloc.deallocate(pa)?;
+            assert!(WORDS_READ.get() <= 2, "read {} words", WORDS_READ.get());
+        }
+
+        // Allocate everything that's left
+        let mut pages = Vec::new();
+        while let Ok(pa) = alloc.allocate() {
+            pages.push(pa.addr());
+        }
+        pages.sort_unstable();
+        assert_eq!(
+            pages,
+            [
+                4 << 30,
+                max_bytes - 4 * 4096,
+                max_bytes - 3 * 4096,
+                max_bytes - 2 * 4096,
+                max_bytes - 4096
+            ]
+        );
+        assert_eq!(alloc.usage_bytes(), (max_bytes as usize, max_bytes as usize));
+        Ok(())
+    }
+
+    #[test]
+    fn bitmappagealloc_allocate_contiguous() -> Result<(), PageAllocError> {
+        // 2 bitmaps of 2 words each, mapped to pages of 4 bytes - 256 pages
+        let mut alloc = BitmapPageAlloc::<2, 16>::new_all_allocated(4);
+        let max_pa = PhysAddr::new(alloc.max_bytes() as u64);
+        alloc.mark_free(&PhysRange::with_end(0, max_pa.addr()))?;
+
+        // Unaligned requests come from the bottom of memory
+        assert_eq!(alloc.allocate_contiguous(3, 1, max_pa)?, PhysAddr::new(0));
+        assert_eq!(alloc.allocate_contiguous(2, 4, max_pa)?, PhysAddr::new(12));
+
+        // Aligned requests skip the free pages before the boundary
+        assert_eq!(alloc.allocate_contiguous(4, 64, max_pa)?, PhysAddr::new(64));
+        assert_eq!(alloc.allocate_contiguous(1, 1, max_pa)?, PhysAddr::new(20));
+        assert_eq!(alloc.usage_bytes(), (40, 1024));
+
+        // Runs can span bitmaps
+        alloc.mark_allocated(&PhysRange::with_end(4 * 6, 4 * 124))?;
+        assert_eq!(alloc.allocate_contiguous(8, 16, max_pa)?, PhysAddr::new(4 * 124));
+        assert_eq!(alloc.bytes()[15..18], [0xff, 0x0f, 0x00]);
+
+        assert_eq!(alloc.allocate_contiguous(1, 3, max_pa), Err(PageAllocError::MisalignedAddr));
+        assert_eq!(alloc.allocate_contiguous(0, 1, max_pa), Err(PageAllocError::OutOfBounds));
+        Ok(())
+    }
+
+    #[test]
+    fn bitmappagealloc_allocate_contiguous_fragmented() -> Result<(), PageAllocError> {
+        let mut alloc = BitmapPageAlloc::<2, 16>::new_all_allocated(4);
+        let max_pa = PhysAddr::new(alloc.max_bytes() as u64);
+        alloc.mark_free(&PhysRange::with_end(0, max_pa.addr()))?;
+
+        // Allocate every other page in the first 160 pages, leaving plenty of
+        // free memory, but no more than one page in a row.
+        for page in (0..160).step_by(2) {
+            alloc.mark_allocated(&PhysRange::with_len(4 * page, 4))?;
+        }
+        assert_eq!(alloc.allocate_contiguous(2, 1, max_pa)?, PhysAddr::new(4 * 159));
+
+        // Free one more page to make a gap of 3 pages (pages 99..102).  A
+        // request for 3 pages fits, but not if it needs to be 8 page aligned,
+        // or if it's for 4 pages.
+        alloc.mark_free(&PhysRange::with_len(4 * 100, 4))?;
+        assert_eq!(alloc.allocate_contiguous(4, 1, max_pa)?, PhysAddr::new(4 * 161));
+        assert_eq!(alloc.allocate_contiguous(3, 32, max_pa)?, PhysAddr::new(4 * 168));
+        assert_eq!(alloc.allocate_contiguous(3, 1, max_pa)?, PhysAddr::new(4 * 99));
+
+        // Limit the address so that nothing above the fragmented region can
+        // be used.
+        let low = PhysAddr::new(4 * 180);
+        assert_eq!(alloc.allocate_contiguous(2, 1, low)?, PhysAddr::new(4 * 165));
+        assert_eq!(alloc.allocate_contiguous(1, 1, low)?, PhysAddr::new(4));
+        assert_eq!(alloc.allocate_contiguous(8, 1, low)?, PhysAddr::new(4 * 171));
+        assert_eq!(alloc.allocate_contiguous(2, 1, low), Err(PageAllocError::OutOfSpace));
+        assert_eq!(alloc.allocate_contiguous(256, 1, max_pa), Err(PageAllocError::OutOfSpace));
+        Ok(())
+    }
+
+    #[test]
+    fn bitmappagealloc_deallocate_contiguous() -> Result<(), PageAllocError> {
+        let mut alloc = BitmapPageAlloc::<2, 16>::new_all_allocated(4);
+        let max_pa = PhysAddr::new(alloc.max_bytes() as u64);
+        alloc.mark_free(&PhysRange::with_end(0, max_pa.addr()))?;
+
+        let pa = alloc.allocate_contiguous(70, 256, max_pa)?;
+        assert_eq!(pa, PhysAddr::new(0));
+        assert_eq!(alloc.usage_bytes(), (280, 1024));
+
+        // Nothing is freed if any of the pages isn't allocated
+        assert_eq!(alloc.deallocate_contiguous(pa, 71), Err(PageAllocError::NotAllocated));
+        assert_eq!(alloc.deallocate_contiguous(pa + 2, 2), Err(PageAllocError::MisalignedAddr));
+        assert_eq!(alloc.deallocate_contiguous(pa, 257), Err(PageAllocError::OutOfBounds));
+        assert_eq!(alloc.usage_bytes(), (280, 1024));
+
+        alloc.deallocate_contiguous(pa, 70)?;
+        assert_eq!(alloc.usage_bytes(), (0, 1024));
+        assert_eq!(alloc.allocate_contiguous(128, 4, max_pa)?, PhysAddr::new(0));
+        Ok(())
+    }
+
     #[test]
     fn physaddr_as_indices() {
         let alloc = BitmapPageAlloc::<2, 4096>::new_all_allocated(4096);
@@ -0,0 +1,115 @@
+//! cpu provides portable access to the few things about the current CPU that
+//! portable code needs: which CPU it is, and masking interrupts on it.  Each
+//! architecture registers its implementation with `set_hooks` early in boot.
+//!
+//! Until then the defaults are used: interrupts aren't touched, and the CPU
+//! has no valid id, so anything that keeps per-CPU state must fall back to
+//! shared, locked state.  This is safe while only one CPU is running.
+
+use core::ptr;
+use core::sync::atomic::{AtomicPtr, Ordering};
+
+/// Maximum number of CPUs that per-CPU state is kept for.  CPUs with higher
+/// ids still work, but don't get per-CPU state.
+pub const MAX_CPUS: usize = 16;
+
+/// Value returned by `cpu_id` when the CPU isn't known.
+pub const NO_CPU: usize = usize::MAX;
+
+/// Architecture-specific implementation of the CPU operations.
+pub struct CpuHooks {
+    /// Returns the index of the current CPU.
+    pub cpu_id: fn() -> usize,
+    /// Disables interrupts on the current CPU, returning the previous state
+    /// in a form that can be passed to `splx`.
+    pub splhi: fn() -> usize,
+    /// Restores the interrupt state returned by `splhi`.
+    pub splx: fn(usize),
+    /// Enables interrupts on the current CPU.
+    pub spllo: fn(),
+}
+
+static DEFAULT_HOOKS: CpuHooks =
+    CpuHooks { cpu_id: || NO_CPU, splhi: || 0, splx: |_| {}, spllo: || {} };
+
+static HOOKS: AtomicPtr<CpuHooks> = AtomicPtr::new(ptr::addr_of!(DEFAULT_HOOKS).cast_mut());
+
+/// Registers the architecture's implementation of the CPU operations.
+pub fn set_hooks(hooks: &'static CpuHooks) {
+    HOOKS.store(ptr::from_ref(hooks).cast_mut(), Ordering::Release);
+}
+
+fn hooks() -> &'static CpuHooks {
+    unsafe { &*HOOKS.load(Ordering::Acquire) }
+}
+
+/// Index of the current CPU, or NO_CPU if it isn't known.  Only meaningful
+/// while interrupts are disabled, since otherwise the caller may be moved to
+/// another CPU at any point.
+pub fn cpu_id() -> usize {
+    (hooks().cpu_id)()
+}
+
+/// Saved interrupt state, returned by `splhi`.
+#[must_use]
+pub struct IntrState(usize);
+
+/// Disable interrupts on the current CPU, returning the previous state.
+pub fn splhi() -> IntrState {
+    IntrState((hooks().splhi)())
+}
+
+/// Restore the interrupt state returned by `splhi`.
+pub fn splx(state: IntrState) {
+    (hooks().splx)(state.0)
+}
+
+/// Enable interrupts on the current CPU.  Only for code that knows they
+/// should be on whatever the caller had, such as a new process starting or
+/// an idle CPU waiting for work.
+pub fn spllo() {
+    (hooks().spllo)()
+}
+
+/// Run f with interrupts disabled on the current CPU.
+pub fn without_interrupts<F, R>(f: F) -> R
+where
+    F: FnOnce() -> R,
+{
+    let state = splhi();
+    let r = f();
+    splx(state);
+    r
+}
+
+/// Hooks for host tests.  Each thread is treated as its own CPU, with its own
+/// interrupt state.
+#[cfg(test)]
+pub(crate) mod testhooks {
+    use super::{CpuHooks, set_hooks};
+    use core::cell::Cell;
+    use core::sync::atomic::{AtomicUsize, Ordering};
+
+    std::thread_local! {
+        static ID: usize = {
+            static NEXT_ID: AtomicUsize = AtomicUsize::new(0);
+            NEXT_ID.fetch_add(1, Ordering::Relaxed)
+        };
+        static INTERRUPTS_ENABLED: Cell<bool> = const { Cell::new(true) };
+    }
+
+    static TEST_HOOKS: CpuHooks = CpuHooks {
+        cpu_id: || ID.with(|id| *id),
+        splhi: || INTERRUPTS_ENABLED.replace(false) as usize,
+        splx: |enabled| INTERRUPTS_ENABLED.set(enabled != 0),
+        spllo: || INTERRUPTS_ENABLED.set(true),
+    };
+
+    pub fn install() {
+        set_hooks(&TEST_HOOKS);
+    }
+
+    pub fn interrupts_enabled() -> bool {
+        INTERRUPTS_ENABLED.get()
+    }
+}
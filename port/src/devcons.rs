@@ -1,5 +1,5 @@
 use crate::Result;
-use crate::mcslock::{Lock, LockNode};
+use crate::mcslock::ILock;
 use core::fmt;
 
 const fn ctrl(b: u8) -> u8 {
@@ -21,7 +21,9 @@ pub trait Uart {
     fn putb(&self, b: u8);
 }
 
-static CONS: Lock<Option<&'static mut dyn Uart>> = Lock::new("cons", None);
+/// Interrupt handlers may print, so interrupts are disabled while the console
+/// is locked.
+static CONS: ILock<Option<&'static mut dyn Uart>> = ILock::new("cons", None);
 
 /// Console is what should be used in almost all cases, as it ensures threadsafe
 /// use of the console.
@@ -32,16 +34,14 @@ impl Console {
     where
         F: FnOnce() -> Result<&'static mut dyn Uart>,
     {
-        let node = LockNode::new();
-        let mut cons = CONS.lock(&node);
+        let mut cons = CONS.lock();
         *cons = uart_fn().ok();
     }
 
     pub fn putstr(&mut self, s: &str) {
         // XXX: Just for testing.
 
-        let node = LockNode::new();
-        let mut uart_guard = CONS.lock(&node);
+        let mut uart_guard = CONS.lock();
         if let Some(uart) = uart_guard.as_deref_mut() {
             for b in s.bytes() {
                 putb(uart, b);
@@ -64,6 +64,28 @@ pub fn print(args: fmt::Arguments) {
     cons.write_fmt(args).unwrap();
 }
 
+/// Print only if the console is free, returning whether it was.  For
+/// messages from code that may be waiting for the console lock, or holding
+/// it, such as lock debugging, which would deadlock waiting for it again.
+pub fn try_print(args: fmt::Arguments) -> bool {
+    struct Writer<'a>(&'a mut dyn Uart);
+
+    impl fmt::Write for Writer<'_> {
+        fn write_str(&mut self, s: &str) -> fmt::Result {
+            s.bytes().for_each(|b| putb(self.0, b));
+            Ok(())
+        }
+    }
+
+    let Some(mut cons) = CONS.try_lock() else {
+        return false;
+    };
+    if let Some(uart) = cons.as_deref_mut() {
+        let _ = fmt::Write::write_fmt(&mut Writer(uart), args);
+    }
+    true
+}
+
 #[macro_export]
 macro_rules! println {
     () => ($crate::print!("\n"));
@@ -86,3 +108,14 @@ fn putb(uart: &mut dyn Uart, b: u8) {
     }
     uart.putb(b);
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn try_print_doesnt_wait_for_console() {
+        let _cons = CONS.lock();
+        assert!(!try_print(format_args!("not printed")));
+    }
+}
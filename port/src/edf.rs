@@ -0,0 +1,269 @@
+//! edf is the real-time scheduling class, after Plan 9's edf.c.  A process
+//! declares a period, a relative deadline and a cost, and once admitted is
+//! released at the start of each period, when it may run for up to its cost
+//! before its deadline.  Released real-time processes run ahead of all the
+//! time-sharing priorities, earliest deadline first.
+//!
+//! A process is only admitted if its density, cost over deadline, together
+//! with those of the processes already admitted, sums to at most one, which
+//! is enough for every deadline to be met.  A process that's still ready
+//! to run when its deadline passes has missed it, and misses are counted.
+//! One that uses up its cost is throttled until its next period.
+//!
+//! Releases, charging and misses are all accounted for by `sched::tick`,
+//! which each architecture's clock interrupt calls HZ times a second.  Times
+//! are kept in clock ticks, so are only as precise as the clock.
+
+use crate::sched::HZ;
+
+/// Densities are kept in millionths.
+const DENSITY_ONE: u64 = 1_000_000;
+
+/// Real-time parameters of a process, in clock ticks.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct EdfParams {
+    pub period: usize,
+    pub deadline: usize, // Relative to the start of each period
+    pub cost: usize,     // Most time used in each period
+}
+
+impl EdfParams {
+    fn validate(&self) -> crate::Result<()> {
+        if self.period == 0 || self.deadline == 0 || self.cost == 0 {
+            return Err("edf: period, deadline and cost must all be set");
+        }
+        if self.cost > self.deadline || self.deadline > self.period {
+            return Err("edf: need cost <= deadline <= period");
+        }
+        Ok(())
+    }
+
+    fn density(&self) -> u64 {
+        (self.cost as u64 * DENSITY_ONE).div_ceil(self.deadline as u64)
+    }
+}
+
+/// Returns whether processes with the given parameters can all meet their
+/// deadlines.
+pub(crate) fn admissible(params: impl Iterator<Item = EdfParams>) -> bool {
+    params.map(|p| p.density()).sum::<u64>() <= DENSITY_ONE
+}
+
+/// A command written to a process's ctl interface.
+#[derive(Debug, PartialEq)]
+pub(crate) enum EdfCtl {
+    Period(usize),
+    Deadline(usize),
+    Cost(usize),
+    Admit,
+    Expel,
+}
+
+impl EdfCtl {
+    /// Parse a command such as "period 10ms", "deadline 5ms", "cost 2ms",
+    /// "admit" or "expel".
+    pub fn parse(cmd: &str) -> crate::Result<EdfCtl> {
+        let mut words = cmd.split_whitespace();
+        let ctl = match (words.next(), words.next()) {
+            (Some("period"), Some(t)) => EdfCtl::Period(parse_ticks(t)?),
+            (Some("deadline"), Some(t)) => EdfCtl::Deadline(parse_ticks(t)?),
+            (Some("cost"), Some(t)) => EdfCtl::Cost(parse_ticks(t)?),
+            (Some("admit"), None) => EdfCtl::Admit,
+            (Some("expel"), None) => EdfCtl::Expel,
+            _ => return Err("edf: bad ctl command"),
+        };
+        if words.next().is_some() {
+            return Err("edf: bad ctl command");
+        }
+        Ok(ctl)
+    }
+}
+
+/// Parse a time such as "10ms" to clock ticks.  The units may be s, ms, us
+/// or ns.
+fn parse_ticks(s: &str) -> crate::Result<usize> {
+    let digits = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+    let (n, unit) = s.split_at(digits);
+    let n: u64 = n.parse().map_err(|_| "edf: bad time")?;
+    let per_sec = match unit {
+        "s" => 1,
+        "ms" => 1_000,
+        "us" => 1_000_000,
+        "ns" => 1_000_000_000,
+        _ => return Err("edf: bad time unit"),
+    };
+    let ticks = n.checked_mul(HZ as u64).ok_or("edf: time too long")? / per_sec;
+    if ticks == 0 {
+        return Err("edf: time shorter than a clock tick");
+    }
+    usize::try_from(ticks).map_err(|_| "edf: time too long")
+}
+
+/// Real-time state of a process.
+#[derive(Clone, Copy)]
+pub(crate) struct Edf {
+    pub params: EdfParams,
+    pub admitted: bool,
+    release: u64, // Start of the current period
+    used: usize,  // Ticks run so far this period
+    done: bool,   // Can't run again until the next period
+    pub misses: usize,
+}
+
+impl Edf {
+    pub const NONE: Edf = Edf {
+        params: EdfParams { period: 0, deadline: 0, cost: 0 },
+        admitted: false,
+        release: 0,
+        used: 0,
+        done: false,
+        misses: 0,
+    };
+
+    pub fn set(&mut self, ctl: &EdfCtl) -> crate::Result<()> {
+        if self.admitted {
+            return Err("edf: expel before changing parameters");
+        }
+        match *ctl {
+            EdfCtl::Period(t) => self.params.period = t,
+            EdfCtl::Deadline(t) => self.params.deadline = t,
+            EdfCtl::Cost(t) => self.params.cost = t,
+            EdfCtl::Admit | EdfCtl::Expel => unreachable!(),
+        }
+        Ok(())
+    }
+
+    /// Check the parameters can be admitted alongside those of the others
+    /// already admitted.  A deadline that hasn't been set is the period.
+    pub fn check_admit(&mut self, others: impl Iterator<Item = EdfParams>) -> crate::Result<()> {
+        if self.admitted {
+            return Err("edf: already admitted");
+        }
+        let mut params = self.params;
+        if params.deadline == 0 {
+            params.deadline = params.period;
+        }
+        params.validate()?;
+        if !admissible(others.chain([params])) {
+            return Err("edf: not admissible");
+        }
+        self.params = params;
+        Ok(())
+    }
+
+    /// Admit the process, with its first period starting now.
+    pub fn admit(&mut self, now: u64) {
+        *self = Edf { admitted: true, release: now, used: 0, done: false, ..*self };
+    }
+
+    /// Absolute deadline of the current period.
+    pub fn deadline(&self) -> u64 {
+        self.release + self.params.deadline as u64
+    }
+
+    /// Whether the process may run now, if it's ready.
+    pub fn eligible(&self) -> bool {
+        self.admitted && !self.done
+    }
+
+    /// Called on each clock tick.  Counts a miss if the process still wants
+    /// to run at its deadline, and starts a new period if the current one is
+    /// over.
+    pub fn clock(&mut self, now: u64, runnable: bool) {
+        if !self.done && now >= self.deadline() {
+            if runnable {
+                self.misses += 1;
+            }
+            self.done = true;
+        }
+        let period = self.params.period as u64;
+        if now >= self.release + period {
+            self.release += (now - self.release) / period * period;
+            self.used = 0;
+            self.done = false;
+        }
+    }
+
+    /// Charge a tick to the running process, throttling it once it's used
+    /// its cost.
+    pub fn charge(&mut self) {
+        self.used += 1;
+        if self.used >= self.params.cost {
+            self.done = true;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_ctl() {
+        let per_10ms = HZ / 100;
+        assert_eq!(EdfCtl::parse("period 100ms"), Ok(EdfCtl::Period(10 * per_10ms)));
+        assert_eq!(EdfCtl::parse(" deadline  2s "), Ok(EdfCtl::Deadline(2 * HZ)));
+        assert_eq!(EdfCtl::parse("cost 30000us"), Ok(EdfCtl::Cost(3 * per_10ms)));
+        assert_eq!(EdfCtl::parse("admit"), Ok(EdfCtl::Admit));
+        assert_eq!(EdfCtl::parse("expel"), Ok(EdfCtl::Expel));
+        assert!(EdfCtl::parse("period").is_err());
+        assert!(EdfCtl::parse("period 10").is_err());
+        assert!(EdfCtl::parse("period 10ms 20ms").is_err());
+        assert!(EdfCtl::parse("cost 1ns").is_err());
+        assert!(EdfCtl::parse("admit now").is_err());
+        assert!(EdfCtl::parse("priority 10").is_err());
+    }
+
+    #[test]
+    fn admission_limits_density() {
+        let p = |period, deadline, cost| EdfParams { period, deadline, cost };
+        assert!(admissible([p(10, 10, 5), p(4, 4, 2)].into_iter()));
+        assert!(!admissible([p(10, 10, 5), p(4, 4, 2), p(100, 100, 1)].into_iter()));
+        // Deadlines shorter than the period count against density
+        assert!(!admissible([p(10, 5, 3), p(10, 10, 5)].into_iter()));
+
+        let mut edf = Edf::NONE;
+        edf.set(&EdfCtl::Period(10)).unwrap();
+        assert!(edf.check_admit([].into_iter()).is_err(), "admitted without a cost");
+        edf.set(&EdfCtl::Cost(11)).unwrap();
+        assert!(edf.check_admit([].into_iter()).is_err(), "admitted cost > period");
+        edf.set(&EdfCtl::Cost(4)).unwrap();
+        assert!(edf.check_admit([p(10, 10, 7)].into_iter()).is_err());
+        edf.check_admit([p(10, 10, 6)].into_iter()).unwrap();
+        assert_eq!(edf.params, p(10, 10, 4));
+        edf.admit(0);
+        assert!(edf.set(&EdfCtl::Cost(1)).is_err());
+    }
+
+    #[test]
+    fn periods_and_misses() {
+        let mut edf = Edf::NONE;
+        edf.params = EdfParams { period: 10, deadline: 5, cost: 2 };
+        edf.admit(100);
+        assert_eq!(edf.deadline(), 105);
+
+        // Throttled once the cost is used, until the next period
+        edf.charge();
+        assert!(edf.eligible());
+        edf.charge();
+        assert!(!edf.eligible());
+        edf.clock(109, true);
+        assert!(!edf.eligible());
+        edf.clock(110, true);
+        assert!(edf.eligible());
+        assert_eq!(edf.deadline(), 115);
+
+        // Still runnable at the deadline is a miss, but blocked isn't
+        edf.clock(115, true);
+        assert!(!edf.eligible());
+        assert_eq!(edf.misses, 1);
+        edf.clock(125, false);
+        assert_eq!(edf.misses, 1);
+
+        // Periods are skipped if the clock jumps
+        edf.clock(147, false);
+        assert_eq!(edf.deadline(), 145);
+        assert!(edf.eligible());
+        assert_eq!(edf.misses, 1);
+    }
+}
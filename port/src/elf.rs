@@ -0,0 +1,207 @@
+//! Parser for statically linked ELF64 executables, for `exec`.  Only the
+//! program headers matter: each PT_LOAD segment is mapped, and everything
+//! else is ignored, apart from the signs of dynamic linking, which are
+//! rejected.
+
+use alloc::vec::Vec;
+
+use crate::exec::{Format, Image, Perm, Segment};
+
+pub const EM_X86_64: u16 = 62;
+pub const EM_AARCH64: u16 = 183;
+pub const EM_RISCV: u16 = 243;
+
+const ELFMAG: &[u8] = b"\x7fELF";
+const ELFCLASS64: u8 = 2;
+const ELFDATA2LSB: u8 = 1;
+const EV_CURRENT: u8 = 1;
+const ET_EXEC: u16 = 2;
+
+const EHDR_SIZE: usize = 64;
+const PHDR_SIZE: usize = 56;
+
+const PT_LOAD: u32 = 1;
+const PT_DYNAMIC: u32 = 2;
+const PT_INTERP: u32 = 3;
+
+const PF_X: u32 = 1 << 0;
+const PF_W: u32 = 1 << 1;
+const PF_R: u32 = 1 << 2;
+
+fn bytes<const N: usize>(b: &[u8], off: usize) -> crate::Result<[u8; N]> {
+    let end = off.checked_add(N).ok_or("elf: truncated")?;
+    Ok(b.get(off..end).ok_or("elf: truncated")?.try_into().unwrap())
+}
+
+fn u16_at(b: &[u8], off: usize) -> crate::Result<u16> {
+    Ok(u16::from_le_bytes(bytes(b, off)?))
+}
+
+fn u32_at(b: &[u8], off: usize) -> crate::Result<u32> {
+    Ok(u32::from_le_bytes(bytes(b, off)?))
+}
+
+fn usize_at(b: &[u8], off: usize) -> crate::Result<usize> {
+    usize::try_from(u64::from_le_bytes(bytes(b, off)?)).map_err(|_| "elf: value too large")
+}
+
+/// Whether the file looks like an ELF file of any kind.
+pub fn is_elf(file: &[u8]) -> bool {
+    file.starts_with(ELFMAG)
+}
+
+/// Parse and check the headers of an executable for the given machine,
+/// returning the segments to load.  Segment data borrows from file.
+pub fn parse(file: &[u8], machine: u16) -> crate::Result<Image<'_>> {
+    let ident = bytes::<16>(file, 0)?;
+    if &ident[..4] != ELFMAG {
+        return Err("elf: bad magic");
+    }
+    if ident[4] != ELFCLASS64 || ident[5] != ELFDATA2LSB || ident[6] != EV_CURRENT {
+        return Err("elf: not a little-endian ELF64 file");
+    }
+    if u16_at(file, 16)? != ET_EXEC {
+        return Err("elf: not an executable");
+    }
+    if u16_at(file, 18)? != machine {
+        return Err("elf: wrong machine");
+    }
+    if u16_at(file, 52)? as usize != EHDR_SIZE || u16_at(file, 54)? as usize != PHDR_SIZE {
+        return Err("elf: bad header size");
+    }
+    let entry = usize_at(file, 24)?;
+    let phoff = usize_at(file, 32)?;
+    let phnum = u16_at(file, 56)? as usize;
+
+    let mut segments = Vec::new();
+    for i in 0..phnum {
+        let ph = phoff.checked_add(i * PHDR_SIZE).ok_or("elf: truncated")?;
+        match u32_at(file, ph)? {
+            PT_LOAD => {}
+            PT_DYNAMIC | PT_INTERP => return Err("elf: dynamically linked"),
+            _ => continue,
+        }
+        let flags = u32_at(file, ph + 4)?;
+        let offset = usize_at(file, ph + 8)?;
+        let va = usize_at(file, ph + 16)?;
+        let filesz = usize_at(file, ph + 32)?;
+        let memsz = usize_at(file, ph + 40)?;
+        if filesz > memsz {
+            return Err("elf: segment file size exceeds memory size");
+        }
+        if memsz == 0 {
+            continue;
+        }
+        va.checked_add(memsz).ok_or("elf: segment wraps")?;
+        let data = offset
+            .checked_add(filesz)
+            .and_then(|end| file.get(offset..end))
+            .ok_or("elf: segment beyond end of file")?;
+
+        let mut perm = Perm::empty();
+        perm.set(Perm::R, flags & PF_R != 0);
+        perm.set(Perm::W, flags & PF_W != 0);
+        perm.set(Perm::X, flags & PF_X != 0);
+        segments.push(Segment { va, data, memsz, perm });
+    }
+    if segments.is_empty() {
+        return Err("elf: nothing to load");
+    }
+    Ok(Image { entry, segments, format: Format::Elf })
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::exec::tests::TestSpace;
+    use crate::exec::{self, register};
+
+    /// Build an executable with a text segment at 0x10000 holding text, and
+    /// a data segment at 0x20000 holding data followed by bss.
+    pub fn build(machine: u16, text: &[u8], data: &[u8], bss: usize) -> Vec<u8> {
+        let phnum = 2;
+        let text_off = EHDR_SIZE + phnum * PHDR_SIZE;
+        let data_off = text_off + text.len();
+
+        let mut f = Vec::new();
+        f.extend_from_slice(ELFMAG);
+        f.extend_from_slice(&[ELFCLASS64, ELFDATA2LSB, EV_CURRENT]);
+        f.resize(16, 0);
+        f.extend_from_slice(&ET_EXEC.to_le_bytes());
+        f.extend_from_slice(&machine.to_le_bytes());
+        f.extend_from_slice(&1u32.to_le_bytes()); // e_version
+        f.extend_from_slice(&0x10000u64.to_le_bytes()); // e_entry
+        f.extend_from_slice(&(EHDR_SIZE as u64).to_le_bytes()); // e_phoff
+        f.extend_from_slice(&0u64.to_le_bytes()); // e_shoff
+        f.extend_from_slice(&0u32.to_le_bytes()); // e_flags
+        for half in [EHDR_SIZE, PHDR_SIZE, phnum, 64, 0, 0] {
+            f.extend_from_slice(&(half as u16).to_le_bytes());
+        }
+
+        let mut phdr = |flags: u32, off: usize, va: u64, filesz: usize, memsz: usize| {
+            f.extend_from_slice(&PT_LOAD.to_le_bytes());
+            f.extend_from_slice(&flags.to_le_bytes());
+            for v in [off as u64, va, va, filesz as u64, memsz as u64, 0x1000] {
+                f.extend_from_slice(&v.to_le_bytes());
+            }
+        };
+        phdr(PF_R | PF_X, text_off, 0x10000, text.len(), text.len());
+        phdr(PF_R | PF_W, data_off, 0x20000, data.len(), data.len() + bss);
+
+        f.extend_from_slice(text);
+        f.extend_from_slice(data);
+        f
+    }
+
+    #[test]
+    fn parses_segments() {
+        let file = build(EM_AARCH64, &[1, 2, 3, 4], &[5, 6], 10);
+        let image = parse(&file, EM_AARCH64).unwrap();
+        assert_eq!(image.entry, 0x10000);
+        assert_eq!(
+            image.segments,
+            [
+                Segment { va: 0x10000, data: &[1, 2, 3, 4], memsz: 4, perm: Perm::R | Perm::X },
+                Segment { va: 0x20000, data: &[5, 6], memsz: 12, perm: Perm::R | Perm::W },
+            ]
+        );
+    }
+
+    #[test]
+    fn rejects_bad_headers() {
+        let file = build(EM_AARCH64, &[1, 2, 3, 4], &[5, 6], 10);
+        assert_eq!(parse(&file, EM_X86_64).unwrap_err(), "elf: wrong machine");
+        assert!(parse(&file[..100], EM_AARCH64).is_err());
+
+        let patched = |off: usize, b: &[u8]| {
+            let mut f = file.clone();
+            f[off..off + b.len()].copy_from_slice(b);
+            f
+        };
+        assert_eq!(parse(&patched(0, b"\x7fELG"), EM_AARCH64).unwrap_err(), "elf: bad magic");
+        assert!(parse(&patched(4, &[1]), EM_AARCH64).is_err(), "accepted ELF32");
+        assert!(parse(&patched(16, &[3, 0]), EM_AARCH64).is_err(), "accepted ET_DYN");
+        let interp = patched(EHDR_SIZE, &PT_INTERP.to_le_bytes());
+        assert_eq!(parse(&interp, EM_AARCH64).unwrap_err(), "elf: dynamically linked");
+        // Data segment's file size beyond the end of the file
+        let past_end = patched(EHDR_SIZE + PHDR_SIZE + 32, &[100]);
+        assert!(parse(&past_end, EM_AARCH64).is_err());
+        // File size larger than memory size
+        let big_filesz = patched(EHDR_SIZE + 32, &[5]);
+        assert!(parse(&big_filesz, EM_AARCH64).is_err());
+    }
+
+    #[test]
+    fn execs_registered_image() {
+        let file = build(EM_AARCH64, &[0xaa; 8], &[0xbb; 4], 0x2000);
+        register("/bin/elftest", Vec::leak(file));
+        let mut space = TestSpace::new();
+        assert!(exec::exec(&mut space, b"/bin/nonesuch", &[]).is_err());
+        let start = exec::exec(&mut space, b"/bin/elftest", &[b"elftest"]).unwrap();
+        assert_eq!(start.entry, 0x10000);
+        assert_eq!(start.argc, 1);
+        assert_eq!(space.read(0x10000, 8), [0xaa; 8]);
+        assert_eq!(space.read(0x20000, 6), [0xbb, 0xbb, 0xbb, 0xbb, 0, 0]);
+        assert_eq!(space.perm(0x21000), Perm::R | Perm::W);
+    }
+}
@@ -0,0 +1,383 @@
+//! exec replaces a process's user memory with a new program, after Plan 9's
+//! sysexec.  The executable is parsed into an `Image` by the loader for its
+//! format, its segments are mapped into a fresh address space along with a
+//! stack holding the arguments, and the process starts at the image's entry
+//! point.
+//!
+//! ELF executables get a System V style stack.  Plan 9 a.out executables get
+//! Plan 9's, with a Tos at the top, so that 9front binaries run unchanged.
+//!
+//! There's no file system yet, so programs are looked up by name among the
+//! images the kernel was built with, which are registered at boot.
+//!
+//! Only aarch64 implements `UserSpace` so far.  Per-process page tables for
+//! x86_64 and riscv64 are separate work, and until it's done exec fails
+//! there, saying so.
+
+use alloc::vec::Vec;
+use bitflags::bitflags;
+
+use crate::maths::{round_down2_usize, round_up2_usize};
+use crate::mcslock::Lock;
+use crate::mem::{PAGE_SIZE_4K, VirtRange};
+use crate::sys9::{Abi, TOS_PID, TOS_SIZE};
+use crate::{aout, elf, sched};
+
+/// Size of a new process's stack.
+pub const USTACK_SIZE: usize = 16 * PAGE_SIZE_4K;
+
+/// Most bytes the arguments may take up on the stack, including pointers.
+pub const MAX_ARGS_SIZE: usize = USTACK_SIZE / 2;
+
+bitflags! {
+    /// Access a user segment allows.
+    #[derive(Clone, Copy, Debug, PartialEq)]
+    pub struct Perm: u8 {
+        const R = 1 << 0;
+        const W = 1 << 1;
+        const X = 1 << 2;
+    }
+}
+
+/// A segment of an executable to be loaded into user memory.
+#[derive(Debug, PartialEq)]
+pub struct Segment<'a> {
+    pub va: usize,
+    pub data: &'a [u8], // Initialised part, followed by zeroes
+    pub memsz: usize,   // Size of the whole segment in memory
+    pub perm: Perm,
+}
+
+impl Segment<'_> {
+    /// The pages the segment occupies.
+    pub fn pages(&self) -> VirtRange {
+        VirtRange(
+            round_down2_usize(self.va, PAGE_SIZE_4K)
+                ..round_up2_usize(self.va + self.memsz, PAGE_SIZE_4K),
+        )
+    }
+}
+
+/// Executable formats, which determine how the stack is laid out.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Format {
+    Elf,
+    Aout,
+}
+
+/// An executable, parsed and checked, ready to be loaded.
+#[derive(Debug)]
+pub struct Image<'a> {
+    pub entry: usize,
+    pub segments: Vec<Segment<'a>>, // Sorted by address, not sharing pages
+    pub format: Format,
+}
+
+impl Image<'_> {
+    /// Check the segments are in order, don't share pages with each other,
+    /// don't map page zero, and leave room for the stack below `end`, and
+    /// that the entry point is in an executable segment.
+    pub fn validate(&self, end: usize) -> crate::Result<()> {
+        let mut prev_end = PAGE_SIZE_4K;
+        for s in &self.segments {
+            let pages = s.pages();
+            if pages.start() < prev_end {
+                return Err("exec: segments overlap or map page zero");
+            }
+            prev_end = pages.end();
+        }
+        if prev_end > end - USTACK_SIZE {
+            return Err("exec: segments overlap the stack");
+        }
+        let entry_in_text = self
+            .segments
+            .iter()
+            .any(|s| s.perm.contains(Perm::X) && (s.va..s.va + s.memsz).contains(&self.entry));
+        if !entry_in_text {
+            return Err("exec: entry point isn't in an executable segment");
+        }
+        Ok(())
+    }
+}
+
+/// A new address space for a program, implemented by each architecture.
+pub trait UserSpace {
+    /// ELF machine number of executables for this architecture.
+    const ELF_MACHINE: u16;
+
+    /// Layout of 9front executables for this architecture, if it has any.
+    const AOUT: Option<Abi>;
+
+    /// User addresses must be below this.
+    fn end(&self) -> usize;
+
+    /// Map zeroed, writable pages over the range, which is page aligned.
+    fn map(&mut self, range: &VirtRange) -> crate::Result<()>;
+
+    /// Copy data into pages already mapped with `map`.
+    fn copy_to(&mut self, va: usize, data: &[u8]) -> crate::Result<()>;
+
+    /// Set the final permissions of pages mapped with `map`.
+    fn protect(&mut self, range: &VirtRange, perm: Perm) -> crate::Result<()>;
+}
+
+/// Where a loaded program starts running.
+#[derive(Debug, PartialEq)]
+pub struct Start {
+    pub entry: usize,
+    pub sp: usize, // Points at argc
+    pub argc: usize,
+    pub argv: usize,        // Address of the argv array
+    pub tos: Option<usize>, // Address of the Tos, for Plan 9 executables
+}
+
+/// Map the image's segments and a stack holding argv into space, which
+/// should be empty.
+pub fn load(space: &mut impl UserSpace, image: &Image, argv: &[&[u8]]) -> crate::Result<Start> {
+    let end = round_down2_usize(space.end(), PAGE_SIZE_4K);
+    image.validate(end)?;
+    for s in &image.segments {
+        space.map(&s.pages())?;
+        space.copy_to(s.va, s.data)?;
+    }
+    for s in &image.segments {
+        space.protect(&s.pages(), s.perm)?;
+    }
+
+    let stack = VirtRange(end - USTACK_SIZE..end);
+    space.map(&stack)?;
+    let (bytes, start) = match image.format {
+        Format::Elf => stack_image(end, argv)?,
+        Format::Aout => plan9_stack_image(end, argv, sched::getpid())?,
+    };
+    space.copy_to(end - bytes.len(), &bytes)?;
+    space.protect(&stack, Perm::R | Perm::W)?;
+    Ok(Start { entry: image.entry, ..start })
+}
+
+/// Build the top of the initial stack, in the System V layout: argc, the
+/// argv pointers and a null, an empty environment, an empty auxiliary
+/// vector, then the argument strings themselves.  Returns the bytes to go
+/// just below top, and where the program starts, but for its entry point.
+fn stack_image(top: usize, argv: &[&[u8]]) -> crate::Result<(Vec<u8>, Start)> {
+    const WORD: usize = size_of::<u64>();
+    let strings_size: usize = argv.iter().map(|a| a.len() + 1).sum();
+    let vector_size = (argv.len() + 5) * WORD; // argc, argv, null, envp null, AT_NULL pair
+    let size = round_up2_usize(strings_size, 16) + round_up2_usize(vector_size, 16);
+    if size > MAX_ARGS_SIZE {
+        return Err("exec: arguments too long");
+    }
+
+    let sp = top - size;
+    let mut bytes = Vec::with_capacity(size);
+    let mut push = |word: usize| bytes.extend_from_slice(&(word as u64).to_le_bytes());
+    push(argv.len());
+    let mut string = top - strings_size;
+    for arg in argv {
+        push(string);
+        string += arg.len() + 1;
+    }
+    push(0); // End of argv
+    push(0); // End of envp
+    push(0); // AT_NULL
+    push(0);
+    bytes.resize(size - strings_size, 0);
+    for arg in argv {
+        bytes.extend_from_slice(arg);
+        bytes.push(0);
+    }
+    Ok((bytes, Start { entry: 0, sp, argc: argv.len(), argv: sp + WORD, tos: None }))
+}
+
+/// Build the top of the initial stack in Plan 9's layout: argc, then the
+/// argv pointers and a null, then the argument strings, then the Tos, which
+/// holds the pid and is otherwise zeroed.
+fn plan9_stack_image(top: usize, argv: &[&[u8]], pid: usize) -> crate::Result<(Vec<u8>, Start)> {
+    const WORD: usize = size_of::<u64>();
+    let strings_size: usize = argv.iter().map(|a| a.len() + 1).sum();
+    let tos = top - TOS_SIZE;
+    let strings = tos - strings_size;
+    let sp = round_down2_usize(strings - (argv.len() + 2) * WORD, 16);
+    if top - sp > MAX_ARGS_SIZE {
+        return Err("exec: arguments too long");
+    }
+
+    let mut bytes = Vec::with_capacity(top - sp);
+    let mut push = |word: usize| bytes.extend_from_slice(&(word as u64).to_le_bytes());
+    push(argv.len());
+    let mut string = strings;
+    for arg in argv {
+        push(string);
+        string += arg.len() + 1;
+    }
+    push(0); // End of argv
+    bytes.resize(strings - sp, 0);
+    for arg in argv {
+        bytes.extend_from_slice(arg);
+        bytes.push(0);
+    }
+    bytes.resize(top - sp, 0);
+    let pid_at = tos + TOS_PID - sp;
+    bytes[pid_at..pid_at + 4].copy_from_slice(&(pid as u32).to_le_bytes());
+    Ok((bytes, Start { entry: 0, sp, argc: argv.len(), argv: sp + WORD, tos: Some(tos) }))
+}
+
+/// Executables built into the kernel, by name.
+static IMAGES: Lock<Vec<(&str, &[u8])>> = Lock::new("images", Vec::new());
+
+/// Make an executable built into the kernel available to `exec`.
+pub fn register(name: &'static str, image: &'static [u8]) {
+    IMAGES.lock().push((name, image));
+}
+
+fn lookup(name: &[u8]) -> Option<&'static [u8]> {
+    IMAGES.lock().iter().find(|(n, _)| n.as_bytes() == name).map(|&(_, image)| image)
+}
+
+/// Load the named program into space, which should be empty, with the
+/// given arguments.
+pub fn exec<S: UserSpace>(space: &mut S, name: &[u8], argv: &[&[u8]]) -> crate::Result<Start> {
+    let file = lookup(name).ok_or("exec: file does not exist")?;
+    let image = match &S::AOUT {
+        _ if elf::is_elf(file) => elf::parse(file, S::ELF_MACHINE)?,
+        Some(abi) => aout::parse(file, abi)?,
+        None => return Err("exec: not an ELF executable"),
+    };
+    load(space, &image, argv)
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::sys9;
+    use alloc::collections::BTreeMap;
+
+    /// A user address space of pages held in a map.
+    pub struct TestSpace {
+        pub pages: BTreeMap<usize, (Vec<u8>, Perm)>,
+    }
+
+    impl TestSpace {
+        pub const END: usize = 1 << 30;
+
+        pub fn new() -> Self {
+            Self { pages: BTreeMap::new() }
+        }
+
+        pub fn read(&self, va: usize, len: usize) -> Vec<u8> {
+            (va..va + len)
+                .map(|a| self.pages[&(a & !(PAGE_SIZE_4K - 1))].0[a % PAGE_SIZE_4K])
+                .collect()
+        }
+
+        pub fn perm(&self, va: usize) -> Perm {
+            self.pages[&(va & !(PAGE_SIZE_4K - 1))].1
+        }
+    }
+
+    impl UserSpace for TestSpace {
+        const ELF_MACHINE: u16 = elf::EM_AARCH64;
+        const AOUT: Option<Abi> = Some(sys9::ARM64);
+
+        fn end(&self) -> usize {
+            Self::END
+        }
+
+        fn map(&mut self, range: &VirtRange) -> crate::Result<()> {
+            for va in range.0.clone().step_by(PAGE_SIZE_4K) {
+                let page = (vec![0; PAGE_SIZE_4K], Perm::R | Perm::W);
+                if self.pages.insert(va, page).is_some() {
+                    return Err("mapped twice");
+                }
+            }
+            Ok(())
+        }
+
+        fn copy_to(&mut self, va: usize, data: &[u8]) -> crate::Result<()> {
+            for (i, b) in data.iter().enumerate() {
+                let a = va + i;
+                let (page, _) =
+                    self.pages.get_mut(&(a & !(PAGE_SIZE_4K - 1))).ok_or("not mapped")?;
+                page[a % PAGE_SIZE_4K] = *b;
+            }
+            Ok(())
+        }
+
+        fn protect(&mut self, range: &VirtRange, perm: Perm) -> crate::Result<()> {
+            for va in range.0.clone().step_by(PAGE_SIZE_4K) {
+                self.pages.get_mut(&va).ok_or("not mapped")?.1 = perm;
+            }
+            Ok(())
+        }
+    }
+
+    fn word(space: &TestSpace, va: usize) -> usize {
+        u64::from_le_bytes(space.read(va, 8).try_into().unwrap()) as usize
+    }
+
+    fn cstr(space: &TestSpace, va: usize) -> Vec<u8> {
+        let mut s = Vec::new();
+        while let [b] = space.read(va + s.len(), 1)[..]
+            && b != 0
+        {
+            s.push(b);
+        }
+        s
+    }
+
+    fn image(segments: Vec<Segment<'_>>) -> Image<'_> {
+        Image { entry: segments[0].va, segments, format: Format::Elf }
+    }
+
+    #[test]
+    fn loads_segments_and_args() {
+        let text = [1, 2, 3, 4];
+        let data = [5, 6];
+        let img = image(vec![
+            Segment { va: 0x1000, data: &text, memsz: 4, perm: Perm::R | Perm::X },
+            Segment { va: 0x2ffe, data: &data, memsz: 0x10, perm: Perm::R | Perm::W },
+        ]);
+        let mut space = TestSpace::new();
+        let start = load(&mut space, &img, &[b"init", b"-s"]).unwrap();
+
+        assert_eq!(start.entry, 0x1000);
+        assert_eq!(space.read(0x1000, 4), text);
+        assert_eq!(space.perm(0x1000), Perm::R | Perm::X);
+        // Data spills onto a second page, and bss is zeroed
+        assert_eq!(space.read(0x2ffe, 4), [5, 6, 0, 0]);
+        assert_eq!(space.perm(0x3000), Perm::R | Perm::W);
+        assert_eq!(space.pages.range(..TestSpace::END - USTACK_SIZE).count(), 3);
+
+        assert_eq!(start.sp % 16, 0);
+        assert_eq!(word(&space, start.sp), 2);
+        assert_eq!(start.argv, start.sp + 8);
+        assert_eq!(cstr(&space, word(&space, start.argv)), b"init");
+        assert_eq!(cstr(&space, word(&space, start.argv + 8)), b"-s");
+        assert_eq!(word(&space, start.argv + 16), 0);
+        assert_eq!(space.perm(start.sp), Perm::R | Perm::W);
+    }
+
+    #[test]
+    fn rejects_bad_layouts() {
+        let text = [0; 4];
+        let seg = |va, memsz, perm| Segment { va, data: &text, memsz, perm };
+        let rx = Perm::R | Perm::X;
+        let end = TestSpace::END;
+
+        let page_zero = image(vec![seg(0x800, 4, rx)]);
+        assert!(page_zero.validate(end).is_err());
+        let shared_page = image(vec![seg(0x1000, 4, rx), seg(0x1800, 4, Perm::R)]);
+        assert!(shared_page.validate(end).is_err());
+        let unordered = image(vec![seg(0x3000, 4, rx), seg(0x1000, 4, rx)]);
+        assert!(unordered.validate(end).is_err());
+        let into_stack = image(vec![seg(0x1000, end - USTACK_SIZE, rx)]);
+        assert!(into_stack.validate(end).is_err());
+        let mut entry_in_data = image(vec![seg(0x1000, 4, rx), seg(0x2000, 4, Perm::R)]);
+        entry_in_data.entry = 0x2000;
+        assert!(entry_in_data.validate(end).is_err());
+
+        let big = vec![0; MAX_ARGS_SIZE];
+        let mut space = TestSpace::new();
+        assert!(load(&mut space, &image(vec![seg(0x1000, 4, rx)]), &[&big]).is_err());
+    }
+}
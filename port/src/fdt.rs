@@ -408,6 +408,67 @@ impl<'a> DeviceTree<'a> {
         })
     }
 
+    /// Iterate over the entries in the memory reservation block.  Each entry
+    /// describes a region of physical memory that the OS must not use.
+    pub fn memory_reservations(&self) -> impl Iterator<Item = RegBlock> + '_ {
+        let mut i = self.header.off_mem_rsvmap as usize;
+
+        core::iter::from_fn(move || {
+            // The block is terminated by an entry with address and size both 0
+            let addr = self.data.get(i..).and_then(bytes_to_u64)?;
+            let size = self.data.get((i + 8)..).and_then(bytes_to_u64)?;
+            if addr == 0 && size == 0 {
+                return None;
+            }
+            i += 16;
+            Some(RegBlock { addr, len: Some(size) })
+        })
+    }
+
+    /// Iterate over the regions described by the children of /reserved-memory.
+    /// Statically placed regions are returned once per reg entry, translated by
+    /// the ranges of the parent.  Dynamically placed regions (those with only a
+    /// size) are returned once with no reg.  Disabled nodes are skipped.
+    pub fn reserved_memory(&self) -> impl Iterator<Item = ReservedMemory> + '_ {
+        let resmem = self.find_by_path("/reserved-memory");
+        let (_, size_cells) = self.node_address_size_cells(resmem);
+
+        self.nodes()
+            .filter(move |n| resmem.is_some_and(|r| n.depth == r.depth + 1 && r.encloses(n)))
+            .filter(|n| {
+                self.property(n, "status")
+                    .is_none_or(|p| !self.property_value_contains(&p, "disabled"))
+            })
+            .flat_map(move |node| {
+                let no_map = self.property(&node, "no-map").is_some();
+                let reusable = self.property(&node, "reusable").is_some();
+                let is_dynamic = self.property(&node, "reg").is_none();
+                let size = self
+                    .property(&node, "size")
+                    .filter(|p| size_cells > 0 && p.value_len == size_cells * 4)
+                    .and_then(|p| self.consume_cells(p.value_start, size_cells));
+
+                let static_regions = self
+                    .property_translated_reg_iter(node)
+                    .flat_map(|r| r.regblock())
+                    .map(move |reg| ReservedMemory {
+                        node,
+                        reg: Some(reg),
+                        size: reg.len,
+                        no_map,
+                        reusable,
+                    });
+                let dynamic_region = is_dynamic.then_some(ReservedMemory {
+                    node,
+                    reg: None,
+                    size,
+                    no_map,
+                    reusable,
+                });
+                static_regions.chain(dynamic_region)
+            })
+    }
+
     fn inline_str(bytes: &[mem::MaybeUninit<u8>], start: usize) -> Option<&str> {
         let maybe_uninit_bytes = bytes.get(start..)?;
         let init_bytes = unsafe { maybe_uninit_bytes.assume_init_ref() };
@@ -724,6 +785,16 @@ impl RegBlock {
     }
 }
 
+/// A region of memory described by a child node of /reserved-memory.
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub struct ReservedMemory {
+    pub node: Node,
+    pub reg: Option<RegBlock>, // Translated reg, or None if dynamically placed
+    pub size: Option<u64>,     // Size of the region
+    pub no_map: bool,          // The region must not be mapped by the OS
+    pub reusable: bool,        // The OS may use the region until the owner claims it
+}
+
 #[derive(Debug, PartialEq, Copy, Clone)]
 pub enum TranslatedReg {
     Translated(RegBlock),
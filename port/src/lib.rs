@@ -1,6 +1,8 @@
 #![allow(clippy::upper_case_acronyms)]
 #![allow(clippy::too_long_first_doc_paragraph)]
 #![cfg_attr(not(any(test)), no_std)]
+#![cfg_attr(any(feature = "heapdebug", feature = "lockdebug"), allow(internal_features))]
+#![cfg_attr(any(feature = "heapdebug", feature = "lockdebug"), feature(core_intrinsics))]
 #![feature(allocator_api)]
 #![feature(maybe_uninit_slice)]
 #![feature(step_trait)]
@@ -9,13 +11,30 @@
 extern crate alloc;
 
 pub mod allocator;
+pub mod aout;
+pub mod asid;
 pub mod bitmapalloc;
+pub mod cpu;
 pub mod dat;
 pub mod devcons;
+pub mod edf;
+pub mod elf;
+pub mod exec;
 pub mod fdt;
+#[cfg(feature = "lockdebug")]
+pub mod lockstat;
 pub mod maths;
 pub mod mcslock;
 pub mod mem;
 pub mod pagealloc;
+pub mod pgrp;
+pub mod qlock;
+pub mod rendez;
+pub mod sched;
+pub mod slab;
+pub mod sys9;
+pub mod syscall;
+pub mod usermem;
+pub mod vmem;
 
 pub type Result<T> = core::result::Result<T, &'static str>;
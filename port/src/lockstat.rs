@@ -0,0 +1,302 @@
+//! lockstat keeps statistics on the use of MCS locks, for the lockdebug
+//! feature.  Each lock records the CPU and pc of its holder, panics if a CPU
+//! tries to take a lock it already holds, and warns if a waiter spins for
+//! too long.  Acquisitions and spins are counted per lock name, so all locks
+//! with the same name share statistics, and `lockstat` lists the most
+//! contended.
+//!
+//! Holders are identified by CPU, so this assumes that a CPU doesn't switch
+//! to other work that takes the same lock while holding it, other than via
+//! interrupts that should have been disabled.
+
+use core::cell::UnsafeCell;
+use core::cmp::Reverse;
+use core::fmt;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+use crate::cpu::{self, NO_CPU};
+
+/// Maximum number of lock names that statistics are kept for.  Locks with
+/// names beyond these still have their holders tracked.
+pub const MAX_LOCKSTAT_NAMES: usize = 64;
+
+/// Default number of spins before a waiter warns that a lock has been held
+/// for too long.
+const DEFAULT_WARN_SPINS: usize = 1 << 26;
+
+static WARN_SPINS: AtomicUsize = AtomicUsize::new(DEFAULT_WARN_SPINS);
+
+/// Set the number of spins a waiter makes before warning that the lock has
+/// been held for too long, and again for every multiple of it.
+pub fn set_warn_spins(spins: usize) {
+    WARN_SPINS.store(usize::max(spins, 1), Ordering::Relaxed);
+}
+
+/// Statistics for all the locks sharing a name.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct LockStat {
+    pub name: &'static str,
+    pub acquires: usize,  // Number of times the lock was taken
+    pub contended: usize, // Number of those that had to wait
+    pub spins: usize,     // Total spins waiting for the lock
+    pub max_spins: usize, // Longest wait
+    pub warnings: usize,  // Number of times a waiter warned
+}
+
+struct Entry {
+    name: UnsafeCell<&'static str>, // Written once, before the entry is published
+    acquires: AtomicUsize,
+    contended: AtomicUsize,
+    spins: AtomicUsize,
+    max_spins: AtomicUsize,
+    warnings: AtomicUsize,
+}
+
+unsafe impl Sync for Entry {}
+
+impl Entry {
+    const fn new() -> Self {
+        Self {
+            name: UnsafeCell::new(""),
+            acquires: AtomicUsize::new(0),
+            contended: AtomicUsize::new(0),
+            spins: AtomicUsize::new(0),
+            max_spins: AtomicUsize::new(0),
+            warnings: AtomicUsize::new(0),
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        unsafe { *self.name.get() }
+    }
+
+    fn stat(&self) -> LockStat {
+        LockStat {
+            name: self.name(),
+            acquires: self.acquires.load(Ordering::Relaxed),
+            contended: self.contended.load(Ordering::Relaxed),
+            spins: self.spins.load(Ordering::Relaxed),
+            max_spins: self.max_spins.load(Ordering::Relaxed),
+            warnings: self.warnings.load(Ordering::Relaxed),
+        }
+    }
+}
+
+static ENTRIES: [Entry; MAX_LOCKSTAT_NAMES] = [const { Entry::new() }; MAX_LOCKSTAT_NAMES];
+static NUM_ENTRIES: AtomicUsize = AtomicUsize::new(0);
+static REGISTERING: AtomicBool = AtomicBool::new(false);
+
+/// Find the index of the entry for name, adding it if it isn't there.
+fn find_or_add(name: &'static str) -> Option<usize> {
+    let find = |num| (0..num).find(|&i| ENTRIES[i].name() == name);
+    if let Some(i) = find(NUM_ENTRIES.load(Ordering::Acquire)) {
+        return Some(i);
+    }
+
+    // Entries are only added by one CPU at a time.  This can't use an MCS
+    // lock, since it's called while taking one.
+    let intr = cpu::splhi();
+    while REGISTERING.swap(true, Ordering::Acquire) {
+        core::hint::spin_loop();
+    }
+    let num = NUM_ENTRIES.load(Ordering::Acquire);
+    let i = find(num).or_else(|| {
+        (num < MAX_LOCKSTAT_NAMES).then(|| {
+            unsafe { *ENTRIES[num].name.get() = name };
+            NUM_ENTRIES.store(num + 1, Ordering::Release);
+            num
+        })
+    });
+    REGISTERING.store(false, Ordering::Release);
+    cpu::splx(intr);
+    i
+}
+
+/// Debugging state kept in each MCS lock.
+pub(crate) struct LockDebug {
+    holder_cpu: AtomicUsize,
+    holder_pc: AtomicUsize,
+    entry: AtomicUsize, // Index into ENTRIES plus one, 0 if not yet found
+}
+
+/// Value of LockDebug::entry when there's no room for the lock's name.
+const NO_ENTRY: usize = usize::MAX;
+
+impl LockDebug {
+    pub const fn new() -> Self {
+        Self {
+            holder_cpu: AtomicUsize::new(NO_CPU),
+            holder_pc: AtomicUsize::new(0),
+            entry: AtomicUsize::new(0),
+        }
+    }
+
+    fn entry(&self, name: &'static str) -> Option<&'static Entry> {
+        let mut entry = self.entry.load(Ordering::Relaxed);
+        if entry == 0 {
+            entry = find_or_add(name).map_or(NO_ENTRY, |i| i + 1);
+            self.entry.store(entry, Ordering::Relaxed);
+        }
+        (entry != NO_ENTRY).then(|| &ENTRIES[entry - 1])
+    }
+
+    /// Called before waiting for the lock.  Panics if this CPU already holds
+    /// it, since otherwise it would wait forever.
+    pub fn check_recursion(&self, name: &'static str, pc: usize) {
+        let cpu = cpu::cpu_id();
+        if cpu != NO_CPU && self.holder_cpu.load(Ordering::Relaxed) == cpu {
+            panic!(
+                "lock {name}: recursive acquisition on cpu {cpu} at pc {pc:#x}, held since pc {:#x}",
+                self.holder_pc.load(Ordering::Relaxed)
+            );
+        }
+    }
+
+    /// Called on every spin while waiting for the lock.
+    pub fn spinning(&self, name: &'static str, spins: usize) {
+        if spins == 0 || !spins.is_multiple_of(WARN_SPINS.load(Ordering::Relaxed)) {
+            return;
+        }
+        if let Some(entry) = self.entry(name) {
+            entry.warnings.fetch_add(1, Ordering::Relaxed);
+        }
+        warn(format_args!(
+            "warning:lockstat:spinning:lock {name} held for {spins} spins by cpu {} pc {:#x}",
+            self.holder_cpu.load(Ordering::Relaxed),
+            self.holder_pc.load(Ordering::Relaxed)
+        ));
+    }
+
+    /// Called once the lock has been taken.
+    pub fn acquired(&self, name: &'static str, pc: usize, spins: usize) {
+        self.holder_cpu.store(cpu::cpu_id(), Ordering::Relaxed);
+        self.holder_pc.store(pc, Ordering::Relaxed);
+        if let Some(entry) = self.entry(name) {
+            entry.acquires.fetch_add(1, Ordering::Relaxed);
+            if spins > 0 {
+                entry.contended.fetch_add(1, Ordering::Relaxed);
+                entry.spins.fetch_add(spins, Ordering::Relaxed);
+                entry.max_spins.fetch_max(spins, Ordering::Relaxed);
+            }
+        }
+    }
+
+    /// Called just before the lock is released.
+    pub fn releasing(&self) {
+        self.holder_cpu.store(NO_CPU, Ordering::Relaxed);
+        self.holder_pc.store(0, Ordering::Relaxed);
+    }
+
+    /// The CPU and pc of the current holder, if there is one.
+    #[cfg(test)]
+    pub fn holder(&self) -> Option<(usize, usize)> {
+        let cpu = self.holder_cpu.load(Ordering::Relaxed);
+        (cpu != NO_CPU).then(|| (cpu, self.holder_pc.load(Ordering::Relaxed)))
+    }
+}
+
+/// Print a warning, unless the console is busy.  The waiter may be waiting
+/// for the console lock itself, or be holding it while it waits for another,
+/// so it mustn't wait for the console.  The warning is still counted in the
+/// lock's statistics.
+fn warn(args: fmt::Arguments) {
+    #[cfg(not(test))]
+    crate::devcons::try_print(args);
+    #[cfg(test)]
+    std::println!("{args}");
+}
+
+/// Returns the statistics for each lock name, most contended first, along
+/// with the number of names.
+pub fn stats() -> ([LockStat; MAX_LOCKSTAT_NAMES], usize) {
+    let num = NUM_ENTRIES.load(Ordering::Acquire);
+    let mut stats = [LockStat::default(); MAX_LOCKSTAT_NAMES];
+    for (stat, entry) in stats.iter_mut().zip(&ENTRIES[..num]) {
+        *stat = entry.stat();
+    }
+    stats[..num].sort_unstable_by_key(|stat| Reverse((stat.spins, stat.max_spins, stat.acquires)));
+    (stats, num)
+}
+
+/// Write out the statistics for at most max lock names, most contended
+/// first.  Suitable for a panic-time report.
+pub fn lockstat(w: &mut dyn fmt::Write, max: usize) -> fmt::Result {
+    let (stats, num) = stats();
+    writeln!(
+        w,
+        "{:<16} {:>10} {:>10} {:>12} {:>10} {:>5}",
+        "lock", "acquires", "contended", "spins", "maxspins", "warns"
+    )?;
+    for stat in stats[..num].iter().take(max) {
+        writeln!(
+            w,
+            "{:<16} {:>10} {:>10} {:>12} {:>10} {:>5}",
+            stat.name, stat.acquires, stat.contended, stat.spins, stat.max_spins, stat.warnings
+        )?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cpu::testhooks;
+    use crate::mcslock::Lock;
+    use std::sync::Barrier;
+    use std::thread;
+    use std::time::Duration;
+
+    fn stat(name: &str) -> LockStat {
+        let (stats, num) = stats();
+        *stats[..num].iter().find(|stat| stat.name == name).unwrap()
+    }
+
+    #[test]
+    fn counts_acquisitions() {
+        let lock = Lock::new("lockstat-uncontended", 0);
+        drop(lock.lock());
+        drop(lock.try_lock().unwrap());
+        let expected = LockStat { name: "lockstat-uncontended", acquires: 2, ..Default::default() };
+        assert_eq!(stat("lockstat-uncontended"), expected);
+    }
+
+    #[test]
+    #[should_panic(expected = "lock lockstat-recursive: recursive acquisition")]
+    fn detects_recursion() {
+        testhooks::install();
+        let lock = Lock::new("lockstat-recursive", 0);
+        let _guard = lock.lock();
+        let _guard2 = lock.lock();
+    }
+
+    #[test]
+    fn counts_contention() {
+        testhooks::install();
+        let lock = Lock::new("lockstat-contended", 0);
+        let barrier = Barrier::new(2);
+        thread::scope(|s| {
+            let guard = lock.lock();
+            s.spawn(|| {
+                barrier.wait();
+                *lock.lock() += 1;
+            });
+            barrier.wait();
+            thread::sleep(Duration::from_millis(50));
+            drop(guard);
+        });
+
+        let stat = stat("lockstat-contended");
+        assert_eq!(stat.acquires, 2);
+        assert_eq!(stat.contended, 1);
+        assert!(stat.spins > 0);
+        assert_eq!(stat.spins, stat.max_spins);
+
+        // The contended lock is listed before the uncontended ones
+        let mut out = String::new();
+        lockstat(&mut out, MAX_LOCKSTAT_NAMES).unwrap();
+        let mut lines = out.lines();
+        assert!(lines.next().unwrap().starts_with("lock "));
+        let line = lines.find(|line| line.contains("lockstat-")).unwrap();
+        assert!(line.starts_with("lockstat-contended "), "{out}");
+    }
+}
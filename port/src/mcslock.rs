@@ -6,13 +6,46 @@
 //! for Scalable Synchronization on Shared Memory Multiprocessors.
 //! ACM Transactions on Computer Systems 9, 1 (Feb. 1991), 21–65.
 //! DOI: https://doi.org/10.1145/103727.103729
+//!
+//! `Lock` leaves the interrupt state alone, so must not be taken by interrupt
+//! handlers.  Data shared with interrupt handlers should be protected by an
+//! `ILock`, which disables interrupts on the current CPU while it's held.
+//!
+//! Each waiter spins on its own `LockNode`.  `lock` takes a node from a
+//! pool kept for the current CPU, returning it when the guard is dropped,
+//! while `lock_with` uses a node provided by the caller.
+//!
+//! Each CPU counts the locks it holds or is waiting for, so that the
+//! scheduler doesn't preempt a process in the middle of a critical section,
+//! leaving other CPUs spinning until it runs again.  See `held_locks`.
+//!
+//! With the lockdebug feature, locks track their holders and keep statistics
+//! on contention.  See `lockstat`.
 
 use core::cell::UnsafeCell;
 use core::hint;
 use core::marker::{Send, Sized, Sync};
 use core::ops::{Deref, DerefMut};
 use core::ptr;
-use core::sync::atomic::{AtomicBool, AtomicPtr, Ordering};
+use core::sync::atomic::{AtomicBool, AtomicPtr, AtomicU64, AtomicUsize, Ordering};
+
+use crate::cpu::{self, IntrState, MAX_CPUS, NO_CPU};
+#[cfg(feature = "lockdebug")]
+use crate::lockstat::LockDebug;
+
+/// Returns the pc that the function this is inlined into will
+/// return to.
+#[cfg(feature = "lockdebug")]
+#[inline(always)]
+fn callerpc() -> usize {
+    core::intrinsics::return_address().addr()
+}
+
+#[cfg(not(feature = "lockdebug"))]
+#[inline(always)]
+fn callerpc() -> usize {
+    0
+}
 
 /// Represents a node in the lock structure.  Note, is cacheline
 /// aligned.
@@ -34,34 +67,200 @@ impl Default for LockNode {
     }
 }
 
+/// Number of lock nodes kept for each CPU.  This limits the number of locks
+/// taken with `lock` that a CPU can hold or wait for at once, including
+/// those taken by interrupt handlers.
+pub const NODES_PER_CPU: usize = 8;
+
+/// Number of lock nodes shared by CPUs without an id, such as before the
+/// architecture has registered its CPU hooks.
+const SHARED_NODES: usize = 64;
+
+/// A fixed set of lock nodes, and a bitmap of those in use.
+struct NodePool<const N: usize> {
+    nodes: [LockNode; N],
+    used: AtomicU64,
+}
+
+impl<const N: usize> NodePool<N> {
+    const fn new() -> Self {
+        assert!(N <= u64::BITS as usize);
+        Self { nodes: [const { LockNode::new() }; N], used: AtomicU64::new(0) }
+    }
+
+    /// Take a free node from the pool.
+    fn take(&'static self) -> Option<PoolNode> {
+        let mut used = self.used.load(Ordering::Relaxed);
+        loop {
+            let i = used.trailing_ones() as usize;
+            if i >= N {
+                return None;
+            }
+            let bit = 1 << i;
+            match self.used.compare_exchange_weak(
+                used,
+                used | bit,
+                Ordering::Acquire,
+                Ordering::Relaxed,
+            ) {
+                Ok(_) => return Some(PoolNode { node: &self.nodes[i], used: &self.used, bit }),
+                Err(current) => used = current,
+            }
+        }
+    }
+}
+
+static CPU_NODES: [NodePool<NODES_PER_CPU>; MAX_CPUS] = [const { NodePool::new() }; MAX_CPUS];
+static SHARED_NODE_POOL: NodePool<SHARED_NODES> = NodePool::new();
+
+/// A node taken from one of the pools, which is given back when dropped.
+struct PoolNode {
+    node: &'static LockNode,
+    used: &'static AtomicU64,
+    bit: u64,
+}
+
+impl PoolNode {
+    /// Take a node from the current CPU's pool.  Nodes are taken and given
+    /// back atomically, so interrupt handlers can take them too, and the
+    /// holder may give a node back from a different CPU.
+    fn take(name: &'static str) -> PoolNode {
+        let cpu = cpu::cpu_id();
+        if cpu < MAX_CPUS {
+            return CPU_NODES[cpu].take().unwrap_or_else(|| {
+                panic!("lock {name}: cpu {cpu} is using all {NODES_PER_CPU} lock nodes")
+            });
+        }
+
+        // CPUs without an id share a pool, so wait if another is using all
+        // of it.
+        loop {
+            if let Some(node) = SHARED_NODE_POOL.take() {
+                return node;
+            }
+            hint::spin_loop();
+        }
+    }
+}
+
+impl Drop for PoolNode {
+    fn drop(&mut self) {
+        self.used.fetch_and(!self.bit, Ordering::Release);
+    }
+}
+
+/// Number of locks each CPU holds or is waiting for.
+static NLOCKS: [AtomicUsize; MAX_CPUS] = [const { AtomicUsize::new(0) }; MAX_CPUS];
+
+/// The current CPU's count of locks, if it keeps one.  Interrupts must be
+/// disabled.  A CPU without an id is assumed to be the only one, as it is by
+/// the scheduler; CPUs with higher ids don't run processes, so don't count.
+fn nlocks() -> Option<&'static AtomicUsize> {
+    match cpu::cpu_id() {
+        NO_CPU => Some(&NLOCKS[0]),
+        id => NLOCKS.get(id),
+    }
+}
+
+fn count_lock() {
+    cpu::without_interrupts(|| nlocks().map(|n| n.fetch_add(1, Ordering::Relaxed)));
+}
+
+fn count_unlock() {
+    cpu::without_interrupts(|| nlocks().map(|n| n.fetch_sub(1, Ordering::Relaxed)));
+}
+
+/// Number of locks the current CPU holds or is waiting for.  Interrupts must
+/// be disabled.  The count only ever changes on the CPU itself, and a process
+/// isn't preempted while it's non-zero, so it stays with the process.
+pub fn held_locks() -> usize {
+    nlocks().map_or(0, |n| n.load(Ordering::Relaxed))
+}
+
 /// An MCS lock.
 pub struct MCSLock {
-    _name: &'static str,
+    #[cfg_attr(not(feature = "lockdebug"), allow(dead_code))]
+    name: &'static str,
     queue: AtomicPtr<LockNode>,
+    #[cfg(feature = "lockdebug")]
+    debug: LockDebug,
 }
 
 impl MCSLock {
     pub const fn new(name: &'static str) -> MCSLock {
-        MCSLock { _name: name, queue: AtomicPtr::new(ptr::null_mut()) }
+        MCSLock {
+            name,
+            queue: AtomicPtr::new(ptr::null_mut()),
+            #[cfg(feature = "lockdebug")]
+            debug: LockDebug::new(),
+        }
     }
 
     pub fn lock<'a>(&self, node: &'a LockNode) -> &'a LockNode {
+        self.lock_from(node, callerpc())
+    }
+
+    /// Take the lock on behalf of the caller at pc.
+    #[cfg_attr(not(feature = "lockdebug"), allow(unused_variables))]
+    fn lock_from<'a>(&self, node: &'a LockNode, pc: usize) -> &'a LockNode {
+        #[cfg(feature = "lockdebug")]
+        self.debug.check_recursion(self.name, pc);
+
+        count_lock();
         node.next.store(ptr::null_mut(), Ordering::Release);
         node.locked.store(false, Ordering::Release);
         let p = node as *const _ as *mut _;
         let predecessor = self.queue.swap(p, Ordering::AcqRel);
+        #[cfg(feature = "lockdebug")]
+        let mut spins = 0;
         if !predecessor.is_null() {
             let predecessor = unsafe { &*predecessor };
             node.locked.store(true, Ordering::Release);
             predecessor.next.store(p, Ordering::Release);
             while node.locked.load(Ordering::Acquire) {
+                #[cfg(feature = "lockdebug")]
+                {
+                    self.debug.spinning(self.name, spins);
+                    spins += 1;
+                }
                 hint::spin_loop();
             }
         }
+
+        #[cfg(feature = "lockdebug")]
+        self.debug.acquired(self.name, pc, spins);
         node
     }
 
+    /// Take the lock only if it's free, without waiting.
+    pub fn try_lock<'a>(&self, node: &'a LockNode) -> Option<&'a LockNode> {
+        self.try_lock_from(node, callerpc())
+    }
+
+    #[cfg_attr(not(feature = "lockdebug"), allow(unused_variables))]
+    fn try_lock_from<'a>(&self, node: &'a LockNode, pc: usize) -> Option<&'a LockNode> {
+        node.next.store(ptr::null_mut(), Ordering::Release);
+        node.locked.store(false, Ordering::Release);
+        let p = node as *const _ as *mut _;
+        count_lock();
+        if self
+            .queue
+            .compare_exchange(ptr::null_mut(), p, Ordering::AcqRel, Ordering::Relaxed)
+            .is_err()
+        {
+            count_unlock();
+            return None;
+        }
+
+        #[cfg(feature = "lockdebug")]
+        self.debug.acquired(self.name, pc, 0);
+        Some(node)
+    }
+
     pub fn unlock(&self, node: &LockNode) {
+        #[cfg(feature = "lockdebug")]
+        self.debug.releasing();
+
         if node.next.load(Ordering::Acquire).is_null() {
             let p = node as *const _ as *mut _;
             if self
@@ -69,6 +268,7 @@ impl MCSLock {
                 .compare_exchange_weak(p, ptr::null_mut(), Ordering::AcqRel, Ordering::Relaxed)
                 .is_ok()
             {
+                count_unlock();
                 return;
             }
             while node.next.load(Ordering::Acquire).is_null() {
@@ -78,6 +278,7 @@ impl MCSLock {
         let next = node.next.load(Ordering::Acquire);
         let next = unsafe { &*next };
         next.locked.store(false, Ordering::Release);
+        count_unlock();
     }
 }
 
@@ -93,10 +294,113 @@ impl<T> Lock<T> {
     pub const fn new(name: &'static str, data: T) -> Lock<T> {
         Lock { lock: UnsafeCell::new(MCSLock::new(name)), data: UnsafeCell::new(data) }
     }
+}
+
+impl<T: ?Sized> Lock<T> {
+    /// Take the lock, using a lock node from the current CPU's pool.
+    pub fn lock(&self) -> LockGuard<'_, T> {
+        let node = PoolNode::take(self.name());
+        let mut guard = self.lock_from(node.node, callerpc());
+        guard.pooled = Some(node);
+        guard
+    }
+
+    /// Take the lock only if it's free, without waiting.
+    pub fn try_lock(&self) -> Option<LockGuard<'_, T>> {
+        let node = PoolNode::take(self.name());
+        let mut guard = self.try_lock_from(node.node, callerpc())?;
+        guard.pooled = Some(node);
+        Some(guard)
+    }
+
+    /// Take the lock, using a lock node provided by the caller.
+    pub fn lock_with<'a>(&'a self, node: &'a LockNode) -> LockGuard<'a, T> {
+        self.lock_from(node, callerpc())
+    }
+
+    /// Take the lock only if it's free, without waiting, using a lock node
+    /// provided by the caller.
+    pub fn try_lock_with<'a>(&'a self, node: &'a LockNode) -> Option<LockGuard<'a, T>> {
+        self.try_lock_from(node, callerpc())
+    }
+
+    fn name(&self) -> &'static str {
+        unsafe { &*self.lock.get() }.name
+    }
+
+    fn lock_from<'a>(&'a self, node: &'a LockNode, pc: usize) -> LockGuard<'a, T> {
+        let node = unsafe { &mut *self.lock.get() }.lock_from(node, pc);
+        LockGuard {
+            lock: &self.lock,
+            node,
+            data: unsafe { &mut *self.data.get() },
+            intr: None,
+            pooled: None,
+        }
+    }
 
-    pub fn lock<'a>(&'a self, node: &'a LockNode) -> LockGuard<'a, T> {
-        let node = unsafe { &mut *self.lock.get() }.lock(node);
-        LockGuard { lock: &self.lock, node, data: unsafe { &mut *self.data.get() } }
+    fn try_lock_from<'a>(&'a self, node: &'a LockNode, pc: usize) -> Option<LockGuard<'a, T>> {
+        let node = unsafe { &mut *self.lock.get() }.try_lock_from(node, pc)?;
+        Some(LockGuard {
+            lock: &self.lock,
+            node,
+            data: unsafe { &mut *self.data.get() },
+            intr: None,
+            pooled: None,
+        })
+    }
+}
+
+/// A lock that disables interrupts on the current CPU while it's held, and
+/// restores the previous state once it's released.  This stops an interrupt
+/// handler deadlocking by trying to take the lock while the code it
+/// interrupted holds it.
+pub struct ILock<T: ?Sized> {
+    lock: Lock<T>,
+}
+
+impl<T> ILock<T> {
+    pub const fn new(name: &'static str, data: T) -> ILock<T> {
+        ILock { lock: Lock::new(name, data) }
+    }
+}
+
+impl<T: ?Sized> ILock<T> {
+    /// Take the lock, using a lock node from the current CPU's pool.
+    pub fn lock(&self) -> LockGuard<'_, T> {
+        let intr = cpu::splhi();
+        let node = PoolNode::take(self.lock.name());
+        let mut guard = self.lock.lock_from(node.node, callerpc());
+        guard.pooled = Some(node);
+        guard.intr = Some(intr);
+        guard
+    }
+
+    /// Take the lock only if it's free, without waiting.  Interrupts are
+    /// left as they were if the lock isn't taken.
+    pub fn try_lock(&self) -> Option<LockGuard<'_, T>> {
+        let intr = cpu::splhi();
+        let node = PoolNode::take(self.lock.name());
+        match self.lock.try_lock_from(node.node, callerpc()) {
+            Some(mut guard) => {
+                guard.pooled = Some(node);
+                guard.intr = Some(intr);
+                Some(guard)
+            }
+            None => {
+                drop(node);
+                cpu::splx(intr);
+                None
+            }
+        }
+    }
+
+    /// Take the lock, using a lock node provided by the caller.
+    pub fn lock_with<'a>(&'a self, node: &'a LockNode) -> LockGuard<'a, T> {
+        let intr = cpu::splhi();
+        let mut guard = self.lock.lock_from(node, callerpc());
+        guard.intr = Some(intr);
+        guard
     }
 }
 
@@ -104,8 +408,11 @@ pub struct LockGuard<'a, T: ?Sized + 'a> {
     lock: &'a UnsafeCell<MCSLock>,
     node: &'a LockNode,
     data: &'a mut T,
+    intr: Option<IntrState>,  // Interrupt state to restore after unlocking
+    pooled: Option<PoolNode>, // Pool node, given back after unlocking
 }
-impl<T> Deref for LockGuard<'_, T> {
+
+impl<T: ?Sized> Deref for LockGuard<'_, T> {
     type Target = T;
 
     fn deref(&self) -> &T {
@@ -113,7 +420,7 @@ impl<T> Deref for LockGuard<'_, T> {
     }
 }
 
-impl<T> DerefMut for LockGuard<'_, T> {
+impl<T: ?Sized> DerefMut for LockGuard<'_, T> {
     fn deref_mut(&mut self) -> &mut T {
         self.data
     }
@@ -122,5 +429,154 @@ impl<T> DerefMut for LockGuard<'_, T> {
 impl<T: ?Sized> Drop for LockGuard<'_, T> {
     fn drop(&mut self) {
         unsafe { &mut *self.lock.get() }.unlock(self.node);
+        drop(self.pooled.take());
+        if let Some(intr) = self.intr.take() {
+            cpu::splx(intr);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cpu::testhooks;
+    use std::sync::atomic::AtomicUsize;
+    use std::thread;
+
+    #[test]
+    fn try_lock() {
+        let lock = Lock::new("test", 0);
+        let mut guard = lock.try_lock().unwrap();
+        *guard += 1;
+
+        let node = LockNode::new();
+        assert!(lock.try_lock_with(&node).is_none());
+        assert!(lock.try_lock().is_none());
+        drop(guard);
+
+        assert_eq!(*lock.try_lock_with(&node).unwrap(), 1);
+    }
+
+    #[test]
+    fn unsized_data() {
+        let lock = Lock::new("test", [1u8, 2, 3]);
+        let lock: &Lock<[u8]> = &lock;
+        lock.lock()[1] = 5;
+        assert_eq!(*lock.lock(), [1, 5, 3]);
+
+        let lock: &Lock<dyn Fn() -> u32> = &Lock::new("test", || 7);
+        assert_eq!((lock.lock())(), 7);
+    }
+
+    #[test]
+    fn pooled_nodes_nest() {
+        testhooks::install();
+        let locks = [const { Lock::new("test", 0) }; NODES_PER_CPU];
+
+        // All the nodes can be used at once, and given back in any order
+        let mut guards = locks.iter().map(|lock| Some(lock.lock())).collect::<Vec<_>>();
+        for guard in guards.iter_mut().step_by(2) {
+            *guard = None;
+        }
+        for (lock, guard) in locks.iter().zip(&mut guards) {
+            if guard.is_none() {
+                *guard = lock.try_lock();
+            }
+        }
+        assert!(guards.iter().all(Option::is_some));
+    }
+
+    #[test]
+    fn counts_held_locks() {
+        let lock = Lock::new("test", 0);
+        let other = ILock::new("test", 0);
+        // Threads that ran without hooks share the first CPU's count, and
+        // higher ids don't count, so only check on a CPU of our own
+        testhooks::install();
+        if !(1..MAX_CPUS).contains(&cpu::cpu_id()) {
+            return;
+        }
+
+        let guard = lock.lock();
+        assert_eq!(held_locks(), 1);
+        assert!(lock.try_lock().is_none());
+        let iguard = other.lock();
+        assert_eq!(held_locks(), 2);
+        drop(guard);
+        assert_eq!(held_locks(), 1);
+        drop(iguard);
+        assert_eq!(held_locks(), 0);
+    }
+
+    #[test]
+    fn node_pool_runs_out() {
+        static POOL: NodePool<2> = NodePool::new();
+        let a = POOL.take().unwrap();
+        let b = POOL.take().unwrap();
+        assert!(POOL.take().is_none());
+        drop(a);
+        let c = POOL.take().unwrap();
+        assert!(ptr::eq(c.node, &POOL.nodes[0]));
+        drop((b, c));
+        assert_eq!(POOL.used.load(Ordering::Relaxed), 0);
+    }
+
+    #[test]
+    fn pooled_nodes_contended() {
+        let lock = Lock::new("test", 0);
+        let count = AtomicUsize::new(0);
+        thread::scope(|s| {
+            for _ in 0..4 {
+                s.spawn(|| {
+                    for _ in 0..1000 {
+                        *lock.lock() += 1;
+                        count.fetch_add(1, Ordering::Relaxed);
+                    }
+                });
+            }
+        });
+        assert_eq!(*lock.lock(), count.load(Ordering::Relaxed));
+    }
+
+    #[test]
+    #[cfg(feature = "lockdebug")]
+    fn lockdebug_tracks_holder() {
+        testhooks::install();
+        let lock = MCSLock::new("test");
+        let node = LockNode::new();
+        lock.lock(&node);
+        let (cpu, pc) = lock.debug.holder().unwrap();
+        assert_eq!(cpu, cpu::cpu_id());
+        assert_ne!(pc, 0);
+        lock.unlock(&node);
+        assert!(lock.debug.holder().is_none());
+    }
+
+    #[test]
+    fn ilock_disables_interrupts() {
+        testhooks::install();
+        let lock = ILock::new("test", 0);
+
+        assert!(testhooks::interrupts_enabled());
+        {
+            let _guard = lock.lock();
+            assert!(!testhooks::interrupts_enabled());
+
+            // Interrupts stay disabled if try_lock fails
+            assert!(lock.try_lock().is_none());
+            assert!(!testhooks::interrupts_enabled());
+        }
+        assert!(testhooks::interrupts_enabled());
+
+        // Only the state before locking is restored
+        let intr = cpu::splhi();
+        drop(lock.try_lock().unwrap());
+        assert!(!testhooks::interrupts_enabled());
+        cpu::splx(intr);
+        assert!(testhooks::interrupts_enabled());
+
+        let node = LockNode::new();
+        drop(lock.lock_with(&node));
+        assert!(testhooks::interrupts_enabled());
     }
 }
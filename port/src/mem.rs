@@ -10,6 +10,7 @@ pub const PAGE_SIZE_4K: usize = 4 << 10;
 pub const PAGE_SIZE_2M: usize = 2 << 20;
 pub const PAGE_SIZE_1G: usize = 1 << 30;
 
+#[derive(Debug)]
 pub struct VirtRange(pub Range<usize>);
 
 impl VirtRange {
@@ -168,6 +169,14 @@ impl PhysRange {
     pub fn round(&self, step_size: usize) -> Self {
         Self(self.start().round_down2(step_size as u64)..self.end().round_up2(step_size as u64))
     }
+
+    /// Return the part of this range that also lies within other, or None if
+    /// the ranges don't overlap.
+    pub fn intersect(&self, other: &PhysRange) -> Option<Self> {
+        let start = max(self.0.start, other.0.start);
+        let end = min(self.0.end, other.0.end);
+        (start < end).then_some(Self(start..end))
+    }
 }
 
 impl fmt::Display for PhysRange {
@@ -204,6 +213,16 @@ mod tests {
         assert_eq!(pas, [PhysAddr::new(4096 * 2), PhysAddr::new(4096 * 3)]);
     }
 
+    #[test]
+    fn physrange_intersect() {
+        let range = PhysRange::with_end(0x1000, 0x5000);
+        let overlap = range.intersect(&PhysRange::with_end(0x4000, 0x8000)).unwrap();
+        assert_eq!(overlap.0, PhysAddr::new(0x4000)..PhysAddr::new(0x5000));
+        let overlap = range.intersect(&PhysRange::with_end(0x0, 0x2000)).unwrap();
+        assert_eq!(overlap.0, PhysAddr::new(0x1000)..PhysAddr::new(0x2000));
+        assert!(range.intersect(&PhysRange::with_end(0x5000, 0x6000)).is_none());
+    }
+
     #[test]
     fn physaddr_step_2m() {
         let range =
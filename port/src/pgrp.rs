@@ -0,0 +1,400 @@
+//! pgrp holds the resources processes can share with each other, after Plan
+//! 9's pgrp.c: the namespace, environment, file descriptors, note group,
+//! rendezvous group and user memory.  Shared resources are reference
+//! counted, and `rfork`'s flags say which a process shares with its parent,
+//! which it gets a copy of, and which it starts afresh.
+
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use bitflags::bitflags;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::dat::Chan;
+use crate::exec::Perm;
+use crate::qlock::QLock;
+
+bitflags! {
+    /// Flags to rfork, with Plan 9's values.
+    #[derive(Clone, Copy, Debug, PartialEq)]
+    pub struct RforkFlags: u32 {
+        const RFNAMEG = 1 << 0;   // Copy the namespace
+        const RFENVG = 1 << 1;    // Copy the environment
+        const RFFDG = 1 << 2;     // Copy the file descriptor table
+        const RFNOTEG = 1 << 3;   // Start a new note group
+        const RFPROC = 1 << 4;    // Create a new process
+        const RFMEM = 1 << 5;     // Share memory with the child
+        const RFNOWAIT = 1 << 6;  // Child leaves no wait record for the parent
+        const RFCNAMEG = 1 << 10; // Start with a clean namespace
+        const RFCENVG = 1 << 11;  // Start with an empty environment
+        const RFCFDG = 1 << 12;   // Start with no file descriptors
+        const RFREND = 1 << 13;   // Start a new rendezvous group
+    }
+}
+
+bitflags! {
+    /// How a bind or mount combines with what's already at the mount point.
+    #[derive(Clone, Copy, Debug, PartialEq)]
+    pub struct MountFlags: u32 {
+        const MREPL = 0;
+        const MBEFORE = 1;
+        const MAFTER = 2;
+        const MCREATE = 4;
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Mount {
+    pub new: String,
+    pub old: String,
+    pub flags: MountFlags,
+}
+
+/// A namespace: the mounts and binds that make up a process's view of the
+/// file tree.
+pub struct Pgrp {
+    mounts: QLock<Vec<Mount>>,
+}
+
+impl Pgrp {
+    pub fn new() -> Pgrp {
+        Pgrp { mounts: QLock::new("pgrp", Vec::new()) }
+    }
+
+    fn copy(&self) -> Pgrp {
+        Pgrp { mounts: QLock::new("pgrp", self.mounts.lock().clone()) }
+    }
+
+    /// Make new visible at old.
+    pub fn bind(&self, new: &str, old: &str, flags: MountFlags) {
+        let mut mounts = self.mounts.lock();
+        if !flags.intersects(MountFlags::MBEFORE | MountFlags::MAFTER) {
+            mounts.retain(|m| m.old != old);
+        }
+        mounts.push(Mount { new: new.into(), old: old.into(), flags });
+    }
+
+    pub fn mounts(&self) -> Vec<Mount> {
+        self.mounts.lock().clone()
+    }
+}
+
+impl Default for Pgrp {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// An environment: named values, as in Plan 9's /env.
+pub struct Egrp {
+    vars: QLock<BTreeMap<String, Vec<u8>>>,
+}
+
+impl Egrp {
+    pub fn new() -> Egrp {
+        Egrp { vars: QLock::new("egrp", BTreeMap::new()) }
+    }
+
+    fn copy(&self) -> Egrp {
+        Egrp { vars: QLock::new("egrp", self.vars.lock().clone()) }
+    }
+
+    pub fn get(&self, name: &str) -> Option<Vec<u8>> {
+        self.vars.lock().get(name).cloned()
+    }
+
+    pub fn set(&self, name: &str, value: &[u8]) {
+        self.vars.lock().insert(name.into(), value.into());
+    }
+
+    pub fn remove(&self, name: &str) -> Option<Vec<u8>> {
+        self.vars.lock().remove(name)
+    }
+}
+
+impl Default for Egrp {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// A file descriptor table.
+pub struct Fgrp {
+    fds: QLock<Vec<Option<Arc<Chan>>>>,
+}
+
+impl Fgrp {
+    pub fn new() -> Fgrp {
+        Fgrp { fds: QLock::new("fgrp", Vec::new()) }
+    }
+
+    /// A copy of the table, whose descriptors refer to the same channels.
+    fn copy(&self) -> Fgrp {
+        Fgrp { fds: QLock::new("fgrp", self.fds.lock().clone()) }
+    }
+
+    /// Install c at the lowest free descriptor, returning it.
+    pub fn newfd(&self, c: Arc<Chan>) -> usize {
+        let mut fds = self.fds.lock();
+        let fd = fds.iter().position(Option::is_none).unwrap_or(fds.len());
+        if fd == fds.len() {
+            fds.push(None);
+        }
+        fds[fd] = Some(c);
+        fd
+    }
+
+    pub fn get(&self, fd: usize) -> Option<Arc<Chan>> {
+        self.fds.lock().get(fd).cloned().flatten()
+    }
+
+    pub fn close(&self, fd: usize) -> Option<Arc<Chan>> {
+        self.fds.lock().get_mut(fd).and_then(Option::take)
+    }
+}
+
+impl Default for Fgrp {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// A rendezvous group.  Processes only rendezvous with others in the same
+/// group, so a group is just its identity.
+#[derive(Default)]
+pub struct Rgrp {}
+
+impl Rgrp {
+    pub fn new() -> Rgrp {
+        Rgrp {}
+    }
+}
+
+/// A process's user address space, implemented by the architecture.
+pub trait UserMem: Send + Sync {
+    /// Make this the current user address space.  Called with interrupts
+    /// disabled, by the scheduler before it switches to a process using it.
+    fn switch_to(&self);
+
+    /// Check that the range lies within the address space's segments,
+    /// allowing perm.
+    fn validaddr(&self, va: usize, len: usize, perm: Perm) -> crate::Result<()>;
+
+    /// A copy of the address space, for a child that doesn't share memory
+    /// with its parent.
+    fn fork(&self) -> crate::Result<Arc<dyn UserMem>>;
+
+    /// An address space for a child that shares memory with its parent.  It
+    /// shares everything but the stack, which it gets its own copy of, so
+    /// that parent and child can both return from rfork.
+    fn fork_shared(self: Arc<Self>) -> crate::Result<Arc<dyn UserMem>>;
+}
+
+static NEXT_NOTEID: AtomicUsize = AtomicUsize::new(1);
+
+fn newnoteid() -> usize {
+    NEXT_NOTEID.fetch_add(1, Ordering::Relaxed)
+}
+
+/// A process's shareable resources.  Only dead processes have none.
+#[derive(Clone)]
+pub struct Groups {
+    pub pgrp: Option<Arc<Pgrp>>,
+    pub egrp: Option<Arc<Egrp>>,
+    pub fgrp: Option<Arc<Fgrp>>,
+    pub rgrp: Option<Arc<Rgrp>>,
+    pub noteid: usize,
+    pub mem: Option<Arc<dyn UserMem>>, // None for kernel processes
+}
+
+/// Share the group, copy it, or start a fresh one.
+fn fork_group<T: Default>(
+    group: &Option<Arc<T>>,
+    copy: bool,
+    clean: bool,
+    copy_fn: fn(&T) -> T,
+) -> Option<Arc<T>> {
+    if clean {
+        Some(Arc::new(T::default()))
+    } else if copy {
+        group.as_deref().map(|g| Arc::new(copy_fn(g)))
+    } else {
+        group.clone()
+    }
+}
+
+impl Groups {
+    pub const NONE: Groups =
+        Groups { pgrp: None, egrp: None, fgrp: None, rgrp: None, noteid: 0, mem: None };
+
+    /// Fresh, empty groups, without user memory.
+    pub fn new() -> Groups {
+        Groups {
+            pgrp: Some(Arc::new(Pgrp::new())),
+            egrp: Some(Arc::new(Egrp::new())),
+            fgrp: Some(Arc::new(Fgrp::new())),
+            rgrp: Some(Arc::new(Rgrp::new())),
+            noteid: newnoteid(),
+            mem: None,
+        }
+    }
+
+    /// The groups that result from rfork with flags: those of a new child
+    /// with RFPROC, otherwise the new groups of the calling process.
+    pub fn fork(&self, flags: RforkFlags) -> crate::Result<Groups> {
+        use RforkFlags as F;
+        let both = |a: F, b: F| flags.contains(a | b);
+        if both(F::RFNAMEG, F::RFCNAMEG) || both(F::RFENVG, F::RFCENVG) || both(F::RFFDG, F::RFCFDG)
+        {
+            return Err("rfork: can't both copy and clean a group");
+        }
+        if flags.intersects(F::RFMEM | F::RFNOWAIT) && !flags.contains(F::RFPROC) {
+            return Err("rfork: RFMEM and RFNOWAIT need RFPROC");
+        }
+
+        let mem = match &self.mem {
+            Some(mem) if flags.contains(F::RFPROC | F::RFMEM) => Some(mem.clone().fork_shared()?),
+            Some(mem) if flags.contains(F::RFPROC) => Some(mem.fork()?),
+            mem => mem.clone(),
+        };
+        Ok(Groups {
+            pgrp: fork_group(
+                &self.pgrp,
+                flags.contains(F::RFNAMEG),
+                flags.contains(F::RFCNAMEG),
+                Pgrp::copy,
+            ),
+            egrp: fork_group(
+                &self.egrp,
+                flags.contains(F::RFENVG),
+                flags.contains(F::RFCENVG),
+                Egrp::copy,
+            ),
+            fgrp: fork_group(
+                &self.fgrp,
+                flags.contains(F::RFFDG),
+                flags.contains(F::RFCFDG),
+                Fgrp::copy,
+            ),
+            rgrp: fork_group(&self.rgrp, false, flags.contains(F::RFREND), |_| Rgrp::new()),
+            noteid: if flags.contains(F::RFNOTEG) { newnoteid() } else { self.noteid },
+            mem,
+        })
+    }
+}
+
+impl Default for Groups {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use RforkFlags as F;
+
+    fn same<T>(a: &Option<Arc<T>>, b: &Option<Arc<T>>) -> bool {
+        Arc::ptr_eq(a.as_ref().unwrap(), b.as_ref().unwrap())
+    }
+
+    #[test]
+    fn shares_by_default() {
+        let parent = Groups::new();
+        let child = parent.fork(F::RFPROC).unwrap();
+        assert!(same(&parent.pgrp, &child.pgrp));
+        assert!(same(&parent.egrp, &child.egrp));
+        assert!(same(&parent.fgrp, &child.fgrp));
+        assert!(same(&parent.rgrp, &child.rgrp));
+        assert_eq!(parent.noteid, child.noteid);
+
+        child.egrp.as_ref().unwrap().set("user", b"glenda");
+        assert_eq!(parent.egrp.as_ref().unwrap().get("user"), Some(b"glenda".to_vec()));
+    }
+
+    #[test]
+    fn copies_and_cleans() {
+        let parent = Groups::new();
+        let (egrp, pgrp) = (parent.egrp.as_ref().unwrap(), parent.pgrp.as_ref().unwrap());
+        egrp.set("user", b"glenda");
+        pgrp.bind("#c", "/dev", MountFlags::MAFTER);
+
+        let child =
+            parent.fork(F::RFPROC | F::RFENVG | F::RFCNAMEG | F::RFNOTEG | F::RFREND).unwrap();
+        assert!(!same(&parent.egrp, &child.egrp));
+        assert!(!same(&parent.rgrp, &child.rgrp));
+        assert!(same(&parent.fgrp, &child.fgrp));
+        assert_ne!(parent.noteid, child.noteid);
+
+        // The copy starts out the same, but changes separately
+        let child_egrp = child.egrp.as_ref().unwrap();
+        assert_eq!(child_egrp.get("user"), Some(b"glenda".to_vec()));
+        child_egrp.set("user", b"none");
+        assert_eq!(egrp.get("user"), Some(b"glenda".to_vec()));
+
+        // The clean namespace is empty
+        assert_eq!(child.pgrp.as_ref().unwrap().mounts(), []);
+        assert_eq!(pgrp.mounts().len(), 1);
+
+        let fdless = parent.fork(F::RFCFDG | F::RFCENVG).unwrap();
+        assert!(!same(&parent.fgrp, &fdless.fgrp));
+        assert_eq!(fdless.egrp.as_ref().unwrap().get("user"), None);
+    }
+
+    #[test]
+    fn memory_is_shared_or_copied() {
+        /// Memory that counts the copies made of it, and those that share it.
+        #[derive(Default)]
+        struct Mem {
+            copies: AtomicUsize,
+            sharers: AtomicUsize,
+        }
+
+        impl UserMem for Mem {
+            fn switch_to(&self) {}
+
+            fn validaddr(&self, _va: usize, _len: usize, _perm: Perm) -> crate::Result<()> {
+                Ok(())
+            }
+
+            fn fork(&self) -> crate::Result<Arc<dyn UserMem>> {
+                self.copies.fetch_add(1, Ordering::Relaxed);
+                Ok(Arc::new(Mem::default()))
+            }
+
+            fn fork_shared(self: Arc<Self>) -> crate::Result<Arc<dyn UserMem>> {
+                self.sharers.fetch_add(1, Ordering::Relaxed);
+                Ok(Arc::new(Mem::default()))
+            }
+        }
+
+        // The child gets its own stack, so its own address space, even when
+        // sharing memory
+        let mem = Arc::new(Mem::default());
+        let parent = Groups { mem: Some(mem.clone()), ..Groups::new() };
+        let shared = parent.fork(F::RFPROC | F::RFMEM).unwrap();
+        assert!(!Arc::ptr_eq(shared.mem.as_ref().unwrap(), parent.mem.as_ref().unwrap()));
+        assert_eq!(mem.sharers.load(Ordering::Relaxed), 1);
+        assert_eq!(mem.copies.load(Ordering::Relaxed), 0);
+
+        let copied = parent.fork(F::RFPROC).unwrap();
+        assert!(!Arc::ptr_eq(copied.mem.as_ref().unwrap(), parent.mem.as_ref().unwrap()));
+        assert_eq!(mem.copies.load(Ordering::Relaxed), 1);
+        assert_eq!(mem.sharers.load(Ordering::Relaxed), 1);
+
+        // Without RFPROC, the caller keeps its memory
+        let same_proc = parent.fork(F::RFNAMEG).unwrap();
+        assert!(Arc::ptr_eq(same_proc.mem.as_ref().unwrap(), parent.mem.as_ref().unwrap()));
+    }
+
+    #[test]
+    fn rejects_bad_flags() {
+        let parent = Groups::new();
+        assert!(parent.fork(F::RFPROC | F::RFNAMEG | F::RFCNAMEG).is_err());
+        assert!(parent.fork(F::RFENVG | F::RFCENVG).is_err());
+        assert!(parent.fork(F::RFFDG | F::RFCFDG).is_err());
+        assert!(parent.fork(F::RFMEM).is_err());
+        assert!(parent.fork(F::RFNOWAIT).is_err());
+    }
+}
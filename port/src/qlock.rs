@@ -0,0 +1,446 @@
+//! Sleeping locks, after Plan 9's QLock and RWlock.  Processes that can't
+//! take the lock straight away queue up and block, rather than spinning, so
+//! these are suitable for locks held over long operations such as I/O.
+//! They must not be taken by interrupt handlers.
+//!
+//! Waiters are served in FIFO order.  Each waiter takes a ticket when it
+//! queues, and the lock is handed over directly to the waiter at the front
+//! of the queue, so a process that has just released the lock can't barge
+//! in ahead of the waiters.
+
+use core::cell::UnsafeCell;
+use core::ops::{Deref, DerefMut};
+use core::ptr;
+
+use crate::mcslock::ILock;
+use crate::sched;
+
+/// A process waiting for a lock.  Each waiter lives on its own stack for as
+/// long as it's queued, like Plan 9's queueing through Proc.qnext, so
+/// queueing never allocates.
+struct Waiter<K> {
+    ticket: u64,
+    id: usize, // Process id, to ready it
+    kind: K,
+    next: *mut Waiter<K>,
+}
+
+impl<K> Waiter<K> {
+    const fn new(kind: K) -> Self {
+        Self { ticket: 0, id: 0, kind, next: ptr::null_mut() }
+    }
+}
+
+/// A queue of processes waiting for a lock.  Only touched with the lock's
+/// state locked.
+struct WaitQueue<K> {
+    head: *mut Waiter<K>,
+    tail: *mut Waiter<K>,
+    next_ticket: u64,
+    granted: u64, // All tickets below this have been granted the lock
+}
+
+impl<K: Copy> WaitQueue<K> {
+    const fn new() -> Self {
+        Self { head: ptr::null_mut(), tail: ptr::null_mut(), next_ticket: 0, granted: 0 }
+    }
+
+    /// Add the current process to the back of the queue, returning its
+    /// ticket.  The waiter must stay where it is until the ticket has been
+    /// granted, which removes it from the queue.
+    fn enqueue(&mut self, waiter: &mut Waiter<K>) -> u64 {
+        waiter.ticket = self.next_ticket;
+        waiter.id = sched::current();
+        waiter.next = ptr::null_mut();
+        self.next_ticket += 1;
+        let waiter = ptr::from_mut(waiter);
+        match unsafe { self.tail.as_mut() } {
+            Some(tail) => tail.next = waiter,
+            None => self.head = waiter,
+        }
+        self.tail = waiter;
+        unsafe { (*waiter).ticket }
+    }
+
+    fn front(&self) -> Option<K> {
+        unsafe { self.head.as_ref() }.map(|w| w.kind)
+    }
+
+    /// Hand the lock to the waiter at the front of the queue, and ready it.
+    fn grant_front(&mut self) {
+        let Some(waiter) = (unsafe { self.head.as_ref() }) else {
+            return;
+        };
+        // The waiter may return as soon as it's granted, so take what we
+        // need from it first.
+        let (ticket, id) = (waiter.ticket, waiter.id);
+        self.head = waiter.next;
+        if self.head.is_null() {
+            self.tail = ptr::null_mut();
+        }
+        self.granted = ticket + 1;
+        sched::ready(id);
+    }
+
+    fn is_granted(&self, ticket: u64) -> bool {
+        ticket < self.granted
+    }
+
+    #[cfg(test)]
+    fn len(&self) -> usize {
+        let mut n = 0;
+        let mut w = self.head;
+        while let Some(waiter) = unsafe { w.as_ref() } {
+            n += 1;
+            w = waiter.next;
+        }
+        n
+    }
+}
+
+/// Block until the ticket has been granted.
+fn wait<S, K: Copy>(state: &ILock<S>, ticket: u64, queue: fn(&S) -> &WaitQueue<K>) {
+    while !queue(&state.lock()).is_granted(ticket) {
+        sched::block();
+    }
+}
+
+struct QState {
+    locked: bool,
+    queue: WaitQueue<()>,
+}
+
+/// A queueing mutual exclusion lock.
+pub struct QLock<T: ?Sized> {
+    state: ILock<QState>,
+    data: UnsafeCell<T>,
+}
+
+unsafe impl<T: ?Sized> Send for QLock<T> {}
+unsafe impl<T: ?Sized> Sync for QLock<T> {}
+
+impl<T> QLock<T> {
+    pub const fn new(name: &'static str, data: T) -> QLock<T> {
+        QLock {
+            state: ILock::new(name, QState { locked: false, queue: WaitQueue::new() }),
+            data: UnsafeCell::new(data),
+        }
+    }
+}
+
+impl<T: ?Sized> QLock<T> {
+    /// Take the lock, sleeping until it's free.
+    pub fn lock(&self) -> QLockGuard<'_, T> {
+        let mut waiter = Waiter::new(());
+        let ticket = {
+            let mut state = self.state.lock();
+            if !state.locked {
+                state.locked = true;
+                return QLockGuard { lock: self };
+            }
+            state.queue.enqueue(&mut waiter)
+        };
+        wait(&self.state, ticket, |state| &state.queue);
+        QLockGuard { lock: self }
+    }
+
+    /// Take the lock only if it's free, without sleeping.
+    pub fn try_lock(&self) -> Option<QLockGuard<'_, T>> {
+        let mut state = self.state.lock();
+        if state.locked {
+            return None;
+        }
+        state.locked = true;
+        Some(QLockGuard { lock: self })
+    }
+
+    fn unlock(&self) {
+        let mut state = self.state.lock();
+        if state.queue.front().is_some() {
+            // Stays locked, now on behalf of the next waiter
+            state.queue.grant_front();
+        } else {
+            state.locked = false;
+        }
+    }
+}
+
+pub struct QLockGuard<'a, T: ?Sized> {
+    lock: &'a QLock<T>,
+}
+
+impl<T: ?Sized> Deref for QLockGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.data.get() }
+    }
+}
+
+impl<T: ?Sized> DerefMut for QLockGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.lock.data.get() }
+    }
+}
+
+impl<T: ?Sized> Drop for QLockGuard<'_, T> {
+    fn drop(&mut self) {
+        self.lock.unlock();
+    }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Access {
+    Read,
+    Write,
+}
+
+struct RWState {
+    readers: usize, // Number of readers holding the lock
+    writer: bool,   // Whether a writer holds the lock
+    queue: WaitQueue<Access>,
+}
+
+/// A queueing readers-writer lock.  Any number of readers can hold the lock
+/// at once, or a single writer.  Once a writer is waiting, new readers queue
+/// behind it, so writers aren't starved.
+pub struct RWlock<T: ?Sized> {
+    state: ILock<RWState>,
+    data: UnsafeCell<T>,
+}
+
+unsafe impl<T: ?Sized> Send for RWlock<T> {}
+unsafe impl<T: ?Sized> Sync for RWlock<T> {}
+
+impl<T> RWlock<T> {
+    pub const fn new(name: &'static str, data: T) -> RWlock<T> {
+        RWlock {
+            state: ILock::new(name, RWState { readers: 0, writer: false, queue: WaitQueue::new() }),
+            data: UnsafeCell::new(data),
+        }
+    }
+}
+
+impl<T: ?Sized> RWlock<T> {
+    /// Take the lock for reading, sleeping until there's no writer holding
+    /// or waiting for it.
+    pub fn rlock(&self) -> RLockGuard<'_, T> {
+        let mut waiter = Waiter::new(Access::Read);
+        let ticket = {
+            let mut state = self.state.lock();
+            if !state.writer && state.queue.front().is_none() {
+                state.readers += 1;
+                return RLockGuard { lock: self };
+            }
+            state.queue.enqueue(&mut waiter)
+        };
+        wait(&self.state, ticket, |state| &state.queue);
+        RLockGuard { lock: self }
+    }
+
+    /// Take the lock for writing, sleeping until nobody else holds it.
+    pub fn wlock(&self) -> WLockGuard<'_, T> {
+        let mut waiter = Waiter::new(Access::Write);
+        let ticket = {
+            let mut state = self.state.lock();
+            if !state.writer && state.readers == 0 && state.queue.front().is_none() {
+                state.writer = true;
+                return WLockGuard { lock: self };
+            }
+            state.queue.enqueue(&mut waiter)
+        };
+        wait(&self.state, ticket, |state| &state.queue);
+        WLockGuard { lock: self }
+    }
+
+    /// Take the lock for reading only if that can be done without sleeping.
+    pub fn try_rlock(&self) -> Option<RLockGuard<'_, T>> {
+        let mut state = self.state.lock();
+        if state.writer || state.queue.front().is_some() {
+            return None;
+        }
+        state.readers += 1;
+        Some(RLockGuard { lock: self })
+    }
+
+    /// Take the lock for writing only if that can be done without sleeping.
+    pub fn try_wlock(&self) -> Option<WLockGuard<'_, T>> {
+        let mut state = self.state.lock();
+        if state.writer || state.readers > 0 {
+            return None;
+        }
+        state.writer = true;
+        Some(WLockGuard { lock: self })
+    }
+
+    fn runlock(&self) {
+        let mut state = self.state.lock();
+        state.readers -= 1;
+        // Only a writer can be at the front while readers hold the lock
+        if state.readers == 0 && state.queue.front() == Some(Access::Write) {
+            state.writer = true;
+            state.queue.grant_front();
+        }
+    }
+
+    fn wunlock(&self) {
+        let mut state = self.state.lock();
+        state.writer = false;
+        match state.queue.front() {
+            Some(Access::Write) => {
+                state.writer = true;
+                state.queue.grant_front();
+            }
+            Some(Access::Read) => {
+                // Let in all the readers up to the next writer
+                while state.queue.front() == Some(Access::Read) {
+                    state.readers += 1;
+                    state.queue.grant_front();
+                }
+            }
+            None => {}
+        }
+    }
+}
+
+pub struct RLockGuard<'a, T: ?Sized> {
+    lock: &'a RWlock<T>,
+}
+
+impl<T: ?Sized> Deref for RLockGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.data.get() }
+    }
+}
+
+impl<T: ?Sized> Drop for RLockGuard<'_, T> {
+    fn drop(&mut self) {
+        self.lock.runlock();
+    }
+}
+
+pub struct WLockGuard<'a, T: ?Sized> {
+    lock: &'a RWlock<T>,
+}
+
+impl<T: ?Sized> Deref for WLockGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.data.get() }
+    }
+}
+
+impl<T: ?Sized> DerefMut for WLockGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.lock.data.get() }
+    }
+}
+
+impl<T: ?Sized> Drop for WLockGuard<'_, T> {
+    fn drop(&mut self) {
+        self.lock.wunlock();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sched::testhooks;
+    use std::sync::Mutex;
+    use std::thread;
+    use std::time::Duration;
+
+    /// Wait until n processes are queued on the lock.
+    fn wait_for_waiters<S, K: Copy>(state: &ILock<S>, queue: fn(&S) -> &WaitQueue<K>, n: usize) {
+        while queue(&state.lock()).len() < n {
+            thread::sleep(Duration::from_millis(1));
+        }
+    }
+
+    #[test]
+    fn qlock_excludes() {
+        testhooks::install();
+        let lock = QLock::new("test", 0);
+        thread::scope(|s| {
+            for _ in 0..4 {
+                s.spawn(|| {
+                    for _ in 0..25 {
+                        let mut guard = lock.lock();
+                        let v = *guard;
+                        thread::yield_now();
+                        *guard = v + 1;
+                    }
+                });
+            }
+        });
+        assert_eq!(*lock.lock(), 100);
+    }
+
+    #[test]
+    fn qlock_is_fifo() {
+        testhooks::install();
+        let lock = QLock::new("test", Vec::new());
+        let guard = lock.lock();
+        assert!(lock.try_lock().is_none());
+        let lock = &lock;
+        thread::scope(|s| {
+            for i in 0..4 {
+                s.spawn(move || lock.lock().push(i));
+                wait_for_waiters(&lock.state, |state| &state.queue, i + 1);
+            }
+            drop(guard);
+        });
+        assert_eq!(*lock.try_lock().unwrap(), [0, 1, 2, 3]);
+    }
+
+    #[test]
+    fn rwlock_shares_readers() {
+        testhooks::install();
+        let lock = RWlock::new("test", 5);
+        let r1 = lock.rlock();
+        let r2 = lock.try_rlock().unwrap();
+        assert_eq!(*r1 + *r2, 10);
+        assert!(lock.try_wlock().is_none());
+        drop((r1, r2));
+
+        let mut w = lock.try_wlock().unwrap();
+        *w += 1;
+        assert!(lock.try_rlock().is_none());
+        drop(w);
+        assert_eq!(*lock.rlock(), 6);
+    }
+
+    #[test]
+    fn rwlock_queues_readers_behind_writer() {
+        testhooks::install();
+        let lock = RWlock::new("test", 0);
+        let order = Mutex::new(Vec::new());
+        let r = lock.rlock();
+        thread::scope(|s| {
+            // A writer waits for the reader, and a later reader waits for the
+            // writer, then readers behind that are all let in together.
+            s.spawn(|| {
+                let mut w = lock.wlock();
+                *w += 1;
+                order.lock().unwrap().push("w");
+            });
+            wait_for_waiters(&lock.state, |state| &state.queue, 1);
+            assert!(lock.try_rlock().is_none());
+            for i in 0..3 {
+                let (lock, order) = (&lock, &order);
+                s.spawn(move || {
+                    let r = lock.rlock();
+                    assert_eq!(*r, 1);
+                    order.lock().unwrap().push("r");
+                });
+                wait_for_waiters(&lock.state, |state| &state.queue, i + 2);
+            }
+            drop(r);
+        });
+        assert_eq!(*order.lock().unwrap(), ["w", "r", "r", "r"]);
+        assert_eq!(lock.state.lock().readers, 0);
+    }
+}
@@ -0,0 +1,115 @@
+//! Rendez is Plan 9's rendezvous point: a process sleeps on it until some
+//! condition holds, and whoever makes the condition true wakes it up.  At
+//! most one process may sleep on a Rendez at a time.
+//!
+//! The condition is checked with the Rendez locked, and `wakeup` takes the
+//! same lock, so a wakeup can't be lost between the check and going to
+//! sleep.  Interrupts are disabled while it's held, so interrupt handlers
+//! may call `wakeup`.
+
+use crate::mcslock::ILock;
+use crate::sched;
+
+pub struct Rendez {
+    sleeper: ILock<Option<usize>>, // Id of the sleeping process
+}
+
+impl Rendez {
+    pub const fn new(name: &'static str) -> Rendez {
+        Rendez { sleeper: ILock::new(name, None) }
+    }
+
+    /// Sleep until cond returns true.  cond is called with the Rendez
+    /// locked, so must be quick, and must not sleep or take the lock of
+    /// anything that might call `wakeup` on it.
+    pub fn sleep<F>(&self, cond: F)
+    where
+        F: Fn() -> bool,
+    {
+        let me = sched::current();
+        loop {
+            {
+                let mut sleeper = self.sleeper.lock();
+                if cond() {
+                    *sleeper = None;
+                    return;
+                }
+                if let Some(other) = *sleeper
+                    && other != me
+                {
+                    panic!("double sleep: process {me} on rendez held by process {other}");
+                }
+                *sleeper = Some(me);
+            }
+            sched::block();
+        }
+    }
+
+    /// Wake up the sleeping process, if any.  The caller should have made the
+    /// condition it's waiting for true first.  Returns the id of the process
+    /// that was woken.
+    pub fn wakeup(&self) -> Option<usize> {
+        let sleeper = self.sleeper.lock().take();
+        if let Some(id) = sleeper {
+            sched::ready(id);
+        }
+        sleeper
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sched::testhooks;
+    use core::sync::atomic::{AtomicBool, Ordering};
+    use std::thread;
+    use std::time::Duration;
+
+    #[test]
+    fn sleep_returns_if_condition_true() {
+        testhooks::install();
+        let r = Rendez::new("test");
+        r.sleep(|| true);
+        assert_eq!(r.wakeup(), None);
+    }
+
+    #[test]
+    fn wakeup_wakes_sleeper() {
+        testhooks::install();
+        let r = Rendez::new("test");
+        let done = AtomicBool::new(false);
+        thread::scope(|s| {
+            let sleeper = s.spawn(|| {
+                r.sleep(|| done.load(Ordering::Relaxed));
+                sched::current()
+            });
+
+            // Wait for the sleeper to go to sleep, then wake it.
+            let id = loop {
+                if let Some(id) = *r.sleeper.lock() {
+                    break id;
+                }
+                thread::sleep(Duration::from_millis(1));
+            };
+            done.store(true, Ordering::Relaxed);
+            assert_eq!(r.wakeup(), Some(id));
+            assert_eq!(sleeper.join().unwrap(), id);
+        });
+    }
+
+    #[test]
+    fn spurious_wakeups_are_ignored() {
+        testhooks::install();
+        let r = Rendez::new("test");
+        let done = AtomicBool::new(false);
+        thread::scope(|s| {
+            s.spawn(|| r.sleep(|| done.load(Ordering::Relaxed)));
+            for _ in 0..10 {
+                r.wakeup();
+                thread::sleep(Duration::from_millis(1));
+            }
+            done.store(true, Ordering::Relaxed);
+            r.wakeup();
+        });
+    }
+}
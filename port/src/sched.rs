@@ -0,0 +1,1219 @@
+//! sched schedules processes, after Plan 9's proc.c.  Processes live in a
+//! fixed-size table, and those ready to run wait in a run queue for their
+//! priority.  Each CPU runs a scheduler loop, `schedinit`, on its own stack:
+//! a process gives up the CPU by switching back to the loop with `sched`,
+//! and the loop requeues it if it's still runnable, then switches to the
+//! highest priority ready process, switching to its user address space
+//! first if it has one.  Kernel processes don't touch user memory, so they
+//! run in whichever was current.  Processes of equal priority take turns,
+//! each running for a quantum of clock ticks before the next gets the CPU.
+//! Real-time processes, scheduled by `edf`, run ahead of all of these.
+//!
+//! The architecture-specific parts are switching between kernel contexts,
+//! which each architecture provides by implementing `Arch` and passing it to
+//! `init`, and switching user address spaces, through each process's
+//! `UserMem`.
+//!
+//! sched is also the interface between the sleeping locks and whatever
+//! schedules processes.  `init` registers the process scheduler's
+//! implementation of `SchedHooks`, after which processes waiting on a
+//! `Rendez`, `QLock` or `RWlock` give up the CPU until they're readied.
+//!
+//! Until then the defaults are used: there's a single process, and blocking
+//! returns straight away, so waiters spin, checking their condition each
+//! time around.
+
+use alloc::alloc::{Layout, alloc, dealloc};
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::cell::UnsafeCell;
+use core::hint;
+use core::ptr::{self, NonNull};
+use core::sync::atomic::{AtomicBool, AtomicPtr, AtomicUsize, Ordering};
+
+use crate::cpu::{self, MAX_CPUS, NO_CPU};
+use crate::edf::{Edf, EdfCtl, EdfParams};
+use crate::mcslock::{self, ILock};
+use crate::pgrp::{Groups, RforkFlags, UserMem};
+use crate::sys9::ErrStr;
+
+/// Scheduler-specific implementation of the operations the sleeping locks
+/// need.
+pub struct SchedHooks {
+    /// Returns an id for the current process, to be passed to `ready`.
+    pub current: fn() -> usize,
+    /// Blocks the current process until `ready` is called for it.  If `ready`
+    /// has been called since it last blocked, returns immediately.  May also
+    /// return early, so callers must check what they're waiting for again.
+    pub block: fn(),
+    /// Makes the process with the given id runnable.  May be called from
+    /// interrupt handlers.
+    pub ready: fn(usize),
+}
+
+static DEFAULT_HOOKS: SchedHooks =
+    SchedHooks { current: || 0, block: hint::spin_loop, ready: |_| {} };
+
+static HOOKS: AtomicPtr<SchedHooks> = AtomicPtr::new(ptr::addr_of!(DEFAULT_HOOKS).cast_mut());
+
+/// Registers the scheduler's implementation of the operations.
+pub fn set_hooks(hooks: &'static SchedHooks) {
+    HOOKS.store(ptr::from_ref(hooks).cast_mut(), Ordering::Release);
+}
+
+fn hooks() -> &'static SchedHooks {
+    unsafe { &*HOOKS.load(Ordering::Acquire) }
+}
+
+/// Id of the current process.
+pub fn current() -> usize {
+    (hooks().current)()
+}
+
+/// Block the current process until it's readied.
+pub fn block() {
+    (hooks().block)()
+}
+
+/// Make the process with the given id runnable.
+pub fn ready(id: usize) {
+    (hooks().ready)(id)
+}
+
+/// Maximum number of processes.
+pub const NPROC: usize = 64;
+
+/// Number of priorities, and so run queues.  Higher numbers run first.
+pub const NPRIQ: usize = 20;
+
+/// Priority of ordinary processes.
+pub const PRI_NORMAL: usize = 10;
+
+/// Priority of kernel processes.
+pub const PRI_KPROC: usize = 13;
+
+/// Number of clock ticks a process runs for before giving way to another
+/// ready process of the same priority.
+pub const QUANTUM: usize = 10;
+
+/// Clock ticks per second, the rate at which architectures should call
+/// `tick`.
+pub const HZ: usize = 100;
+
+/// Value returned by `current` when the CPU isn't running a process.
+pub const NO_PROC: usize = usize::MAX;
+
+/// Saved kernel registers of a process, or of a CPU's scheduler loop, in
+/// whatever form the architecture's context switch uses.
+#[repr(C, align(16))]
+pub struct Context([u64; 16]);
+
+impl Context {
+    pub const fn new() -> Context {
+        Context([0; 16])
+    }
+
+    /// The context as the architecture's own register save area.
+    ///
+    /// # Safety
+    ///
+    /// T must be valid when all zeroes, and hold nothing that needs dropping.
+    pub unsafe fn regs_mut<T>(&mut self) -> &mut T {
+        const {
+            assert!(size_of::<T>() <= size_of::<Context>());
+            assert!(align_of::<T>() <= align_of::<Context>());
+        }
+        unsafe { &mut *ptr::from_mut(self).cast::<T>() }
+    }
+}
+
+impl Default for Context {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// An architecture's kernel context switch.
+pub trait Arch {
+    /// Size of each process's kernel stack.
+    const KSTACK_SIZE: usize;
+
+    /// Save the current kernel registers in save, and resume those in next.
+    /// Returns when something switches back to save.
+    ///
+    /// # Safety
+    ///
+    /// next must have been saved by `swtch` or set up by `init_context`, and
+    /// its stack must still be valid.
+    unsafe fn swtch(save: &mut Context, next: &mut Context);
+
+    /// Set up ctx so that switching to it calls start, with interrupts
+    /// disabled, on the stack ending at stack_top.
+    fn init_context(ctx: &mut Context, start: extern "C" fn() -> !, stack_top: usize);
+
+    /// Called before switching to a process, with the top of its kernel
+    /// stack, for architectures that must be told which stack to enter the
+    /// kernel on from user mode.
+    fn set_kstack(_stack_top: usize) {}
+}
+
+/// An `Arch`'s functions, so they can be registered at run time.
+struct ArchOps {
+    kstack_size: usize,
+    swtch: unsafe fn(&mut Context, &mut Context),
+    init_context: fn(&mut Context, extern "C" fn() -> !, usize),
+    set_kstack: fn(usize),
+}
+
+static ARCH: AtomicPtr<ArchOps> = AtomicPtr::new(ptr::null_mut());
+
+fn arch() -> &'static ArchOps {
+    let ops = ARCH.load(Ordering::Acquire);
+    assert!(!ops.is_null(), "sched: no arch registered");
+    unsafe { &*ops }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ProcState {
+    Dead,     // Slot is free
+    Moribund, // Exited, but still on its stack
+    Ready,    // On a run queue
+    Running,  // On a CPU
+    Wakeme,   // Blocked until readied
+}
+
+struct Proc {
+    pid: usize,
+    name: &'static str,
+    state: ProcState,
+    priority: usize,
+    next: Option<usize>, // Next slot in the same run queue
+    on_cpu: bool,        // Running, or switched away from but context not yet saved
+    wakeup: bool,        // Readied while not blocked, so the next block returns at once
+    entry: fn(usize),
+    arg: usize,
+    kstack: Option<NonNull<u8>>, // Freed once the process has exited
+    kproc: bool,                 // Kernel process, with no user address space
+    edf: Edf,                    // Real-time parameters and state
+    groups: Groups,              // Resources shared with other processes
+    parent: Option<usize>,       // Pid of the parent waiting for it, if any
+    ticks: usize,                // Clock ticks spent running
+    quantum: usize,              // Clock ticks left before giving way to an equal priority process
+    errstr: ErrStr,              // Why the last system call failed
+}
+
+impl Proc {
+    const DEAD: Proc = Proc {
+        pid: 0,
+        name: "",
+        state: ProcState::Dead,
+        priority: 0,
+        next: None,
+        on_cpu: false,
+        wakeup: false,
+        entry: |_| {},
+        arg: 0,
+        kstack: None,
+        kproc: false,
+        edf: Edf::NONE,
+        groups: Groups::NONE,
+        parent: None,
+        ticks: 0,
+        quantum: 0,
+        errstr: ErrStr::EMPTY,
+    };
+}
+
+/// A process, as listed by `procs`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ProcInfo {
+    pub pid: usize,
+    pub name: &'static str,
+    pub state: ProcState,
+    pub priority: usize,
+    pub kproc: bool,
+    pub parent: Option<usize>,
+    pub edf: Option<EdfParams>, // Real-time parameters, if admitted
+    pub misses: usize,          // Real-time deadlines missed
+    pub ticks: usize,
+}
+
+#[derive(Clone, Copy)]
+struct RunQueue {
+    head: Option<usize>,
+    tail: Option<usize>,
+}
+
+/// The processes, and the run queues linking those that are ready.
+/// Real-time processes aren't queued, but picked from the table by deadline.
+struct ProcTable {
+    procs: [Proc; NPROC],
+    runq: [RunQueue; NPRIQ],
+    next_pid: usize,
+    now: u64, // Clock ticks since boot
+}
+
+impl ProcTable {
+    const fn new() -> Self {
+        Self {
+            procs: [const { Proc::DEAD }; NPROC],
+            runq: [RunQueue { head: None, tail: None }; NPRIQ],
+            next_pid: 1,
+            now: 0,
+        }
+    }
+
+    /// Add a ready process, returning its slot.
+    fn add(
+        &mut self,
+        name: &'static str,
+        priority: usize,
+        entry: fn(usize),
+        arg: usize,
+    ) -> Option<usize> {
+        let slot = self.procs.iter().position(|p| p.state == ProcState::Dead)?;
+        let pid = self.next_pid;
+        self.next_pid += 1;
+        self.procs[slot] =
+            Proc { pid, name, state: ProcState::Ready, priority, entry, arg, ..Proc::DEAD };
+        self.enqueue(slot);
+        Some(slot)
+    }
+
+    /// Add the process to the back of the run queue for its priority,
+    /// unless it's real-time.
+    fn enqueue(&mut self, slot: usize) {
+        if self.procs[slot].edf.admitted {
+            return;
+        }
+        let q = &mut self.runq[self.procs[slot].priority];
+        self.procs[slot].next = None;
+        match q.tail {
+            Some(tail) => self.procs[tail].next = Some(slot),
+            None => q.head = Some(slot),
+        }
+        q.tail = Some(slot);
+    }
+
+    /// Remove the first process from the highest priority run queue that
+    /// can be run now.  Processes still switching away on another CPU are
+    /// passed over.
+    fn dequeue(&mut self) -> Option<usize> {
+        for pri in (0..NPRIQ).rev() {
+            let mut prev: Option<usize> = None;
+            let mut cur = self.runq[pri].head;
+            while let Some(slot) = cur {
+                let next = self.procs[slot].next;
+                if !self.procs[slot].on_cpu {
+                    self.unlink(pri, prev, slot);
+                    return Some(slot);
+                }
+                prev = cur;
+                cur = next;
+            }
+        }
+        None
+    }
+
+    /// Remove the process from the run queue for pri, given the one before
+    /// it in the queue.
+    fn unlink(&mut self, pri: usize, prev: Option<usize>, slot: usize) {
+        let next = self.procs[slot].next.take();
+        match prev {
+            Some(prev) => self.procs[prev].next = next,
+            None => self.runq[pri].head = next,
+        }
+        if next.is_none() {
+            self.runq[pri].tail = prev;
+        }
+    }
+
+    /// Remove a ready process from its run queue.
+    fn unqueue(&mut self, slot: usize) {
+        let pri = self.procs[slot].priority;
+        let mut prev = None;
+        let mut cur = self.runq[pri].head;
+        while let Some(s) = cur {
+            if s == slot {
+                self.unlink(pri, prev, slot);
+                return;
+            }
+            prev = cur;
+            cur = self.procs[s].next;
+        }
+    }
+
+    /// The ready real-time process with the earliest deadline that can run
+    /// now, along with its deadline.
+    fn earliest_edf(&self) -> Option<(usize, u64)> {
+        self.procs
+            .iter()
+            .enumerate()
+            .filter(|(_, p)| p.state == ProcState::Ready && !p.on_cpu && p.edf.eligible())
+            .map(|(slot, p)| (slot, p.edf.deadline()))
+            .min_by_key(|&(_, deadline)| deadline)
+    }
+
+    /// Priority of the highest priority ready process.
+    fn highest_ready(&self) -> Option<usize> {
+        (0..NPRIQ).rev().find(|&pri| self.runq[pri].head.is_some())
+    }
+
+    /// Pick the next process for this CPU to run: real-time processes
+    /// first, then the highest priority time-sharing process.
+    fn dispatch(&mut self) -> Option<usize> {
+        let slot = self.earliest_edf().map(|(slot, _)| slot).or_else(|| self.dequeue())?;
+        let p = &mut self.procs[slot];
+        p.state = ProcState::Running;
+        p.on_cpu = true;
+        p.quantum = QUANTUM;
+        Some(slot)
+    }
+
+    /// Called once the process's context has been saved after it gave up the
+    /// CPU.  If it's still runnable, it goes to the back of its run queue.
+    /// If it has exited, its slot is freed, and it's returned for the caller
+    /// to free its kernel stack and drop its groups.
+    fn switched_out(&mut self, slot: usize) -> Option<Proc> {
+        let p = &mut self.procs[slot];
+        p.on_cpu = false;
+        match p.state {
+            ProcState::Running => {
+                p.state = ProcState::Ready;
+                self.enqueue(slot);
+                None
+            }
+            ProcState::Moribund => Some(core::mem::replace(p, Proc::DEAD)),
+            _ => None,
+        }
+    }
+
+    /// Returns whether the process should give up the CPU to block, or
+    /// carry on because it's been readied since it last blocked.
+    fn block(&mut self, slot: usize) -> bool {
+        let p = &mut self.procs[slot];
+        if p.wakeup {
+            p.wakeup = false;
+            return false;
+        }
+        p.state = ProcState::Wakeme;
+        true
+    }
+
+    fn ready(&mut self, slot: usize) {
+        let p = &mut self.procs[slot];
+        match p.state {
+            ProcState::Wakeme => {
+                p.state = ProcState::Ready;
+                self.enqueue(slot);
+            }
+            ProcState::Ready | ProcState::Running => p.wakeup = true,
+            ProcState::Dead | ProcState::Moribund => {}
+        }
+    }
+
+    /// Advance the clock, starting new real-time periods and noting missed
+    /// deadlines.
+    fn clock(&mut self) {
+        self.now += 1;
+        for p in self.procs.iter_mut().filter(|p| p.edf.admitted) {
+            let runnable = matches!(p.state, ProcState::Ready | ProcState::Running);
+            p.edf.clock(self.now, runnable);
+        }
+    }
+
+    /// Charge a clock tick to the running process, returning whether it
+    /// should be preempted.
+    fn tick(&mut self, slot: usize) -> bool {
+        let p = &mut self.procs[slot];
+        p.ticks += 1;
+        if p.edf.admitted {
+            p.edf.charge();
+            if !p.edf.eligible() {
+                return true;
+            }
+            let deadline = p.edf.deadline();
+            return self.earliest_edf().is_some_and(|(_, d)| d < deadline);
+        }
+        if self.earliest_edf().is_some() {
+            return true;
+        }
+        let p = &mut self.procs[slot];
+        p.quantum = p.quantum.saturating_sub(1);
+        let (priority, expired) = (p.priority, p.quantum == 0);
+        match self.highest_ready() {
+            Some(pri) => pri > priority || (pri == priority && expired),
+            None => false,
+        }
+    }
+
+    /// Apply a real-time ctl command to the process.
+    fn ctl(&mut self, slot: usize, cmd: &str) -> crate::Result<()> {
+        let ctl = EdfCtl::parse(cmd)?;
+        match ctl {
+            EdfCtl::Admit => {
+                let mut edf = self.procs[slot].edf;
+                let others =
+                    self.procs.iter().enumerate().filter(|&(s, p)| s != slot && p.edf.admitted);
+                edf.check_admit(others.map(|(_, p)| p.edf.params))?;
+                edf.admit(self.now);
+                if self.procs[slot].state == ProcState::Ready {
+                    self.unqueue(slot);
+                }
+                self.procs[slot].edf = edf;
+            }
+            EdfCtl::Expel => {
+                if !self.procs[slot].edf.admitted {
+                    return Err("edf: not admitted");
+                }
+                self.procs[slot].edf.admitted = false;
+                if self.procs[slot].state == ProcState::Ready {
+                    self.enqueue(slot);
+                }
+            }
+            _ => self.procs[slot].edf.set(&ctl)?,
+        }
+        Ok(())
+    }
+
+    fn info(&self, slot: usize) -> ProcInfo {
+        let p = &self.procs[slot];
+        ProcInfo {
+            pid: p.pid,
+            name: p.name,
+            state: p.state,
+            priority: p.priority,
+            kproc: p.kproc,
+            parent: p.parent,
+            edf: p.edf.admitted.then_some(p.edf.params),
+            misses: p.edf.misses,
+            ticks: p.ticks,
+        }
+    }
+}
+
+struct ContextCell(UnsafeCell<Context>);
+
+unsafe impl Sync for ContextCell {}
+
+impl ContextCell {
+    const fn new() -> Self {
+        Self(UnsafeCell::new(Context::new()))
+    }
+
+    /// Only the CPU running the context, or switching to it, may use it.
+    #[allow(clippy::mut_from_ref)]
+    unsafe fn get(&self) -> &mut Context {
+        unsafe { &mut *self.0.get() }
+    }
+}
+
+/// Scheduling state of each CPU.
+struct Mach {
+    sched: ContextCell,       // Context of the scheduler loop
+    up: AtomicUsize,          // Slot of the running process, or NO_PROC
+    need_resched: AtomicBool, // Running process should be preempted
+}
+
+impl Mach {
+    const fn new() -> Self {
+        Self {
+            sched: ContextCell::new(),
+            up: AtomicUsize::new(NO_PROC),
+            need_resched: AtomicBool::new(false),
+        }
+    }
+
+    /// Whether to preempt the running process, which holds nlocks spinning
+    /// locks.  Like Plan 9 with up->nlocks, this waits until it holds none,
+    /// since other CPUs could otherwise spin on them until it runs again.
+    fn preempting(&self, nlocks: usize) -> bool {
+        nlocks == 0 && self.need_resched.swap(false, Ordering::Relaxed)
+    }
+}
+
+static PROCS: ILock<ProcTable> = ILock::new("procs", ProcTable::new());
+static CONTEXTS: [ContextCell; NPROC] = [const { ContextCell::new() }; NPROC];
+static MACHS: [Mach; MAX_CPUS] = [const { Mach::new() }; MAX_CPUS];
+
+/// Scheduling state of the current CPU.  Interrupts must be disabled.  A CPU
+/// without a valid id is assumed to be the only one.
+fn mach() -> &'static Mach {
+    let id = cpu::cpu_id();
+    &MACHS[if id == NO_CPU { 0 } else { id }]
+}
+
+static PROC_HOOKS: SchedHooks = SchedHooks { current: up, block: block_up, ready: ready_slot };
+
+/// Register the architecture's context switch, and have the sleeping locks
+/// use the process scheduler.
+pub fn init<A: Arch>() {
+    let ops = const {
+        &ArchOps {
+            kstack_size: A::KSTACK_SIZE,
+            swtch: A::swtch,
+            init_context: A::init_context,
+            set_kstack: A::set_kstack,
+        }
+    };
+    ARCH.store(ptr::from_ref(ops).cast_mut(), Ordering::Release);
+    set_hooks(&PROC_HOOKS);
+}
+
+/// Slot of the current process, or NO_PROC, as it is on a CPU that has no
+/// scheduling state.
+fn up() -> usize {
+    cpu::without_interrupts(|| match cpu::cpu_id() {
+        id if id != NO_CPU && id >= MAX_CPUS => NO_PROC,
+        _ => mach().up.load(Ordering::Relaxed),
+    })
+}
+
+fn block_up() {
+    let intr = cpu::splhi();
+    let slot = mach().up.load(Ordering::Relaxed);
+    if slot == NO_PROC {
+        hint::spin_loop();
+    } else if PROCS.lock().block(slot) {
+        sched();
+    }
+    cpu::splx(intr);
+}
+
+fn ready_slot(slot: usize) {
+    if slot < NPROC {
+        PROCS.lock().ready(slot);
+    }
+}
+
+fn kstack_layout() -> Layout {
+    Layout::from_size_align(arch().kstack_size, 16).unwrap()
+}
+
+/// Create a process that calls entry(arg) on a new kernel stack, and make it
+/// ready to run.  Returns its pid.  The process exits when entry returns.
+fn spawn(
+    name: &'static str,
+    priority: usize,
+    entry: fn(usize),
+    arg: usize,
+    kproc: bool,
+    groups: Groups,
+    parent: Option<usize>,
+) -> crate::Result<usize> {
+    let layout = kstack_layout();
+    let Some(kstack) = NonNull::new(unsafe { alloc(layout) }) else {
+        return Err("spawn: no memory for kernel stack");
+    };
+    let mut procs = PROCS.lock();
+    let Some(slot) = procs.add(name, priority, entry, arg) else {
+        drop(procs);
+        unsafe { dealloc(kstack.as_ptr(), layout) };
+        return Err("spawn: no free procs");
+    };
+    // Not yet dispatchable, since the table is locked
+    let stack_top = kstack.addr().get() + layout.size();
+    (arch().init_context)(unsafe { CONTEXTS[slot].get() }, procstart, stack_top);
+    let p = &mut procs.procs[slot];
+    p.kstack = Some(kstack);
+    p.kproc = kproc;
+    p.groups = groups;
+    p.parent = parent;
+    Ok(p.pid)
+}
+
+/// Create a kernel process, with no user address space, that calls
+/// entry(arg), and make it ready to run.  Returns its pid.  The process
+/// exits when entry returns.
+pub fn kproc(name: &'static str, entry: fn(usize), arg: usize) -> crate::Result<usize> {
+    spawn(name, PRI_KPROC, entry, arg, true, Groups::new(), None)
+}
+
+/// Create a user process that calls entry(arg), which should exec a
+/// program and drop to user mode, and make it ready to run.  Returns its
+/// pid.
+pub fn userproc(name: &'static str, entry: fn(usize), arg: usize) -> crate::Result<usize> {
+    spawn(name, PRI_NORMAL, entry, arg, false, Groups::new(), None)
+}
+
+/// Plan 9's rfork.  With RFPROC, creates a child process that calls
+/// entry(arg), and returns its pid.  The child has the caller's name and
+/// priority, and shares, copies or starts afresh each of the caller's groups
+/// and its memory as flags say.  Without RFPROC, the flags apply to the
+/// caller's own groups instead, and 0 is returned.
+///
+/// The rfork system call's entry returns the child to user mode with a copy
+/// of the caller's registers, and 0 as the system call's result.
+pub fn rfork(flags: RforkFlags, entry: fn(usize), arg: usize) -> crate::Result<usize> {
+    let slot = up();
+    if slot == NO_PROC {
+        return Err("rfork: not in a process");
+    }
+    fork_slot(slot, flags, entry, arg)
+}
+
+/// rfork, by the process in slot.
+fn fork_slot(slot: usize, flags: RforkFlags, entry: fn(usize), arg: usize) -> crate::Result<usize> {
+    let (groups, name, priority, kproc, pid) = {
+        let procs = PROCS.lock();
+        let p = &procs.procs[slot];
+        (p.groups.clone(), p.name, p.priority, p.kproc, p.pid)
+    };
+    // Copying groups may sleep, so can't be done with the table locked
+    let groups = groups.fork(flags)?;
+    if !flags.contains(RforkFlags::RFPROC) {
+        // Drop the old groups once the table's unlocked
+        let old = core::mem::replace(&mut PROCS.lock().procs[slot].groups, groups);
+        drop(old);
+        return Ok(0);
+    }
+    let parent = (!flags.contains(RforkFlags::RFNOWAIT)).then_some(pid);
+    spawn(name, priority, entry, arg, kproc, groups, parent)
+}
+
+/// Where new processes start, switched to from the scheduler loop.
+extern "C" fn procstart() -> ! {
+    let slot = mach().up.load(Ordering::Relaxed);
+    let (entry, arg) = {
+        let procs = PROCS.lock();
+        (procs.procs[slot].entry, procs.procs[slot].arg)
+    };
+    cpu::spllo();
+    entry(arg);
+    pexit()
+}
+
+/// Run the scheduler loop on this CPU, once it's ready to run processes.
+pub fn schedinit() -> ! {
+    let _intr = cpu::splhi();
+    let id = cpu::cpu_id();
+    assert!(id == NO_CPU || id < MAX_CPUS, "schedinit: cpu {id} has no scheduling state");
+    let m = mach();
+    loop {
+        let prev = m.up.swap(NO_PROC, Ordering::Relaxed);
+        let (next, kstack, mem, dead) = {
+            let mut procs = PROCS.lock();
+            let dead = if prev != NO_PROC { procs.switched_out(prev) } else { None };
+            let next = procs.dispatch();
+            // Only the process itself replaces its memory, and it isn't
+            // running until we switch to it
+            let mem = next.and_then(|slot| procs.procs[slot].groups.mem.as_deref());
+            let kstack = next.and_then(|slot| procs.procs[slot].kstack);
+            (next, kstack, mem.map(ptr::from_ref), dead)
+        };
+        if let Some(kstack) = dead.and_then(|p| p.kstack) {
+            unsafe { dealloc(kstack.as_ptr(), kstack_layout()) };
+        }
+        let Some(next) = next else {
+            // Idle, letting interrupts in to ready something
+            cpu::spllo();
+            hint::spin_loop();
+            let _ = cpu::splhi();
+            continue;
+        };
+        if let Some(mem) = mem {
+            unsafe { (*mem).switch_to() };
+        }
+        if let Some(kstack) = kstack {
+            (arch().set_kstack)(kstack.addr().get() + arch().kstack_size);
+        }
+        m.up.store(next, Ordering::Relaxed);
+        m.need_resched.store(false, Ordering::Relaxed);
+        unsafe { (arch().swtch)(m.sched.get(), CONTEXTS[next].get()) };
+    }
+}
+
+/// Give up the CPU, switching to the scheduler loop.  A process that's still
+/// running is put back on its run queue; one that's blocked or exiting isn't.
+pub fn sched() {
+    let intr = cpu::splhi();
+    let m = mach();
+    let slot = m.up.load(Ordering::Relaxed);
+    assert!(slot != NO_PROC, "sched: not in a process");
+    unsafe { (arch().swtch)(CONTEXTS[slot].get(), m.sched.get()) };
+    cpu::splx(intr);
+}
+
+/// Let other ready processes of the same or higher priority run.
+pub fn yield_now() {
+    if up() != NO_PROC {
+        sched();
+    }
+}
+
+/// Exit the current process.
+pub fn pexit() -> ! {
+    let _intr = cpu::splhi();
+    let slot = mach().up.load(Ordering::Relaxed);
+    PROCS.lock().procs[slot].state = ProcState::Moribund;
+    sched();
+    unreachable!("pexit: dead process rescheduled");
+}
+
+/// Called HZ times a second from the clock interrupt on each CPU.  Charges
+/// the tick to the running process, and notes whether it should be
+/// preempted.  The first CPU also advances the scheduler's clock.
+pub fn tick() {
+    let m = mach();
+    let slot = m.up.load(Ordering::Relaxed);
+    let mut procs = PROCS.lock();
+    if matches!(cpu::cpu_id(), 0 | NO_CPU) {
+        procs.clock();
+    }
+    if slot != NO_PROC && procs.tick(slot) {
+        m.need_resched.store(true, Ordering::Relaxed);
+    }
+}
+
+/// Preempt the running process if a clock tick said it should be.  Called
+/// by the architecture where switching away is safe, such as on the way out
+/// of an interrupt taken on the process's kernel stack.  A process holding
+/// locks is left running until a later call finds it holds none.
+pub fn preempt() {
+    let resched = cpu::without_interrupts(|| mach().preempting(mcslock::held_locks()));
+    if resched {
+        yield_now();
+    }
+}
+
+/// The current process's user address space, if it has one.
+pub fn usermem() -> Option<Arc<dyn UserMem>> {
+    let slot = up();
+    if slot == NO_PROC { None } else { PROCS.lock().procs[slot].groups.mem.clone() }
+}
+
+/// Replace the current process's user address space, as exec does, and
+/// switch to the new one.  Returns the old one, for the caller to drop, or
+/// to put back if loading the new one fails.
+pub fn set_usermem(mem: Option<Arc<dyn UserMem>>) -> crate::Result<Option<Arc<dyn UserMem>>> {
+    let slot = up();
+    if slot == NO_PROC {
+        return Err("set_usermem: not in a process");
+    }
+    // Switched to with the table locked, so the process can't be preempted
+    // in between
+    let mut procs = PROCS.lock();
+    let p = &mut procs.procs[slot];
+    let old = core::mem::replace(&mut p.groups.mem, mem);
+    if let Some(mem) = &p.groups.mem {
+        mem.switch_to();
+    }
+    Ok(old)
+}
+
+/// Pid of the current process, or 0 outside a process.
+pub fn getpid() -> usize {
+    let slot = up();
+    if slot == NO_PROC { 0 } else { PROCS.lock().procs[slot].pid }
+}
+
+/// Set the current process's error string, as a failing system call does.
+pub fn seterrstr(msg: &str) {
+    let slot = up();
+    if slot != NO_PROC {
+        PROCS.lock().procs[slot].errstr.set(msg);
+    }
+}
+
+/// Plan 9's errstr: exchange the current process's error string with the
+/// one in buf.
+pub fn errstr(buf: &mut [u8]) -> crate::Result<()> {
+    let slot = up();
+    if slot == NO_PROC {
+        return Err("errstr: not in a process");
+    }
+    PROCS.lock().procs[slot].errstr.swap(buf);
+    Ok(())
+}
+
+/// Write a ctl command to the process with the given pid.  The commands set
+/// its real-time parameters, then admit it to or expel it from the
+/// real-time class:
+///
+///   period t      Time between releases
+///   deadline t    Time after each release to finish by (default: period)
+///   cost t        Most time to run for after each release
+///   admit         Become real-time, if all deadlines can still be met
+///   expel         Go back to time-sharing
+///
+/// Times have units of s, ms, us or ns, and are rounded down to clock ticks.
+pub fn procctl(pid: usize, cmd: &str) -> crate::Result<()> {
+    let mut procs = PROCS.lock();
+    let slot = procs
+        .procs
+        .iter()
+        .position(|p| p.pid == pid && p.state != ProcState::Dead)
+        .ok_or("procctl: no such process")?;
+    procs.ctl(slot, cmd)
+}
+
+/// List the processes, both kernel and user.
+pub fn procs() -> Vec<ProcInfo> {
+    let procs = PROCS.lock();
+    (0..NPROC)
+        .filter(|&slot| procs.procs[slot].state != ProcState::Dead)
+        .map(|slot| procs.info(slot))
+        .collect()
+}
+
+/// Hooks for host tests.  Each thread is treated as a process, blocking by
+/// parking the thread.
+#[cfg(test)]
+pub(crate) mod testhooks {
+    use super::{SchedHooks, set_hooks};
+    use core::sync::atomic::{AtomicUsize, Ordering};
+    use std::collections::HashMap;
+    use std::sync::Mutex;
+    use std::thread::{self, Thread};
+
+    static THREADS: Mutex<Option<HashMap<usize, Thread>>> = Mutex::new(None);
+
+    std::thread_local! {
+        static ID: usize = {
+            static NEXT_ID: AtomicUsize = AtomicUsize::new(1);
+            let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+            THREADS.lock().unwrap().get_or_insert_default().insert(id, thread::current());
+            id
+        };
+    }
+
+    static TEST_HOOKS: SchedHooks = SchedHooks {
+        current: || ID.with(|id| *id),
+        block: thread::park,
+        ready: |id| THREADS.lock().unwrap().as_ref().unwrap()[&id].unpark(),
+    };
+
+    pub fn install() {
+        set_hooks(&TEST_HOOKS);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::exec::Perm;
+
+    fn nop(_: usize) {}
+
+    #[test]
+    fn runs_highest_priority_first() {
+        let mut t = ProcTable::new();
+        let low = t.add("low", PRI_NORMAL - 1, nop, 0).unwrap();
+        let a = t.add("a", PRI_NORMAL, nop, 0).unwrap();
+        let b = t.add("b", PRI_NORMAL, nop, 0).unwrap();
+        assert_eq!(t.dispatch(), Some(a));
+        assert_eq!(t.procs[a].state, ProcState::Running);
+
+        // A preempted process goes behind others of its priority
+        t.switched_out(a);
+        assert_eq!(t.procs[a].state, ProcState::Ready);
+        assert_eq!(t.dispatch(), Some(b));
+        assert_eq!(t.dispatch(), Some(a));
+        assert_eq!(t.dispatch(), Some(low));
+        assert_eq!(t.dispatch(), None);
+    }
+
+    #[test]
+    fn ready_before_block_isnt_lost() {
+        let mut t = ProcTable::new();
+        let p = t.add("p", PRI_NORMAL, nop, 0).unwrap();
+        assert_eq!(t.dispatch(), Some(p));
+        t.ready(p);
+        assert!(!t.block(p));
+        assert_eq!(t.procs[p].state, ProcState::Running);
+
+        assert!(t.block(p));
+        t.switched_out(p);
+        assert_eq!(t.procs[p].state, ProcState::Wakeme);
+        assert_eq!(t.dispatch(), None);
+        t.ready(p);
+        assert_eq!(t.procs[p].state, ProcState::Ready);
+        assert_eq!(t.dispatch(), Some(p));
+    }
+
+    #[test]
+    fn not_run_until_switched_out() {
+        let mut t = ProcTable::new();
+        let p = t.add("p", PRI_NORMAL, nop, 0).unwrap();
+        let q = t.add("q", PRI_NORMAL - 1, nop, 0).unwrap();
+        assert_eq!(t.dispatch(), Some(p));
+
+        // Readied by another CPU before its context has been saved
+        assert!(t.block(p));
+        t.ready(p);
+        assert_eq!(t.dispatch(), Some(q));
+        assert_eq!(t.dispatch(), None);
+        t.switched_out(p);
+        assert_eq!(t.dispatch(), Some(p));
+    }
+
+    #[test]
+    fn tick_preempts() {
+        let mut t = ProcTable::new();
+        let p = t.add("p", PRI_NORMAL, nop, 0).unwrap();
+        assert_eq!(t.dispatch(), Some(p));
+        assert!(!(0..2 * QUANTUM).any(|_| t.tick(p)), "preempted with nothing else ready");
+
+        // An equal priority process gets the CPU once the quantum is used up
+        let q = t.add("q", PRI_NORMAL, nop, 0).unwrap();
+        t.switched_out(p);
+        assert_eq!(t.dispatch(), Some(q));
+        assert!(!(1..QUANTUM).any(|_| t.tick(q)));
+        assert!(t.tick(q));
+
+        // A higher priority process gets it straight away
+        t.switched_out(q);
+        assert_eq!(t.dispatch(), Some(p));
+        t.add("high", PRI_KPROC, nop, 0).unwrap();
+        assert!(t.tick(p));
+        assert_eq!(t.info(p).ticks, 2 * QUANTUM + 1);
+    }
+
+    #[test]
+    fn preempt_waits_for_locks() {
+        let m = Mach::new();
+        assert!(!m.preempting(0));
+
+        // The request is kept until the process lets go of its locks
+        m.need_resched.store(true, Ordering::Relaxed);
+        assert!(!m.preempting(1));
+        assert!(!m.preempting(2));
+        assert!(m.preempting(0));
+        assert!(!m.preempting(0));
+    }
+
+    #[test]
+    fn exited_slots_are_reused() {
+        let mut t = ProcTable::new();
+        let slots: Vec<_> = (0..NPROC).map(|_| t.add("p", PRI_NORMAL, nop, 0).unwrap()).collect();
+        assert_eq!(t.add("full", PRI_NORMAL, nop, 0), None);
+
+        let p = t.dispatch().unwrap();
+        assert_eq!(p, slots[0]);
+        t.procs[p].state = ProcState::Moribund;
+        t.switched_out(p);
+        assert_eq!(t.procs[p].state, ProcState::Dead);
+        assert_eq!(t.add("new", PRI_NORMAL, nop, 0), Some(p));
+        assert_eq!(t.info(p).pid, NPROC + 1);
+    }
+
+    #[test]
+    fn edf_runs_first_by_deadline() {
+        let mut t = ProcTable::new();
+        let kproc = t.add("kproc", PRI_KPROC, nop, 0).unwrap();
+        let a = t.add("a", PRI_NORMAL, nop, 0).unwrap();
+        let b = t.add("b", PRI_NORMAL, nop, 0).unwrap();
+        for cmd in ["period 100ms", "deadline 50ms", "cost 20ms", "admit"] {
+            t.ctl(a, cmd).unwrap();
+        }
+        for cmd in ["period 100ms", "cost 40ms", "admit"] {
+            t.ctl(b, cmd).unwrap();
+        }
+        let c = t.add("c", PRI_NORMAL, nop, 0).unwrap();
+        for cmd in ["period 100ms", "cost 50ms"] {
+            t.ctl(c, cmd).unwrap();
+        }
+        assert_eq!(t.ctl(c, "admit"), Err("edf: not admissible"));
+        assert_eq!(t.ctl(a, "cost 10ms"), Err("edf: expel before changing parameters"));
+        assert_eq!(t.info(a).edf, Some(EdfParams { period: 10, deadline: 5, cost: 2 }));
+        assert_eq!(t.info(b).edf, Some(EdfParams { period: 10, deadline: 10, cost: 4 }));
+
+        // a has the earliest deadline, so preempts b
+        assert_eq!(t.dispatch(), Some(a));
+        assert_eq!(t.dispatch(), Some(b));
+        t.switched_out(a);
+        assert!(t.tick(b));
+        t.switched_out(b);
+
+        // a is throttled after using its cost, then b, until the next period
+        assert_eq!(t.dispatch(), Some(a));
+        t.clock();
+        assert!(!t.tick(a));
+        t.clock();
+        assert!(t.tick(a));
+        t.switched_out(a);
+        assert_eq!(t.dispatch(), Some(b));
+        for _ in 0..2 {
+            t.clock();
+            assert!(!t.tick(b));
+        }
+        t.clock();
+        assert!(t.tick(b));
+        t.switched_out(b);
+        assert_eq!(t.dispatch(), Some(kproc));
+
+        // Once expelled, b is time-sharing again
+        t.ctl(b, "expel").unwrap();
+        assert_eq!(t.info(b).edf, None);
+        assert_eq!(t.dispatch(), Some(c));
+        assert_eq!(t.dispatch(), Some(b));
+    }
+
+    #[test]
+    fn edf_counts_misses() {
+        let mut t = ProcTable::new();
+        let a = t.add("a", PRI_NORMAL, nop, 0).unwrap();
+        let b = t.add("b", PRI_NORMAL, nop, 0).unwrap();
+        for cmd in ["period 40ms", "cost 20ms", "admit"] {
+            t.ctl(a, cmd).unwrap();
+            t.ctl(b, cmd).unwrap();
+        }
+
+        // a hogs the CPU through b's deadline
+        assert_eq!(t.dispatch(), Some(a));
+        for _ in 0..4 {
+            t.clock();
+        }
+        assert_eq!(t.info(b).misses, 1);
+        assert_eq!(t.info(a).misses, 1);
+
+        // Blocked at the deadline isn't a miss
+        t.switched_out(a);
+        assert!(t.block(b));
+        for _ in 0..4 {
+            t.clock();
+        }
+        assert_eq!(t.info(b).misses, 1);
+        assert_eq!(t.info(a).misses, 2);
+    }
+
+    #[test]
+    fn exited_kproc_stack_is_returned() {
+        let mut t = ProcTable::new();
+        let p = t.add("kproc", PRI_KPROC, nop, 0).unwrap();
+        let kstack = NonNull::dangling();
+        t.procs[p].kstack = Some(kstack);
+        t.procs[p].kproc = true;
+        let info = t.info(p);
+        assert!(info.kproc);
+        assert_eq!((info.name, info.state, info.priority), ("kproc", ProcState::Ready, PRI_KPROC));
+
+        assert_eq!(t.dispatch(), Some(p));
+        assert!(t.switched_out(p).is_none());
+        assert_eq!(t.dispatch(), Some(p));
+        t.procs[p].state = ProcState::Moribund;
+        assert_eq!(t.switched_out(p).unwrap().kstack, Some(kstack));
+        assert_eq!(t.procs[p].kstack, None);
+    }
+
+    /// A context switch for processes that are created but never run.
+    struct NoSwitch;
+
+    impl Arch for NoSwitch {
+        const KSTACK_SIZE: usize = 4096;
+
+        unsafe fn swtch(_save: &mut Context, _next: &mut Context) {
+            unreachable!("sched: test processes don't run");
+        }
+
+        fn init_context(_ctx: &mut Context, _start: extern "C" fn() -> !, _stack_top: usize) {}
+    }
+
+    /// User memory that counts the copies made of it, and those that share
+    /// it.
+    #[derive(Default)]
+    struct Mem {
+        copies: AtomicUsize,
+        sharers: AtomicUsize,
+    }
+
+    impl UserMem for Mem {
+        fn switch_to(&self) {}
+
+        fn validaddr(&self, _va: usize, _len: usize, _perm: Perm) -> crate::Result<()> {
+            Ok(())
+        }
+
+        fn fork(&self) -> crate::Result<Arc<dyn UserMem>> {
+            self.copies.fetch_add(1, Ordering::Relaxed);
+            Ok(Arc::new(Mem::default()))
+        }
+
+        fn fork_shared(self: Arc<Self>) -> crate::Result<Arc<dyn UserMem>> {
+            self.sharers.fetch_add(1, Ordering::Relaxed);
+            Ok(Arc::new(Mem::default()))
+        }
+    }
+
+    fn slot_of(pid: usize) -> usize {
+        PROCS.lock().procs.iter().position(|p| p.pid == pid && p.state != ProcState::Dead).unwrap()
+    }
+
+    fn info_of(pid: usize) -> ProcInfo {
+        procs().into_iter().find(|p| p.pid == pid).unwrap()
+    }
+
+    #[test]
+    fn rfork_makes_child() {
+        // Without sched's hooks, so other tests' locks don't use the table
+        let ops = const {
+            &ArchOps {
+                kstack_size: NoSwitch::KSTACK_SIZE,
+                swtch: NoSwitch::swtch,
+                init_context: NoSwitch::init_context,
+                set_kstack: NoSwitch::set_kstack,
+            }
+        };
+        ARCH.store(ptr::from_ref(ops).cast_mut(), Ordering::Release);
+        let mem = Arc::new(Mem::default());
+        let parent = {
+            let mut procs = PROCS.lock();
+            let slot = procs.add("parent", PRI_NORMAL, nop, 0).unwrap();
+            procs.procs[slot].groups = Groups { mem: Some(mem.clone()), ..Groups::new() };
+            slot
+        };
+        let ppid = PROCS.lock().procs[parent].pid;
+
+        // The child calls entry(arg) on its own stack, with a copy of memory
+        let pid = fork_slot(parent, RforkFlags::RFPROC, nop, 42).unwrap();
+        let info = info_of(pid);
+        assert_eq!(
+            (info.name, info.state, info.priority, info.kproc, info.parent),
+            ("parent", ProcState::Ready, PRI_NORMAL, false, Some(ppid))
+        );
+        let child = slot_of(pid);
+        {
+            let procs = PROCS.lock();
+            assert_eq!(procs.procs[child].arg, 42);
+            assert!(procs.procs[child].kstack.is_some());
+            let (pmem, cmem) = (&procs.procs[parent].groups.mem, &procs.procs[child].groups.mem);
+            assert!(!Arc::ptr_eq(pmem.as_ref().unwrap(), cmem.as_ref().unwrap()));
+        }
+        assert_eq!(mem.copies.load(Ordering::Relaxed), 1);
+
+        // Sharing memory but for the stack, and leaving no wait record
+        let flags = RforkFlags::RFPROC | RforkFlags::RFMEM | RforkFlags::RFNOWAIT;
+        let sharer = fork_slot(parent, flags, nop, 0).unwrap();
+        assert_eq!(info_of(sharer).parent, None);
+        let sharer = slot_of(sharer);
+        {
+            let procs = PROCS.lock();
+            let (pmem, smem) = (&procs.procs[parent].groups.mem, &procs.procs[sharer].groups.mem);
+            assert!(!Arc::ptr_eq(pmem.as_ref().unwrap(), smem.as_ref().unwrap()));
+        }
+        assert_eq!(mem.copies.load(Ordering::Relaxed), 1);
+        assert_eq!(mem.sharers.load(Ordering::Relaxed), 1);
+
+        // Without RFPROC, the caller's own groups change
+        let noteid = PROCS.lock().procs[parent].groups.noteid;
+        let nprocs = procs().len();
+        assert_eq!(fork_slot(parent, RforkFlags::RFNOTEG, nop, 0), Ok(0));
+        assert_ne!(PROCS.lock().procs[parent].groups.noteid, noteid);
+        assert!(fork_slot(parent, RforkFlags::RFMEM, nop, 0).is_err());
+        assert_eq!(procs().len(), nprocs);
+
+        let dead: Vec<Proc> = {
+            let mut procs = PROCS.lock();
+            [parent, child, sharer]
+                .into_iter()
+                .map(|slot| {
+                    procs.unqueue(slot);
+                    core::mem::replace(&mut procs.procs[slot], Proc::DEAD)
+                })
+                .collect()
+        };
+        for kstack in dead.iter().filter_map(|p| p.kstack) {
+            unsafe { dealloc(kstack.as_ptr(), kstack_layout()) };
+        }
+    }
+}
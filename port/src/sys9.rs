@@ -0,0 +1,289 @@
+//! sys9 describes the system call interface of 9front, so that its amd64
+//! and arm64 binaries can run unmodified: the system call numbers and
+//! arguments from /sys/src/libc/9syscall/sys.h, how each architecture passes
+//! them, the Tos page at the top of the stack, and error strings.
+//!
+//! On both architectures the C library's system call stubs store their
+//! first argument on the stack alongside the rest, then trap with the
+//! system call number in the register used for the first argument: BP with
+//! SYSCALL on amd64, R0 with SVC 0 on arm64.  So the arguments are in 8 byte
+//! slots on the user stack, starting just above the return address at the
+//! stack pointer.  The result comes back in AX on amd64 and R0 on arm64.
+//!
+//! A system call that fails returns -1 and sets the process's error string,
+//! which the process fetches with errstr.
+
+use crate::mem::PAGE_SIZE_4K;
+
+pub const SYSR1: usize = 0;
+pub const _ERRSTR: usize = 1;
+pub const BIND: usize = 2;
+pub const CHDIR: usize = 3;
+pub const CLOSE: usize = 4;
+pub const DUP: usize = 5;
+pub const ALARM: usize = 6;
+pub const EXEC: usize = 7;
+pub const EXITS: usize = 8;
+pub const _FSESSION: usize = 9;
+pub const FAUTH: usize = 10;
+pub const _FSTAT: usize = 11;
+pub const SEGBRK: usize = 12;
+pub const _MOUNT: usize = 13;
+pub const OPEN: usize = 14;
+pub const _READ: usize = 15;
+pub const OSEEK: usize = 16;
+pub const SLEEP: usize = 17;
+pub const _STAT: usize = 18;
+pub const RFORK: usize = 19;
+pub const _WRITE: usize = 20;
+pub const PIPE: usize = 21;
+pub const CREATE: usize = 22;
+pub const FD2PATH: usize = 23;
+pub const BRK_: usize = 24;
+pub const REMOVE: usize = 25;
+pub const _WSTAT: usize = 26;
+pub const _FWSTAT: usize = 27;
+pub const NOTIFY: usize = 28;
+pub const NOTED: usize = 29;
+pub const SEGATTACH: usize = 30;
+pub const SEGDETACH: usize = 31;
+pub const SEGFREE: usize = 32;
+pub const SEGFLUSH: usize = 33;
+pub const RENDEZVOUS: usize = 34;
+pub const UNMOUNT: usize = 35;
+pub const _WAIT: usize = 36;
+pub const SEMACQUIRE: usize = 37;
+pub const SEMRELEASE: usize = 38;
+pub const SEEK: usize = 39;
+pub const FVERSION: usize = 40;
+pub const ERRSTR: usize = 41;
+pub const STAT: usize = 42;
+pub const FSTAT: usize = 43;
+pub const WSTAT: usize = 44;
+pub const FWSTAT: usize = 45;
+pub const MOUNT: usize = 46;
+pub const AWAIT: usize = 47;
+pub const PREAD: usize = 50;
+pub const PWRITE: usize = 51;
+pub const TSEMACQUIRE: usize = 52;
+pub const _NSEC: usize = 53;
+
+/// One more than the highest system call number.
+pub const NSYSCALL: usize = 54;
+
+/// Offset from the user stack pointer of the first argument's slot.
+pub const ARG_OFFSET: usize = 8;
+
+/// Size of each argument's slot on the stack.
+pub const ARG_SLOT: usize = 8;
+
+/// The type of a system call argument.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Arg {
+    Int,   // int, in the low 32 bits of its slot
+    Ulong, // ulong, also 32 bits
+    Vlong, // vlong, the whole slot
+    Ptr,   // User address, which may be nil
+    Str,   // User address of a NUL-terminated string
+}
+
+/// The name and arguments of a system call.
+#[derive(Debug, PartialEq)]
+pub struct Syscall {
+    pub name: &'static str,
+    pub args: &'static [Arg],
+}
+
+const fn sys(name: &'static str, args: &'static [Arg]) -> Option<Syscall> {
+    Some(Syscall { name, args })
+}
+
+use Arg::*;
+
+/// 9front's system calls, by number.  Those whose names start with an
+/// underscore are obsolete, and are only used by old binaries.  Seek and
+/// nsec return their vlong results through a pointer passed as an extra
+/// first argument.
+pub static SYSCALLS: [Option<Syscall>; NSYSCALL] = [
+    sys("sysr1", &[]),
+    sys("_errstr", &[Ptr]),
+    sys("bind", &[Str, Str, Int]),
+    sys("chdir", &[Str]),
+    sys("close", &[Int]),
+    sys("dup", &[Int, Int]),
+    sys("alarm", &[Ulong]),
+    sys("exec", &[Str, Ptr]),
+    sys("exits", &[Ptr]),
+    sys("_fsession", &[Int, Ptr, Int]),
+    sys("fauth", &[Int, Str]),
+    sys("_fstat", &[Int, Ptr]),
+    sys("segbrk", &[Ptr, Ptr]),
+    sys("_mount", &[Int, Str, Int, Str]),
+    sys("open", &[Str, Int]),
+    sys("_read", &[Int, Ptr, Int]),
+    sys("oseek", &[Int, Int, Int]),
+    sys("sleep", &[Int]),
+    sys("_stat", &[Str, Ptr]),
+    sys("rfork", &[Int]),
+    sys("_write", &[Int, Ptr, Int]),
+    sys("pipe", &[Ptr]),
+    sys("create", &[Str, Int, Ulong]),
+    sys("fd2path", &[Int, Ptr, Int]),
+    sys("brk_", &[Ptr]),
+    sys("remove", &[Str]),
+    sys("_wstat", &[Str, Ptr]),
+    sys("_fwstat", &[Int, Ptr]),
+    sys("notify", &[Ptr]),
+    sys("noted", &[Int]),
+    sys("segattach", &[Int, Str, Ptr, Ulong]),
+    sys("segdetach", &[Ptr]),
+    sys("segfree", &[Ptr, Ulong]),
+    sys("segflush", &[Ptr, Ulong]),
+    sys("rendezvous", &[Ptr, Ptr]),
+    sys("unmount", &[Ptr, Str]),
+    sys("_wait", &[Ptr]),
+    sys("semacquire", &[Ptr, Int]),
+    sys("semrelease", &[Ptr, Int]),
+    sys("seek", &[Ptr, Int, Vlong, Int]),
+    sys("fversion", &[Int, Int, Ptr, Int]),
+    sys("errstr", &[Ptr, Ulong]),
+    sys("stat", &[Str, Ptr, Int]),
+    sys("fstat", &[Int, Ptr, Int]),
+    sys("wstat", &[Str, Ptr, Int]),
+    sys("fwstat", &[Int, Ptr, Int]),
+    sys("mount", &[Int, Int, Str, Int, Str]),
+    sys("await", &[Ptr, Int]),
+    None,
+    None,
+    sys("pread", &[Int, Ptr, Int, Vlong]),
+    sys("pwrite", &[Int, Ptr, Int, Vlong]),
+    sys("tsemacquire", &[Ptr, Ulong]),
+    sys("_nsec", &[Ptr]),
+];
+
+/// The system call with the given number, if there is one.
+pub fn syscall(n: usize) -> Option<&'static Syscall> {
+    SYSCALLS.get(n)?.as_ref()
+}
+
+/// How 9front lays out executables for an architecture.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Abi {
+    pub magic: u32,    // a.out magic number
+    pub utzero: usize, // Where the text segment, and the a.out header, go
+    pub round: usize,  // Alignment of the data segment
+}
+
+const HDR_MAGIC: u32 = 0x00008000; // An extra 8 byte entry point follows the header
+
+const fn magic(b: u32) -> u32 {
+    HDR_MAGIC | ((4 * b) * b + 7)
+}
+
+/// amd64 (6l): S_MAGIC.  Data starts on the next 2MiB boundary after text.
+pub const AMD64: Abi = Abi { magic: magic(26), utzero: 0x200000, round: 0x200000 };
+
+/// arm64 (7l): R_MAGIC.
+pub const ARM64: Abi = Abi { magic: magic(28), utzero: 0x10000, round: 0x10000 };
+
+/// Whether the magic number says the header is followed by a 64-bit entry
+/// point.
+pub const fn has_long_entry(magic: u32) -> bool {
+    magic & HDR_MAGIC != 0
+}
+
+/// Size of the Tos, the structure at the very top of the stack that the
+/// kernel shares with the process: profiling state, cycle counts and the
+/// pid.  Its address is passed to the process in the result register at
+/// exec.
+pub const TOS_SIZE: usize = 72;
+
+/// Offset of the pid, a ulong, within the Tos.
+pub const TOS_PID: usize = 64;
+
+/// Longest error string, including its NUL.
+pub const ERRMAX: usize = 128;
+
+const _: () = assert!(ERRMAX + TOS_SIZE < PAGE_SIZE_4K);
+
+/// A process's error string, NUL-terminated.
+#[derive(Clone, Copy)]
+pub struct ErrStr([u8; ERRMAX]);
+
+impl ErrStr {
+    pub const EMPTY: ErrStr = ErrStr([0; ERRMAX]);
+
+    pub fn as_bytes(&self) -> &[u8] {
+        let len = self.0.iter().position(|&b| b == 0).unwrap_or(ERRMAX);
+        &self.0[..len]
+    }
+
+    /// Set the string, truncating it to fit, without splitting a character.
+    pub fn set(&mut self, msg: &str) {
+        let mut len = msg.len().min(ERRMAX - 1);
+        while !msg.is_char_boundary(len) {
+            len -= 1;
+        }
+        self.set_bytes(&msg.as_bytes()[..len]);
+    }
+
+    fn set_bytes(&mut self, msg: &[u8]) {
+        let msg = &msg[..msg.iter().position(|&b| b == 0).unwrap_or(msg.len())];
+        let len = msg.len().min(ERRMAX - 1);
+        self.0.fill(0);
+        self.0[..len].copy_from_slice(&msg[..len]);
+    }
+
+    /// Plan 9's errstr: exchange the string with the NUL-terminated one in
+    /// buf.  What's returned in buf is truncated to fit, and always
+    /// terminated.
+    pub fn swap(&mut self, buf: &mut [u8]) {
+        let old = *self;
+        self.set_bytes(buf);
+        if let Some(last) = buf.len().checked_sub(1) {
+            let old = old.as_bytes();
+            let len = old.len().min(last);
+            buf[..len].copy_from_slice(&old[..len]);
+            buf[len] = 0;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn syscall_table() {
+        assert_eq!(AMD64.magic, 0x8a97);
+        assert_eq!(ARM64.magic, 0x8c47);
+        assert_eq!(syscall(EXEC).unwrap().name, "exec");
+        assert_eq!(syscall(ERRSTR).unwrap().args, [Ptr, Ulong]);
+        assert_eq!(syscall(PWRITE).unwrap().name, "pwrite");
+        assert_eq!(syscall(_NSEC).unwrap().name, "_nsec");
+        assert!(syscall(48).is_none());
+        assert!(syscall(NSYSCALL).is_none());
+    }
+
+    #[test]
+    fn errstr_swaps() {
+        let mut e = ErrStr::EMPTY;
+        e.set("file does not exist");
+        let mut buf = [0u8; 64];
+        buf[..5].copy_from_slice(b"mine\0");
+        e.swap(&mut buf);
+        assert_eq!(&buf[..20], b"file does not exist\0");
+        assert_eq!(e.as_bytes(), b"mine");
+
+        // Returned strings are truncated to the buffer
+        let mut small = [b'x'; 3];
+        e.swap(&mut small);
+        assert_eq!(&small, b"mi\0");
+        assert_eq!(e.as_bytes(), b"xxx");
+
+        // Set strings are truncated on a character boundary
+        e.set(&"é".repeat(ERRMAX));
+        assert_eq!(e.as_bytes().len(), ERRMAX - 2);
+        assert!(str::from_utf8(e.as_bytes()).is_ok());
+    }
+}
@@ -0,0 +1,367 @@
+//! The system call table.  Each architecture's trap handler wraps its trap
+//! frame in a `Frame`, which finds the system call number and the raw
+//! argument slots wherever that architecture's convention puts them (see
+//! sys9), and passes it to `syscall`.  The arguments are read into typed
+//! values, according to sys9's description of the call, before its handler
+//! runs.  The handler's result, or -1 if it failed, goes back into the
+//! frame's result register, and a failure also sets the process's error
+//! string.
+
+use alloc::boxed::Box;
+use alloc::string::String;
+use alloc::vec::Vec;
+
+use crate::exec::{MAX_ARGS_SIZE, Start};
+use crate::mem::PAGE_SIZE_4K;
+use crate::pgrp::RforkFlags;
+use crate::sys9::{self, Arg, ERRMAX, NSYSCALL};
+use crate::usermem::{EBADADDR, copyin, copyout};
+use crate::{println, sched};
+
+/// Longest string argument, including its NUL, other than exec's.
+const MAX_STR: usize = PAGE_SIZE_4K;
+
+/// A user's registers at the time of a system call, implemented by each
+/// architecture over its trap frame.
+pub trait Frame {
+    /// Number of the system call being made.
+    fn sysno(&self) -> usize;
+
+    /// Raw contents of the slot holding the given argument, read with
+    /// usermem if it's in user memory.
+    fn arg(&self, i: usize) -> crate::Result<u64>;
+
+    /// Set what the system call returns to the user.
+    fn set_ret(&mut self, ret: usize);
+
+    /// Replace the user program with the named one, and set the frame up to
+    /// return to its entry point, on its stack, with argv in the register
+    /// for the second argument.  The first is set from the system call's
+    /// result.  The frame is unchanged if the program can't be loaded.
+    fn exec(&mut self, name: &[u8], argv: &[&[u8]]) -> crate::Result<Start>;
+
+    /// A copy of the frame, for a child made by rfork to return to user
+    /// mode with.
+    fn fork(&self) -> Box<dyn Frame + Send>;
+
+    /// Return to user mode with the frame's registers, on the current
+    /// process's kernel stack.
+    fn touser(self: Box<Self>) -> !;
+}
+
+/// A system call argument, read as the type sys9 says it has.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Value {
+    Int(i32),
+    Ulong(u32),
+    Vlong(i64),
+    Ptr(usize),
+    Str(Vec<u8>), // Without its NUL
+}
+
+/// The arguments of a system call.  The accessors panic if the argument
+/// isn't of the type asked for, as that means the table in sys9 and the
+/// handler disagree.
+#[derive(Debug, PartialEq)]
+pub struct Args(Vec<Value>);
+
+impl Args {
+    /// Read the arguments of the given types from the frame, and the strings
+    /// they point to from user memory.
+    fn fetch(frame: &dyn Frame, types: &[Arg]) -> crate::Result<Args> {
+        let mut args = Vec::with_capacity(types.len());
+        for (i, ty) in types.iter().enumerate() {
+            let raw = frame.arg(i)?;
+            args.push(match ty {
+                Arg::Int => Value::Int(raw as i32),
+                Arg::Ulong => Value::Ulong(raw as u32),
+                Arg::Vlong => Value::Vlong(raw as i64),
+                Arg::Ptr => Value::Ptr(raw as usize),
+                Arg::Str => Value::Str(user_str(raw as usize, MAX_STR)?),
+            });
+        }
+        Ok(Args(args))
+    }
+
+    pub fn int(&self, i: usize) -> i32 {
+        match self.0[i] {
+            Value::Int(v) => v,
+            ref v => panic!("syscall: argument {i} is {v:?}, not an int"),
+        }
+    }
+
+    pub fn ulong(&self, i: usize) -> u32 {
+        match self.0[i] {
+            Value::Ulong(v) => v,
+            ref v => panic!("syscall: argument {i} is {v:?}, not a ulong"),
+        }
+    }
+
+    pub fn vlong(&self, i: usize) -> i64 {
+        match self.0[i] {
+            Value::Vlong(v) => v,
+            ref v => panic!("syscall: argument {i} is {v:?}, not a vlong"),
+        }
+    }
+
+    pub fn ptr(&self, i: usize) -> usize {
+        match self.0[i] {
+            Value::Ptr(v) => v,
+            ref v => panic!("syscall: argument {i} is {v:?}, not a pointer"),
+        }
+    }
+
+    pub fn str(&self, i: usize) -> &[u8] {
+        match &self.0[i] {
+            Value::Str(v) => v,
+            v => panic!("syscall: argument {i} is {v:?}, not a string"),
+        }
+    }
+}
+
+/// Read a NUL-terminated string of fewer than max bytes from user memory.
+fn user_str(va: usize, max: usize) -> crate::Result<Vec<u8>> {
+    let mut s = Vec::new();
+    let mut b = [0u8];
+    loop {
+        copyin(va.checked_add(s.len()).ok_or(EBADADDR)?, &mut b)?;
+        match b[0] {
+            0 => return Ok(s),
+            _ if s.len() + 1 >= max => return Err("string too long"),
+            c => s.push(c),
+        }
+    }
+}
+
+/// Read a null-terminated array of pointers to strings from user memory,
+/// with no more than MAX_ARGS_SIZE bytes between them.
+fn user_strs(va: usize) -> crate::Result<Vec<Vec<u8>>> {
+    const WORD: usize = size_of::<u64>();
+    let mut strs = Vec::new();
+    let mut size = 0;
+    loop {
+        let mut p = [0u8; WORD];
+        copyin(va.checked_add(strs.len() * WORD).ok_or(EBADADDR)?, &mut p)?;
+        match u64::from_le_bytes(p) as usize {
+            0 => return Ok(strs),
+            s => {
+                let s = user_str(s, MAX_ARGS_SIZE - size)?;
+                size += WORD + s.len() + 1;
+                if size > MAX_ARGS_SIZE {
+                    return Err("exec: arguments too long");
+                }
+                strs.push(s);
+            }
+        }
+    }
+}
+
+/// A system call's implementation, returning its result.
+pub type Handler = fn(&mut dyn Frame, &Args) -> crate::Result<usize>;
+
+/// Handlers for the system calls that are implemented, by number.
+static HANDLERS: [Option<Handler>; NSYSCALL] = {
+    let mut t: [Option<Handler>; NSYSCALL] = [None; NSYSCALL];
+    t[sys9::SYSR1] = Some(sysr1);
+    t[sys9::EXEC] = Some(sysexec);
+    t[sys9::RFORK] = Some(sysrfork);
+    t[sys9::EXITS] = Some(sysexits);
+    t[sys9::ERRSTR] = Some(syserrstr);
+    t
+};
+
+/// Reserved for debugging.
+fn sysr1(_frame: &mut dyn Frame, _args: &Args) -> crate::Result<usize> {
+    Ok(0)
+}
+
+/// exec(name, argv): returns the Tos for a Plan 9 program, or argc.
+fn sysexec(frame: &mut dyn Frame, args: &Args) -> crate::Result<usize> {
+    let argv = user_strs(args.ptr(1))?;
+    let argv: Vec<&[u8]> = argv.iter().map(Vec::as_slice).collect();
+    let start = frame.exec(args.str(0), &argv)?;
+    Ok(start.tos.unwrap_or(start.argc))
+}
+
+/// rfork(flags): returns the child's pid, or 0 without RFPROC.  The child
+/// returns from the same call, with 0.
+fn sysrfork(frame: &mut dyn Frame, args: &Args) -> crate::Result<usize> {
+    let flags = RforkFlags::from_bits(args.int(0) as u32).ok_or("rfork: bad flags")?;
+    if !flags.contains(RforkFlags::RFPROC) {
+        return sched::rfork(flags, |_| {}, 0);
+    }
+    let mut child = frame.fork();
+    child.set_ret(0);
+    let child = Box::into_raw(Box::new(child));
+    sched::rfork(flags, forkret, child as usize).inspect_err(|_| {
+        drop(unsafe { Box::from_raw(child) });
+    })
+}
+
+/// Where an rforked child starts, with the frame it returns to user mode
+/// with.
+fn forkret(frame: usize) {
+    let frame = unsafe { Box::from_raw(frame as *mut Box<dyn Frame + Send>) };
+    (*frame).touser();
+}
+
+/// exits(msg), where a nil msg means success.  Outside a process there's
+/// nothing to switch to, so the CPU just stops.
+fn sysexits(_frame: &mut dyn Frame, args: &Args) -> crate::Result<usize> {
+    if sched::getpid() != 0 {
+        sched::pexit();
+    }
+    let msg = match args.ptr(0) {
+        0 => Vec::new(),
+        va => user_str(va, ERRMAX).unwrap_or_default(),
+    };
+    println!("exits: {}", String::from_utf8_lossy(&msg));
+    loop {
+        core::hint::spin_loop();
+    }
+}
+
+/// errstr(buf, n): exchange the process's error string with buf's.
+fn syserrstr(_frame: &mut dyn Frame, args: &Args) -> crate::Result<usize> {
+    let mut buf = [0u8; ERRMAX];
+    let buf = &mut buf[..(args.ulong(1) as usize).min(ERRMAX)];
+    copyin(args.ptr(0), buf)?;
+    sched::errstr(buf)?;
+    copyout(args.ptr(0), buf)?;
+    Ok(0)
+}
+
+fn dispatch(frame: &mut dyn Frame) -> crate::Result<usize> {
+    let n = frame.sysno();
+    let call = sys9::syscall(n).ok_or("bad system call number")?;
+    let handler = HANDLERS[n].ok_or("system call not implemented")?;
+    let args = Args::fetch(frame, call.args)?;
+    handler(frame, &args)
+}
+
+/// Make the system call described by frame, leaving its result in the
+/// frame.  A failed call returns -1, with the error in the process's error
+/// string.
+pub fn syscall(frame: &mut dyn Frame) {
+    let ret = dispatch(frame).unwrap_or_else(|err| {
+        sched::seterrstr(err);
+        usize::MAX
+    });
+    frame.set_ret(ret);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::exec::Perm;
+    use crate::usermem::testhooks;
+
+    /// A frame with its arguments in a vector.  User memory is the test
+    /// hooks'.
+    #[derive(Clone)]
+    struct TestFrame {
+        sysno: usize,
+        args: Vec<u64>,
+        ret: Option<usize>,
+        execed: Option<(Vec<u8>, Vec<Vec<u8>>)>,
+    }
+
+    const BASE: usize = 0x1000;
+
+    impl TestFrame {
+        fn new(sysno: usize, args: &[u64]) -> Self {
+            Self { sysno, args: args.to_vec(), ret: None, execed: None }
+        }
+    }
+
+    impl Frame for TestFrame {
+        fn sysno(&self) -> usize {
+            self.sysno
+        }
+
+        fn arg(&self, i: usize) -> crate::Result<u64> {
+            self.args.get(i).copied().ok_or(EBADADDR)
+        }
+
+        fn set_ret(&mut self, ret: usize) {
+            self.ret = Some(ret);
+        }
+
+        fn exec(&mut self, name: &[u8], argv: &[&[u8]]) -> crate::Result<Start> {
+            let argv = argv.iter().map(|a| a.to_vec()).collect();
+            self.execed = Some((name.to_vec(), argv));
+            Ok(Start { entry: 0x10000, sp: 0x7000, argc: 2, argv: 0x7008, tos: None })
+        }
+
+        fn fork(&self) -> Box<dyn Frame + Send> {
+            Box::new(self.clone())
+        }
+
+        fn touser(self: Box<Self>) -> ! {
+            panic!("syscall: test frames can't return to user mode");
+        }
+    }
+
+    fn call(frame: &mut TestFrame) -> usize {
+        syscall(frame);
+        frame.ret.unwrap()
+    }
+
+    #[test]
+    fn fetches_typed_args() {
+        testhooks::install();
+        testhooks::map(BASE..BASE + 8, Perm::R, b"abc\0xxxx");
+        let frame = TestFrame::new(0, &[(-2i64) as u64, 0x1_0000_0005, BASE as u64, 7]);
+        let types = [Arg::Int, Arg::Ulong, Arg::Str, Arg::Vlong];
+        let args = Args::fetch(&frame, &types).unwrap();
+        assert_eq!(
+            (args.int(0), args.ulong(1), args.str(2), args.vlong(3)),
+            (-2, 5, &b"abc"[..], 7)
+        );
+
+        // Strings must be terminated within user memory
+        let frame = TestFrame::new(0, &[0, 0, BASE as u64 + 4, 0]);
+        assert_eq!(Args::fetch(&frame, &types).unwrap_err(), EBADADDR);
+        assert!(Args::fetch(&frame, &[Arg::Int; 5]).is_err());
+    }
+
+    #[test]
+    fn dispatches_by_number() {
+        testhooks::install();
+        assert_eq!(call(&mut TestFrame::new(sys9::SYSR1, &[])), 0);
+        assert_eq!(call(&mut TestFrame::new(48, &[])), usize::MAX);
+        assert_eq!(call(&mut TestFrame::new(NSYSCALL, &[])), usize::MAX);
+        assert_eq!(call(&mut TestFrame::new(sys9::BIND, &[])), usize::MAX);
+        // errstr needs a process
+        testhooks::map(BASE..BASE + 0x1000, Perm::R | Perm::W, &[]);
+        let mut frame = TestFrame::new(sys9::ERRSTR, &[BASE as u64, ERRMAX as u64]);
+        assert_eq!(call(&mut frame), usize::MAX);
+        // So does rfork, once its flags are checked
+        assert_eq!(call(&mut TestFrame::new(sys9::RFORK, &[1 << 31])), usize::MAX);
+        let mut frame = TestFrame::new(sys9::RFORK, &[RforkFlags::RFPROC.bits() as u64]);
+        assert_eq!(call(&mut frame), usize::MAX);
+    }
+
+    #[test]
+    fn exec_reads_name_and_argv() {
+        testhooks::install();
+        let mut mem = [0u8; 56];
+        mem[..10].copy_from_slice(b"/bin/echo\0");
+        mem[16..24].copy_from_slice(b"echo\0hi\0");
+        for (i, p) in [BASE + 16, BASE + 21, 0].into_iter().enumerate() {
+            mem[32 + i * 8..40 + i * 8].copy_from_slice(&(p as u64).to_le_bytes());
+        }
+        testhooks::map(BASE..BASE + mem.len(), Perm::R, &mem);
+
+        let mut frame = TestFrame::new(sys9::EXEC, &[BASE as u64, BASE as u64 + 32]);
+        assert_eq!(call(&mut frame), 2);
+        let (name, argv) = frame.execed.unwrap();
+        assert_eq!(name, b"/bin/echo");
+        assert_eq!(argv, [&b"echo"[..], b"hi"]);
+
+        // A bad argv pointer fails the call without execing anything
+        let mut frame = TestFrame::new(sys9::EXEC, &[BASE as u64, 0x10]);
+        assert_eq!(call(&mut frame), usize::MAX);
+        assert!(frame.execed.is_none());
+    }
+}
@@ -0,0 +1,294 @@
+//! usermem is how the kernel touches user memory on behalf of a system call,
+//! after Plan 9's validaddr.  A user range is first checked to lie within
+//! the segments of the current user address space, with the access needed,
+//! then copied by the architecture, with any fault that happens anyway,
+//! such as on memory the kernel mustn't touch for the user, turned into an
+//! error rather than a panic.
+//!
+//! Each architecture registers its implementation with `set_hooks` once it
+//! can access user memory.  Until then every user address is bad.
+
+use alloc::vec::Vec;
+use core::ops::Range;
+use core::ptr;
+use core::sync::atomic::{AtomicPtr, Ordering};
+
+use crate::exec::Perm;
+
+/// The error for any user range that can't be accessed.
+pub const EBADADDR: &str = "bad address";
+
+/// The access allowed to the pages of a user address space, as a sorted
+/// list of disjoint ranges.  Pages not in the list aren't mapped.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Segments(Vec<(Range<usize>, Perm)>);
+
+impl Segments {
+    pub const fn new() -> Self {
+        Self(Vec::new())
+    }
+
+    /// Record that the range allows perm, replacing whatever was recorded
+    /// for any part of it.
+    pub fn protect(&mut self, range: Range<usize>, perm: Perm) {
+        let mut segs = Vec::with_capacity(self.0.len() + 2);
+        for (r, p) in self.0.drain(..) {
+            if r.end <= range.start || r.start >= range.end {
+                segs.push((r, p));
+                continue;
+            }
+            if r.start < range.start {
+                segs.push((r.start..range.start, p));
+            }
+            if r.end > range.end {
+                segs.push((range.end..r.end, p));
+            }
+        }
+        if !range.is_empty() {
+            segs.push((range, perm));
+        }
+        segs.sort_by_key(|(r, _)| r.start);
+        self.0 = segs;
+    }
+
+    /// The segments and the user's permissions on them, in address order.
+    pub fn iter(&self) -> impl Iterator<Item = &(Range<usize>, Perm)> {
+        self.0.iter()
+    }
+
+    /// The segments of a child that shares memory with its parent, split at
+    /// stack, and whether the child shares each part.  As in Plan 9, the
+    /// stack, from stack up, is the one part the child gets its own copy of.
+    pub fn fork_shared(&self, stack: usize) -> impl Iterator<Item = (Range<usize>, Perm, bool)> {
+        self.0.iter().flat_map(move |(r, p)| {
+            let split = stack.clamp(r.start, r.end);
+            [(r.start..split, *p, true), (split..r.end, *p, false)]
+                .into_iter()
+                .filter(|(r, _, _)| !r.is_empty())
+        })
+    }
+
+    /// Check that every byte of the len bytes at va allows perm.  As with
+    /// Plan 9's validaddr, va must be mapped even if len is zero.
+    pub fn check(&self, va: usize, len: usize, perm: Perm) -> crate::Result<()> {
+        let end = va.checked_add(len.max(1)).ok_or(EBADADDR)?;
+        let mut at = va;
+        for (r, p) in &self.0 {
+            if at >= end {
+                break;
+            }
+            if r.end <= at {
+                continue;
+            }
+            if r.start > at || !p.contains(perm) {
+                return Err(EBADADDR);
+            }
+            at = r.end;
+        }
+        if at < end { Err(EBADADDR) } else { Ok(()) }
+    }
+}
+
+/// Architecture-specific access to the current user address space.
+pub struct UserHooks {
+    /// Checks the range lies within the current user address space's
+    /// segments, allowing perm.
+    pub validaddr: fn(usize, usize, Perm) -> crate::Result<()>,
+    /// Copies user memory at the address into the buffer, failing if it
+    /// faults.
+    pub copyin: fn(usize, &mut [u8]) -> crate::Result<()>,
+    /// Copies the data into user memory at the address, failing if it
+    /// faults.
+    pub copyout: fn(usize, &[u8]) -> crate::Result<()>,
+}
+
+static DEFAULT_HOOKS: UserHooks = UserHooks {
+    validaddr: |_, _, _| Err(EBADADDR),
+    copyin: |_, _| Err(EBADADDR),
+    copyout: |_, _| Err(EBADADDR),
+};
+
+static HOOKS: AtomicPtr<UserHooks> = AtomicPtr::new(ptr::addr_of!(DEFAULT_HOOKS).cast_mut());
+
+/// Registers the architecture's implementation of user memory access.
+pub fn set_hooks(hooks: &'static UserHooks) {
+    HOOKS.store(ptr::from_ref(hooks).cast_mut(), Ordering::Release);
+}
+
+fn hooks() -> &'static UserHooks {
+    unsafe { &*HOOKS.load(Ordering::Acquire) }
+}
+
+/// Check the len bytes at va are mapped in the current user address space,
+/// allowing perm.
+pub fn validaddr(va: usize, len: usize, perm: Perm) -> crate::Result<()> {
+    (hooks().validaddr)(va, len, perm)
+}
+
+/// Copy readable user memory at va into buf.
+pub fn copyin(va: usize, buf: &mut [u8]) -> crate::Result<()> {
+    validaddr(va, buf.len(), Perm::R)?;
+    (hooks().copyin)(va, buf)
+}
+
+/// Copy data into writable user memory at va.
+pub fn copyout(va: usize, data: &[u8]) -> crate::Result<()> {
+    validaddr(va, data.len(), Perm::W)?;
+    (hooks().copyout)(va, data)
+}
+
+/// Hooks for host tests.  Each thread has its own user memory, which starts
+/// out empty.  Copies fault on memory that's been unmapped behind the back
+/// of validaddr with `unmap`.
+#[cfg(test)]
+pub(crate) mod testhooks {
+    use super::*;
+    use alloc::collections::BTreeMap;
+    use core::cell::RefCell;
+
+    #[derive(Default)]
+    struct TestMem {
+        segments: Segments,
+        bytes: BTreeMap<usize, u8>,
+        unmapped: Vec<Range<usize>>,
+    }
+
+    std::thread_local! {
+        static MEM: RefCell<TestMem> = RefCell::new(TestMem::default());
+    }
+
+    fn faults(mem: &TestMem, va: usize, len: usize) -> bool {
+        mem.unmapped.iter().any(|r| r.start < va + len && va < r.end)
+    }
+
+    static TEST_HOOKS: UserHooks = UserHooks {
+        validaddr: |va, len, perm| MEM.with_borrow(|m| m.segments.check(va, len, perm)),
+        copyin: |va, buf| {
+            MEM.with_borrow(|m| {
+                if faults(m, va, buf.len()) {
+                    return Err("fault");
+                }
+                for (i, b) in buf.iter_mut().enumerate() {
+                    *b = m.bytes.get(&(va + i)).copied().unwrap_or(0);
+                }
+                Ok(())
+            })
+        },
+        copyout: |va, data| {
+            MEM.with_borrow_mut(|m| {
+                if faults(m, va, data.len()) {
+                    return Err("fault");
+                }
+                m.bytes.extend(data.iter().enumerate().map(|(i, &b)| (va + i, b)));
+                Ok(())
+            })
+        },
+    };
+
+    /// Use the test hooks, with this thread's user memory emptied.
+    pub fn install() {
+        set_hooks(&TEST_HOOKS);
+        MEM.set(TestMem::default());
+    }
+
+    /// Map the range with perm, and set the data at its start.
+    pub fn map(range: Range<usize>, perm: Perm, data: &[u8]) {
+        MEM.with_borrow_mut(|m| {
+            m.bytes.extend(data.iter().enumerate().map(|(i, &b)| (range.start + i, b)));
+            m.segments.protect(range, perm);
+        });
+    }
+
+    /// Make copies from the range fault, while validaddr still accepts it.
+    pub fn unmap(range: Range<usize>) {
+        MEM.with_borrow_mut(|m| m.unmapped.push(range));
+    }
+
+    pub fn read(va: usize, len: usize) -> Vec<u8> {
+        MEM.with_borrow(|m| (va..va + len).map(|a| m.bytes.get(&a).copied().unwrap_or(0)).collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec;
+
+    #[test]
+    fn segments_track_permissions() {
+        let mut segs = Segments::new();
+        segs.protect(0x1000..0x3000, Perm::R | Perm::X);
+        segs.protect(0x3000..0x5000, Perm::R | Perm::W);
+        segs.protect(0x8000..0x9000, Perm::R | Perm::W);
+        assert!(segs.check(0x1000, 0x1000, Perm::R).is_ok());
+        assert!(segs.check(0x2ff0, 0x20, Perm::R).is_ok(), "spans adjacent segments");
+        assert!(segs.check(0x2ff0, 0x20, Perm::W).is_err(), "text isn't writable");
+        assert!(segs.check(0x4ff0, 0x20, Perm::R).is_err(), "runs into a hole");
+        assert!(segs.check(0x0ff0, 0x20, Perm::R).is_err(), "starts in a hole");
+        assert!(segs.check(0x9000, 0, Perm::R).is_err(), "unmapped, even if empty");
+        assert!(segs.check(usize::MAX, 2, Perm::R).is_err());
+
+        // Changing part of a segment splits it
+        segs.protect(0x3800..0x4000, Perm::R);
+        assert_eq!(
+            segs,
+            Segments(vec![
+                (0x1000..0x3000, Perm::R | Perm::X),
+                (0x3000..0x3800, Perm::R | Perm::W),
+                (0x3800..0x4000, Perm::R),
+                (0x4000..0x5000, Perm::R | Perm::W),
+                (0x8000..0x9000, Perm::R | Perm::W),
+            ])
+        );
+        assert!(segs.check(0x3000, 0x1000, Perm::W).is_err());
+        assert!(segs.check(0x3000, 0x1000, Perm::R).is_ok());
+    }
+
+    #[test]
+    fn stack_isnt_shared() {
+        let mut segs = Segments::new();
+        segs.protect(0x1000..0x3000, Perm::R | Perm::X);
+        segs.protect(0x3000..0x5000, Perm::R | Perm::W);
+        segs.protect(0x8000..0x9000, Perm::R | Perm::W);
+        let rw = Perm::R | Perm::W;
+        assert_eq!(
+            segs.fork_shared(0x8000).collect::<Vec<_>>(),
+            vec![
+                (0x1000..0x3000, Perm::R | Perm::X, true),
+                (0x3000..0x5000, rw, true),
+                (0x8000..0x9000, rw, false),
+            ]
+        );
+
+        // A segment running into the stack is split
+        segs.protect(0x5000..0x8000, rw);
+        assert_eq!(
+            segs.fork_shared(0x7000).skip(2).collect::<Vec<_>>(),
+            vec![
+                (0x5000..0x7000, rw, true),
+                (0x7000..0x8000, rw, false),
+                (0x8000..0x9000, rw, false)
+            ]
+        );
+    }
+
+    #[test]
+    fn copies_check_and_recover() {
+        testhooks::install();
+        testhooks::map(0x1000..0x2000, Perm::R | Perm::X, b"text");
+        testhooks::map(0x2000..0x3000, Perm::R | Perm::W, b"data");
+
+        let mut buf = [0u8; 4];
+        copyin(0x1000, &mut buf).unwrap();
+        assert_eq!(&buf, b"text");
+        assert_eq!(copyout(0x1000, b"oops"), Err(EBADADDR), "wrote to text");
+        copyout(0x2ffe, b"ok").unwrap();
+        assert_eq!(testhooks::read(0x2ffe, 2), b"ok");
+        assert_eq!(copyout(0x2ffe, b"oops"), Err(EBADADDR), "wrote past the end");
+        assert_eq!(copyin(0, &mut buf), Err(EBADADDR), "read nil");
+
+        // A fault during the copy is an error too
+        testhooks::unmap(0x2000..0x3000);
+        assert!(copyin(0x2000, &mut buf).is_err());
+    }
+}
@@ -0,0 +1,251 @@
+/// vmem implements a simple arena allocator for ranges of virtual address
+/// space, in the spirit of Bonwick's vmem.  The arena tracks free spans in a
+/// fixed size array, sorted by address, so it doesn't require any
+/// allocations and can be used while manipulating the page tables.
+///
+/// Each allocation is followed by an unallocated guard gap, so that overruns
+/// of one mapping fault rather than silently corrupting the next.
+///
+/// Reference:
+///
+/// Jeff Bonwick and Jonathan Adams. 2001. Magazines and Vmem: Extending the
+/// Slab Allocator to Many CPUs and Arbitrary Resources.  USENIX Annual
+/// Technical Conference.
+use crate::maths::round_up2_usize;
+use crate::mem::VirtRange;
+
+#[derive(Debug, PartialEq)]
+pub enum VmemError {
+    InvalidSize,      // Zero sized, or overflowing request
+    InvalidAlignment, // Alignment isn't a power of 2 multiple of the quantum
+    OutOfSpace,       // No free span is large enough
+    TooFragmented,    // No room left to track free spans
+    NotAllocated,     // Attempt to free a range that isn't allocated
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct Span {
+    start: usize,
+    end: usize,
+}
+
+impl Span {
+    const fn empty() -> Self {
+        Span { start: 0, end: 0 }
+    }
+}
+
+/// An arena of virtual address space.  `MAX_SPANS` is the maximum number of
+/// discontiguous free spans the arena can track.
+pub struct VmemArena<const MAX_SPANS: usize> {
+    name: &'static str,
+    base: usize,
+    size: usize,
+    quantum: usize,          // All allocations are a multiple of this
+    guard: usize,            // Size of the unallocated gap after each allocation
+    free: [Span; MAX_SPANS], // Free spans, sorted by address, never adjacent
+    nfree: usize,            // Number of valid entries in free
+}
+
+impl<const MAX_SPANS: usize> VmemArena<MAX_SPANS> {
+    /// Create an arena covering `size` bytes from `base`, all of which is free.
+    /// `quantum` must be a power of 2, and `base`, `size` and `guard` must be
+    /// multiples of it.
+    pub const fn new(
+        name: &'static str,
+        base: usize,
+        size: usize,
+        quantum: usize,
+        guard: usize,
+    ) -> Self {
+        assert!(quantum.is_power_of_two());
+        assert!(base.is_multiple_of(quantum) && size.is_multiple_of(quantum));
+        assert!(guard.is_multiple_of(quantum));
+        assert!(MAX_SPANS > 0);
+        let mut free = [Span::empty(); MAX_SPANS];
+        free[0] = Span { start: base, end: base + size };
+        Self { name, base, size, quantum, guard, free, nfree: 1 }
+    }
+
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+
+    /// Allocate `size` bytes of address space aligned to `align`, which must
+    /// be a power of 2.  The size is rounded up to the quantum.  The returned
+    /// range excludes the guard gap.
+    pub fn alloc(&mut self, size: usize, align: usize) -> Result<VirtRange, VmemError> {
+        if size == 0 || size > self.size {
+            return Err(VmemError::InvalidSize);
+        }
+        if !align.is_power_of_two() {
+            return Err(VmemError::InvalidAlignment);
+        }
+        let align = align.max(self.quantum);
+        let size = round_up2_usize(size, self.quantum);
+        let need = size.checked_add(self.guard).ok_or(VmemError::InvalidSize)?;
+
+        // First fit
+        for i in 0..self.nfree {
+            let span = self.free[i];
+            let Some(start) = span.start.checked_add(align - 1).map(|s| s & !(align - 1)) else {
+                continue;
+            };
+            let Some(end) = start.checked_add(need) else {
+                continue;
+            };
+            if end > span.end {
+                continue;
+            }
+
+            let has_prefix = start > span.start;
+            let has_suffix = end < span.end;
+            match (has_prefix, has_suffix) {
+                (false, false) => self.remove_span(i),
+                (true, false) => self.free[i].end = start,
+                (false, true) => self.free[i].start = end,
+                (true, true) => {
+                    self.insert_span(i + 1, Span { start: end, end: span.end })?;
+                    self.free[i].end = start;
+                }
+            }
+            return Ok(VirtRange(start..start + size));
+        }
+        Err(VmemError::OutOfSpace)
+    }
+
+    /// Free a range previously returned by `alloc`, along with its guard gap.
+    pub fn free(&mut self, range: &VirtRange) -> Result<(), VmemError> {
+        let size = round_up2_usize(range.size(), self.quantum);
+        let start = range.start();
+        let end = start + size + self.guard;
+        if size == 0 || start < self.base || end > self.base + self.size {
+            return Err(VmemError::NotAllocated);
+        }
+
+        // Find the first free span after the range being freed.  Neither it
+        // nor its predecessor may overlap, otherwise this is a double free.
+        let i = self.free[..self.nfree].partition_point(|s| s.start < start);
+        let prev = i.checked_sub(1).map(|j| self.free[j]);
+        let next = (i < self.nfree).then(|| self.free[i]);
+        if prev.is_some_and(|p| p.end > start) || next.is_some_and(|n| n.start < end) {
+            return Err(VmemError::NotAllocated);
+        }
+
+        // Coalesce with neighbours where possible
+        let merge_prev = prev.is_some_and(|p| p.end == start);
+        let merge_next = next.is_some_and(|n| n.start == end);
+        match (merge_prev, merge_next) {
+            (true, true) => {
+                self.free[i - 1].end = self.free[i].end;
+                self.remove_span(i);
+            }
+            (true, false) => self.free[i - 1].end = end,
+            (false, true) => self.free[i].start = start,
+            (false, false) => self.insert_span(i, Span { start, end })?,
+        }
+        Ok(())
+    }
+
+    /// Return a tuple of (bytes used, total bytes available) in the arena.
+    /// Used bytes include guard gaps.
+    pub fn usage_bytes(&self) -> (usize, usize) {
+        let free: usize = self.free[..self.nfree].iter().map(|s| s.end - s.start).sum();
+        (self.size - free, self.size)
+    }
+
+    fn insert_span(&mut self, i: usize, span: Span) -> Result<(), VmemError> {
+        if self.nfree >= MAX_SPANS {
+            return Err(VmemError::TooFragmented);
+        }
+        self.free.copy_within(i..self.nfree, i + 1);
+        self.free[i] = span;
+        self.nfree += 1;
+        Ok(())
+    }
+
+    fn remove_span(&mut self, i: usize) {
+        self.free.copy_within(i + 1..self.nfree, i);
+        self.nfree -= 1;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PAGE: usize = 4096;
+
+    fn arena() -> VmemArena<4> {
+        VmemArena::new("test", 0x10_0000, 16 * PAGE, PAGE, PAGE)
+    }
+
+    #[test]
+    fn alloc_leaves_guard_gaps() {
+        let mut a = arena();
+        let r1 = a.alloc(PAGE, PAGE).unwrap();
+        let r2 = a.alloc(100, PAGE).unwrap();
+        assert_eq!(r1.0, 0x10_0000..0x10_1000);
+        assert_eq!(r2.0, 0x10_2000..0x10_3000);
+        assert_eq!(a.usage_bytes(), (4 * PAGE, 16 * PAGE));
+    }
+
+    #[test]
+    fn alloc_aligned() {
+        let mut a = arena();
+        let _ = a.alloc(PAGE, PAGE).unwrap();
+        let r = a.alloc(PAGE, 4 * PAGE).unwrap();
+        assert_eq!(r.start() % (4 * PAGE), 0);
+        assert_eq!(r.0, 0x10_4000..0x10_5000);
+
+        // The gap below the aligned allocation remains available
+        let r = a.alloc(PAGE, PAGE).unwrap();
+        assert_eq!(r.0, 0x10_2000..0x10_3000);
+    }
+
+    #[test]
+    fn free_coalesces() {
+        let mut a = arena();
+        let r1 = a.alloc(PAGE, PAGE).unwrap();
+        let r2 = a.alloc(PAGE, PAGE).unwrap();
+        let r3 = a.alloc(PAGE, PAGE).unwrap();
+        a.free(&r1).unwrap();
+        a.free(&r3).unwrap();
+        a.free(&r2).unwrap();
+        assert_eq!(a.usage_bytes(), (0, 16 * PAGE));
+        assert_eq!(a.nfree, 1);
+
+        // Whole arena can be allocated again
+        assert_eq!(a.alloc(15 * PAGE, PAGE).unwrap().0, 0x10_0000..0x10_f000);
+        assert_eq!(a.alloc(PAGE, PAGE).unwrap_err(), VmemError::OutOfSpace);
+    }
+
+    #[test]
+    fn double_free_fails() {
+        let mut a = arena();
+        let r = a.alloc(2 * PAGE, PAGE).unwrap();
+        a.free(&r).unwrap();
+        assert_eq!(a.free(&r).unwrap_err(), VmemError::NotAllocated);
+        assert_eq!(a.free(&VirtRange::with_len(0, PAGE)).unwrap_err(), VmemError::NotAllocated);
+    }
+
+    #[test]
+    fn too_fragmented() {
+        let mut a = VmemArena::<2>::new("test", 0, 16 * PAGE, PAGE, 0);
+        let r: [VirtRange; 4] = core::array::from_fn(|_| a.alloc(PAGE, PAGE).unwrap());
+        a.free(&r[0]).unwrap();
+        assert_eq!(a.free(&r[2]).unwrap_err(), VmemError::TooFragmented);
+        a.free(&r[1]).unwrap();
+        a.free(&r[2]).unwrap();
+        a.free(&r[3]).unwrap();
+        assert_eq!(a.usage_bytes(), (0, 16 * PAGE));
+    }
+
+    #[test]
+    fn invalid_requests() {
+        let mut a = arena();
+        assert_eq!(a.alloc(0, PAGE).unwrap_err(), VmemError::InvalidSize);
+        assert_eq!(a.alloc(PAGE, 3).unwrap_err(), VmemError::InvalidAlignment);
+        assert_eq!(a.alloc(32 * PAGE, PAGE).unwrap_err(), VmemError::InvalidSize);
+    }
+}
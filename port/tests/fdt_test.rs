@@ -1,6 +1,7 @@
-use port::fdt::{DeviceTree, Range, RangeMapping, RegBlock, TranslatedReg};
+use port::fdt::{DeviceTree, Range, RangeMapping, RegBlock, ReservedMemory, TranslatedReg};
 
 static TEST1_DTB: &[u8] = include_bytes!("../lib/test/fdt/test1.dtb");
+static TEST2_DTB: &[u8] = include_bytes!("../lib/test/fdt/test2.dtb");
 
 #[test]
 fn find_by_path() {
@@ -205,3 +206,60 @@ fn get_translated_reg() {
         vec![TranslatedReg::Translated(RegBlock { addr: 0x3f20_1000, len: Some(0x200) })]
     );
 }
+
+#[test]
+fn memory_reservations() {
+    let dt = DeviceTree::new(TEST1_DTB).unwrap();
+    let rsv = dt.memory_reservations().collect::<Vec<RegBlock>>();
+    assert_eq!(rsv, vec![RegBlock { addr: 0x0, len: Some(0x1000) }]);
+
+    let dt = DeviceTree::new(TEST2_DTB).unwrap();
+    let rsv = dt.memory_reservations().collect::<Vec<RegBlock>>();
+    assert_eq!(
+        rsv,
+        vec![
+            RegBlock { addr: 0x0, len: Some(0x1000) },
+            RegBlock { addr: 0x87e0_0000, len: Some(0x20_0000) }
+        ]
+    );
+}
+
+#[test]
+fn reserved_memory() {
+    // Only a dynamically placed region, which has no address
+    let dt = DeviceTree::new(TEST1_DTB).unwrap();
+    let resmem = dt.reserved_memory().collect::<Vec<ReservedMemory>>();
+    assert_eq!(resmem.len(), 1);
+    assert_eq!(dt.node_name(&resmem[0].node).unwrap(), "linux,cma");
+    assert_eq!(resmem[0].reg, None);
+    assert_eq!(resmem[0].size, Some(0x400_0000));
+    assert!(resmem[0].reusable);
+    assert!(!resmem[0].no_map);
+
+    // Static regions are returned per reg entry, and disabled nodes are skipped
+    let dt = DeviceTree::new(TEST2_DTB).unwrap();
+    let resmem = dt.reserved_memory().collect::<Vec<ReservedMemory>>();
+    assert_eq!(
+        resmem.iter().flat_map(|r| dt.node_name(&r.node)).collect::<Vec<&str>>(),
+        vec![
+            "mmode_resources0@80000000",
+            "framebuffer@90000000",
+            "framebuffer@90000000",
+            "linux,cma"
+        ]
+    );
+    assert_eq!(
+        resmem.iter().map(|r| r.reg).collect::<Vec<Option<RegBlock>>>(),
+        vec![
+            Some(RegBlock { addr: 0x8000_0000, len: Some(0x4_0000) }),
+            Some(RegBlock { addr: 0x9000_0000, len: Some(0x10_0000) }),
+            Some(RegBlock { addr: 0x9020_0000, len: Some(0x1000) }),
+            None,
+        ]
+    );
+    assert_eq!(resmem[0].size, Some(0x4_0000));
+    assert!(resmem[0].no_map);
+    assert!(!resmem[1].no_map);
+    assert_eq!(resmem[3].size, Some(0x400_0000));
+    assert!(resmem[3].reusable);
+}
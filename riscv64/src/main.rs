@@ -9,7 +9,11 @@ mod allocator;
 mod platform;
 mod runtime;
 mod sbi;
+mod trap;
 mod uart16550;
+mod usermem;
+
+extern crate alloc;
 
 use port::println;
 
@@ -24,6 +28,8 @@ pub extern "C" fn main9(hartid: usize, dtb_ptr: usize) -> ! {
     let dt = unsafe { DeviceTree::from_usize(dtb_ptr).unwrap() };
     crate::devcons::init(&dt);
     platform_init();
+    trap::init();
+    usermem::init();
 
     println!();
     println!("r9 from the Internet");
@@ -1,7 +1,5 @@
 #![cfg(not(test))]
 
-extern crate alloc;
-
 use alloc::alloc::Layout;
 use core::arch::asm;
 use core::panic::PanicInfo;
@@ -19,6 +17,10 @@ fn panic(info: &PanicInfo) -> ! {
     } else {
         println!("no information available.");
     }
+
+    // List the most contended locks, in case the panic was a deadlock
+    #[cfg(feature = "lockdebug")]
+    let _ = port::lockstat::lockstat(&mut port::devcons::Console, 10);
     abort();
 }
 
@@ -0,0 +1,169 @@
+use crate::usermem;
+use alloc::boxed::Box;
+use port::cpu::CpuHooks;
+use port::exec::Start;
+use port::println;
+use port::sys9::{ARG_OFFSET, ARG_SLOT};
+use port::usermem::EBADADDR;
+
+#[cfg(not(test))]
+core::arch::global_asm!(include_str!("trap.S"));
+
+static CPU_HOOKS: CpuHooks = CpuHooks { cpu_id, splhi, splx, spllo };
+
+/// Interrupt enable bit in sstatus.
+const SSTATUS_SIE: usize = 1 << 1;
+
+/// Take traps in supervisor mode at trapvec.
+pub fn init() {
+    port::cpu::set_hooks(&CPU_HOOKS);
+
+    #[cfg(not(test))]
+    unsafe {
+        core::arch::asm!(
+            "la {tmp}, trapvec",
+            "csrw stvec, {tmp}",
+            "csrw sscratch, zero",
+            tmp = out(reg) _,
+        );
+    }
+}
+
+/// Hart id, which l.S and trap.S keep in tp while in the kernel.
+fn cpu_id() -> usize {
+    #[cfg(not(test))]
+    unsafe {
+        let id: usize;
+        core::arch::asm!("mv {}, tp", out(reg) id);
+        id
+    }
+    #[cfg(test)]
+    0
+}
+
+/// Mask interrupts, returning the previous sstatus.SIE.
+fn splhi() -> usize {
+    #[cfg(not(test))]
+    unsafe {
+        let sstatus: usize;
+        core::arch::asm!("csrrci {}, sstatus, {sie}", out(reg) sstatus, sie = const SSTATUS_SIE);
+        sstatus & SSTATUS_SIE
+    }
+    #[cfg(test)]
+    0
+}
+
+/// Restore the sstatus.SIE returned by splhi.
+fn splx(sie: usize) {
+    if sie & SSTATUS_SIE != 0 {
+        spllo();
+    }
+}
+
+/// Unmask interrupts.
+fn spllo() {
+    #[cfg(not(test))]
+    unsafe {
+        core::arch::asm!("csrsi sstatus, {sie}", sie = const SSTATUS_SIE);
+    }
+}
+
+/// Register frame at time trap was taken, built by trap.S.  x[0] only holds
+/// the kernel's tp while the hart is in user mode.
+#[derive(Clone, Debug)]
+#[repr(C)]
+pub struct TrapFrame {
+    x: [u64; 32],
+    sepc: u64,
+    sstatus: u64,
+    scause: u64,
+    stval: u64,
+}
+
+// Must match the frame built by trap.S
+const _: () = assert!(size_of::<TrapFrame>() == 36 * 8);
+
+const SP: usize = 2;
+const A0: usize = 10;
+
+/// SCAUSE for an ecall from user mode.
+const ECALL_FROM_U: u64 = 8;
+
+/// SCAUSEs for faults on loads and stores.
+const LOAD_ACCESS_FAULT: u64 = 5;
+const STORE_ACCESS_FAULT: u64 = 7;
+const LOAD_PAGE_FAULT: u64 = 13;
+const STORE_PAGE_FAULT: u64 = 15;
+
+#[unsafe(no_mangle)]
+pub extern "C" fn trap_unsafe(frame: *mut TrapFrame) {
+    unsafe { trap(frame.as_mut().unwrap()) }
+}
+
+fn trap(frame: &mut TrapFrame) {
+    if frame.scause == ECALL_FROM_U {
+        // SEPC points at the ecall, so step over it.
+        frame.sepc += 4;
+        port::syscall::syscall(frame);
+        return;
+    }
+    if matches!(
+        frame.scause,
+        LOAD_ACCESS_FAULT | STORE_ACCESS_FAULT | LOAD_PAGE_FAULT | STORE_PAGE_FAULT
+    ) && let Some(pc) = usermem::fixup(frame.sepc)
+    {
+        frame.sepc = pc;
+        return;
+    }
+
+    println!("{:#x?}", frame);
+    println!("Unhandled trap");
+    loop {
+        core::hint::spin_loop();
+    }
+}
+
+/// 9front has no riscv64 port, so system calls follow its arm64 convention:
+/// ecall with the number in a0, the arguments on the user stack, and the
+/// result in a0.
+impl port::syscall::Frame for TrapFrame {
+    fn sysno(&self) -> usize {
+        self.x[A0] as usize
+    }
+
+    fn arg(&self, i: usize) -> port::Result<u64> {
+        let mut slot = [0u8; ARG_SLOT];
+        let va = (self.x[SP] as usize).checked_add(ARG_OFFSET + i * ARG_SLOT);
+        port::usermem::copyin(va.ok_or(EBADADDR)?, &mut slot)?;
+        Ok(u64::from_le_bytes(slot))
+    }
+
+    fn set_ret(&mut self, ret: usize) {
+        self.x[A0] = ret as u64;
+    }
+
+    fn exec(&mut self, _name: &[u8], _argv: &[&[u8]]) -> port::Result<Start> {
+        // Loading needs a port::exec::UserSpace, which needs paging and
+        // per-process page tables, which are still to be written.
+        Err("exec: not supported on riscv64 yet")
+    }
+
+    fn fork(&self) -> Box<dyn port::syscall::Frame + Send> {
+        Box::new(self.clone())
+    }
+
+    #[cfg(not(test))]
+    fn touser(self: Box<Self>) -> ! {
+        unsafe extern "C" {
+            fn forkret(frame: *const TrapFrame) -> !;
+        }
+        // trap.S restores the frame from the stack
+        let frame = *self;
+        unsafe { forkret(&frame) }
+    }
+
+    #[cfg(test)]
+    fn touser(self: Box<Self>) -> ! {
+        unreachable!("trap: no user mode in tests");
+    }
+}
@@ -0,0 +1,82 @@
+/// Access to user memory for system calls.  Supervisor accesses to user
+/// pages fault unless SSTATUS.SUM is set, so copies set it for just their
+/// duration.  A fault in the copy routine, on a page that isn't mapped, say,
+/// resumes at ucopy_fault, which fails the copy.
+#[cfg(not(test))]
+use port::usermem::{EBADADDR, UserHooks};
+
+#[cfg(not(test))]
+core::arch::global_asm!(
+    r#"
+.section .text
+// ucopy(dst, src, len): copy len bytes from src to dst, with SSTATUS.SUM
+// set.  Returns 0, or -1 from ucopy_fault.
+.globl ucopy
+ucopy:
+	li	t1, 1<<18	// SSTATUS.SUM
+	csrs	sstatus, t1
+.globl ucopy_start
+ucopy_start:
+	beqz	a2, 2f
+1:	lb	t0, 0(a1)
+	sb	t0, 0(a0)
+	addi	a0, a0, 1
+	addi	a1, a1, 1
+	addi	a2, a2, -1
+	bnez	a2, 1b
+.globl ucopy_end
+ucopy_end:
+2:	li	a0, 0
+	j	3f
+.globl ucopy_fault
+ucopy_fault:
+	li	a0, -1
+3:	li	t1, 1<<18
+	csrc	sstatus, t1
+	ret
+"#
+);
+
+#[cfg(not(test))]
+unsafe extern "C" {
+    fn ucopy(dst: *mut u8, src: *const u8, len: usize) -> isize;
+    static ucopy_start: [u8; 0];
+    static ucopy_end: [u8; 0];
+    static ucopy_fault: [u8; 0];
+}
+
+#[cfg(not(test))]
+static USER_HOOKS: UserHooks = UserHooks {
+    // There are no user address spaces yet, so no user addresses are valid.
+    validaddr: |_, _, _| Err(EBADADDR),
+    copyin: |va, buf| copy(buf.as_mut_ptr(), va as *const u8, buf.len()),
+    copyout: |va, data| copy(va as *mut u8, data.as_ptr(), data.len()),
+};
+
+/// Register the copy routine with port.  Host tests use port's own hooks.
+pub fn init() {
+    #[cfg(not(test))]
+    port::usermem::set_hooks(&USER_HOOKS);
+}
+
+#[cfg(not(test))]
+fn copy(dst: *mut u8, src: *const u8, len: usize) -> port::Result<()> {
+    match unsafe { ucopy(dst, src, len) } {
+        0 => Ok(()),
+        _ => Err("fault accessing user memory"),
+    }
+}
+
+/// Where to resume after a fault at pc, if it was in a user copy.
+pub fn fixup(pc: u64) -> Option<u64> {
+    #[cfg(not(test))]
+    unsafe {
+        let copies = ucopy_start.as_ptr().addr()..ucopy_end.as_ptr().addr();
+        copies.contains(&(pc as usize)).then(|| ucopy_fault.as_ptr().addr() as u64)
+    }
+    #[cfg(test)]
+    {
+        let _ = pc;
+        None
+    }
+}
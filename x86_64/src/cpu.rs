@@ -146,3 +146,50 @@ pub(crate) unsafe fn wrgsbase(value: u64) {
         asm!("wrgsbase {}", in(reg) value, options(att_syntax));
     }
 }
+
+/// Reads control register 4.
+pub(crate) fn cr4() -> u64 {
+    let value: u64;
+    unsafe {
+        asm!("movq %cr4, {}", out(reg) value, options(att_syntax, nomem, nostack));
+    }
+    value
+}
+
+/// Writes control register 4.
+///
+/// # Safety
+/// The value must be valid for CR4, and only enable features
+/// the CPU supports and the kernel is prepared for.
+pub(crate) unsafe fn wrcr4(value: u64) {
+    unsafe {
+        asm!("movq {}, %cr4", in(reg) value, options(att_syntax, nostack));
+    }
+}
+
+/// Returns true if the CPU supports "Supervisor Mode Access
+/// Prevention" (SMAP), from CPUID leaf 7.
+pub(crate) fn has_smap() -> bool {
+    use core::arch::x86_64::{__cpuid, __cpuid_count};
+    const CPUID_SMAP: u32 = 1 << 20;
+    __cpuid(0).eax >= 7 && __cpuid_count(7, 0).ebx & CPUID_SMAP != 0
+}
+
+/// Executes the `STAC` instruction, which sets the "Alignment
+/// Check" bit (`AC`) in `RFLAGS`, allowing supervisor access to
+/// user pages while SMAP is enabled.  Must only be used if the
+/// CPU has SMAP.
+pub(crate) fn stac() {
+    unsafe {
+        asm!("stac", options(nostack));
+    }
+}
+
+/// Executes the `CLAC` instruction, which clears `AC`, so that
+/// supervisor accesses to user pages fault again.  Must only be
+/// used if the CPU has SMAP.
+pub(crate) fn clac() {
+    unsafe {
+        asm!("clac", options(nostack));
+    }
+}
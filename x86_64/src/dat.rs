@@ -1,7 +1,6 @@
 pub use crate::vsvm::{Gdt, Idt, Tss};
 
 use bitstruct::bitstruct;
-use port::dat as portdat;
 use zerocopy::FromZeros;
 
 pub const UREG_TRAPNO_OFFSET: usize = 19 * core::mem::size_of::<u64>();
@@ -34,13 +33,13 @@ impl HPA {
 #[repr(C)]
 pub struct Ureg {
     // Pushed by software.
-    ax: u64,
+    pub ax: u64,
     bx: u64,
     cx: u64,
     dx: u64,
     si: u64,
     di: u64,
-    bp: u64,
+    pub bp: u64,
     r8: u64,
     r9: u64,
     r10: u64,
@@ -73,7 +72,7 @@ pub struct Ureg {
     pub pc: u64,
     cs: u64,
     flags: u64,
-    sp: u64,
+    pub sp: u64,
     ss: u64,
 }
 
@@ -134,10 +133,10 @@ pub struct Mach {
     pub zero: Page,           // Mapped to (per-node) read-only zeroed page
     pub stack: KStack,        // Kernel stack for scheduler
 
-    me: *mut Mach,            // %gs:0 is a `*mut Mach` pointing to this `Mach`.
-    scratch: usize,           // A scratch word used on entry to kernel
-    splpc: usize,             // PC of last caller to ` k`.  Cleared by `spllo`.
-    proc: *mut portdat::Proc, // Current process on this process.
+    me: *mut Mach,  // %gs:0 is a `*mut Mach` pointing to this `Mach`.
+    scratch: usize, // A scratch word used on entry to kernel
+    splpc: usize,   // PC of last caller to ` k`.  Cleared by `spllo`.
+    kstack: usize,  // Top of the running process's kernel stack
 
     machno: u32,  // Logical ID of CPU.
     cpuno: u32,   // Physical ID of CPU.
@@ -183,12 +182,25 @@ impl Mach {
             (trap::BREAKPOINT_TRAPNO, &mut self.bp_stack),
             (trap::DOUBLE_FAULT_TRAPNO, &mut self.df_stack),
         ]);
+        self.kstack = self.stack.top_mut().addr();
         unsafe {
             self.gdt.load();
             self.idt.load();
             self.tss.load();
         }
     }
+
+    /// Enter the kernel on the stack ending at top from now on, both for
+    /// interrupts from user mode, through the TSS, and for system calls.
+    pub fn set_kstack(&mut self, top: usize) {
+        self.kstack = top;
+        self.tss.set_rsp0(top);
+    }
+
+    /// Returns the logical ID of the CPU.
+    pub fn machno(&self) -> usize {
+        self.machno as usize
+    }
 }
 
 #[repr(u8)]
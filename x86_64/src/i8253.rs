@@ -0,0 +1,21 @@
+//! The 8253 (or 8254) programmable interval timer, whose first counter
+//! interrupts on IRQ 0.  It's the clock that drives the scheduler.
+
+use crate::pio::outb;
+use port::sched::HZ;
+
+const COUNTER0: u16 = 0x40;
+const MODE: u16 = 0x43;
+
+const FREQ: usize = 1_193_182; // Input clock, in Hz
+const RATE_GENERATOR: u8 = 0x34; // Counter 0, low then high byte, mode 2
+
+/// Have counter 0 interrupt HZ times a second.
+pub fn init() {
+    const COUNT: u16 = (FREQ / HZ) as u16;
+    unsafe {
+        outb(MODE, RATE_GENERATOR);
+        outb(COUNTER0, COUNT as u8);
+        outb(COUNTER0, (COUNT >> 8) as u8);
+    }
+}
@@ -0,0 +1,47 @@
+//! The pair of 8259 programmable interrupt controllers on the PC, which
+//! deliver the legacy ISA interrupts.  They're remapped above the CPU's own
+//! exceptions, and everything but the clock is masked.
+
+use crate::pio::outb;
+
+const MASTER_CMD: u16 = 0x20;
+const MASTER_DATA: u16 = 0x21;
+const SLAVE_CMD: u16 = 0xa0;
+const SLAVE_DATA: u16 = 0xa1;
+
+const ICW1_INIT: u8 = 0x11; // Edge triggered, cascaded, ICW4 follows
+const ICW4_8086: u8 = 0x01;
+const EOI: u8 = 0x20;
+
+/// Vector of IRQ 0.  IRQs 0 to 15 take the 16 vectors from here.
+pub const IRQ_BASE: u8 = 32;
+
+/// The interval timer's IRQ.
+pub const IRQ_CLOCK: u8 = 0;
+
+/// Remap both controllers to IRQ_BASE, and unmask only the clock.
+pub fn init() {
+    unsafe {
+        outb(MASTER_CMD, ICW1_INIT);
+        outb(SLAVE_CMD, ICW1_INIT);
+        outb(MASTER_DATA, IRQ_BASE);
+        outb(SLAVE_DATA, IRQ_BASE + 8);
+        outb(MASTER_DATA, 1 << 2); // Slave on IRQ 2
+        outb(SLAVE_DATA, 2); // Its cascade identity
+        outb(MASTER_DATA, ICW4_8086);
+        outb(SLAVE_DATA, ICW4_8086);
+
+        outb(MASTER_DATA, !(1 << IRQ_CLOCK));
+        outb(SLAVE_DATA, 0xff);
+    }
+}
+
+/// Acknowledge the IRQ, so the controllers deliver the next.
+pub fn eoi(irq: u8) {
+    unsafe {
+        if irq >= 8 {
+            outb(SLAVE_CMD, EOI);
+        }
+        outb(MASTER_CMD, EOI);
+    }
+}
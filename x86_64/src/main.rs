@@ -10,32 +10,28 @@ mod allocator;
 mod cpu;
 mod dat;
 mod devcons;
+mod i8253;
+mod i8259;
 mod node0;
 mod pio;
 mod proc;
 mod syscall;
 mod trap;
 mod uart16550;
+mod usermem;
 mod vsvm;
 
-use proc::{Label, swtch};
+extern crate alloc;
+
+use port::sched;
 
 #[cfg(not(test))]
 core::arch::global_asm!(include_str!("l.S"), options(att_syntax));
 
 use port::println;
 
-static mut THRSTACK: [u64; 1024] = [0; 1024];
-static mut CTX: u64 = 0;
-static mut THR: u64 = 0;
-
-fn jumpback() {
+fn thread(_: usize) {
     println!("in a thread");
-    unsafe {
-        let thr = &mut *(THR as *mut Label);
-        let ctx = &mut *(CTX as *mut Label);
-        swtch(thr, ctx);
-    }
 }
 
 #[cfg_attr(not(test), unsafe(no_mangle))]
@@ -43,26 +39,19 @@ pub extern "C" fn main(mach: &mut dat::Mach, _mbdata: u64) {
     unsafe {
         vsvm::init(mach);
     }
+    port::cpu::set_hooks(&trap::CPU_HOOKS);
+    sched::init::<proc::SchedArch>();
     syscall::init();
-    let x = trap::splhi();
+    usermem::init();
+    trap::splhi();
     devcons::init();
     println!();
     println!("r9 from the Internet");
-    println!("looping now");
-    let mut ctx = Label::new();
-    let mut thr = Label::new();
-    thr.pc = jumpback as usize as u64;
-    unsafe {
-        thr.sp = &mut THRSTACK[1023] as *mut _ as u64;
-        CTX = &mut ctx as *mut _ as u64;
-        THR = &mut thr as *mut _ as u64;
-        swtch(&mut ctx, &mut thr);
-    }
-    println!("came out the other side of a context switch");
-    trap::splx(x);
-    loop {
-        trap::spllo();
-    }
+    i8259::init();
+    i8253::init();
+    sched::kproc("thread", thread, 0).expect("couldn't create thread");
+    println!("scheduling now");
+    sched::schedinit();
 }
 
 mod runtime;
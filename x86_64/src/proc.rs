@@ -1,5 +1,7 @@
 pub use crate::dat::Label;
-use core::arch::naked_asm;
+use crate::dat::{KStack, Mach};
+use core::arch::{asm, naked_asm};
+use port::sched::{self, Context};
 
 #[unsafe(naked)]
 pub unsafe extern "C" fn swtch(save: &mut Label, next: &mut Label) {
@@ -29,3 +31,32 @@ pub unsafe extern "C" fn swtch(save: &mut Label, next: &mut Label) {
         options(att_syntax)
     );
 }
+
+/// Context switching for the portable scheduler, which keeps a `Label` in
+/// each `port::sched::Context`.
+pub struct SchedArch;
+
+impl sched::Arch for SchedArch {
+    const KSTACK_SIZE: usize = size_of::<KStack>();
+
+    unsafe fn swtch(save: &mut Context, next: &mut Context) {
+        unsafe { swtch(save.regs_mut(), next.regs_mut()) }
+    }
+
+    fn init_context(ctx: &mut Context, start: extern "C" fn() -> !, stack_top: usize) {
+        let label = unsafe { ctx.regs_mut::<Label>() };
+        *label = Label::new();
+        label.pc = start as usize as u64;
+        // swtch returns to pc from the top of the stack, leaving sp aligned
+        // as if start had been called.
+        label.sp = ((stack_top & !15) - 16) as u64;
+    }
+
+    fn set_kstack(stack_top: usize) {
+        let mach: *mut Mach;
+        unsafe {
+            asm!("movq %gs:0, {}", out(reg) mach, options(att_syntax, nostack, readonly, preserves_flags));
+            (*mach).set_kstack(stack_top);
+        }
+    }
+}
@@ -1,12 +1,18 @@
 #![cfg(not(test))]
 
-extern crate alloc;
-
 use alloc::alloc::Layout;
 use core::panic::PanicInfo;
 
+use port::println;
+
 #[panic_handler]
-pub fn panic(_info: &PanicInfo) -> ! {
+pub fn panic(info: &PanicInfo) -> ! {
+    println!("{}\n", info);
+
+    // List the most contended locks, in case the panic was a deadlock
+    #[cfg(feature = "lockdebug")]
+    let _ = port::lockstat::lockstat(&mut port::devcons::Console, 10);
+
     #[allow(clippy::empty_loop)]
     loop {}
 }
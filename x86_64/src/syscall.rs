@@ -3,7 +3,11 @@
 use crate::cpu;
 use crate::dat;
 use crate::vsvm;
+use port::exec::Start;
+use port::sys9::{ARG_OFFSET, ARG_SLOT};
+use port::usermem::EBADADDR;
 
+use alloc::boxed::Box;
 use core::arch::naked_asm;
 
 pub(crate) fn init() {
@@ -17,9 +21,45 @@ pub(crate) fn init() {
     }
 }
 
-extern "C" fn dispatch(user: &mut dat::Ureg, sysno: u32) -> i64 {
-    crate::println!("Got a system call ({sysno}): {user:#x?}");
-    0
+/// System calls follow 9front's amd64 convention: the number in %rbp, the
+/// arguments on the user stack, and the result in %rax.
+impl port::syscall::Frame for dat::Ureg {
+    fn sysno(&self) -> usize {
+        self.bp as usize
+    }
+
+    fn arg(&self, i: usize) -> port::Result<u64> {
+        let mut slot = [0u8; ARG_SLOT];
+        let va = (self.sp as usize).checked_add(ARG_OFFSET + i * ARG_SLOT);
+        port::usermem::copyin(va.ok_or(EBADADDR)?, &mut slot)?;
+        Ok(u64::from_le_bytes(slot))
+    }
+
+    fn set_ret(&mut self, ret: usize) {
+        self.ax = ret as u64;
+    }
+
+    fn exec(&mut self, _name: &[u8], _argv: &[&[u8]]) -> port::Result<Start> {
+        // Loading needs a port::exec::UserSpace, which needs per-process
+        // page tables, which are still to be written.
+        Err("exec: not supported on x86_64 yet")
+    }
+
+    fn fork(&self) -> Box<dyn port::syscall::Frame + Send> {
+        Box::new(self.clone())
+    }
+
+    fn touser(self: Box<Self>) -> ! {
+        // `ret` restores the Ureg from the stack
+        let user = *self;
+        unsafe { forkret(&user) }
+    }
+}
+
+/// Make the system call, returning its result, which `ret` leaves in %rax.
+extern "C" fn dispatch(user: &mut dat::Ureg) -> u64 {
+    port::syscall::syscall(user);
+    user.ax
 }
 
 /// This is the system call entry handler, that is invoked by
@@ -28,9 +68,9 @@ extern "C" fn dispatch(user: &mut dat::Ureg, sysno: u32) -> i64 {
 /// On entry:
 ///  - The user %rip is in %rcx (hardware)
 ///  - The user %rflags is in %r11 (hardware)
-///  - System call number is in %rax (software convention)
-///  - Up to 6 system call arguments are passed in %rdi, %rsi,
-///    %rdx, %r10, %r8, and $9, respectively.
+///  - System call number is in %rbp (9front convention)
+///  - The system call arguments are on the user stack, in
+///    8 byte slots starting just above the return address.
 ///
 /// None of the other general purpose registers are handled
 /// specially.
@@ -43,8 +83,9 @@ unsafe extern "C" fn entry() {
 
         // Stash the user stack pointer in the Mach's
         // `scratch` field, and set the kernel stack
-        // pointer.  The kernel stack is known to be
-        // just below the proc.
+        // pointer to the top of the running process's
+        // kernel stack, which the scheduler keeps in
+        // the Mach's `kstack` field.
         movq	%rsp, %gs:8
         movq	%gs:24, %rsp
 
@@ -53,13 +94,13 @@ unsafe extern "C" fn entry() {
         // We push dummy values for them anyway.
         subq    $(26*8), %rsp       // mem::size_of::<dat::Ureg>()
 
-        movq    %rax, 0*8(%rsp)     // ureg.ax  (syscall number)
+        movq    %rax, 0*8(%rsp)     // ureg.ax
         movq    %rbx, 1*8(%rsp)     // ureg.bx
         movq    %rcx, 2*8(%rsp)     // ureg.cx  (user pc)
         movq    %rdx, 3*8(%rsp)     // ureg.dx
         movq    %rsi, 4*8(%rsp)     // ureg.si
         movq    %rdi, 5*8(%rsp)     // ureg.di
-        movq    %rbp, 6*8(%rsp)     // ureg.bp
+        movq    %rbp, 6*8(%rsp)     // ureg.bp  (syscall number)
         movq    %r8, 7*8(%rsp)      // ureg.r8
         movq    %r9, 8*8(%rsp)      // ureg.r9
         movq    %r10, 9*8(%rsp)     // ureg.r10
@@ -104,9 +145,7 @@ unsafe extern "C" fn entry() {
         pushq   %rcx                // ret is user PC
         movq    %gs:8, %rbp         // user sp
 
-        // System call number is 4th argument to `syscall` function.
-        movq    %rsp, %rdi          // *mut Ureg is first argument
-        movq    %rax, %rsi          // system call num is second arg
+        movq    %rsp, %rdi          // *mut Ureg is the only argument
 
         // Call the handler in Rust.
         // XXX: Could we `sti` here?
@@ -178,3 +217,18 @@ pub unsafe extern "C" fn ret() {
         options(att_syntax)
     );
 }
+
+/// Return to user mode from a copy of a Ureg, as an rforked child does.
+/// Like the system call it copies, it leaves the kernel stack empty.
+#[unsafe(naked)]
+unsafe extern "C" fn forkret(user: *const dat::Ureg) -> ! {
+    naked_asm!(
+        r#"
+        movq    %rdi, %rsp          // *const Ureg is the only argument
+        movq    0*8(%rsp), %rax     // ureg.ax is the return value
+        jmp     {syscallret}
+        "#,
+        syscallret = sym ret,
+        options(att_syntax)
+    );
+}
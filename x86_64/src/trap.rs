@@ -1,13 +1,18 @@
 use crate::cpu;
-use crate::dat::Ureg;
+use crate::dat::{Mach, Ureg};
 use crate::dat::{UREG_CS_OFFSET, UREG_TRAPNO_OFFSET};
+use crate::{i8259, usermem};
 
-use core::arch::naked_asm;
+use core::arch::{asm, naked_asm};
+use port::cpu::CpuHooks;
+use port::sched;
 
 pub const DEBUG_TRAPNO: u8 = 1;
 pub const NMI_TRAPNO: u8 = 2;
 pub const BREAKPOINT_TRAPNO: u8 = 3;
 pub const DOUBLE_FAULT_TRAPNO: u8 = 8;
+pub const GENERAL_PROTECTION_TRAPNO: u8 = 13;
+pub const PAGE_FAULT_TRAPNO: u8 = 14;
 
 type Thunk = unsafe extern "C" fn();
 
@@ -222,7 +227,49 @@ pub fn splx(x: IntrStatus) -> IntrStatus {
     }
 }
 
+/// How the portable code masks interrupts and finds the current CPU.  Must
+/// only be installed once %gs points to the current `Mach`.
+pub static CPU_HOOKS: CpuHooks = CpuHooks {
+    cpu_id: machno,
+    splhi: || splhi() as usize,
+    splx: |x| {
+        splx(if x == 0 { IntrStatus::Disabled } else { IntrStatus::Enabled });
+    },
+    spllo: || {
+        spllo();
+    },
+};
+
+fn machno() -> usize {
+    let mach: *const Mach;
+    unsafe {
+        asm!("movq %gs:0, {}", out(reg) mach, options(att_syntax, nostack, readonly, preserves_flags));
+        (*mach).machno()
+    }
+}
+
 extern "C" fn trap(vector: u8, trap_frame: &mut Ureg) -> u32 {
+    // The clock drives the scheduler.  Acknowledge it before anything that
+    // might switch away, so the next tick isn't held up.
+    if vector == i8259::IRQ_BASE + i8259::IRQ_CLOCK {
+        i8259::eoi(i8259::IRQ_CLOCK);
+        sched::tick();
+        sched::preempt();
+        return 0;
+    }
+    // The 8259 raises IRQ 7 spuriously when an interrupt goes away before
+    // it's delivered.  It isn't in service, so mustn't be acknowledged.
+    if vector == i8259::IRQ_BASE + 7 {
+        return 0;
+    }
+    // A fault copying user memory fails the copy.  A bad address
+    // that isn't canonical causes a general protection fault.
+    if matches!(vector, GENERAL_PROTECTION_TRAPNO | PAGE_FAULT_TRAPNO)
+        && let Some(pc) = usermem::fixup(trap_frame.pc)
+    {
+        trap_frame.pc = pc;
+        return 0;
+    }
     unsafe { core::arch::asm!("cli;hlt;") };
     crate::println!("trap {vector}");
     crate::println!("frame: {trap_frame:#x?}");
@@ -0,0 +1,87 @@
+//! Access to user memory for system calls.
+//!
+//! When the CPU supports it, SMAP is enabled, so the kernel faults
+//! if it touches a user page by accident.  Copies deliberately
+//! touching user memory set `RFLAGS.AC` with `stac` for just the
+//! duration of the copy, and clear it again with `clac`.
+//!
+//! A fault during the copy itself, on a page that isn't mapped,
+//! say, is recovered by `trap`, which resumes at `ucopy_fault`,
+//! failing the copy.
+
+use crate::cpu;
+
+use core::arch::naked_asm;
+use core::sync::atomic::{AtomicBool, Ordering};
+use port::usermem::{EBADADDR, UserHooks};
+
+/// Whether SMAP is enabled, and so `stac` and `clac` are needed.
+static SMAP: AtomicBool = AtomicBool::new(false);
+
+static USER_HOOKS: UserHooks = UserHooks {
+    // There are no user address spaces yet, so no user
+    // addresses are valid.
+    validaddr: |_, _, _| Err(EBADADDR),
+    copyin: |va, buf| ucopy(buf.as_mut_ptr(), va as *const u8, buf.len()),
+    copyout: |va, data| ucopy(va as *mut u8, data.as_ptr(), data.len()),
+};
+
+pub(crate) fn init() {
+    const CR4_SMAP: u64 = 1 << 21;
+    if cpu::has_smap() {
+        unsafe { cpu::wrcr4(cpu::cr4() | CR4_SMAP) };
+        SMAP.store(true, Ordering::Relaxed);
+    }
+    port::usermem::set_hooks(&USER_HOOKS);
+}
+
+unsafe extern "C" {
+    static ucopy_start: [u8; 0];
+    static ucopy_end: [u8; 0];
+    static ucopy_fault: [u8; 0];
+}
+
+/// Copies memory to or from user space, with user access
+/// enabled if SMAP is on.
+fn ucopy(dst: *mut u8, src: *const u8, len: usize) -> port::Result<()> {
+    let smap = SMAP.load(Ordering::Relaxed);
+    if smap {
+        cpu::stac();
+    }
+    let r = unsafe { ucopy_raw(dst, src, len) };
+    if smap {
+        cpu::clac();
+    }
+    if r == 0 { Ok(()) } else { Err("fault accessing user memory") }
+}
+
+/// Copies len bytes from src to dst, returning 0, or -1 if the
+/// copy faulted.  Only the `rep movsb` can fault.
+#[unsafe(naked)]
+unsafe extern "C" fn ucopy_raw(dst: *mut u8, src: *const u8, len: usize) -> i64 {
+    naked_asm!(
+        r#"
+        movq    %rdx, %rcx
+        .globl  ucopy_start
+        ucopy_start:
+        rep movsb
+        .globl  ucopy_end
+        ucopy_end:
+        xorl    %eax, %eax
+        ret
+
+        .globl  ucopy_fault
+        ucopy_fault:
+        movq    $-1, %rax
+        ret
+        "#,
+        options(att_syntax)
+    );
+}
+
+/// Where to resume after a fault at pc, if it was in a user copy.
+pub(crate) fn fixup(pc: u64) -> Option<u64> {
+    let (start, end, fault) =
+        unsafe { (ucopy_start.as_ptr(), ucopy_end.as_ptr(), ucopy_fault.as_ptr()) };
+    (start.addr()..end.addr()).contains(&(pc as usize)).then_some(fault.addr() as u64)
+}
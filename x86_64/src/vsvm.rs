@@ -319,13 +319,16 @@ impl Tss {
         self.iomb = core::mem::size_of::<Tss>() as u16;
     }
 
-    pub fn set_rsp0(&mut self, stack: &mut dyn Stack) {
-        self.set_stack(0, stack);
+    pub fn set_rsp0(&mut self, top: usize) {
+        self.set_stack_top(0, top);
     }
 
     fn set_stack(&mut self, trapno: u8, stack: &mut dyn Stack) {
+        self.set_stack_top(trapno, stack.top_mut().addr());
+    }
+
+    fn set_stack_top(&mut self, trapno: u8, va: usize) {
         let index = IstIndex::from_trap(trapno);
-        let va = stack.top_mut().addr();
         let lower = va.get_bits(0..32) as u32;
         let upper = va.get_bits(32..64) as u32;
         match index {
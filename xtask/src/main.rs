@@ -678,6 +678,17 @@ impl TestStep {
             "port".to_string(),
             "--lib".to_string(),
         ]);
+        // The heapdebug allocator walks frame pointers to find
+        // who allocated, so its tests need them kept, as the
+        // kernel targets do.
+        all_cmd_args.push(vec![
+            "test".to_string(),
+            "--package".to_string(),
+            "port".to_string(),
+            "--lib".to_string(),
+            "--features".to_string(),
+            "heapdebug".to_string(),
+        ]);
 
         let rustup_state = RustupState::new();
 
@@ -697,6 +708,10 @@ impl TestStep {
             let mut cmd = Command::new(cargo());
             cmd.current_dir(workspace());
 
+            if cmd_args.iter().any(|arg| arg == "heapdebug") {
+                let rustflags = env::var("RUSTFLAGS").unwrap_or_default();
+                cmd.env("RUSTFLAGS", format!("{rustflags} -C force-frame-pointers=yes"));
+            }
             cmd.args(cmd_args);
             if self.json_output {
                 cmd.arg("--message-format=json").arg("--quiet");